@@ -0,0 +1,126 @@
+use async_trait::async_trait;
+use std::env;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::process;
+use tracing::{info, warn};
+
+use pingora_core::server::{ListenFds, ShutdownWatch};
+use pingora_core::services::Service;
+
+// The first descriptor systemd passes, after stdin/stdout/stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listening sockets passed by systemd socket activation, in the
+/// order of the unit's `ListenStream=` lines.
+///
+/// Returns nothing unless `LISTEN_PID` names this process. The variables are
+/// cleared afterwards so child processes don't try to claim the sockets.
+pub fn take_listen_fds() -> Vec<RawFd> {
+    let pid_matches = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.trim().parse::<u32>().ok())
+        .is_some_and(|pid| pid == process::id());
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.trim().parse::<RawFd>().ok());
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    match count {
+        Some(count) if pid_matches => (LISTEN_FDS_START..LISTEN_FDS_START + count).collect(),
+        Some(_) => {
+            warn!("Ignoring LISTEN_FDS meant for another process");
+            Vec::new()
+        }
+        None => Vec::new(),
+    }
+}
+
+// What pingora uses for the listeners it binds itself
+const LISTEN_BACKLOG: i32 = 65535;
+
+/// Binds a listening socket on `addr`, as pingora would, for listeners that
+/// have to be bound before the server starts. With `interface` the socket
+/// only accepts connections arriving on that network interface
+/// (`SO_BINDTODEVICE`, Linux only). IPv6 sockets are IPv6 only, so `[::]`
+/// and `0.0.0.0` can listen on the same port side by side.
+pub fn bind_listener(addr: SocketAddr, interface: Option<&str>) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if let Some(interface) = interface {
+        #[cfg(target_os = "linux")]
+        socket.bind_device(Some(interface.as_bytes()))?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("binding to interface {} is only supported on Linux", interface),
+        ));
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Wraps a listening service so it accepts on an inherited socket instead
+/// of binding its address.
+///
+/// Pingora looks up each listen address in the fd table it also uses for
+/// graceful upgrades, so registering the socket there under the service's
+/// address is enough for it to be picked up (and handed on at upgrade).
+pub struct InheritedListener<S> {
+    service: S,
+    addr: String,
+    fd: RawFd,
+}
+
+impl<S> InheritedListener<S> {
+    pub fn new(service: S, addr: &str, fd: RawFd) -> Self {
+        // Safety: systemd hands these descriptors to this process exclusively
+        // and each is claimed by a single listener
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(local) => info!("Using inherited socket (fd {}, bound to {}) for {}", fd, local, addr),
+            Err(e) => warn!("Inherited fd {} for {} does not look like a TCP socket: {}", fd, addr, e),
+        }
+        Self::with_listener(service, addr, listener)
+    }
+
+    /// Wraps a listening service around a socket bound before the server
+    /// starts, such as one on port 0 whose real address the service needs
+    pub fn with_listener(service: S, addr: &str, listener: TcpListener) -> Self {
+        // The event loop needs it non-blocking; systemd leaves that to the service
+        if let Err(e) = listener.set_nonblocking(true) {
+            warn!("Failed to make the socket for {} non-blocking: {}", addr, e);
+        }
+        InheritedListener {
+            service,
+            addr: addr.to_string(),
+            fd: listener.into_raw_fd(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for InheritedListener<S> {
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        match &fds {
+            Some(table) => table.lock().await.add(self.addr.clone(), self.fd),
+            None => warn!("No listener fd table available, {} will bind instead of using fd {}", self.addr, self.fd),
+        }
+        self.service.start_service(fds, shutdown).await
+    }
+
+    fn name(&self) -> &str {
+        self.service.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.service.threads()
+    }
+}
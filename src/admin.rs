@@ -0,0 +1,628 @@
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Instant;
+
+use pingora_core::apps::http_app::ServeHttp;
+use pingora_core::listeners::Listeners;
+use pingora_core::protocols::http::ServerSession;
+use pingora_core::server::{ListenFds, ShutdownWatch};
+use pingora_core::services::listening::Service;
+
+use crate::connection::{BackendTotals, BackendTraffic};
+use crate::metrics::{LatencyHistogram, SizeHistogram};
+use crate::rate::ConnectionRate;
+use crate::registry::ConnectionRegistry;
+use crate::summary::DRAIN_CHECK_INTERVAL;
+use crate::{ListenMode, ProxyMapping};
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The commit the binary was built from, or "unknown" outside a git checkout
+pub const GIT_HASH: &str = env!("PJ_GIT_HASH");
+
+/// A configured mapping as `/info` lists it
+#[derive(Debug, Clone, Serialize)]
+pub struct MappingInfo {
+    pub name: Option<String>,
+    pub listen_addr: String,
+    /// The upstreams of a forward mapping, the `server name=upstream` routes
+    /// of a TLS one, or the kind of proxy otherwise
+    pub backend: String,
+}
+
+impl From<&ProxyMapping> for MappingInfo {
+    fn from(mapping: &ProxyMapping) -> Self {
+        let backend = match mapping.mode {
+            ListenMode::Forward => mapping.proxy_addr.clone(),
+            ListenMode::Connect => "CONNECT proxy".to_string(),
+            ListenMode::Socks5 => "SOCKS5 proxy".to_string(),
+            ListenMode::Transparent => "original destination".to_string(),
+            ListenMode::Sink => "sink".to_string(),
+            ListenMode::Tls => mapping
+                .sni_routes
+                .iter()
+                .map(|(server_name, upstream)| format!("{}={}", server_name, upstream))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        MappingInfo {
+            name: mapping.name.clone(),
+            listen_addr: mapping.listen_addr.clone(),
+            backend,
+        }
+    }
+}
+
+/// HTTP admin API for inspecting the running proxy
+pub struct AdminApp {
+    registry: Arc<ConnectionRegistry>,
+    connection_rate: Option<Arc<ConnectionRate>>,
+    backend_traffic: Option<Arc<BackendTraffic>>,
+    connect_latency: Option<Arc<LatencyHistogram>>,
+    connection_sizes: Option<Arc<SizeHistogram>>,
+    started: Instant,
+    mappings: Vec<MappingInfo>,
+}
+
+impl AdminApp {
+    pub fn new(registry: Arc<ConnectionRegistry>) -> Self {
+        AdminApp {
+            registry,
+            connection_rate: None,
+            backend_traffic: None,
+            connect_latency: None,
+            connection_sizes: None,
+            started: Instant::now(),
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Count `/info` uptime from `started` rather than from when the app was built
+    pub fn with_start_time(mut self, started: Instant) -> Self {
+        self.started = started;
+        self
+    }
+
+    /// List these mappings under `/info`
+    pub fn with_mappings(mut self, mappings: Vec<MappingInfo>) -> Self {
+        self.mappings = mappings;
+        self
+    }
+
+    /// Report this rate under `/stats`
+    pub fn with_connection_rate(mut self, connection_rate: Arc<ConnectionRate>) -> Self {
+        self.connection_rate = Some(connection_rate);
+        self
+    }
+
+    /// Report these per-backend totals under `/stats`
+    pub fn with_backend_traffic(mut self, backend_traffic: Arc<BackendTraffic>) -> Self {
+        self.backend_traffic = Some(backend_traffic);
+        self
+    }
+
+    /// Expose these upstream connect times under `/metrics`
+    pub fn with_connect_latency(mut self, connect_latency: Arc<LatencyHistogram>) -> Self {
+        self.connect_latency = Some(connect_latency);
+        self
+    }
+
+    /// Expose percentiles of these bytes per connection under `/metrics`
+    pub fn with_connection_sizes(mut self, connection_sizes: Arc<SizeHistogram>) -> Self {
+        self.connection_sizes = Some(connection_sizes);
+        self
+    }
+
+    fn route(&self, method: &Method, path: &str) -> Response<Vec<u8>> {
+        match (method, path) {
+            (&Method::GET, "/connections") => json_response(StatusCode::OK, &self.registry.snapshot()),
+            (_, "/connections") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (&Method::GET, "/stats") => self.stats(),
+            (_, "/stats") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (&Method::GET, "/metrics") => self.metrics(),
+            (_, "/metrics") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (&Method::GET, "/info") => self.info(),
+            (_, "/info") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (&Method::GET, "/healthz") => self.healthz(),
+            (_, "/healthz") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (&Method::GET, "/listeners") => json_response(StatusCode::OK, &self.registry.listeners()),
+            (_, "/listeners") => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            (method, path) => {
+                if let Some(key) = path.strip_prefix("/connections/") {
+                    return match method {
+                        &Method::DELETE => self.close_connection(key),
+                        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+                    };
+                }
+                let listener_action = path
+                    .strip_prefix("/listeners/")
+                    .and_then(|rest| rest.rsplit_once('/'));
+                match listener_action {
+                    Some((addr, action)) if action == "pause" || action == "resume" => match method {
+                        &Method::POST => self.pause_listener(addr, action == "pause"),
+                        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+                    },
+                    _ => error_response(StatusCode::NOT_FOUND, "not found"),
+                }
+            }
+        }
+    }
+
+    fn stats(&self) -> Response<Vec<u8>> {
+        let rate = self.connection_rate.as_ref().map(|rate| rate.per_second()).unwrap_or(0.0);
+        json_response(
+            StatusCode::OK,
+            &serde_json::json!({
+                "active_connections": self.registry.len(),
+                "pj_connections_per_second": rate,
+                "backends": self.backend_traffic.as_ref().map(|traffic| traffic.snapshot()).unwrap_or_default(),
+            }),
+        )
+    }
+
+    /// Prometheus text exposition of the proxy's metrics
+    fn metrics(&self) -> Response<Vec<u8>> {
+        let mut body = format!(
+            "# HELP pj_active_connections Connections being relayed\n# TYPE pj_active_connections gauge\npj_active_connections {}\n",
+            self.registry.len()
+        );
+        if let Some(latency) = &self.connect_latency {
+            body.push_str(&latency.render("pj_upstream_connect_seconds", "Time taken to connect to the upstream"));
+        }
+        if let Some(sizes) = &self.connection_sizes {
+            body.push_str(&sizes.render("pj_connection_bytes", "Bytes relayed in both directions per finished connection"));
+        }
+        if let Some(rate) = &self.connection_rate {
+            body.push_str(&format!(
+                "# HELP pj_connections_per_second New connections per second over the last minute\n\
+                 # TYPE pj_connections_per_second gauge\n\
+                 pj_connections_per_second {}\n",
+                rate.per_second()
+            ));
+        }
+        body.push_str(
+            "# HELP pj_listener_last_error_timestamp_seconds When each listener's last upstream error happened, with the error as a label\n\
+             # TYPE pj_listener_last_error_timestamp_seconds gauge\n",
+        );
+        for listener in self.registry.listeners() {
+            if let (Some(error), Some(at)) = (&listener.last_error, listener.last_error_at) {
+                body.push_str(&format!(
+                    "pj_listener_last_error_timestamp_seconds{{listen_addr=\"{}\",error=\"{}\"}} {}\n",
+                    label_value(&listener.listen_addr),
+                    label_value(error),
+                    at
+                ));
+            }
+        }
+        body.push_str(
+            "# HELP pj_empty_connections_total Connections that closed without relaying a byte either way, like health checks and port scans\n\
+             # TYPE pj_empty_connections_total counter\n",
+        );
+        for listener in self.registry.listeners() {
+            body.push_str(&format!(
+                "pj_empty_connections_total{{listen_addr=\"{}\"}} {}\n",
+                label_value(&listener.listen_addr),
+                listener.empty_connections
+            ));
+        }
+        body.push_str(
+            "# HELP pj_long_connections_total Connections that stayed open past PJ_LONG_CONN_THRESHOLD\n\
+             # TYPE pj_long_connections_total counter\n",
+        );
+        for listener in self.registry.listeners() {
+            body.push_str(&format!(
+                "pj_long_connections_total{{listen_addr=\"{}\"}} {}\n",
+                label_value(&listener.listen_addr),
+                listener.long_connections
+            ));
+        }
+        body.push_str(
+            "# HELP pj_closes_total Connections a side ended, by whether it closed gracefully with a FIN or reset with an RST\n\
+             # TYPE pj_closes_total counter\n",
+        );
+        for listener in self.registry.listeners() {
+            for (kind, count) in [("graceful", listener.graceful_closes), ("reset", listener.reset_closes)] {
+                body.push_str(&format!(
+                    "pj_closes_total{{listen_addr=\"{}\",kind=\"{}\"}} {}\n",
+                    label_value(&listener.listen_addr),
+                    kind,
+                    count
+                ));
+            }
+        }
+        if let Some(traffic) = &self.backend_traffic {
+            let backends = traffic.snapshot();
+            let mut counter = |metric: &str, help: &str, value: fn(&BackendTotals) -> u64| {
+                body.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", metric, help, metric));
+                for (backend, totals) in &backends {
+                    body.push_str(&format!("{}{{backend=\"{}\"}} {}\n", metric, label_value(backend), value(totals)));
+                }
+            };
+            counter("pj_backend_connections_total", "Finished connections per backend address", |t| t.connections);
+            counter("pj_backend_bytes_sent_total", "Bytes sent to each backend by finished connections", |t| t.bytes_sent);
+            counter("pj_backend_bytes_received_total", "Bytes received from each backend by finished connections", |t| t.bytes_received);
+        }
+        build_response(StatusCode::OK, PROMETHEUS_CONTENT_TYPE, body.into_bytes())
+    }
+
+    fn info(&self) -> Response<Vec<u8>> {
+        json_response(
+            StatusCode::OK,
+            &serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "git_hash": GIT_HASH,
+                "uptime_secs": self.started.elapsed().as_secs_f64(),
+                "mappings": self.mappings,
+            }),
+        )
+    }
+
+    /// 200 while at least one listener is bound and the proxy isn't shutting
+    /// down, 503 otherwise, for liveness probes
+    fn healthz(&self) -> Response<Vec<u8>> {
+        let bound = self.registry.listeners().iter().filter(|listener| listener.bound).count();
+        let (status, state) = if self.registry.shutting_down() {
+            (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+        } else if bound == 0 {
+            (StatusCode::SERVICE_UNAVAILABLE, "no listener bound")
+        } else {
+            (StatusCode::OK, "ok")
+        };
+        json_response(status, &serde_json::json!({ "status": state, "listeners_bound": bound }))
+    }
+
+    fn pause_listener(&self, addr: &str, paused: bool) -> Response<Vec<u8>> {
+        if self.registry.set_paused(addr, paused) {
+            json_response(StatusCode::OK, &serde_json::json!({ "listen_addr": addr, "paused": paused }))
+        } else {
+            error_response(StatusCode::NOT_FOUND, &format!("no listener on {}", addr))
+        }
+    }
+
+    fn close_connection(&self, key: &str) -> Response<Vec<u8>> {
+        let key: u64 = match key.parse() {
+            Ok(key) => key,
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, &format!("invalid connection key: {}", key)),
+        };
+
+        if self.registry.close(key) {
+            json_response(StatusCode::OK, &serde_json::json!({ "key": key, "closed": true }))
+        } else {
+            error_response(StatusCode::NOT_FOUND, &format!("connection {} not found", key))
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let header = http_session.req_header();
+        let path = header.uri.path().trim_end_matches('/').to_string();
+        self.route(&header.method, &path)
+    }
+}
+
+/// Escapes `value` for use inside a quoted Prometheus label
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Vec<u8>> {
+    match serde_json::to_vec(value) {
+        Ok(body) => build_response(status, "application/json", body),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    let body = serde_json::json!({ "error": message }).to_string().into_bytes();
+    build_response(status, "application/json", body)
+}
+
+fn build_response(status: StatusCode, content_type: &'static str, body: Vec<u8>) -> Response<Vec<u8>> {
+    let mut response = Response::new(Vec::new());
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(content_type),
+    );
+    response.headers_mut().insert(http::header::CONTENT_LENGTH, body.len().into());
+    *response.body_mut() = body;
+    response
+}
+
+/// The admin service, kept answering through a graceful shutdown until the
+/// proxy's connections have drained, so `/healthz` can report the drain
+pub struct DrainingAdmin {
+    service: Service<AdminApp>,
+    registry: Arc<ConnectionRegistry>,
+}
+
+#[async_trait]
+impl pingora_core::services::Service for DrainingAdmin {
+    async fn start_service(&mut self, fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        let (stop, stopped) = tokio::sync::watch::channel(false);
+        let registry = self.registry.clone();
+        let drain = async move {
+            let _ = shutdown.changed().await;
+            registry.begin_shutdown();
+            while !registry.is_empty() {
+                tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+            }
+            let _ = stop.send(true);
+        };
+        tokio::join!(self.service.start_service(fds, stopped), drain);
+    }
+
+    fn name(&self) -> &str {
+        self.service.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.service.threads()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn admin_service(
+    addr: &str,
+    registry: Arc<ConnectionRegistry>,
+    connection_rate: Arc<ConnectionRate>,
+    backend_traffic: Arc<BackendTraffic>,
+    connect_latency: Arc<LatencyHistogram>,
+    connection_sizes: Arc<SizeHistogram>,
+    started: Instant,
+    mappings: Vec<MappingInfo>,
+) -> DrainingAdmin {
+    let service = Service::with_listeners(
+        "Admin Service".to_string(),
+        Listeners::tcp(addr),
+        AdminApp::new(registry.clone())
+            .with_connection_rate(connection_rate)
+            .with_backend_traffic(backend_traffic)
+            .with_connect_latency(connect_latency)
+            .with_connection_sizes(connection_sizes)
+            .with_start_time(started)
+            .with_mappings(mappings),
+    );
+    DrainingAdmin { service, registry }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{ClientAddr, ConnectionInfo, ConnectionStats};
+    use crate::id_manager::ConnectionIdManager;
+
+    #[test]
+    fn test_list_connections() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let info = ConnectionInfo::new(
+            ClientAddr::Inet("127.0.0.1:50000".parse().unwrap()),
+            "127.0.0.1:8080",
+            "127.0.0.1:9090",
+            1,
+            &id_manager,
+        );
+        let _registration = registry.register(&info);
+        let app = AdminApp::new(registry);
+
+        let response = app.route(&Method::GET, "/connections");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body[0]["key"], 0);
+        assert_eq!(body[0]["id"], 0);
+        assert_eq!(body[0]["client_addr"], "127.0.0.1:50000");
+        assert_eq!(body[0]["backend_addr"], "127.0.0.1:9090");
+    }
+
+    #[tokio::test]
+    async fn test_close_connection_shared_id_across_mappings() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        // Each mapping counts its own IDs, so both connections get ID 0
+        let first_ids = Arc::new(ConnectionIdManager::new(None, None));
+        let second_ids = Arc::new(ConnectionIdManager::new(None, None));
+        let info = |listen_addr: &str, id_manager| {
+            ConnectionInfo::new(ClientAddr::Inet("127.0.0.1:50000".parse().unwrap()), listen_addr, "127.0.0.1:9090", 1, id_manager)
+        };
+        let first = registry.register(&info("127.0.0.1:8080", &first_ids));
+        let second = registry.register(&info("127.0.0.1:8081", &second_ids));
+        let app = AdminApp::new(registry);
+
+        let body: serde_json::Value = serde_json::from_slice(app.route(&Method::GET, "/connections").body()).unwrap();
+        assert_eq!((&body[0]["id"], &body[1]["id"]), (&serde_json::json!(0), &serde_json::json!(0)));
+        assert_ne!(body[0]["key"], body[1]["key"]);
+        let second_key = body.as_array().unwrap().iter().find(|conn| conn["proxy_addr"] == "127.0.0.1:8081").unwrap()["key"].clone();
+
+        let response = app.route(&Method::DELETE, &format!("/connections/{}", second_key));
+        assert_eq!(response.status(), StatusCode::OK);
+        let wait = std::time::Duration::from_millis(50);
+        assert!(tokio::time::timeout(wait, second.close_requested()).await.is_ok());
+        assert!(tokio::time::timeout(wait, first.close_requested()).await.is_err(), "The other mapping's connection 0 stays open");
+    }
+
+    #[test]
+    fn test_info() {
+        let mappings = ["127.0.0.1:8080:10.0.0.1:80|10.0.0.2:80?name=web", "socks5://0.0.0.0:1080"]
+            .iter()
+            .map(|mapping| MappingInfo::from(&crate::parse_proxy_mapping(mapping).unwrap()))
+            .collect();
+        let started = Instant::now() - std::time::Duration::from_secs(90);
+        let app = AdminApp::new(Arc::new(ConnectionRegistry::new()))
+            .with_start_time(started)
+            .with_mappings(mappings);
+
+        let response = app.route(&Method::GET, "/info");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["git_hash"], GIT_HASH);
+        assert!(body["uptime_secs"].as_f64().unwrap() >= 90.0);
+        assert_eq!(
+            body["mappings"],
+            serde_json::json!([
+                { "name": "web", "listen_addr": "127.0.0.1:8080", "backend": "10.0.0.1:80|10.0.0.2:80" },
+                { "name": null, "listen_addr": "0.0.0.0:1080", "backend": "SOCKS5 proxy" },
+            ])
+        );
+        assert_eq!(app.route(&Method::POST, "/info").status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_healthz() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        registry.register_listener("127.0.0.1:8080");
+        let app = AdminApp::new(registry.clone());
+        assert_eq!(app.route(&Method::GET, "/healthz").status(), StatusCode::SERVICE_UNAVAILABLE, "Nothing is bound yet");
+
+        registry.record_bound("127.0.0.1:8080");
+        let response = app.route(&Method::GET, "/healthz");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({ "status": "ok", "listeners_bound": 1 }));
+
+        registry.begin_shutdown();
+        assert_eq!(app.route(&Method::GET, "/healthz").status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(app.route(&Method::POST, "/healthz").status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_close_connection() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let info = ConnectionInfo::new(
+            ClientAddr::Inet("127.0.0.1:50000".parse().unwrap()),
+            "127.0.0.1:8080",
+            "127.0.0.1:9090",
+            1,
+            &id_manager,
+        );
+        let _registration = registry.register(&info);
+        let app = AdminApp::new(registry);
+
+        assert_eq!(app.route(&Method::DELETE, "/connections/0").status(), StatusCode::OK);
+        assert_eq!(app.route(&Method::DELETE, "/connections/7").status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.route(&Method::DELETE, "/connections/abc").status(), StatusCode::BAD_REQUEST);
+        assert_eq!(app.route(&Method::GET, "/connections/0").status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_pause_listener() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let paused = registry.register_listener("127.0.0.1:8080");
+        let app = AdminApp::new(registry);
+
+        assert_eq!(app.route(&Method::POST, "/listeners/127.0.0.1:8080/pause").status(), StatusCode::OK);
+        assert!(paused.load(std::sync::atomic::Ordering::Relaxed));
+        let body: serde_json::Value = serde_json::from_slice(app.route(&Method::GET, "/listeners").body()).unwrap();
+        assert_eq!(body[0]["listen_addr"], "127.0.0.1:8080");
+        assert_eq!(body[0]["paused"], true);
+
+        assert_eq!(app.route(&Method::POST, "/listeners/127.0.0.1:8080/resume").status(), StatusCode::OK);
+        assert!(!paused.load(std::sync::atomic::Ordering::Relaxed));
+
+        assert_eq!(app.route(&Method::POST, "/listeners/127.0.0.1:9999/pause").status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.route(&Method::POST, "/listeners/127.0.0.1:8080/stop").status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.route(&Method::GET, "/listeners/127.0.0.1:8080/pause").status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_stats() {
+        let start = std::time::Instant::now();
+        let rate = Arc::new(ConnectionRate::new(start));
+        for _ in 0..120 {
+            rate.record_at(start);
+        }
+        let backends = Arc::new(BackendTraffic::default());
+        let mut stats = ConnectionStats::new();
+        stats.add_sent(300);
+        stats.add_received(20);
+        backends.record("10.0.0.1:80", &stats);
+        let app = AdminApp::new(Arc::new(ConnectionRegistry::new()))
+            .with_connection_rate(rate)
+            .with_backend_traffic(backends);
+
+        let response = app.route(&Method::GET, "/stats");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["active_connections"], 0);
+        assert_eq!(body["pj_connections_per_second"], 2.0);
+        assert_eq!(
+            body["backends"]["10.0.0.1:80"],
+            serde_json::json!({ "connections": 1, "bytes_sent": 300, "bytes_received": 20 })
+        );
+
+        let metrics = String::from_utf8(app.route(&Method::GET, "/metrics").body().clone()).unwrap();
+        assert!(metrics.contains("# TYPE pj_backend_connections_total counter\n"), "{}", metrics);
+        assert!(metrics.contains("pj_backend_connections_total{backend=\"10.0.0.1:80\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("pj_backend_bytes_sent_total{backend=\"10.0.0.1:80\"} 300\n"), "{}", metrics);
+        assert!(metrics.contains("pj_backend_bytes_received_total{backend=\"10.0.0.1:80\"} 20\n"), "{}", metrics);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let latency = Arc::new(LatencyHistogram::default());
+        latency.observe(std::time::Duration::from_millis(3));
+        let sizes = Arc::new(SizeHistogram::default());
+        sizes.observe(10);
+        let rate = Arc::new(ConnectionRate::default());
+        for _ in 0..120 {
+            rate.record();
+        }
+        let app = AdminApp::new(Arc::new(ConnectionRegistry::new()))
+            .with_connect_latency(latency)
+            .with_connection_sizes(sizes)
+            .with_connection_rate(rate);
+
+        let response = app.route(&Method::GET, "/metrics");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], PROMETHEUS_CONTENT_TYPE);
+        let body = String::from_utf8(response.body().clone()).unwrap();
+        assert!(body.contains("pj_active_connections 0\n"), "{}", body);
+        assert!(body.contains("pj_upstream_connect_seconds_bucket{le=\"0.001\"} 0\n"), "{}", body);
+        assert!(body.contains("pj_upstream_connect_seconds_bucket{le=\"0.005\"} 1\n"), "{}", body);
+        assert!(body.contains("pj_upstream_connect_seconds_count 1\n"), "{}", body);
+        assert!(body.contains("pj_connection_bytes{quantile=\"0.5\"} 10\n"), "{}", body);
+        assert!(body.contains("# TYPE pj_connections_per_second gauge\n"), "{}", body);
+        assert!(body.contains("pj_connections_per_second 2\n"), "{}", body);
+        assert!(!body.contains("pj_listener_last_error_timestamp_seconds{"), "No listener has failed yet: {}", body);
+        assert_eq!(app.route(&Method::POST, "/metrics").status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_last_error() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        registry.register_listener("127.0.0.1:8080");
+        let app = AdminApp::new(registry.clone());
+        registry.record_error_at(
+            "127.0.0.1:8080",
+            "10.0.0.1:80: Connection failed: \"refused\"",
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000),
+        );
+
+        let body: serde_json::Value = serde_json::from_slice(app.route(&Method::GET, "/listeners").body()).unwrap();
+        assert_eq!(body[0]["last_error"], "10.0.0.1:80: Connection failed: \"refused\"");
+        assert_eq!(body[0]["last_error_at"], 1_700_000_000.0);
+        assert_eq!(body[0]["empty_connections"], 0);
+
+        let metrics = String::from_utf8(app.route(&Method::GET, "/metrics").body().clone()).unwrap();
+        assert!(
+            metrics.contains("pj_listener_last_error_timestamp_seconds{listen_addr=\"127.0.0.1:8080\",error=\"10.0.0.1:80: Connection failed: \\\"refused\\\"\"} 1700000000\n"),
+            "{}",
+            metrics
+        );
+        assert!(metrics.contains("pj_empty_connections_total{listen_addr=\"127.0.0.1:8080\"} 0\n"), "{}", metrics);
+        assert!(metrics.contains("pj_closes_total{listen_addr=\"127.0.0.1:8080\",kind=\"reset\"} 0\n"), "{}", metrics);
+        assert!(metrics.contains("pj_long_connections_total{listen_addr=\"127.0.0.1:8080\"} 0\n"), "{}", metrics);
+    }
+
+    #[test]
+    fn test_unknown_route() {
+        let app = AdminApp::new(Arc::new(ConnectionRegistry::new()));
+        assert_eq!(app.route(&Method::GET, "/nope").status(), StatusCode::NOT_FOUND);
+        assert_eq!(app.route(&Method::POST, "/connections").status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
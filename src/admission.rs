@@ -0,0 +1,219 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Connect outcomes older than two of these no longer count
+pub const ADMISSION_WINDOW: Duration = Duration::from_secs(10);
+
+/// Connects an upstream needs in the window before it can be shed for
+const MIN_ATTEMPTS: u64 = 10;
+
+// Tunnels reach arbitrary targets; past this many upstreams the quiet ones are dropped
+const PRUNE_AT: usize = 1024;
+
+/// Sheds new connections to upstreams whose connects keep failing, so a
+/// flapping backend isn't dialed once for every client that arrives.
+///
+/// This is adaptive throttling: with `requests` connections wanting an
+/// upstream and `successes` of its connects succeeding in the window, a
+/// connection is shed with probability
+/// `(requests - successes / threshold) / (requests + 1)`. Nothing is shed
+/// while at least `threshold` of connects succeed, and as they start
+/// succeeding again more connections are let through.
+#[derive(Debug)]
+pub struct AdmissionControl {
+    threshold: f64,
+    origin: Instant,
+    upstreams: Mutex<HashMap<String, History>>,
+}
+
+/// Whether a connection may dial its upstream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Admission {
+    pub admitted: bool,
+    /// Set when this connection started (`true`) or stopped (`false`) the
+    /// upstream's shedding
+    pub shedding_changed: Option<bool>,
+    /// Share of the upstream's connects in the window that succeeded
+    pub success_ratio: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    attempts: u64,
+    successes: u64,
+    shed: u64,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    window: u64,
+    current: Counts,
+    previous: Counts,
+    shedding: bool,
+}
+
+impl History {
+    /// Moves to `window`, dropping counts that have left it
+    fn advance(&mut self, window: u64) {
+        if window == self.window {
+            return;
+        }
+        self.previous = if window == self.window + 1 { self.current } else { Counts::default() };
+        self.current = Counts::default();
+        self.window = window;
+    }
+
+    fn totals(&self) -> Counts {
+        Counts {
+            attempts: self.current.attempts + self.previous.attempts,
+            successes: self.current.successes + self.previous.successes,
+            shed: self.current.shed + self.previous.shed,
+        }
+    }
+}
+
+/// Parses the success ratio below which upstreams are shed for, as used by
+/// `PJ_ADMISSION_RATIO`
+pub fn parse_threshold(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio <= 1.0 => Ok(ratio),
+        _ => Err(format!("'{}' is not a success ratio above 0 and at most 1", s)),
+    }
+}
+
+impl AdmissionControl {
+    /// Sheds for upstreams whose connects succeed less than `threshold` (0 to 1) of the time
+    pub fn new(threshold: f64) -> Self {
+        AdmissionControl { threshold, origin: Instant::now(), upstreams: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// Decides whether a new connection may dial `upstream`
+    pub fn admit(&self, upstream: &str) -> Admission {
+        let roll = (RandomState::new().build_hasher().finish() % 1_000_000) as f64 / 1_000_000.0;
+        self.admit_at(upstream, Instant::now(), roll)
+    }
+
+    /// Decides at `now`, shedding if `roll` (0 to 1) falls under the shed probability
+    pub fn admit_at(&self, upstream: &str, now: Instant, roll: f64) -> Admission {
+        let window = self.window_at(now);
+        let mut upstreams = self.upstreams.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(history) = upstreams.get_mut(upstream) else {
+            return Admission { admitted: true, shedding_changed: None, success_ratio: 1.0 };
+        };
+        history.advance(window);
+        let totals = history.totals();
+        let success_ratio = if totals.attempts == 0 { 1.0 } else { totals.successes as f64 / totals.attempts as f64 };
+        let probability = if totals.attempts < MIN_ATTEMPTS {
+            0.0
+        } else {
+            let requests = (totals.attempts + totals.shed) as f64;
+            ((requests - totals.successes as f64 / self.threshold) / (requests + 1.0)).max(0.0)
+        };
+
+        let shedding = probability > 0.0;
+        let shedding_changed = (shedding != history.shedding).then_some(shedding);
+        history.shedding = shedding;
+        let admitted = roll >= probability;
+        if !admitted {
+            history.current.shed += 1;
+        }
+        Admission { admitted, shedding_changed, success_ratio }
+    }
+
+    /// Records how a connect to `upstream` went
+    pub fn record(&self, upstream: &str, connected: bool) {
+        self.record_at(upstream, connected, Instant::now());
+    }
+
+    pub fn record_at(&self, upstream: &str, connected: bool, at: Instant) {
+        let window = self.window_at(at);
+        let mut upstreams = self.upstreams.lock().unwrap_or_else(PoisonError::into_inner);
+        if upstreams.len() >= PRUNE_AT && !upstreams.contains_key(upstream) {
+            upstreams.retain(|_, history| history.window + 1 >= window);
+        }
+        let history = upstreams.entry(upstream.to_string()).or_default();
+        history.advance(window);
+        history.current.attempts += 1;
+        if connected {
+            history.current.successes += 1;
+        }
+    }
+
+    /// Connects to `upstream` recorded in the window
+    pub fn attempts(&self, upstream: &str) -> u64 {
+        let window = self.window_at(Instant::now());
+        let mut upstreams = self.upstreams.lock().unwrap_or_else(PoisonError::into_inner);
+        upstreams.get_mut(upstream).map_or(0, |history| {
+            history.advance(window);
+            history.totals().attempts
+        })
+    }
+
+    fn window_at(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.origin).as_secs_f64() / ADMISSION_WINDOW.as_secs_f64()) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sheds_failing_upstream_and_recovers() {
+        let admission = AdmissionControl::new(0.5);
+        let start = admission.origin;
+        assert!(admission.admit_at("10.0.0.1:80", start, 0.0).admitted, "Unknown upstreams are admitted");
+
+        // A few failures aren't enough to judge by
+        for _ in 0..MIN_ATTEMPTS - 1 {
+            admission.record_at("10.0.0.1:80", false, start);
+        }
+        assert!(admission.admit_at("10.0.0.1:80", start, 0.0).admitted);
+
+        admission.record_at("10.0.0.1:80", false, start);
+        let shed = admission.admit_at("10.0.0.1:80", start, 0.5);
+        assert_eq!(shed, Admission { admitted: false, shedding_changed: Some(true), success_ratio: 0.0 });
+        // The roll decides: shedding is probabilistic
+        let lucky = admission.admit_at("10.0.0.1:80", start, 0.99);
+        assert_eq!((lucky.admitted, lucky.shedding_changed), (true, None));
+
+        // Other upstreams are unaffected
+        for _ in 0..MIN_ATTEMPTS {
+            admission.record_at("10.0.0.2:80", true, start);
+        }
+        assert!(admission.admit_at("10.0.0.2:80", start, 0.0).admitted);
+
+        // Once the failures leave the window, the upstream is admitted again
+        let later = start + ADMISSION_WINDOW * 2;
+        for _ in 0..MIN_ATTEMPTS {
+            admission.record_at("10.0.0.1:80", true, later);
+        }
+        let recovered = admission.admit_at("10.0.0.1:80", later, 0.0);
+        assert_eq!(recovered, Admission { admitted: true, shedding_changed: Some(false), success_ratio: 1.0 });
+    }
+
+    #[test]
+    fn test_mostly_succeeding_upstream_is_not_shed() {
+        let admission = AdmissionControl::new(0.5);
+        let start = admission.origin;
+        for i in 0..20 {
+            admission.record_at("10.0.0.1:80", i % 3 != 0, start);
+        }
+        let admitted = admission.admit_at("10.0.0.1:80", start, 0.0);
+        assert!(admitted.admitted, "{:?}", admitted);
+        assert!((admitted.success_ratio - 0.65).abs() < 1e-9);
+
+        assert_eq!(parse_threshold("0.5").unwrap(), 0.5);
+        assert_eq!(parse_threshold(" 1 ").unwrap(), 1.0);
+        for input in ["0", "1.5", "-0.1", "half"] {
+            assert!(parse_threshold(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+}
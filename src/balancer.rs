@@ -0,0 +1,312 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use pingora_core::upstreams::peer::BasicPeer;
+
+use crate::connection::canonical_ip;
+
+/// How long a peer is skipped after a failed connect
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// How a mapping with several upstreams picks one for each connection
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LbStrategy {
+    /// Cycle through the healthy peers, in proportion to their weights
+    #[default]
+    RoundRobin,
+    /// Hash the client IP so each client keeps hitting the same peer
+    IpHash,
+}
+
+impl LbStrategy {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "round_robin" => Ok(LbStrategy::RoundRobin),
+            "ip_hash" => Ok(LbStrategy::IpHash),
+            other => Err(format!("Unknown load balancing strategy '{}'. Supported: round_robin, ip_hash", other)),
+        }
+    }
+}
+
+/// Splits an upstream entry into its address and optional `*weight` suffix
+/// (1 when omitted)
+pub fn parse_upstream(s: &str) -> Result<(&str, u32), String> {
+    match s.rsplit_once('*') {
+        Some((addr, weight)) => match weight.parse::<u32>() {
+            Ok(weight) if weight > 0 => Ok((addr, weight)),
+            _ => Err(format!("Invalid weight in upstream '{}'. Expected a positive integer", s)),
+        },
+        None => Ok((s, 1)),
+    }
+}
+
+/// How a failover tier is named in logs: the first is "primary", the second "secondary"
+pub fn tier_name(tier: usize) -> String {
+    match tier {
+        0 => "primary".to_string(),
+        1 => "secondary".to_string(),
+        n => format!("tier {}", n + 1),
+    }
+}
+
+/// Upstreams of a mapping that lists more than one.
+///
+/// Health is tracked passively: a peer that fails to connect is left out of
+/// selection for a short cooldown. Peers are grouped in failover tiers and
+/// picked from the first tier with a healthy peer, so a later tier only
+/// serves while every peer before it is cooling down. If every peer is
+/// cooling down the primaries are all tried again rather than refusing the
+/// connection.
+pub struct UpstreamPool {
+    peers: Vec<PoolPeer>,
+    tiers: usize,
+    strategy: LbStrategy,
+    // Smooth weighted round-robin state, one current weight per peer
+    current_weights: Mutex<Vec<i64>>,
+    // The tier the last connection was served from, to log failover and recovery once
+    serving_tier: AtomicUsize,
+}
+
+struct PoolPeer {
+    peer: BasicPeer,
+    weight: u32,
+    tier: usize,
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl UpstreamPool {
+    /// Builds a pool from `(peer, weight)` pairs
+    pub fn new(peers: Vec<(BasicPeer, u32)>, strategy: LbStrategy) -> Self {
+        Self::with_tiers(vec![peers], strategy)
+    }
+
+    /// Builds a pool from failover tiers of `(peer, weight)` pairs, primaries first
+    pub fn with_tiers(tiers: Vec<Vec<(BasicPeer, u32)>>, strategy: LbStrategy) -> Self {
+        let tier_count = tiers.len();
+        let peers: Vec<PoolPeer> = tiers
+            .into_iter()
+            .enumerate()
+            .flat_map(|(tier, peers)| {
+                peers.into_iter().map(move |(peer, weight)| PoolPeer { peer, weight, tier, failed_at: Mutex::new(None) })
+            })
+            .collect();
+        UpstreamPool {
+            current_weights: Mutex::new(vec![0; peers.len()]),
+            peers,
+            tiers: tier_count,
+            strategy,
+            serving_tier: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many failover tiers the peers are grouped in
+    pub fn tiers(&self) -> usize {
+        self.tiers
+    }
+
+    /// The failover tier `peer` belongs to
+    pub fn tier_of(&self, peer: &BasicPeer) -> Option<usize> {
+        self.peers.iter().find(|p| p.peer._address == peer._address).map(|p| p.tier)
+    }
+
+    /// Notes that a connection was served from `tier`, returning the tier
+    /// before it if that was a different one
+    pub fn record_served(&self, tier: usize) -> Option<usize> {
+        let previous = self.serving_tier.swap(tier, Ordering::Relaxed);
+        (previous != tier).then_some(previous)
+    }
+
+    /// Every peer's address, in the order they were given
+    pub fn addresses(&self) -> Vec<String> {
+        self.peers.iter().map(|p| p.peer._address.to_string()).collect()
+    }
+
+    pub fn peers_mut(&mut self) -> impl Iterator<Item = &mut BasicPeer> {
+        self.peers.iter_mut().map(|p| &mut p.peer)
+    }
+
+    /// Picks the peer for a connection from `client`
+    pub fn select(&self, client: IpAddr) -> &BasicPeer {
+        let now = Instant::now();
+        let healthy_tier = (0..self.tiers)
+            .find(|&tier| self.peers.iter().any(|p| p.tier == tier && p.is_healthy(now)))
+            .unwrap_or(0);
+        let mut healthy: Vec<usize> = (0..self.peers.len())
+            .filter(|&i| self.peers[i].tier == healthy_tier && self.peers[i].is_healthy(now))
+            .collect();
+        if healthy.is_empty() {
+            healthy = (0..self.peers.len()).filter(|&i| self.peers[i].tier == 0).collect();
+        }
+        let total_weight: i64 = healthy.iter().map(|&i| self.peers[i].weight as i64).sum();
+
+        let index = match self.strategy {
+            LbStrategy::RoundRobin => {
+                // Every healthy peer gains its weight and the leader pays back the
+                // total, which interleaves picks instead of sending them in runs
+                let mut current = self.current_weights.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut best = healthy[0];
+                for &i in &healthy {
+                    current[i] += self.peers[i].weight as i64;
+                    if current[i] > current[best] {
+                        best = i;
+                    }
+                }
+                current[best] -= total_weight;
+                best
+            }
+            LbStrategy::IpHash => {
+                // Each peer owns a slice of the hash space as wide as its weight
+                let mut slot = (ip_hash(client) % total_weight as u64) as i64;
+                *healthy
+                    .iter()
+                    .find(|&&i| {
+                        slot -= self.peers[i].weight as i64;
+                        slot < 0
+                    })
+                    .expect("slot is within the total weight")
+            }
+        };
+        &self.peers[index].peer
+    }
+
+    /// Takes `peer` out of rotation for the failure cooldown
+    pub fn mark_failed(&self, peer: &BasicPeer) {
+        if let Some(p) = self.peers.iter().find(|p| p.peer._address == peer._address) {
+            *p.failed_at.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+        }
+    }
+}
+
+impl PoolPeer {
+    fn is_healthy(&self, now: Instant) -> bool {
+        match *self.failed_at.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(failed_at) => now.duration_since(failed_at) >= FAILURE_COOLDOWN,
+            None => true,
+        }
+    }
+}
+
+// FNV-1a, so the client to peer mapping is the same across restarts
+fn ip_hash(ip: IpAddr) -> u64 {
+    let octets = match canonical_ip(ip) {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    octets.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: LbStrategy) -> UpstreamPool {
+        let peers = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"].iter().map(|a| (BasicPeer::new(a), 1)).collect();
+        UpstreamPool::new(peers, strategy)
+    }
+
+    fn address(peer: &BasicPeer) -> String {
+        peer._address.to_string()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(LbStrategy::RoundRobin);
+        let client = "127.0.0.1".parse().unwrap();
+        let picks: Vec<String> = (0..4).map(|_| address(pool.select(client))).collect();
+        assert_eq!(picks, vec!["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80", "10.0.0.1:80"]);
+    }
+
+    #[test]
+    fn test_smooth_weighted_round_robin() {
+        let peers = vec![(BasicPeer::new("10.0.0.1:80"), 3), (BasicPeer::new("10.0.0.2:80"), 1)];
+        let pool = UpstreamPool::new(peers, LbStrategy::RoundRobin);
+        let client = "127.0.0.1".parse().unwrap();
+        let picks: Vec<String> = (0..8).map(|_| address(pool.select(client))).collect();
+        // Interleaved rather than three in a row
+        assert_eq!(
+            picks,
+            vec![
+                "10.0.0.1:80", "10.0.0.1:80", "10.0.0.2:80", "10.0.0.1:80",
+                "10.0.0.1:80", "10.0.0.1:80", "10.0.0.2:80", "10.0.0.1:80",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_upstream_weight() {
+        assert_eq!(parse_upstream("10.0.0.1:9000*3").unwrap(), ("10.0.0.1:9000", 3));
+        assert_eq!(parse_upstream("10.0.0.1:9000").unwrap(), ("10.0.0.1:9000", 1));
+        assert!(parse_upstream("10.0.0.1:9000*0").is_err());
+        assert!(parse_upstream("10.0.0.1:9000*-1").is_err());
+        assert!(parse_upstream("10.0.0.1:9000*1.5").is_err());
+        assert!(parse_upstream("10.0.0.1:9000*").is_err());
+    }
+
+    #[test]
+    fn test_ip_hash_is_sticky() {
+        let pool = pool(LbStrategy::IpHash);
+        for client in ["127.0.0.1", "192.168.1.20", "2001:db8::1"] {
+            let client = client.parse().unwrap();
+            let first = address(pool.select(client));
+            assert!((0..10).all(|_| address(pool.select(client)) == first));
+        }
+
+        // Different clients spread over the peers
+        let spread: std::collections::HashSet<String> = (0..=255u8)
+            .map(|i| address(pool.select(IpAddr::from([10, 1, 0, i]))))
+            .collect();
+        assert_eq!(spread.len(), 3);
+    }
+
+    #[test]
+    fn test_failed_peer_is_skipped() {
+        let pool = pool(LbStrategy::IpHash);
+        let client = "127.0.0.1".parse().unwrap();
+        let first = pool.select(client).clone();
+
+        pool.mark_failed(&first);
+        let second = address(pool.select(client));
+        assert_ne!(second, address(&first));
+        assert!((0..10).all(|_| address(pool.select(client)) == second));
+
+        // With every peer failed, selection falls back to the full set
+        for peer in ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"] {
+            pool.mark_failed(&BasicPeer::new(peer));
+        }
+        assert_eq!(address(pool.select(client)), address(&first));
+    }
+
+    #[test]
+    fn test_failover_tiers() {
+        let primaries = vec![(BasicPeer::new("10.0.0.1:80"), 1), (BasicPeer::new("10.0.0.2:80"), 1)];
+        let pool = UpstreamPool::with_tiers(vec![primaries, vec![(BasicPeer::new("10.0.1.1:80"), 1)]], LbStrategy::RoundRobin);
+        let client = "127.0.0.1".parse().unwrap();
+        assert_eq!(pool.tiers(), 2);
+
+        // The secondary is left alone while any primary is up
+        pool.mark_failed(&BasicPeer::new("10.0.0.1:80"));
+        assert!((0..4).all(|_| address(pool.select(client)) == "10.0.0.2:80"));
+
+        pool.mark_failed(&BasicPeer::new("10.0.0.2:80"));
+        let secondary = pool.select(client).clone();
+        assert_eq!(address(&secondary), "10.0.1.1:80");
+        assert_eq!(pool.tier_of(&secondary), Some(1));
+        assert_eq!(pool.record_served(1), Some(0));
+        assert_eq!(pool.record_served(1), None);
+
+        // With every tier down, the primaries are tried again
+        pool.mark_failed(&secondary);
+        assert_eq!(pool.tier_of(pool.select(client)), Some(0));
+        assert_eq!(tier_name(0), "primary");
+        assert_eq!(tier_name(2), "tier 3");
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(LbStrategy::parse("ip_hash").unwrap(), LbStrategy::IpHash);
+        assert_eq!(LbStrategy::parse("ROUND_ROBIN").unwrap(), LbStrategy::RoundRobin);
+        assert!(LbStrategy::parse("least_conn").is_err());
+    }
+}
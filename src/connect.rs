@@ -0,0 +1,425 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+use pingora_core::protocols::Stream;
+
+use crate::connection::canonical_ip;
+use crate::eyeballs;
+
+/// Most bytes of a CONNECT request head or SOCKS5 handshake read unless
+/// `PJ_MAX_HANDSHAKE_BYTES` says otherwise
+pub const DEFAULT_MAX_HANDSHAKE: usize = 8192;
+/// How long a client has to finish sending its CONNECT request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const ESTABLISHED_RESPONSE: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+
+/// Targets a CONNECT listener is allowed to tunnel to.
+///
+/// Parsed from comma separated `host:port` entries where `host` is an exact
+/// name or IP, `*` for any host, `*.example.com` for subdomains or a CIDR
+/// block such as `10.0.0.0/8`, and `port` is a number, a range such as
+/// `8000-8999` or `*`. An empty allowlist denies everything.
+///
+/// Entries added with [`ConnectAllowlist::with_denied`] take precedence over
+/// the allowed ones. CIDR entries are checked against the addresses a target
+/// resolves to, so a name cannot be used to reach a denied network.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectAllowlist {
+    entries: Vec<TargetRule>,
+    denied: Vec<TargetRule>,
+}
+
+#[derive(Debug, Clone)]
+struct TargetRule {
+    host: HostRule,
+    ports: RangeInclusive<u16>,
+}
+
+#[derive(Debug, Clone)]
+enum HostRule {
+    Any,
+    Subdomain(String),
+    Exact(String),
+    Network(IpAddr, u8),
+}
+
+/// Why a CONNECT request was refused, mapped to the HTTP status sent back
+#[derive(Debug, PartialEq)]
+pub enum ConnectRejection {
+    BadRequest(String),
+    Forbidden(String),
+    BadGateway(String),
+}
+
+/// A validated CONNECT request
+#[derive(Debug)]
+pub struct ConnectRequest {
+    pub host: String,
+    pub port: u16,
+    /// Bytes the client sent after the request head, to be tunnelled as-is
+    pub leftover: Vec<u8>,
+}
+
+impl ConnectAllowlist {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Ok(ConnectAllowlist { entries: parse_rules(s, "allow")?, denied: Vec::new() })
+    }
+
+    /// Refuse the targets in `s`, in the same format, even where allowed
+    pub fn with_denied(mut self, s: &str) -> Result<Self, String> {
+        self.denied = parse_rules(s, "deny")?;
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether `host:port` passes the checks that can be made before it is
+    /// resolved; a name only an allowed network could admit still has to
+    /// pass [`ConnectAllowlist::allowed_addrs`]
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        let host = host.to_lowercase();
+        if self.denied.iter().any(|rule| rule.matches_name(&host, port)) {
+            return false;
+        }
+        self.entries.iter().any(|rule| rule.matches_name(&host, port))
+            || (host.parse::<IpAddr>().is_err()
+                && self.entries.iter().any(|rule| matches!(rule.host, HostRule::Network(..)) && rule.ports.contains(&port)))
+    }
+
+    /// The addresses `host:port` resolved to that may be dialed, or why none may
+    pub fn allowed_addrs(&self, host: &str, port: u16, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, String> {
+        let host = host.to_lowercase();
+        let allowed_by_name = self.entries.iter().any(|rule| rule.matches_name(&host, port));
+        let allowed: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| !self.denied.iter().any(|rule| rule.matches_addr(addr)))
+            .filter(|addr| allowed_by_name || self.entries.iter().any(|rule| rule.matches_addr(addr)))
+            .collect();
+        if allowed.is_empty() {
+            return Err(format!("{}:{} resolved only to addresses outside the CONNECT allowlist", host, port));
+        }
+        Ok(allowed)
+    }
+}
+
+fn parse_rules(s: &str, kind: &str) -> Result<Vec<TargetRule>, String> {
+    let mut rules = Vec::new();
+    for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (host, port) = entry
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Invalid CONNECT {} entry '{}'. Expected host:port", kind, entry))?;
+        let invalid_port = || format!("Invalid port in CONNECT {} entry '{}'", kind, entry);
+        let ports = match port {
+            "*" => 0..=u16::MAX,
+            port => match port.split_once('-') {
+                Some((low, high)) => {
+                    let (low, high): (u16, u16) = (low.parse().map_err(|_| invalid_port())?, high.parse().map_err(|_| invalid_port())?);
+                    if low > high {
+                        return Err(invalid_port());
+                    }
+                    low..=high
+                }
+                None => {
+                    let port = port.parse().map_err(|_| invalid_port())?;
+                    port..=port
+                }
+            },
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+        let host = if host.is_empty() {
+            return Err(format!("Missing host in CONNECT {} entry '{}'", kind, entry));
+        } else if host == "*" {
+            HostRule::Any
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            HostRule::Subdomain(format!(".{}", domain))
+        } else if let Some((ip, prefix)) = host.split_once('/') {
+            let invalid_network = || format!("Invalid network in CONNECT {} entry '{}'", kind, entry);
+            let ip: IpAddr = ip.parse().map_err(|_| invalid_network())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid_network())?;
+            if prefix > if ip.is_ipv4() { 32 } else { 128 } {
+                return Err(invalid_network());
+            }
+            // An IPv4-mapped network is the IPv4 network its last 32 bits cover
+            match ip {
+                IpAddr::V6(v6) if prefix >= 96 && v6.to_ipv4_mapped().is_some() => HostRule::Network(canonical_ip(ip), prefix - 96),
+                _ => HostRule::Network(ip, prefix),
+            }
+        } else {
+            HostRule::Exact(host)
+        };
+        rules.push(TargetRule { host, ports });
+    }
+    Ok(rules)
+}
+
+impl TargetRule {
+    /// Matches a requested (lowercased) host by name, or by network when it is an IP
+    fn matches_name(&self, host: &str, port: u16) -> bool {
+        let host_matches = match &self.host {
+            HostRule::Any => true,
+            HostRule::Subdomain(suffix) => host.ends_with(suffix.as_str()),
+            HostRule::Exact(name) => name == host,
+            HostRule::Network(..) => host.parse().is_ok_and(|ip| self.contains(ip)),
+        };
+        host_matches && self.ports.contains(&port)
+    }
+
+    /// Matches a resolved address; only network rules look at addresses
+    fn matches_addr(&self, addr: &SocketAddr) -> bool {
+        matches!(self.host, HostRule::Network(..)) && self.contains(addr.ip()) && self.ports.contains(&addr.port())
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let HostRule::Network(network, prefix) = self.host else { return false };
+        match (network, canonical_ip(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl ConnectRejection {
+    pub fn response(&self) -> &'static [u8] {
+        match self {
+            ConnectRejection::BadRequest(_) => b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            ConnectRejection::Forbidden(_) => b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            ConnectRejection::BadGateway(_) => b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            ConnectRejection::BadRequest(reason)
+            | ConnectRejection::Forbidden(reason)
+            | ConnectRejection::BadGateway(reason) => reason,
+        }
+    }
+}
+
+/// Parses a complete request head (`CONNECT host:port HTTP/1.x` plus headers)
+pub fn parse_connect_head(head: &[u8]) -> Result<(String, u16), ConnectRejection> {
+    let head = std::str::from_utf8(head)
+        .map_err(|_| ConnectRejection::BadRequest("request is not valid UTF-8".to_string()))?;
+    let request_line = head.lines().next().unwrap_or_default();
+
+    let mut parts = request_line.split(' ');
+    let (method, authority, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(authority), Some(version), None) => (method, authority, version),
+        _ => return Err(ConnectRejection::BadRequest(format!("malformed request line '{}'", request_line))),
+    };
+    if method != "CONNECT" {
+        return Err(ConnectRejection::BadRequest(format!("unsupported method '{}'", method)));
+    }
+    if !version.starts_with("HTTP/1.") {
+        return Err(ConnectRejection::BadRequest(format!("unsupported version '{}'", version)));
+    }
+
+    let (host, port) = authority
+        .rsplit_once(':')
+        .ok_or_else(|| ConnectRejection::BadRequest(format!("target '{}' is missing a port", authority)))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| ConnectRejection::BadRequest(format!("invalid port in target '{}'", authority)))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(ConnectRejection::BadRequest(format!("target '{}' is missing a host", authority)));
+    }
+
+    Ok((host.to_string(), port))
+}
+
+/// Reads the client's CONNECT request and checks it against the allowlist,
+/// answering 400 once `max_head` bytes arrive without the end of the head
+pub async fn read_connect_request(
+    stream: &mut Stream,
+    allowlist: &ConnectAllowlist,
+    max_head: usize,
+) -> Result<ConnectRequest, ConnectRejection> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    let head_end = timeout(REQUEST_TIMEOUT, async {
+        loop {
+            if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                return Ok(end + 4);
+            }
+            if buf.len() >= max_head {
+                return Err(ConnectRejection::BadRequest(format!("request head exceeds {} bytes", max_head)));
+            }
+            // Never read past the cap, so a head that doesn't end is cut off right at it
+            let want = chunk.len().min(max_head - buf.len());
+            match stream.read(&mut chunk[..want]).await {
+                Ok(0) => return Err(ConnectRejection::BadRequest("client closed before completing the request".to_string())),
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(e) => return Err(ConnectRejection::BadRequest(format!("read failed: {}", e))),
+            }
+        }
+    })
+    .await
+    .map_err(|_| ConnectRejection::BadRequest("timed out waiting for the request".to_string()))??;
+
+    let (host, port) = parse_connect_head(&buf[..head_end])?;
+    if !allowlist.allows(&host, port) {
+        return Err(ConnectRejection::Forbidden(format!("{}:{} is not in the CONNECT allowlist", host, port)));
+    }
+
+    Ok(ConnectRequest {
+        host,
+        port,
+        leftover: buf.split_off(head_end),
+    })
+}
+
+/// Resolves the requested target to every address it maps to, with the
+/// address families alternating for Happy Eyeballs
+pub async fn resolve_target(host: &str, port: u16) -> Result<Vec<SocketAddr>, ConnectRejection> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| ConnectRejection::BadGateway(format!("failed to resolve {}: {}", host, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(ConnectRejection::BadGateway(format!("{} did not resolve to any address", host)));
+    }
+    Ok(eyeballs::interleave_families(addrs))
+}
+
+pub async fn send_response(stream: &mut Stream, response: &[u8]) -> io::Result<()> {
+    stream.write_all(response).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_connect_head() {
+        let (host, port) = parse_connect_head(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+        assert_eq!(host, "example.com");
+        assert_eq!(port, 443);
+
+        let (host, port) = parse_connect_head(b"CONNECT [::1]:8443 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(host, "::1");
+        assert_eq!(port, 8443);
+    }
+
+    #[test]
+    fn test_parse_connect_head_malformed() {
+        let test_cases: Vec<&[u8]> = vec![
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"CONNECT example.com HTTP/1.1\r\n\r\n",
+            b"CONNECT example.com:http HTTP/1.1\r\n\r\n",
+            b"CONNECT :443 HTTP/1.1\r\n\r\n",
+            b"CONNECT example.com:443\r\n\r\n",
+            b"CONNECT example.com:443 SPDY/3\r\n\r\n",
+        ];
+
+        for head in test_cases {
+            assert!(
+                matches!(parse_connect_head(head), Err(ConnectRejection::BadRequest(_))),
+                "Expected 400 for {:?}",
+                String::from_utf8_lossy(head)
+            );
+        }
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = ConnectAllowlist::parse("127.0.0.1:*, *.example.com:443, db.internal:5432").unwrap();
+        assert!(allowlist.allows("127.0.0.1", 22));
+        assert!(allowlist.allows("api.example.com", 443));
+        assert!(allowlist.allows("DB.internal", 5432));
+        assert!(!allowlist.allows("example.com", 443));
+        assert!(!allowlist.allows("api.example.com", 80));
+        assert!(!allowlist.allows("10.0.0.1", 22));
+
+        assert!(ConnectAllowlist::parse("*:*").unwrap().allows("anything", 1));
+        assert!(ConnectAllowlist::parse("").unwrap().is_empty());
+        assert!(!ConnectAllowlist::default().allows("127.0.0.1", 22));
+    }
+
+    #[test]
+    fn test_allowlist_invalid() {
+        assert!(ConnectAllowlist::parse("example.com").is_err());
+        assert!(ConnectAllowlist::parse("example.com:https").is_err());
+        assert!(ConnectAllowlist::parse(":443").is_err());
+        assert!(ConnectAllowlist::parse("*:9000-8000").is_err());
+        assert!(ConnectAllowlist::parse("10.0.0.0/33:*").is_err());
+        assert!(ConnectAllowlist::parse("10.0.0/8:*").is_err());
+        assert!(ConnectAllowlist::default().with_denied("*:smtp").is_err());
+    }
+
+    #[test]
+    fn test_allowlist_networks_and_port_ranges() {
+        let allowlist = ConnectAllowlist::parse("10.1.0.0/16:8000-8999, [fd00::/8]:443").unwrap();
+        assert!(allowlist.allows("10.1.2.3", 8080));
+        assert!(!allowlist.allows("10.1.2.3", 9000));
+        assert!(!allowlist.allows("10.2.0.1", 8080));
+        assert!(allowlist.allows("fd12::1", 443));
+        assert!(allowlist.allows("::ffff:10.1.0.1", 8000), "IPv4-mapped addresses match IPv4 networks");
+        let mapped = ConnectAllowlist::parse("[::ffff:10.1.0.0/112]:*").unwrap();
+        assert!(mapped.allows("10.1.200.3", 22), "IPv4-mapped networks match IPv4 addresses");
+        assert!(!mapped.allows("10.2.0.1", 22));
+
+        // A name is only admitted by what it resolves to
+        assert!(allowlist.allows("svc.internal", 8080));
+        assert!(!allowlist.allows("svc.internal", 22));
+        let addrs = vec!["10.1.0.7:8080".parse().unwrap(), "192.0.2.1:8080".parse().unwrap()];
+        assert_eq!(allowlist.allowed_addrs("svc.internal", 8080, addrs).unwrap(), vec!["10.1.0.7:8080".parse().unwrap()]);
+        assert!(allowlist.allowed_addrs("svc.internal", 8080, vec!["192.0.2.1:8080".parse().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn test_denied_targets() {
+        let allowlist = ConnectAllowlist::parse("*:*")
+            .unwrap()
+            .with_denied("10.0.0.0/8:*, 192.168.0.0/16:*, *:25, *.internal:*")
+            .unwrap();
+        assert!(allowlist.allows("example.com", 443));
+        assert!(!allowlist.allows("example.com", 25));
+        assert!(!allowlist.allows("10.0.0.1", 443));
+        assert!(!allowlist.allows("db.internal", 5432));
+
+        // Names that resolve into a denied network lose those addresses
+        let addrs = vec!["192.168.1.5:443".parse().unwrap(), "203.0.113.9:443".parse().unwrap()];
+        assert_eq!(allowlist.allowed_addrs("mixed.example", 443, addrs).unwrap(), vec!["203.0.113.9:443".parse().unwrap()]);
+        assert!(allowlist.allowed_addrs("private.example", 443, vec!["10.9.9.9:443".parse().unwrap()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_head_stops_at_cap() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let mut io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(listener.accept().await.unwrap().0));
+
+        // A request line that never ends
+        let mut sent = b"CONNECT ".to_vec();
+        sent.resize(5000, b'a');
+        client.write_all(&sent).await.unwrap();
+        let allowlist = ConnectAllowlist::parse("*:*").unwrap();
+        match read_connect_request(&mut io, &allowlist, 1024).await {
+            Err(ConnectRejection::BadRequest(reason)) => assert_eq!(reason, "request head exceeds 1024 bytes"),
+            other => panic!("Expected a 400, got {:?}", other.map(|request| request.host)),
+        }
+
+        // Nothing past the cap was read
+        drop(client);
+        let mut rest = Vec::new();
+        io.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest.len(), 5000 - 1024);
+    }
+}
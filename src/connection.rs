@@ -1,10 +1,18 @@
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Instant;
-use tracing::info;
-use crate::id_manager::ConnectionIdManager;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{field, info_span, warn, Level, Span};
+use crate::id_manager::{ConnectionIdManager, DisplayId};
+use crate::metrics::SizeHistogram;
+use crate::options::ClientSubnets;
+use crate::statsd::StatsdClient;
+use crate::telemetry::SPAN_TARGET;
 
-fn format_bytes(bytes: u64) -> String {
+pub(crate) fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
     const GB: u64 = MB * 1024;
@@ -20,19 +28,257 @@ fn format_bytes(bytes: u64) -> String {
     }
 }
 
-#[derive(Debug, Clone)]
+fn format_rate(bytes_per_sec: u64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec))
+}
+
+/// Classic hex dump, 16 bytes per line with an ASCII column
+pub fn hex_dump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            format!("{:04x}  {:<47}  |{}|", line * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Why a connection ended, logged as a short token so asymmetric
+/// disconnects can be told apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    /// The client closed its side
+    DownstreamEof,
+    /// The upstream closed its side
+    UpstreamEof,
+    /// Reading from or writing to the client failed
+    DownstreamError,
+    /// Reading from or writing to the upstream failed
+    UpstreamError,
+    /// The idle timeout, write timeout or max lifetime ran out
+    Timeout,
+    /// Closed through the admin API
+    AdminClose,
+    /// A direction reached its byte cap
+    ByteCap,
+    /// One side reset the connection (or the pipe broke writing to it); the
+    /// other side was still handed what had been relayed to it
+    PeerReset,
+    /// The upstream connect failed, so nothing was relayed
+    ConnectFailed,
+}
+
+impl CloseReason {
+    /// Whether the connection broke rather than being closed by a side or by policy
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::DownstreamError | CloseReason::UpstreamError | CloseReason::PeerReset | CloseReason::ConnectFailed
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::DownstreamEof => "downstream_eof",
+            CloseReason::UpstreamEof => "upstream_eof",
+            CloseReason::DownstreamError => "downstream_error",
+            CloseReason::UpstreamError => "upstream_error",
+            CloseReason::Timeout => "timeout",
+            CloseReason::AdminClose => "admin_close",
+            CloseReason::ByteCap => "byte_cap",
+            CloseReason::PeerReset => "peer_reset",
+            CloseReason::ConnectFailed => "connect_failed",
+        }
+    }
+
+    /// How the side that ended the connection closed its TCP session, or
+    /// `None` when neither side did: pj closed it or it broke another way
+    pub fn close_kind(&self) -> Option<CloseKind> {
+        match self {
+            CloseReason::DownstreamEof | CloseReason::UpstreamEof => Some(CloseKind::Graceful),
+            CloseReason::PeerReset => Some(CloseKind::Reset),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a side closed its TCP session with a FIN or reset it with an RST
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseKind {
+    Graceful,
+    Reset,
+}
+
+impl CloseKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseKind::Graceful => "graceful",
+            CloseKind::Reset => "reset",
+        }
+    }
+}
+
+/// Levels the per-connection start and end lines are logged at; `None`
+/// leaves the line out. Ends with an error are logged at warn whatever the
+/// end level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnLogLevels {
+    pub start: Option<Level>,
+    pub end: Option<Level>,
+    /// Instead of `end` for connections that relayed nothing either way,
+    /// like health checks and port scans
+    pub empty: Option<Level>,
+}
+
+impl Default for ConnLogLevels {
+    fn default() -> Self {
+        ConnLogLevels { start: Some(Level::INFO), end: Some(Level::INFO), empty: Some(Level::INFO) }
+    }
+}
+
+impl ConnLogLevels {
+    /// Parses `PJ_LOG_CONN_LEVEL`: one level for every line (`debug`), or
+    /// `start=`, `end=` and `empty=` levels, comma separated, where a missing
+    /// start or end stays at info and a missing empty follows end. `off`
+    /// drops a line.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if !s.contains('=') {
+            let level = parse_level(s)?;
+            return Ok(ConnLogLevels { start: level, end: level, empty: level });
+        }
+        let mut levels = ConnLogLevels::default();
+        let mut empty = None;
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=').map(|(line, level)| (line.trim(), level)) {
+                Some(("start", level)) => levels.start = parse_level(level)?,
+                Some(("end", level)) => levels.end = parse_level(level)?,
+                Some(("empty", level)) => empty = Some(parse_level(level)?),
+                _ => return Err(format!("Unknown setting '{}'. Expected start=<level>, end=<level> or empty=<level>", part)),
+            }
+        }
+        levels.empty = empty.unwrap_or(levels.end);
+        Ok(levels)
+    }
+}
+
+fn parse_level(s: &str) -> Result<Option<Level>, String> {
+    match s.trim().to_lowercase().as_str() {
+        "off" => Ok(None),
+        level => level
+            .parse::<Level>()
+            .map(Some)
+            .map_err(|_| format!("Unknown log level '{}'. Supported: trace, debug, info, warn, error, off", s.trim())),
+    }
+}
+
+/// Logs `line` at `level`, which unlike the tracing macros' may be picked at runtime
+fn log_at(level: Level, line: &str) {
+    match level {
+        Level::ERROR => tracing::error!("{}", line),
+        Level::WARN => tracing::warn!("{}", line),
+        Level::INFO => tracing::info!("{}", line),
+        Level::DEBUG => tracing::debug!("{}", line),
+        Level::TRACE => tracing::trace!("{}", line),
+    }
+}
+
+/// Receives connection lifecycle events, for embedders that want them
+/// without parsing logs. Both methods default to doing nothing.
+///
+/// Callbacks run on the connection's task, so slow work belongs elsewhere.
+pub trait ConnectionObserver: Send + Sync {
+    /// A connection to the upstream is established and about to be relayed
+    fn on_start(&self, _info: &ConnectionInfo) {}
+
+    /// A relayed connection has ended, with the error that closed it if any
+    fn on_end(&self, _info: &ConnectionInfo, _stats: &ConnectionStats, _error: Option<&str>) {}
+}
+
+/// `ip` as everything keyed on a client's IP sees it: a dual-stack listener
+/// sees IPv4 clients as IPv4-mapped IPv6 addresses (`::ffff:1.2.3.4`), which
+/// become plain IPv4 so logs, balancing, subnets and ACLs treat them alike
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    ip.to_canonical()
+}
+
+/// Where a connection came from, as far as its socket can tell. An IP
+/// client's address is kept in its `canonical_ip` form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientAddr {
+    Inet(SocketAddr),
+    /// A client of a Unix socket listener, shown as the socket's path
+    Unix(String),
+    /// The socket had no peer address to give
+    Unknown,
+}
+
+impl ClientAddr {
+    /// The client's IP, for balancing and `X-Forwarded-For`
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Inet(addr) => Some(addr.ip()),
+            ClientAddr::Unix(_) | ClientAddr::Unknown => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for ClientAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ClientAddr::Inet(SocketAddr::new(canonical_ip(addr.ip()), addr.port()))
+    }
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddr::Inet(addr) => addr.fmt(f),
+            ClientAddr::Unix(path) => f.write_str(path),
+            ClientAddr::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+impl Serialize for ClientAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Clone)]
 pub struct ConnectionInfo {
     pub id: u64,
-    pub client_addr: SocketAddr,
+    /// `id` as it appears in log lines
+    pub display_id: DisplayId,
+    pub name: String,
+    pub client_addr: ClientAddr,
     pub proxy_addr: String,
     pub backend_addr: String,
+    /// Where a transparently proxied client was headed before being redirected
+    pub original_dst: Option<SocketAddr>,
+    /// The failover tier of a tiered mapping the backend was picked from
+    pub tier: Option<String>,
+    /// How long the upstream connect took
+    pub connect_time: Option<Duration>,
     pub start_instant: Instant,
     pub active_connections: u64,
+    pub statsd: Option<Arc<StatsdClient>>,
+    /// The client's subnet, tagged on StatsD metrics
+    pub client_subnet: Option<String>,
+    pub observer: Option<Arc<dyn ConnectionObserver>>,
+    pub backend_traffic: Option<Arc<BackendTraffic>>,
+    pub connection_sizes: Option<Arc<SizeHistogram>>,
+    pub log_levels: ConnLogLevels,
 }
 
 impl ConnectionInfo {
     pub fn new(
-        client_addr: SocketAddr, 
+        client_addr: ClientAddr, 
         proxy_addr: &str, 
         backend_addr: &str, 
         active_connections: u64,
@@ -41,46 +287,321 @@ impl ConnectionInfo {
         let id = id_manager.next_id();
         Self {
             id,
+            display_id: id_manager.display(id),
+            name: proxy_addr.to_string(),
             client_addr,
             proxy_addr: proxy_addr.to_string(),
             backend_addr: backend_addr.to_string(),
+            original_dst: None,
+            tier: None,
+            connect_time: None,
             start_instant: Instant::now(),
             active_connections,
+            statsd: None,
+            client_subnet: None,
+            observer: None,
+            backend_traffic: None,
+            connection_sizes: None,
+            log_levels: ConnLogLevels::default(),
         }
     }
 
+    /// Label log lines with the mapping's name instead of its listen address
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Record the destination a redirected connection was originally made to
+    pub fn with_original_dst(mut self, original_dst: Option<SocketAddr>) -> Self {
+        self.original_dst = original_dst;
+        self
+    }
+
+    /// Record the failover tier the backend was picked from
+    pub fn with_tier(mut self, tier: Option<String>) -> Self {
+        self.tier = tier;
+        self
+    }
+
+    /// Record how long the upstream connect took
+    pub fn with_connect_time(mut self, connect_time: Duration) -> Self {
+        self.connect_time = Some(connect_time);
+        self
+    }
+
+    /// Report the connection's start and end to StatsD as well as the log
+    pub fn with_statsd(mut self, statsd: Option<Arc<StatsdClient>>) -> Self {
+        self.statsd = statsd;
+        self
+    }
+
+    /// Tag the StatsD metrics with the subnet the client falls in
+    pub fn with_client_subnet(mut self, subnets: Option<ClientSubnets>) -> Self {
+        self.client_subnet = subnets.zip(self.client_addr.ip()).map(|(subnets, ip)| subnets.bucket(ip));
+        self
+    }
+
+    /// Tags of the connection's StatsD metrics, the mapping's name first
+    fn statsd_tags(&self) -> Vec<(&str, &str)> {
+        let mut tags = vec![("name", self.name.as_str()), ("backend", self.backend_addr.as_str())];
+        if let Some(subnet) = &self.client_subnet {
+            tags.push(("client_subnet", subnet));
+        }
+        tags
+    }
+
+    /// Report the connection's start and end to `observer`
+    pub fn with_observer(mut self, observer: Option<Arc<dyn ConnectionObserver>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Add the connection's totals to its backend's in `backend_traffic` when it ends
+    pub fn with_backend_traffic(mut self, backend_traffic: Option<Arc<BackendTraffic>>) -> Self {
+        self.backend_traffic = backend_traffic;
+        self
+    }
+
+    /// Add the connection's total bytes, both ways, to `connection_sizes` when it ends
+    pub fn with_connection_sizes(mut self, connection_sizes: Option<Arc<SizeHistogram>>) -> Self {
+        self.connection_sizes = connection_sizes;
+        self
+    }
+
+    /// Log the start and end lines at `log_levels` instead of info
+    pub fn with_log_levels(mut self, log_levels: ConnLogLevels) -> Self {
+        self.log_levels = log_levels;
+        self
+    }
+
+    /// Span covering the connection's lifetime; the counters are recorded by `log_end`
+    pub fn span(&self) -> Span {
+        info_span!(
+            target: SPAN_TARGET,
+            "connection",
+            conn_id = self.id,
+            name = %self.name,
+            client = %self.client_addr,
+            proxy = %self.proxy_addr,
+            backend = %self.backend_addr,
+            original_dst = self.original_dst.map(field::display),
+            tier = self.tier.as_deref(),
+            connect_secs = self.connect_time.map(|time| time.as_secs_f64()),
+            bytes_sent = field::Empty,
+            bytes_received = field::Empty,
+            duration_secs = field::Empty,
+            buffer_saturated = field::Empty,
+            backpressured = field::Empty,
+            close_reason = field::Empty,
+            close_kind = field::Empty,
+            error = field::Empty,
+        )
+    }
+
     pub fn log_start(&self) {
-        info!(
-            "Conn #{} estab [{}]: {} -> {} -> {}",
-            self.id,
-            self.active_connections,
-            self.client_addr,
-            self.proxy_addr,
-            self.backend_addr
-        );
+        if let Some(level) = self.log_levels.start {
+            log_at(level, &format!(
+                "[{}] Conn #{} estab [{}]: {} -> {} -> {}{}{}{}",
+                self.name,
+                self.display_id,
+                self.active_connections,
+                self.client_addr,
+                self.proxy_addr,
+                self.backend_addr,
+                self.original_dst.map(|dst| format!(" (original destination {})", dst)).unwrap_or_default(),
+                self.tier.as_ref().map(|tier| format!(" ({} tier)", tier)).unwrap_or_default(),
+                self.connect_time.map(|time| format!(" | Connect: {:.1}ms", time.as_secs_f64() * 1000.0)).unwrap_or_default()
+            ));
+        }
+        
+        if let Some(statsd) = &self.statsd {
+            statsd.count("connections.accepted", 1, &self.statsd_tags());
+            statsd.mapping_gauge("connections.active", &self.name, self.active_connections);
+        }
+        
+        if let Some(observer) = &self.observer {
+            observer.on_start(self);
+        }
     }
 
-    pub fn log_end(&self, bytes_sent: u64, bytes_received: u64, error: Option<&str>, remaining_connections: u64) {
+    pub fn log_end(&self, stats: &ConnectionStats, reason: CloseReason, error: Option<&str>, remaining_connections: u64) {
         let duration = self.start_instant.elapsed();
         let status = if error.is_some() { "fail " } else { "close" };
         
-        info!(
-            "Conn #{} {} [{}]: Duration: {:.2}s | Sent: {} | Received: {}{}",
-            self.id,
-            status,
-            remaining_connections,
-            duration.as_secs_f64(),
-            format_bytes(bytes_sent),
-            format_bytes(bytes_received),
-            error.map(|e| format!(" | Error: {}", e)).unwrap_or_default()
-        );
+        let span = Span::current();
+        span.record("bytes_sent", stats.bytes_sent());
+        span.record("bytes_received", stats.bytes_received());
+        span.record("duration_secs", duration.as_secs_f64());
+        span.record("buffer_saturated", stats.buffer_saturated());
+        span.record("backpressured", stats.backpressured());
+        span.record("close_reason", field::display(reason.as_str()));
+        let close_kind = reason.close_kind();
+        if let Some(kind) = close_kind {
+            span.record("close_kind", kind.as_str());
+        }
+        if let Some(error) = error {
+            span.record("error", error);
+        }
+        
+        let empty = stats.is_empty() && !reason.is_error();
+        // Errors are never logged below warn, so dropping the end lines can't hide them
+        let level = match if empty { self.log_levels.empty } else { self.log_levels.end } {
+            // In tracing, more severe levels compare lower
+            Some(end) if reason.is_error() => Some(end.min(Level::WARN)),
+            None if reason.is_error() => Some(Level::WARN),
+            end => end,
+        };
+        if let Some(level) = level {
+            log_at(level, &format!(
+                "[{}] Conn #{} {} [{}]: Duration: {:.2}s | Sent: {} | Received: {} | PeakTx: {} | PeakRx: {}{}{} | Reason: {}{}{}",
+                self.name,
+                self.display_id,
+                status,
+                remaining_connections,
+                duration.as_secs_f64(),
+                format_bytes(stats.bytes_sent()),
+                format_bytes(stats.bytes_received()),
+                format_rate(stats.peak_tx()),
+                format_rate(stats.peak_rx()),
+                if stats.buffer_saturated() { " | Buffer saturated" } else { "" },
+                if stats.backpressured() { " | Backpressured" } else { "" },
+                reason.as_str(),
+                close_kind.map(|kind| format!(" | Close: {}", kind.as_str())).unwrap_or_default(),
+                error.map(|e| format!(" | Error: {}", e)).unwrap_or_default()
+            ));
+        }
+        
+        if stats.buffer_saturated() {
+            warn!(
+                "[{}] Conn #{} was buffer-bound: {} of {} reads filled the buffer, of up to {}",
+                self.name, self.display_id, stats.full_reads, stats.reads, format_bytes(stats.largest_buffer as u64)
+            );
+        }
+        if stats.backpressured() {
+            warn!(
+                "[{}] Conn #{} was backpressured: {} writes waited past the threshold on a side not draining, the longest {:.1}s",
+                self.name, self.display_id, stats.stalls, stats.longest_stall.as_secs_f64()
+            );
+        }
+        
+        if let Some(statsd) = &self.statsd {
+            let tags = self.statsd_tags();
+            if error.is_some() {
+                statsd.count("connections.failed", 1, &tags);
+            }
+            if stats.buffer_saturated() {
+                statsd.count("connections.buffer_saturated", 1, &tags);
+            }
+            if stats.backpressured() {
+                statsd.count("connections.backpressured", 1, &tags);
+            }
+            if stats.long_lived() {
+                statsd.count("connections.long_lived", 1, &tags);
+            }
+            if empty {
+                statsd.count("connections.empty", 1, &tags);
+            }
+            match close_kind {
+                Some(CloseKind::Graceful) => statsd.count("connections.closed_graceful", 1, &tags),
+                Some(CloseKind::Reset) => statsd.count("connections.closed_reset", 1, &tags),
+                None => {}
+            }
+            statsd.timing("connection.duration", duration, &tags);
+            statsd.count("bytes.sent", stats.bytes_sent() as i64, &tags);
+            statsd.count("bytes.received", stats.bytes_received() as i64, &tags);
+            statsd.mapping_gauge("connections.active", &self.name, remaining_connections);
+        }
+        
+        if let Some(backend_traffic) = &self.backend_traffic {
+            backend_traffic.record(&self.backend_addr, stats);
+        }
+        
+        if let Some(connection_sizes) = &self.connection_sizes {
+            connection_sizes.observe(stats.bytes_sent() + stats.bytes_received());
+        }
+        
+        if let Some(observer) = &self.observer {
+            observer.on_end(self, stats, error);
+        }
+    }
+}
+
+const RATE_BUCKETS: usize = 10;
+const RATE_BUCKET_WIDTH: Duration = Duration::from_millis(100);
+
+/// Rolling one-second throughput window split into fixed buckets, remembering
+/// the highest bytes/sec observed over the lifetime of the connection.
+#[derive(Debug, Default)]
+pub struct RateTracker {
+    origin: Option<Instant>,
+    current_tick: u64,
+    buckets: [u64; RATE_BUCKETS],
+    peak: u64,
+}
+
+impl RateTracker {
+    pub fn record(&mut self, bytes: u64, at: Instant) {
+        let origin = *self.origin.get_or_insert(at);
+        let tick = (at.saturating_duration_since(origin).as_millis() / RATE_BUCKET_WIDTH.as_millis()) as u64;
+        
+        if tick > self.current_tick {
+            // Clear buckets that fell out of the window since the last sample
+            let stale = (tick - self.current_tick).min(RATE_BUCKETS as u64);
+            for offset in 1..=stale {
+                self.buckets[((self.current_tick + offset) % RATE_BUCKETS as u64) as usize] = 0;
+            }
+            self.current_tick = tick;
+        }
+        
+        self.buckets[(self.current_tick % RATE_BUCKETS as u64) as usize] += bytes;
+        
+        // The buckets span exactly one second, so their sum is bytes/sec
+        let window_total: u64 = self.buckets.iter().sum();
+        self.peak = self.peak.max(window_total);
+    }
+
+    pub fn peak(&self) -> u64 {
+        self.peak
+    }
+}
+
+/// Reads a connection needs before it can be flagged as buffer saturated
+const SATURATION_MIN_READS: u64 = 16;
+/// Share of reads (in percent) that must fill the buffer for the flag
+const SATURATION_PERCENT: u64 = 50;
+
+/// Byte totals of one connection. Clones share the counters, so another task
+/// can follow a connection's progress while it is still being relayed.
+#[derive(Debug, Clone, Default)]
+pub struct ByteCounters {
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+}
+
+impl ByteCounters {
+    /// Bytes sent to the client so far
+    pub fn bytes_sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received from the client so far
+    pub fn bytes_received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
     }
 }
 
 #[derive(Debug, Default)]
 pub struct ConnectionStats {
-    pub bytes_sent: u64,
-    pub bytes_received: u64,
+    bytes: ByteCounters,
+    tx_rate: RateTracker,
+    rx_rate: RateTracker,
+    reads: u64,
+    full_reads: u64,
+    largest_buffer: usize,
+    stalls: u64,
+    longest_stall: Duration,
+    long_lived: bool,
 }
 
 impl ConnectionStats {
@@ -88,11 +609,523 @@ impl ConnectionStats {
         Self::default()
     }
 
+    /// Stats that count into `bytes`, so whoever holds a clone of it sees
+    /// the connection's totals as they grow
+    pub fn with_counters(bytes: ByteCounters) -> Self {
+        ConnectionStats { bytes, ..Self::default() }
+    }
+
+    /// A handle on the byte totals that can be read from another task
+    pub fn counters(&self) -> ByteCounters {
+        self.bytes.clone()
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes.bytes_sent()
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes.bytes_received()
+    }
+
+    /// Whether nothing was relayed either way
+    pub fn is_empty(&self) -> bool {
+        self.bytes_sent() == 0 && self.bytes_received() == 0
+    }
+
     pub fn add_sent(&mut self, bytes: usize) {
-        self.bytes_sent += bytes as u64;
+        self.add_sent_at(bytes, Instant::now());
     }
 
     pub fn add_received(&mut self, bytes: usize) {
-        self.bytes_received += bytes as u64;
+        self.add_received_at(bytes, Instant::now());
+    }
+
+    pub fn add_sent_at(&mut self, bytes: usize, at: Instant) {
+        self.bytes.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.tx_rate.record(bytes as u64, at);
+    }
+
+    pub fn add_received_at(&mut self, bytes: usize, at: Instant) {
+        self.bytes.received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.rx_rate.record(bytes as u64, at);
+    }
+
+    /// Takes back bytes counted as sent that never reached the client; the
+    /// peak rate still includes them
+    pub fn retract_sent(&mut self, bytes: usize) {
+        self.bytes.sent.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Takes back bytes counted as received that never reached the upstream
+    pub fn retract_received(&mut self, bytes: usize) {
+        self.bytes.received.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a read of `bytes` into a buffer of `capacity` bytes
+    pub fn add_read(&mut self, bytes: usize, capacity: usize) {
+        self.reads += 1;
+        self.largest_buffer = self.largest_buffer.max(capacity);
+        if bytes == capacity {
+            self.full_reads += 1;
+        }
+    }
+
+    /// Whether most reads filled the whole buffer, a sign the connection
+    /// was held back by the buffer size rather than by its peers
+    pub fn buffer_saturated(&self) -> bool {
+        self.reads >= SATURATION_MIN_READS && self.full_reads * 100 >= self.reads * SATURATION_PERCENT
+    }
+
+    /// Counts a write (with its flush) that was held up for `stalled` by a
+    /// side not draining, past the backpressure threshold
+    pub fn add_stall(&mut self, stalled: Duration) {
+        self.stalls += 1;
+        self.longest_stall = self.longest_stall.max(stalled);
+    }
+
+    /// Whether a side stopped draining for longer than the backpressure
+    /// threshold at some point, holding back reads from the other
+    pub fn backpressured(&self) -> bool {
+        self.stalls > 0
+    }
+
+    /// The longest write held up past the backpressure threshold
+    pub fn longest_stall(&self) -> Duration {
+        self.longest_stall
+    }
+
+    /// Marks the connection as open past the long-lived threshold
+    pub fn mark_long_lived(&mut self) {
+        self.long_lived = true;
+    }
+
+    /// Whether the connection stayed open past the long-lived threshold
+    pub fn long_lived(&self) -> bool {
+        self.long_lived
+    }
+
+    /// The largest read buffer either direction used, which only changes
+    /// over a connection with `ProxyOptions::adaptive_buffer`
+    pub fn largest_buffer(&self) -> usize {
+        self.largest_buffer
+    }
+
+    /// Peak bytes/sec sent to the client
+    pub fn peak_tx(&self) -> u64 {
+        self.tx_rate.peak()
+    }
+
+    /// Peak bytes/sec received from the client
+    pub fn peak_rx(&self) -> u64 {
+        self.rx_rate.peak()
+    }
+}
+
+/// Running totals across every connection of a listener. Clones share the
+/// same counters, so an embedder can keep one and read it while the proxy runs.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficCounters {
+    bytes_sent: Arc<AtomicU64>,
+    bytes_received: Arc<AtomicU64>,
+    connections: Arc<AtomicU64>,
+    active: Arc<AtomicU64>,
+}
+
+impl TrafficCounters {
+    /// The gauge the listener keeps its open connection count in
+    pub(crate) fn active_counter(&self) -> Arc<AtomicU64> {
+        self.active.clone()
+    }
+
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
     }
-}
\ No newline at end of file
+
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Takes back bytes counted by `add_sent` that were never delivered
+    pub fn retract_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Takes back bytes counted by `add_received` that were never delivered
+    pub fn retract_received(&self, bytes: usize) {
+        self.bytes_received.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Bytes sent to clients
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received from clients
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Connections relayed so far, open or closed
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Connections being relayed right now
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// Backends broken out by `BackendTraffic`; tunnels reach arbitrary targets,
+/// so past this many the rest are left out rather than growing the table
+pub const MAX_TRACKED_BACKENDS: usize = 1024;
+
+/// Totals of the finished connections to one backend
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BackendTotals {
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Traffic totals per backend address, across every listener sharing it.
+/// Connections are added as they end.
+#[derive(Debug, Default)]
+pub struct BackendTraffic {
+    backends: Mutex<BTreeMap<String, BackendTotals>>,
+}
+
+impl BackendTraffic {
+    pub fn record(&self, backend: &str, stats: &ConnectionStats) {
+        let mut backends = self.backends.lock().unwrap_or_else(PoisonError::into_inner);
+        if !backends.contains_key(backend) && backends.len() >= MAX_TRACKED_BACKENDS {
+            return;
+        }
+        let totals = backends.entry(backend.to_string()).or_default();
+        totals.connections += 1;
+        totals.bytes_sent += stats.bytes_sent();
+        totals.bytes_received += stats.bytes_received();
+    }
+
+    /// Totals so far, keyed and ordered by backend address
+    pub fn snapshot(&self) -> BTreeMap<String, BackendTotals> {
+        self.backends.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    /// Collects the fields of every `connection` span, keyed by field name
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        fields: Arc<Mutex<HashMap<String, String>>>,
+        events: Arc<Mutex<usize>>,
+    }
+
+    impl Visit for SpanRecorder {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.fields.lock().unwrap().insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            if attrs.metadata().name() == "connection" {
+                attrs.record(&mut self.clone());
+            }
+        }
+
+        fn on_record(&self, _id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+
+        fn on_event(&self, _event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+            if ctx.current_span().metadata().is_some_and(|m| m.name() == "connection") {
+                *self.events.lock().unwrap() += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_connection_span_fields() {
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+            let conn_info = ConnectionInfo::new(
+                ClientAddr::Inet("127.0.0.1:5000".parse().unwrap()),
+                "0.0.0.0:8080",
+                "10.0.0.1:80",
+                1,
+                &id_manager,
+            )
+            .with_name("web");
+            let mut stats = ConnectionStats::new();
+            stats.add_sent(100);
+            stats.add_received(42);
+
+            let _entered = conn_info.span().entered();
+            conn_info.log_start();
+            conn_info.log_end(&stats, CloseReason::UpstreamEof, None, 0);
+        });
+
+        let fields = recorder.fields.lock().unwrap();
+        assert_eq!(fields["conn_id"], "0");
+        assert_eq!(fields["name"], "web");
+        assert_eq!(fields["client"], "127.0.0.1:5000");
+        assert_eq!(fields["backend"], "10.0.0.1:80");
+        assert_eq!(fields["bytes_sent"], "100");
+        assert_eq!(fields["bytes_received"], "42");
+        assert!(fields.contains_key("duration_secs"));
+        assert_eq!(fields["buffer_saturated"], "false");
+        assert_eq!(fields["backpressured"], "false");
+        assert_eq!(fields["close_reason"], "upstream_eof");
+        assert!(!fields.contains_key("error"));
+        assert_eq!(*recorder.events.lock().unwrap(), 2, "log_start and log_end should be span events");
+    }
+
+    /// Collects what a `tracing_subscriber::fmt` subscriber writes
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_unknown_client_addr_in_log_lines() {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+            let unknown = ConnectionInfo::new(ClientAddr::Unknown, "0.0.0.0:8080", "10.0.0.1:80", 1, &id_manager);
+            unknown.log_start();
+            let unix = ConnectionInfo::new(ClientAddr::Unix("/run/pj.sock".to_string()), "0.0.0.0:8080", "10.0.0.1:80", 1, &id_manager);
+            unix.log_start();
+        });
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Conn #0 estab [1]: unknown -> 0.0.0.0:8080 -> 10.0.0.1:80"), "{}", logs);
+        assert!(logs.contains("Conn #1 estab [1]: /run/pj.sock -> 0.0.0.0:8080 -> 10.0.0.1:80"), "{}", logs);
+        assert!(!logs.contains("0.0.0.0:0"), "{}", logs);
+
+        assert_eq!(ClientAddr::from("127.0.0.1:5000".parse::<SocketAddr>().unwrap()).to_string(), "127.0.0.1:5000");
+        assert_eq!(ClientAddr::Unknown.ip(), None);
+        assert_eq!(serde_json::to_value(ClientAddr::Unknown).unwrap(), "unknown");
+    }
+
+    #[test]
+    fn test_mapped_ipv4_client_is_ipv4() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        let hash = |client: &ClientAddr| {
+            let mut hasher = DefaultHasher::new();
+            client.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:5000".parse().unwrap();
+        let plain: SocketAddr = "1.2.3.4:5000".parse().unwrap();
+        assert_eq!(canonical_ip(mapped.ip()), plain.ip());
+        let (mapped, plain) = (ClientAddr::from(mapped), ClientAddr::from(plain));
+        assert_eq!(mapped, plain);
+        assert_eq!(hash(&mapped), hash(&plain));
+        assert_eq!(mapped.ip(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(mapped.to_string(), "1.2.3.4:5000");
+
+        // Real IPv6 clients are left alone
+        let v6 = ClientAddr::from("[2001:db8::1]:5000".parse::<SocketAddr>().unwrap());
+        assert_eq!(v6.ip(), Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_format_rate() {
+        assert_eq!(format_rate(512), "512 B/s");
+        assert_eq!(format_rate(2048), "2.0 KB/s");
+        assert_eq!(format_rate(3 * 1024 * 1024), "3.0 MB/s");
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(
+            hex_dump(b"GET / HTTP/1.1\r\n"),
+            "0000  47 45 54 20 2f 20 48 54 54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|"
+        );
+        assert_eq!(
+            hex_dump(&[0x00, 0x41, 0xff, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f]),
+            "0000  00 41 ff 42 43 44 45 46 47 48 49 4a 4b 4c 4d 4e  |.A.BCDEFGHIJKLMN|\n\
+             0010  4f                                               |O|"
+        );
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    #[test]
+    fn test_peak_within_single_window() {
+        let start = Instant::now();
+        let mut stats = ConnectionStats::new();
+        stats.add_sent_at(1000, start);
+        stats.add_sent_at(1000, start + Duration::from_millis(300));
+        stats.add_sent_at(1000, start + Duration::from_millis(900));
+        
+        assert_eq!(stats.bytes_sent(), 3000);
+        assert_eq!(stats.peak_tx(), 3000);
+        assert_eq!(stats.peak_rx(), 0);
+    }
+
+    #[test]
+    fn test_peak_rolls_off_old_samples() {
+        let start = Instant::now();
+        let mut stats = ConnectionStats::new();
+        stats.add_received_at(5000, start);
+        // A second later the first burst has left the window
+        stats.add_received_at(100, start + Duration::from_millis(1500));
+        stats.add_received_at(100, start + Duration::from_millis(1600));
+        
+        assert_eq!(stats.bytes_received(), 5200);
+        assert_eq!(stats.peak_rx(), 5000);
+    }
+
+    #[test]
+    fn test_counters_read_while_relaying() {
+        let mut stats = ConnectionStats::new();
+        let counters = stats.counters();
+        let relay = std::thread::spawn(move || {
+            for _ in 0..10_000 {
+                stats.add_sent(3);
+                stats.add_received(1);
+            }
+            stats
+        });
+
+        let (mut sent, mut received) = (0, 0);
+        while !relay.is_finished() {
+            let (now_sent, now_received) = (counters.bytes_sent(), counters.bytes_received());
+            assert!(now_sent >= sent && now_received >= received, "Totals should never go back");
+            (sent, received) = (now_sent, now_received);
+        }
+
+        let stats = relay.join().unwrap();
+        assert_eq!((stats.bytes_sent(), stats.bytes_received()), (30_000, 10_000));
+        assert_eq!((counters.bytes_sent(), counters.bytes_received()), (30_000, 10_000));
+    }
+
+    #[test]
+    fn test_buffer_saturation() {
+        let mut stats = ConnectionStats::new();
+        // Too few reads to judge, even if all were full
+        for _ in 0..SATURATION_MIN_READS - 1 {
+            stats.add_read(1024, 1024);
+        }
+        assert!(!stats.buffer_saturated());
+        stats.add_read(1024, 1024);
+        assert!(stats.buffer_saturated());
+
+        // Mostly short reads
+        let mut stats = ConnectionStats::new();
+        for i in 0..100 {
+            stats.add_read(if i % 4 == 0 { 1024 } else { 200 }, 1024);
+        }
+        assert!(!stats.buffer_saturated());
+
+        // Exactly at the threshold
+        let mut stats = ConnectionStats::new();
+        for i in 0..100 {
+            stats.add_read(if i % 2 == 0 { 1024 } else { 1 }, 1024);
+        }
+        assert!(stats.buffer_saturated());
+    }
+
+    #[test]
+    fn test_peak_tracks_busiest_window() {
+        let start = Instant::now();
+        let mut stats = ConnectionStats::new();
+        // Steady 1000 B per 100ms for 2s => 10000 B/s
+        for i in 0..20 {
+            stats.add_sent_at(1000, start + Duration::from_millis(i * 100));
+        }
+        // Followed by a burst of 4000 B per 100ms for half a second
+        for i in 20..25 {
+            stats.add_sent_at(4000, start + Duration::from_millis(i * 100));
+        }
+        
+        // Window at t=2.4s holds five 1000 B and five 4000 B buckets
+        assert_eq!(stats.peak_tx(), 25000);
+    }
+
+    #[test]
+    fn test_statsd_tags_client_subnet() {
+        let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let statsd = Arc::new(StatsdClient::new(&sink.local_addr().unwrap().to_string(), true).unwrap());
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let subnets = ClientSubnets::parse("/24").ok();
+
+        // Two loopback sources in different /24s, and one without an IP to bucket
+        for client in [ClientAddr::Inet("127.0.0.1:5000".parse().unwrap()), ClientAddr::Inet("127.0.1.9:5000".parse().unwrap()), ClientAddr::Unknown] {
+            let conn_info = ConnectionInfo::new(client, "0.0.0.0:8080", "10.0.0.1:80", 1, &id_manager)
+                .with_name("web")
+                .with_statsd(Some(statsd.clone()))
+                .with_client_subnet(subnets);
+            conn_info.log_start();
+            let mut stats = ConnectionStats::new();
+            stats.add_sent(10);
+            conn_info.log_end(&stats, CloseReason::DownstreamEof, None, 0);
+        }
+
+        let mut lines = Vec::new();
+        let mut buf = [0u8; 2048];
+        while lines.iter().filter(|line: &&String| line.starts_with("pj.bytes.sent")).count() < 3 {
+            let n = sink.recv(&mut buf).expect("Should receive the metrics");
+            lines.extend(std::str::from_utf8(&buf[..n]).unwrap().lines().map(str::to_string));
+        }
+        let tagged = |metric: &str| -> Vec<&str> {
+            lines.iter().filter(|line| line.starts_with(metric)).map(|line| line.split_once("|#").unwrap().1).collect()
+        };
+        assert_eq!(
+            tagged("pj.connections.accepted"),
+            vec![
+                "name:web,backend:10.0.0.1:80,client_subnet:127.0.0.0/24",
+                "name:web,backend:10.0.0.1:80,client_subnet:127.0.1.0/24",
+                "name:web,backend:10.0.0.1:80",
+            ]
+        );
+        assert_eq!(tagged("pj.bytes.sent:10"), tagged("pj.connections.accepted"));
+        // The active gauge stays per mapping
+        assert!(tagged("pj.connections.active").iter().all(|tags| *tags == "name:web"), "{:?}", lines);
+    }
+
+    #[test]
+    fn test_parse_conn_log_levels() {
+        let debug = Some(Level::DEBUG);
+        assert_eq!(ConnLogLevels::parse("debug").unwrap(), ConnLogLevels { start: debug, end: debug, empty: debug });
+        assert_eq!(ConnLogLevels::parse("start=debug").unwrap(), ConnLogLevels { start: debug, ..Default::default() });
+        assert_eq!(ConnLogLevels::parse(" start=off, end=WARN ").unwrap(), ConnLogLevels { start: None, end: Some(Level::WARN), empty: Some(Level::WARN) });
+        assert_eq!(ConnLogLevels::parse("end=off, empty=info").unwrap(), ConnLogLevels { end: None, ..Default::default() });
+        assert_eq!(ConnLogLevels::parse("empty=trace").unwrap(), ConnLogLevels { empty: Some(Level::TRACE), ..Default::default() });
+        for input in ["start=loud", "middle=debug", "loud", ""] {
+            assert!(ConnLogLevels::parse(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::time::timeout;
+
+use pingora_core::protocols::Stream;
+
+const HTTP_METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// How long to wait for the client's first line before treating the
+/// connection as raw TCP. Server-speaks-first protocols hit this delay.
+pub const DETECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether `data` starts with an HTTP/1.x request line (`METHOD target HTTP/x`).
+///
+/// Only a complete first line counts; a partial line is treated as raw TCP.
+pub fn looks_like_http(data: &[u8]) -> bool {
+    let line_end = match data.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None => return false,
+    };
+    let line = match std::str::from_utf8(&data[..line_end]) {
+        Ok(line) => line.trim_end_matches('\r'),
+        Err(_) => return false,
+    };
+
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => {
+            HTTP_METHODS.contains(&method) && !target.is_empty() && version.starts_with("HTTP/")
+        }
+        _ => false,
+    }
+}
+
+/// Reads the start of a connection until the first line is complete, `limit`
+/// bytes are buffered, the client closes, or `wait` elapses. The returned
+/// bytes have been consumed from `stream` and must be replayed upstream.
+pub async fn read_preamble(stream: &mut Stream, limit: usize, wait: Duration) -> io::Result<Vec<u8>> {
+    let mut preamble = vec![0; limit];
+    let mut filled = 0;
+
+    let read_line = async {
+        while filled < limit {
+            let n = stream.read(&mut preamble[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+            if preamble[..filled].contains(&b'\n') {
+                break;
+            }
+        }
+        Ok::<(), io::Error>(())
+    };
+
+    // Timing out just means the client hasn't sent a full line; route what we have
+    if let Ok(result) = timeout(wait, read_line).await {
+        result?;
+    }
+
+    preamble.truncate(filled);
+    Ok(preamble)
+}
+
+/// Keeps reading into `head` until it holds a complete header block (up to
+/// the blank line), `limit` bytes are buffered, the client closes, or `wait`
+/// elapses. Returns whether the block is complete.
+pub async fn read_header_block(stream: &mut Stream, head: &mut Vec<u8>, limit: usize, wait: Duration) -> io::Result<bool> {
+    let read_block = async {
+        let mut chunk = [0u8; 1024];
+        while header_block_end(head).is_none() && head.len() < limit {
+            let n = stream.read(&mut chunk[..(limit - head.len()).min(1024)]).await?;
+            if n == 0 {
+                break;
+            }
+            head.extend_from_slice(&chunk[..n]);
+        }
+        Ok::<(), io::Error>(())
+    };
+    if let Ok(result) = timeout(wait, read_block).await {
+        result?;
+    }
+    Ok(header_block_end(head).is_some())
+}
+
+/// Where the blank line ending the header block starts
+fn header_block_end(head: &[u8]) -> Option<usize> {
+    head.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+/// Adds `client` to the request's `X-Forwarded-For` header, appending to the
+/// existing one or adding it after the last header. Leaves `head` alone and
+/// returns false when it does not hold a complete header block.
+pub fn add_forwarded_for(head: &mut Vec<u8>, client: IpAddr) -> bool {
+    let Some(end) = header_block_end(head) else { return false };
+    let mut line_start = match head.windows(2).position(|w| w == b"\r\n") {
+        Some(request_line_end) => request_line_end + 2,
+        None => return false,
+    };
+    while line_start < end + 2 {
+        let line_end = line_start + head[line_start..].windows(2).position(|w| w == b"\r\n").unwrap_or(0);
+        let line = &head[line_start..line_end];
+        let is_forwarded_for = line
+            .iter()
+            .position(|&b| b == b':')
+            .is_some_and(|colon| line[..colon].eq_ignore_ascii_case(b"x-forwarded-for"));
+        if is_forwarded_for {
+            head.splice(line_end..line_end, format!(", {}", client).into_bytes());
+            return true;
+        }
+        line_start = line_end + 2;
+    }
+    head.splice(end + 2..end + 2, format!("X-Forwarded-For: {}\r\n", client).into_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_http() {
+        assert!(looks_like_http(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+        assert!(looks_like_http(b"POST /api/v1/items?x=1 HTTP/1.0\r\n"));
+        assert!(looks_like_http(b"OPTIONS * HTTP/1.1\n"));
+    }
+
+    #[test]
+    fn test_looks_like_http_rejects_other_protocols() {
+        assert!(!looks_like_http(b"SSH-2.0-OpenSSH_9.6\r\n"));
+        assert!(!looks_like_http(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03"));
+        assert!(!looks_like_http(b"get / HTTP/1.1\r\n"));
+        assert!(!looks_like_http(b"GET / HTTP/1.1"));
+        assert!(!looks_like_http(b"GET /\r\n"));
+        assert!(!looks_like_http(b"GET  / HTTP/1.1\r\n"));
+        assert!(!looks_like_http(b""));
+    }
+
+    #[test]
+    fn test_add_forwarded_for() {
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let mut head = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nbody".to_vec();
+        assert!(add_forwarded_for(&mut head, client));
+        assert_eq!(head, b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 203.0.113.7\r\n\r\nbody");
+
+        // An existing header gets the client appended, as proxies in a chain do
+        let mut head = b"GET / HTTP/1.1\r\nx-forwarded-for: 10.0.0.1\r\nHost: a\r\n\r\n".to_vec();
+        assert!(add_forwarded_for(&mut head, "::1".parse().unwrap()));
+        assert_eq!(head, b"GET / HTTP/1.1\r\nx-forwarded-for: 10.0.0.1, ::1\r\nHost: a\r\n\r\n");
+
+        let mut head = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+        assert!(add_forwarded_for(&mut head, client));
+        assert_eq!(head, b"GET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n");
+
+        // Without the whole header block the request is left as it is
+        let mut head = b"GET / HTTP/1.1\r\nHost: exa".to_vec();
+        assert!(!add_forwarded_for(&mut head, client));
+        assert_eq!(head, b"GET / HTTP/1.1\r\nHost: exa");
+    }
+}
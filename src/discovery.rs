@@ -0,0 +1,265 @@
+use async_trait::async_trait;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::process::Command;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use tracing::{error, info};
+
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_core::upstreams::peer::BasicPeer;
+
+use crate::balancer::{self, LbStrategy, UpstreamPool};
+
+/// Upstreams of a mapping that can be swapped while it is serving. Each
+/// connection picks from whichever pool is current when it arrives, and
+/// keeps its upstream when the pool is replaced.
+pub struct DiscoveredUpstream {
+    pool: RwLock<Arc<UpstreamPool>>,
+    strategy: LbStrategy,
+    // Connections relayed right now, by upstream address
+    active: Mutex<HashMap<String, u64>>,
+}
+
+/// Counts a connection against its upstream address until dropped
+pub struct UpstreamLease<'a> {
+    upstream: &'a DiscoveredUpstream,
+    addr: String,
+}
+
+impl Drop for UpstreamLease<'_> {
+    fn drop(&mut self) {
+        let mut active = self.upstream.active.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = active.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.addr);
+            }
+        }
+    }
+}
+
+/// A replacement that changed which addresses new connections go to
+#[derive(Debug, PartialEq)]
+pub struct UpstreamChange {
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+    /// Connections still relayed to each address that was dropped
+    pub remaining: Vec<(String, u64)>,
+}
+
+impl fmt::Display for UpstreamChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstreams changed from {} to {}", self.old.join("|"), self.new.join("|"))?;
+        if self.remaining.is_empty() {
+            return Ok(());
+        }
+        let total: u64 = self.remaining.iter().map(|(_, count)| count).sum();
+        let removed: Vec<String> = match self.remaining.as_slice() {
+            [(addr, _)] => vec![addr.clone()],
+            remaining => remaining.iter().map(|(addr, count)| format!("{} ({})", addr, count)).collect(),
+        };
+        write!(f, "; {} connections remain on {}", total, removed.join(", "))
+    }
+}
+
+impl DiscoveredUpstream {
+    pub fn new(peers: Vec<(BasicPeer, u32)>, strategy: LbStrategy) -> Self {
+        DiscoveredUpstream {
+            pool: RwLock::new(Arc::new(UpstreamPool::new(peers, strategy))),
+            strategy,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn current(&self) -> Arc<UpstreamPool> {
+        self.pool.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Counts a connection relayed to `addr` while the lease is held
+    pub fn lease(&self, addr: &str) -> UpstreamLease<'_> {
+        *self.active.lock().unwrap_or_else(PoisonError::into_inner).entry(addr.to_string()).or_default() += 1;
+        UpstreamLease { upstream: self, addr: addr.to_string() }
+    }
+
+    /// Connections relayed to `addr` right now
+    pub fn active(&self, addr: &str) -> u64 {
+        self.active.lock().unwrap_or_else(PoisonError::into_inner).get(addr).copied().unwrap_or(0)
+    }
+
+    /// Swaps in `peers` for new connections, returning how the addresses
+    /// changed if they did; connections already relayed stay where they are
+    pub fn replace(&self, peers: Vec<(BasicPeer, u32)>) -> Option<UpstreamChange> {
+        let pool = Arc::new(UpstreamPool::new(peers, self.strategy));
+        let new = pool.addresses();
+        let old = std::mem::replace(&mut *self.pool.write().unwrap_or_else(PoisonError::into_inner), pool).addresses();
+        let sorted = |addrs: &[String]| addrs.iter().cloned().collect::<BTreeSet<_>>();
+        if sorted(&old) == sorted(&new) {
+            return None;
+        }
+        let remaining = sorted(&old)
+            .into_iter()
+            .filter(|addr| !new.contains(addr))
+            .map(|addr| {
+                let count = self.active(&addr);
+                (addr, count)
+            })
+            .collect();
+        Some(UpstreamChange { old, new, remaining })
+    }
+}
+
+/// Shell command whose output names a mapping's upstreams (`PJ_UPSTREAM_CMD`)
+pub struct UpstreamCommand {
+    command: String,
+    upstream: Arc<DiscoveredUpstream>,
+    interval: Option<Duration>,
+    // Output of the last successful run, so unchanged results keep the pool's state
+    last_output: Mutex<Option<String>>,
+}
+
+impl UpstreamCommand {
+    pub fn new(command: &str, upstream: Arc<DiscoveredUpstream>) -> Self {
+        UpstreamCommand {
+            command: command.to_string(),
+            upstream,
+            interval: None,
+            last_output: Mutex::new(None),
+        }
+    }
+
+    /// Re-run the command this often once the server is up
+    pub fn every(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Runs the command and swaps in the upstreams it printed. On failure the
+    /// current upstreams stay in place.
+    pub fn refresh(&self) -> Result<(), String> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .output()
+            .map_err(|e| format!("failed to run '{}': {}", self.command, e))?;
+        if !output.status.success() {
+            return Err(format!("'{}' exited with {}", self.command, output.status));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        let mut last_output = self.last_output.lock().unwrap_or_else(PoisonError::into_inner);
+        if last_output.as_deref() == Some(stdout.as_str()) {
+            return Ok(());
+        }
+        let peers = parse_upstreams(&stdout)?;
+        info!("Upstream command returned {}", stdout);
+        if let Some(change) = self.upstream.replace(peers) {
+            info!("{}", change);
+        }
+        *last_output = Some(stdout);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UpstreamCommand {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(interval) = self.interval else { return };
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.changed() => return,
+            }
+            // Blocking is fine here: background services get a runtime of their own
+            if let Err(e) = self.refresh() {
+                error!("Upstream command failed, keeping the current upstreams: {}", e);
+            }
+        }
+    }
+}
+
+/// Parses command output: `host:port` entries with optional `*weight`,
+/// separated by `|`, commas or whitespace. Host names are resolved here.
+pub fn parse_upstreams(output: &str) -> Result<Vec<(BasicPeer, u32)>, String> {
+    let mut peers = Vec::new();
+    for entry in output.split(|c: char| c == '|' || c == ',' || c.is_whitespace()).filter(|e| !e.is_empty()) {
+        let (addr, weight) = balancer::parse_upstream(entry)?;
+        let resolved = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("'{}' is not a resolvable host:port", addr))?;
+        peers.push((BasicPeer::new(&resolved.to_string()), weight));
+    }
+    if peers.is_empty() {
+        return Err("no upstreams in command output".to_string());
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(pool: &UpstreamPool, picks: usize) -> Vec<String> {
+        let client = "127.0.0.1".parse().unwrap();
+        (0..picks).map(|_| pool.select(client)._address.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_upstreams() {
+        let peers = parse_upstreams("10.0.0.1:80*2|10.0.0.2:80, 10.0.0.3:80\n").unwrap();
+        let parsed: Vec<(String, u32)> = peers.iter().map(|(p, w)| (p._address.to_string(), *w)).collect();
+        assert_eq!(
+            parsed,
+            vec![
+                ("10.0.0.1:80".to_string(), 2),
+                ("10.0.0.2:80".to_string(), 1),
+                ("10.0.0.3:80".to_string(), 1),
+            ]
+        );
+
+        let resolved = parse_upstreams("localhost:8080").unwrap();
+        assert_eq!(resolved[0].0._address.as_inet().unwrap().port(), 8080);
+
+        assert!(parse_upstreams("").is_err());
+        assert!(parse_upstreams("10.0.0.1").is_err());
+        assert!(parse_upstreams("10.0.0.1:80*0").is_err());
+    }
+
+    #[test]
+    fn test_refresh_keeps_upstreams_on_failure() {
+        let upstream = Arc::new(DiscoveredUpstream::new(vec![(BasicPeer::new("10.0.0.1:80"), 1)], LbStrategy::RoundRobin));
+
+        UpstreamCommand::new("echo 10.0.0.2:80", upstream.clone()).refresh().unwrap();
+        assert_eq!(addresses(&upstream.current(), 1), vec!["10.0.0.2:80"]);
+
+        assert!(UpstreamCommand::new("exit 3", upstream.clone()).refresh().is_err());
+        assert!(UpstreamCommand::new("echo not-an-address", upstream.clone()).refresh().is_err());
+        assert_eq!(addresses(&upstream.current(), 1), vec!["10.0.0.2:80"]);
+    }
+
+    #[test]
+    fn test_replace_reports_connections_left_on_old_upstream() {
+        let upstream = DiscoveredUpstream::new(vec![(BasicPeer::new("10.0.0.1:80"), 1)], LbStrategy::RoundRobin);
+        let first = upstream.lease("10.0.0.1:80");
+        let second = upstream.lease("10.0.0.1:80");
+        drop(upstream.lease("10.0.0.1:80"));
+        assert_eq!(upstream.active("10.0.0.1:80"), 2);
+
+        // The name now resolves elsewhere: new connections move, open ones stay
+        let change = upstream.replace(vec![(BasicPeer::new("10.0.0.2:80"), 1)]).expect("The addresses changed");
+        assert_eq!(change.remaining, vec![("10.0.0.1:80".to_string(), 2)]);
+        assert_eq!(change.to_string(), "Upstreams changed from 10.0.0.1:80 to 10.0.0.2:80; 2 connections remain on 10.0.0.1:80");
+        assert_eq!(addresses(&upstream.current(), 1), vec!["10.0.0.2:80"]);
+
+        drop((first, second));
+        assert_eq!(upstream.active("10.0.0.1:80"), 0);
+        let change = upstream.replace(vec![(BasicPeer::new("10.0.0.2:80"), 1), (BasicPeer::new("10.0.0.1:80"), 1)]).unwrap();
+        assert_eq!(change.to_string(), "Upstreams changed from 10.0.0.2:80 to 10.0.0.2:80|10.0.0.1:80");
+        // The same addresses with other weights aren't a change
+        assert_eq!(upstream.replace(vec![(BasicPeer::new("10.0.0.1:80"), 3), (BasicPeer::new("10.0.0.2:80"), 1)]), None);
+    }
+}
@@ -21,6 +21,14 @@ impl fmt::Display for ProxyError {
     }
 }
 
+impl ProxyError {
+    /// Data transfer failure labelled with the leg and operation that failed,
+    /// e.g. `upstream write`
+    pub fn transfer(operation: &str, error: io::Error) -> Self {
+        ProxyError::DataTransfer(format!("{}: {}", operation, error))
+    }
+}
+
 impl StdError for ProxyError {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match self {
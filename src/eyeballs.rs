@@ -0,0 +1,132 @@
+use std::future::{poll_fn, Future};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+/// How long an attempt gets before the next address is tried alongside it
+/// (the RFC 8305 recommendation)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Reorders resolved addresses so the families alternate, keeping the
+/// resolver's preference within each family and starting with its first pick
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else { return addrs };
+    let preferred_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == preferred_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop());
+        ordered.extend(other.pop());
+    }
+    ordered
+}
+
+/// Happy Eyeballs over `count` candidates: attempt `i` starts once attempt
+/// `i - 1` has had `stagger` to connect, or as soon as it fails. The first
+/// attempt to succeed wins, along with its index, and the others are
+/// dropped. When every attempt fails the last error is returned.
+///
+/// `count` must be at least one.
+pub async fn race<T, E, F, Fut>(count: usize, stagger: Duration, mut connect: F) -> Result<(usize, T), E>
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    assert!(count > 0, "nothing to connect to");
+    let mut attempts: Vec<(usize, Pin<Box<Fut>>)> = Vec::with_capacity(count);
+    let mut next = 0;
+    let mut start_now = true;
+    let mut last_error = None;
+    let mut timer = Box::pin(tokio::time::sleep(stagger));
+
+    poll_fn(|cx| loop {
+        if next < count && (start_now || timer.as_mut().poll(cx).is_ready()) {
+            attempts.push((next, Box::pin(connect(next))));
+            next += 1;
+            start_now = false;
+            timer.as_mut().reset(tokio::time::Instant::now() + stagger);
+            // Polls the new timer too, so it wakes this task when it fires
+            continue;
+        }
+
+        let mut i = 0;
+        while i < attempts.len() {
+            match attempts[i].1.as_mut().poll(cx) {
+                Poll::Ready(Ok(stream)) => return Poll::Ready(Ok((attempts[i].0, stream))),
+                Poll::Ready(Err(e)) => {
+                    attempts.swap_remove(i);
+                    last_error = Some(e);
+                    start_now = true;
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+
+        if start_now && next < count {
+            continue;
+        }
+        if attempts.is_empty() {
+            return Poll::Ready(Err(last_error.take().expect("every attempt failed")));
+        }
+        return Poll::Pending;
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:80", "[2001:db8::2]:80", "[2001:db8::3]:80", "192.0.2.1:80", "192.0.2.2:80"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave_families(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            ordered,
+            vec!["[2001:db8::1]:80", "192.0.2.1:80", "[2001:db8::2]:80", "192.0.2.2:80", "[2001:db8::3]:80"]
+        );
+        assert!(interleave_families(Vec::new()).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_attempt_is_overtaken() {
+        let started = Instant::now();
+        // The first address never answers, the second connects at once
+        let result: Result<(usize, &str), String> = race(2, CONNECTION_ATTEMPT_DELAY, |i| async move {
+            if i == 0 {
+                std::future::pending::<()>().await;
+            }
+            Ok("connected")
+        })
+        .await;
+        assert_eq!(result.unwrap(), (1, "connected"));
+        assert_eq!(started.elapsed(), CONNECTION_ATTEMPT_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failure_starts_next_attempt_at_once() {
+        let started = Instant::now();
+        let result: Result<(usize, u32), String> = race(3, CONNECTION_ATTEMPT_DELAY, |i| async move {
+            match i {
+                0 => Err("refused".to_string()),
+                _ => {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    Ok(i as u32)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), (1, 1));
+        assert_eq!(started.elapsed(), Duration::from_millis(10));
+
+        let all_failed: Result<(usize, ()), String> = race(2, CONNECTION_ATTEMPT_DELAY, |i| async move { Err(format!("attempt {}", i)) }).await;
+        assert_eq!(all_failed.unwrap_err(), "attempt 1");
+    }
+}
@@ -0,0 +1,110 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use pingora_core::listeners::Listeners;
+use pingora_core::server::Fds;
+use pingora_core::services::listening::Service as ListeningService;
+use pingora_core::services::Service;
+
+use crate::activation::{bind_listener, InheritedListener};
+use crate::id_manager::ConnectionIdManager;
+use crate::sni::SniRoutes;
+use crate::{parse_proxy_mappings, proxy_app, ListenMode, ProxyApp, ProxyOptions};
+
+/// How long `TestProxy::shutdown` waits for each listener to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Forward and TLS passthrough mappings run on the caller's tokio runtime,
+/// for tests that would otherwise spawn the `pj` binary and wait for it to
+/// bind.
+///
+/// Pingora's `Server::run_forever` exits the process when it shuts down, so
+/// the mappings' services are started the way it starts them instead, on
+/// sockets bound before `start` returns. Listen on port 0 to have the OS
+/// pick a free port; `addrs` has the ports it picked.
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+/// use pj::harness::TestProxy;
+/// use pj::ProxyOptions;
+///
+/// let proxy = TestProxy::start("127.0.0.1:0:127.0.0.1:9", ProxyOptions::default()).await.unwrap();
+/// assert_ne!(proxy.addr().port(), 0);
+/// proxy.shutdown().await;
+/// # }
+/// ```
+pub struct TestProxy {
+    addrs: Vec<SocketAddr>,
+    shutdown: watch::Sender<bool>,
+    services: Vec<JoinHandle<()>>,
+}
+
+impl TestProxy {
+    /// Starts `mappings`, given as for `--proxy`, with `options` for the
+    /// settings the mappings don't set themselves
+    pub async fn start(mappings: &str, options: ProxyOptions) -> io::Result<TestProxy> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let fds = Arc::new(Mutex::new(Fds::new()));
+        let (shutdown, watch) = watch::channel(false);
+        let mut addrs = Vec::new();
+        let mut services = Vec::new();
+        for mut mapping in parse_proxy_mappings(mappings).map_err(invalid)? {
+            if !matches!(mapping.mode, ListenMode::Forward | ListenMode::Tls) {
+                return Err(invalid(format!("{} is not a forward or TLS mapping", mapping.listen_addr)));
+            }
+            let addr: SocketAddr = mapping
+                .listen_addr
+                .parse()
+                .map_err(|_| invalid(format!("{} is not an IP address and port", mapping.listen_addr)))?;
+            let listener = bind_listener(addr, mapping.interface.as_deref())?;
+            let bound = listener.local_addr()?;
+            mapping.listen_addr = bound.to_string();
+
+            let (ids, mapping_options) = (mapping.id_manager(&id_manager), mapping.options(&options));
+            let app = match mapping.mode {
+                ListenMode::Tls => ProxyApp::sni(mapping.listen_addr.clone(), ids, SniRoutes::new(&mapping.sni_routes), mapping_options),
+                _ => proxy_app(&mapping.listen_addr, &mapping.proxy_addr, ids, mapping_options)?,
+            };
+            let proxy = ListeningService::with_listeners("Proxy Service".to_string(), Listeners::tcp(&mapping.listen_addr), app);
+            let mut service = InheritedListener::with_listener(proxy, &mapping.listen_addr, listener);
+            let (fds, watch) = (fds.clone(), watch.clone());
+            services.push(tokio::spawn(async move { service.start_service(Some(fds), watch).await }));
+            addrs.push(bound);
+        }
+        Ok(TestProxy { addrs, shutdown, services })
+    }
+
+    /// The first mapping's listen address
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// Every mapping's listen address, in the order they were given
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Stops accepting, as a graceful shutdown would, and waits for the
+    /// listeners to close; connections already accepted are left to finish
+    pub async fn shutdown(mut self) {
+        let _ = self.shutdown.send(true);
+        for service in self.services.drain(..) {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, service).await.is_err() {
+                warn!("A test proxy listener did not stop within {:?}", SHUTDOWN_TIMEOUT);
+            }
+        }
+    }
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send(true);
+    }
+}
@@ -0,0 +1,161 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+
+use crate::connection::{format_bytes, BackendTraffic, TrafficCounters};
+use crate::metrics::{SizeHistogram, SIZE_QUANTILES};
+
+/// How often the heartbeat is logged unless `PJ_HEARTBEAT_INTERVAL` says otherwise
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Logs a summary of every listener's traffic periodically, so a quiet log
+/// still shows the proxy is alive
+pub struct Heartbeat {
+    listeners: Vec<TrafficCounters>,
+    backends: Option<Arc<BackendTraffic>>,
+    sizes: Option<Arc<SizeHistogram>>,
+    interval: Duration,
+}
+
+impl Heartbeat {
+    pub fn new(listeners: Vec<TrafficCounters>, interval: Duration) -> Self {
+        Heartbeat { listeners, backends: None, sizes: None, interval }
+    }
+
+    /// Follow the summary with a line per backend from `backends`
+    pub fn with_backends(mut self, backends: Arc<BackendTraffic>) -> Self {
+        self.backends = Some(backends);
+        self
+    }
+
+    /// Follow the summary with percentiles of the bytes per connection in `sizes`
+    pub fn with_connection_sizes(mut self, sizes: Arc<SizeHistogram>) -> Self {
+        self.sizes = Some(sizes);
+        self
+    }
+
+    /// Percentiles of the bytes finished connections relayed, once there are any
+    pub fn size_summary(&self) -> Option<String> {
+        let sizes = self.sizes.as_ref()?;
+        let percentiles: Option<Vec<String>> = SIZE_QUANTILES
+            .iter()
+            .map(|quantile| sizes.percentile(*quantile).map(|bytes| format!("p{}: {}", quantile * 100.0, format_bytes(bytes))))
+            .collect();
+        Some(format!("Heartbeat: bytes per connection | {}", percentiles?.join(" | ")))
+    }
+
+    /// One line per backend that has finished connections, in address order
+    pub fn backend_summaries(&self) -> Vec<String> {
+        let Some(backends) = &self.backends else { return Vec::new() };
+        backends
+            .snapshot()
+            .iter()
+            .map(|(backend, totals)| {
+                format!(
+                    "Heartbeat: backend {} | {} connections | Sent: {} | Received: {}",
+                    backend,
+                    totals.connections,
+                    format_bytes(totals.bytes_sent),
+                    format_bytes(totals.bytes_received),
+                )
+            })
+            .collect()
+    }
+
+    /// The line the heartbeat logs, totalled over all listeners
+    pub fn summary(&self) -> String {
+        let total = |count: fn(&TrafficCounters) -> u64| self.listeners.iter().map(count).sum::<u64>();
+        format!(
+            "Heartbeat: {} active | {} connections since start | Sent: {} | Received: {}",
+            total(TrafficCounters::active),
+            total(TrafficCounters::connections),
+            format_bytes(total(TrafficCounters::bytes_sent)),
+            format_bytes(total(TrafficCounters::bytes_received)),
+        )
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Heartbeat {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = shutdown.changed() => return,
+            }
+            info!("{}", self.summary());
+            if let Some(line) = self.size_summary() {
+                info!("{}", line);
+            }
+            for line in self.backend_summaries() {
+                info!("{}", line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionStats;
+
+    #[test]
+    fn test_summary_totals_listeners() {
+        let (web, ssh) = (TrafficCounters::default(), TrafficCounters::default());
+        web.add_connection();
+        web.add_sent(2048);
+        web.add_received(100);
+        ssh.add_connection();
+        ssh.add_connection();
+        ssh.add_received(24);
+        ssh.active_counter().store(1, std::sync::atomic::Ordering::Relaxed);
+
+        let heartbeat = Heartbeat::new(vec![web, ssh], Duration::from_secs(60));
+        assert_eq!(
+            heartbeat.summary(),
+            "Heartbeat: 1 active | 3 connections since start | Sent: 2.0 KB | Received: 124 B"
+        );
+        assert!(heartbeat.backend_summaries().is_empty());
+        assert!(heartbeat.size_summary().is_none());
+    }
+
+    #[test]
+    fn test_size_summary() {
+        let sizes = Arc::new(SizeHistogram::default());
+        let heartbeat = Heartbeat::new(Vec::new(), Duration::from_secs(60)).with_connection_sizes(sizes.clone());
+        assert!(heartbeat.size_summary().is_none(), "Nothing to report before a connection ends");
+
+        // Sizes at their bucket's midpoint are reported exactly
+        for bytes in [10, 10, 10, 10, 10, 102, 102, 102, 102, 2112] {
+            sizes.observe(bytes);
+        }
+        assert_eq!(
+            heartbeat.size_summary().unwrap(),
+            "Heartbeat: bytes per connection | p50: 10 B | p90: 102 B | p99: 2.1 KB"
+        );
+    }
+
+    #[test]
+    fn test_backend_summaries() {
+        let backends = Arc::new(BackendTraffic::default());
+        let mut stats = ConnectionStats::new();
+        stats.add_sent(4096);
+        stats.add_received(10);
+        backends.record("10.0.0.2:80", &stats);
+        backends.record("10.0.0.1:80", &ConnectionStats::new());
+        backends.record("10.0.0.2:80", &stats);
+
+        let heartbeat = Heartbeat::new(Vec::new(), Duration::from_secs(60)).with_backends(backends);
+        assert_eq!(
+            heartbeat.backend_summaries(),
+            vec![
+                "Heartbeat: backend 10.0.0.1:80 | 1 connections | Sent: 0 B | Received: 0 B",
+                "Heartbeat: backend 10.0.0.2:80 | 2 connections | Sent: 8.0 KB | Received: 20 B",
+            ]
+        );
+    }
+}
@@ -1,14 +1,33 @@
+use std::fmt;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 use tracing::info;
 
 pub struct ConnectionIdManager {
+    // Connections since the last reset; IDs are this plus `offset`
     counter: AtomicU64,
     last_reset_time: Mutex<Instant>,
     last_reset_count: AtomicU64,
     reset_interval: Option<Duration>,
     reset_threshold: Option<u64>,
+    offset: u64,
+    width: usize,
+    // The mapping whose IDs these are, when it doesn't share them
+    label: Option<String>,
+}
+
+/// A connection ID as logged, zero-padded to the manager's width
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayId {
+    id: u64,
+    width: usize,
+}
+
+impl fmt::Display for DisplayId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:0width$}", self.id, width = self.width)
+    }
 }
 
 impl ConnectionIdManager {
@@ -19,20 +38,52 @@ impl ConnectionIdManager {
             last_reset_count: AtomicU64::new(0),
             reset_interval,
             reset_threshold,
+            offset: 0,
+            width: 0,
+            label: None,
         }
     }
 
+    /// A manager for one mapping's connections alone, numbered with this
+    /// one's offset and width but reset by its own policy, each setting left
+    /// unset falling back to this one's. Its reset lines name `mapping`.
+    pub fn for_mapping(&self, mapping: &str, reset_interval: Option<Duration>, reset_threshold: Option<u64>) -> Self {
+        Self {
+            label: Some(mapping.to_string()),
+            ..Self::new(reset_interval.or(self.reset_interval), reset_threshold.or(self.reset_threshold))
+                .with_offset(self.offset)
+                .with_width(self.width)
+        }
+    }
+
+    /// Start IDs at `offset` instead of 0, and wrap back to it on reset, so
+    /// instances whose logs are merged can be told apart
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Zero-pad logged IDs to `width` digits, so they sort as text
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
     pub fn next_id(&self) -> u64 {
         // Check if we need to reset before incrementing
         let current_count = self.counter.load(Ordering::Relaxed);
         if self.should_reset(current_count) {
             self.reset(current_count);
             // After reset, counter is 0, so fetch_add returns 0 and sets it to 1
-            return self.counter.fetch_add(1, Ordering::Relaxed);
+            return self.offset.wrapping_add(self.counter.fetch_add(1, Ordering::Relaxed));
         }
         
         // Normal case: increment and return the old value
-        self.counter.fetch_add(1, Ordering::Relaxed)
+        self.offset.wrapping_add(self.counter.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn display(&self, id: u64) -> DisplayId {
+        DisplayId { id, width: self.width }
     }
 
     fn should_reset(&self, current_count: u64) -> bool {
@@ -60,19 +111,20 @@ impl ConnectionIdManager {
         };
         
         let reset_count = self.last_reset_count.fetch_add(1, Ordering::Relaxed) + 1;
-        let by_count = self.reset_threshold.map_or(false, |t| last_id >= t);
-        let by_time = self.reset_interval.map_or(false, |i| elapsed >= i);
+        let by_count = self.reset_threshold.is_some_and(|t| last_id >= t);
+        let by_time = self.reset_interval.is_some_and(|i| elapsed >= i);
         
         info!(
-            "Connection ID reset #{}: {} (last_id: {}, elapsed: {:.2}s)",
+            "Connection ID reset #{}{}: {} (last_id: {}, elapsed: {:.2}s)",
             reset_count,
+            self.label.as_ref().map(|mapping| format!(" for {}", mapping)).unwrap_or_default(),
             match (by_count, by_time) {
                 (true, false) => "count threshold reached",
                 (false, true) => "time interval elapsed",
                 (true, true) => "both count and time triggered",
                 _ => "unknown trigger",
             },
-            last_id,
+            self.offset.wrapping_add(last_id),
             elapsed.as_secs_f64()
         );
         
@@ -81,16 +133,22 @@ impl ConnectionIdManager {
     }
 }
 
+/// Parses durations such as `30m`, `1d12h`, `2w` or `50ms`.
+///
+/// Units are w/d/h/m/s/ms and each may appear at most once, so a typo like
+/// `1d1d` is rejected rather than silently summed.
 pub fn parse_duration(s: &str) -> Result<Duration, String> {
     let s = s.trim().to_lowercase();
     if s.is_empty() {
         return Err("Empty duration string".to_string());
     }
     
-    let mut total_seconds = 0u64;
+    let mut total_millis = 0u64;
     let mut current_num = String::new();
+    let mut seen_units: Vec<&str> = Vec::new();
+    let mut chars = s.chars().peekable();
     
-    for ch in s.chars() {
+    while let Some(ch) = chars.next() {
         if ch.is_ascii_digit() {
             current_num.push(ch);
         } else {
@@ -101,31 +159,54 @@ pub fn parse_duration(s: &str) -> Result<Duration, String> {
             let num: u64 = current_num.parse()
                 .map_err(|_| format!("Invalid number: {}", current_num))?;
             
-            let multiplier = match ch {
-                'd' => 86400,
-                'h' => 3600,
-                'm' => 60,
-                's' => 1,
+            let (unit, multiplier) = match ch {
+                'w' => ("w", 604_800_000),
+                'd' => ("d", 86_400_000),
+                'h' => ("h", 3_600_000),
+                'm' if chars.next_if_eq(&'s').is_some() => ("ms", 1),
+                'm' => ("m", 60_000),
+                's' => ("s", 1000),
                 _ => return Err(format!("Invalid time unit: '{}'", ch)),
             };
             
-            total_seconds += num * multiplier;
+            if seen_units.contains(&unit) {
+                return Err(format!("Duplicate time unit: '{}'", unit));
+            }
+            seen_units.push(unit);
+            
+            total_millis = num
+                .checked_mul(multiplier)
+                .and_then(|millis| total_millis.checked_add(millis))
+                .ok_or_else(|| "Duration value too large".to_string())?;
             current_num.clear();
         }
     }
     
     if !current_num.is_empty() {
-        return Err("Duration must include a unit (d/h/m/s)".to_string());
+        return Err("Duration must include a unit (w/d/h/m/s/ms)".to_string());
     }
     
-    if total_seconds == 0 {
+    if total_millis == 0 {
         return Err("Duration must be greater than 0".to_string());
     }
     
-    Ok(Duration::from_secs(total_seconds))
+    Ok(Duration::from_millis(total_millis))
 }
 
+/// Parses counts such as `500000`, `100k` or `1.5m`.
+///
+/// Units are decimal (k/m/g/t = 10^3/10^6/10^9/10^12). A single decimal
+/// point is allowed and the result is rounded to the nearest whole count.
 pub fn parse_count(s: &str) -> Result<u64, String> {
+    match parse_number(s)? {
+        0 => Err("Count must be greater than 0".to_string()),
+        count => Ok(count),
+    }
+}
+
+/// Parses a number in the same format as `parse_count`, allowing 0, for
+/// settings such as an offset where 0 is a value rather than nothing.
+pub fn parse_number(s: &str) -> Result<u64, String> {
     let s = s.trim().to_lowercase();
     if s.is_empty() {
         return Err("Empty count string".to_string());
@@ -137,19 +218,38 @@ pub fn parse_count(s: &str) -> Result<u64, String> {
         (&s[..s.len()-1], 1_000_000)
     } else if s.ends_with('g') {
         (&s[..s.len()-1], 1_000_000_000)
+    } else if s.ends_with('t') {
+        (&s[..s.len()-1], 1_000_000_000_000)
     } else {
         (s.as_str(), 1)
     };
     
-    let num: u64 = num_str.parse()
-        .map_err(|_| format!("Invalid number: {}", num_str))?;
-    
-    if num == 0 {
-        return Err("Count must be greater than 0".to_string());
+    // Only plain digits with at most one '.'; f64 parsing alone would also
+    // accept signs, exponents and "inf"
+    let well_formed = num_str.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && num_str.matches('.').count() <= 1
+        && num_str.chars().any(|c| c.is_ascii_digit());
+    if !well_formed {
+        return Err(format!("Invalid number: {}", num_str));
     }
     
-    num.checked_mul(unit)
-        .ok_or_else(|| "Count value too large".to_string())
+    let count = if num_str.contains('.') {
+        let num: f64 = num_str.parse()
+            .map_err(|_| format!("Invalid number: {}", num_str))?;
+        let scaled = (num * unit as f64).round();
+        if scaled >= u64::MAX as f64 {
+            return Err("Count value too large".to_string());
+        }
+        scaled as u64
+    } else {
+        // Integers skip the f64 path so large values keep full precision
+        let num: u64 = num_str.parse()
+            .map_err(|_| format!("Invalid number: {}", num_str))?;
+        num.checked_mul(unit)
+            .ok_or_else(|| "Count value too large".to_string())?
+    };
+    
+    Ok(count)
 }
 
 #[cfg(test)]
@@ -180,6 +280,35 @@ mod tests {
         assert!(parse_duration("h10").is_err());
     }
 
+    #[test]
+    fn test_parse_duration_millis() {
+        assert_eq!(parse_duration("50ms").unwrap(), Duration::from_millis(50));
+        assert_eq!(parse_duration("1s500ms").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("1m30ms").unwrap(), Duration::from_millis(60030));
+        assert!(parse_duration("5ms5ms").is_err());
+        assert!(parse_duration("0ms").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_weeks() {
+        assert_eq!(parse_duration("2w").unwrap(), Duration::from_secs(1209600));
+        assert_eq!(parse_duration("1w2d").unwrap(), Duration::from_secs(777600));
+    }
+
+    #[test]
+    fn test_parse_duration_overflow() {
+        assert!(parse_duration("99999999999999999999s").is_err());
+        assert!(parse_duration("40000000000000w").is_err());
+        assert!(parse_duration("18446744073709551615s1m").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_repeated_unit() {
+        let err = parse_duration("1d1d").unwrap_err();
+        assert!(err.contains("Duplicate time unit"), "Unexpected error: {}", err);
+        assert!(parse_duration("1h30m15m").is_err());
+    }
+
     #[test]
     fn test_parse_count_plain() {
         assert_eq!(parse_count("1000").unwrap(), 1000);
@@ -204,6 +333,45 @@ mod tests {
         assert!(parse_count("-100").is_err());
     }
 
+    #[test]
+    fn test_parse_count_decimals() {
+        assert_eq!(parse_count("1.5k").unwrap(), 1_500);
+        assert_eq!(parse_count("2.5m").unwrap(), 2_500_000);
+        assert_eq!(parse_count("0.5k").unwrap(), 500);
+        assert_eq!(parse_count("1.0005k").unwrap(), 1_001);
+    }
+
+    #[test]
+    fn test_parse_count_terabyte_unit() {
+        assert_eq!(parse_count("1t").unwrap(), 1_000_000_000_000);
+        assert_eq!(parse_count("2.5t").unwrap(), 2_500_000_000_000);
+    }
+
+    #[test]
+    fn test_parse_count_malformed_decimals() {
+        assert!(parse_count("1.2.3k").is_err());
+        assert!(parse_count(".k").is_err());
+        assert!(parse_count("0.0k").is_err());
+        assert!(parse_count("0.0001k").is_err());
+        assert!(parse_count("-1.5k").is_err());
+        assert!(parse_count("1e3").is_err());
+        assert!(parse_count("99999999999.5t").is_err());
+        assert!(parse_count("99999999t").is_err());
+    }
+
+    #[test]
+    fn test_parse_number_allows_zero() {
+        assert_eq!(parse_number("0").unwrap(), 0);
+        assert_eq!(parse_number("0k").unwrap(), 0);
+        assert_eq!(parse_number("1.5m").unwrap(), 1_500_000);
+        assert!(parse_number("").is_err());
+        assert!(parse_number("-1").is_err());
+        
+        // An offset of 0 is the default starting ID
+        let manager = ConnectionIdManager::new(None, None).with_offset(parse_number("0").unwrap());
+        assert_eq!(manager.next_id(), 0);
+    }
+
     #[test]
     fn test_id_manager_no_reset() {
         let manager = ConnectionIdManager::new(None, None);
@@ -232,4 +400,34 @@ mod tests {
         assert_eq!(manager.next_id(), 0); // Time elapsed triggers reset, returns 0
         assert_eq!(manager.next_id(), 1);
     }
+
+    #[test]
+    fn test_id_manager_offset_count_reset() {
+        let manager = ConnectionIdManager::new(None, Some(3)).with_offset(2_000_000);
+        assert_eq!(manager.next_id(), 2_000_000);
+        assert_eq!(manager.next_id(), 2_000_001);
+        assert_eq!(manager.next_id(), 2_000_002);
+        assert_eq!(manager.next_id(), 2_000_000); // The threshold counts from the offset, and wraps back to it
+        assert_eq!(manager.next_id(), 2_000_001);
+    }
+
+    #[test]
+    fn test_id_manager_for_mapping() {
+        let shared = ConnectionIdManager::new(Some(Duration::from_secs(3600)), Some(100)).with_offset(10).with_width(4);
+        let web = shared.for_mapping("web", None, Some(2));
+        assert_eq!((web.reset_interval, web.reset_threshold), (Some(Duration::from_secs(3600)), Some(2)));
+        assert_eq!((web.offset, web.width, web.label.as_deref()), (10, 4, Some("web")));
+        assert_eq!([web.next_id(), web.next_id(), web.next_id()], [10, 11, 10]);
+        assert_eq!(shared.next_id(), 10, "The shared counter is left alone");
+    }
+
+    #[test]
+    fn test_display_id_width() {
+        let manager = ConnectionIdManager::new(None, None).with_offset(42).with_width(6);
+        let id = manager.next_id();
+        assert_eq!(manager.display(id).to_string(), "000042");
+        // Wider IDs are never truncated
+        assert_eq!(manager.display(12_345_678).to_string(), "12345678");
+        assert_eq!(ConnectionIdManager::new(None, None).display(7).to_string(), "7");
+    }
 }
\ No newline at end of file
@@ -1,263 +1,2313 @@
 use async_trait::async_trait;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::select;
-use tracing::{debug, warn};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{debug, info, warn, Instrument};
 
 use pingora_core::apps::ServerApp;
-use pingora_core::connectors::TransportConnector;
+use pingora_core::connectors::l4::BindTo;
+use pingora_core::connectors::{ConnectorOptions, TransportConnector};
 use pingora_core::listeners::Listeners;
 use pingora_core::protocols::Stream;
 use pingora_core::server::ShutdownWatch;
 use pingora_core::services::listening::Service;
-use pingora_core::upstreams::peer::BasicPeer;
+use pingora_core::upstreams::peer::{BasicPeer, Peer};
 
 pub mod error;
+#[cfg(unix)]
+pub mod activation;
+pub mod admission;
+pub mod admin;
+pub mod balancer;
+pub mod connect;
 pub mod connection;
+pub mod detect;
+pub mod discovery;
+pub mod eyeballs;
+#[cfg(all(unix, feature = "test-util"))]
+pub mod harness;
+pub mod heartbeat;
 pub mod id_manager;
+pub mod limiter;
+pub mod metrics;
+pub mod mirror;
+pub mod options;
+pub mod rate;
+pub mod rebind;
+pub mod registry;
+pub mod relay;
+pub mod sni;
+pub mod socks5;
+pub mod stats_port;
+pub mod statsd;
+pub mod summary;
+pub mod telemetry;
+pub mod transparent;
+pub use connection::{BackendTraffic, ClientAddr, CloseKind, CloseReason, ConnectionObserver};
 pub use error::{ProxyError, Result};
-use connection::{ConnectionInfo, ConnectionStats};
+pub use options::{AdaptiveBuffer, AdaptiveIdle, BindFailure, ClientSubnets, ConnectRetry, DelayDirection, Dscp, FailResponse, FlushMode, InjectedDelay, ProxyOptions, ShedMarks, SourcePorts, UpstreamReuse};
+use admission::AdmissionControl;
+use balancer::UpstreamPool;
+use connect::{ConnectAllowlist, ConnectRejection};
+use discovery::DiscoveredUpstream;
+use connection::{ConnectionInfo, ConnectionStats, TrafficCounters};
 use id_manager::ConnectionIdManager;
+use mirror::Mirror;
+use rate::AcceptPacer;
+use registry::Registration;
+use relay::RelayOptions;
+use sni::{ClientHello, SniRoutes};
+use socks5::{Socks5Config, Socks5Error};
+
+/// Largest request head read to add `X-Forwarded-For` to; longer ones go without
+const MAX_FORWARDED_HEAD: usize = 8192;
+/// How long a client that was sent the fail response gets to close its side
+const FAIL_RESPONSE_LINGER: Duration = Duration::from_secs(1);
 
 pub struct ProxyApp {
     client_connector: TransportConnector,
-    proxy_to: BasicPeer,
+    upstream: Upstream,
+    http_to: Option<BasicPeer>,
+    mirror_to: Option<BasicPeer>,
+    /// The outbound SOCKS5 proxy upstream connections are tunneled through
+    socks5_via: Option<BasicPeer>,
     listen_addr: String,
+    name: String,
     active_connections: Arc<AtomicU64>,
+    traffic: TrafficCounters,
+    /// Set through the admin API to stop accepting new connections
+    paused: Arc<AtomicBool>,
+    /// Whether the listener is between its shed marks, turning connections away
+    shedding: AtomicBool,
+    /// Connect outcomes per upstream, shedding for the failing ones
+    admission: Option<AdmissionControl>,
+    /// Paces this listener's new connections to `PJ_ACCEPT_RATE`
+    accept_pacer: Option<AcceptPacer>,
     id_manager: Arc<ConnectionIdManager>,
+    bind_to: Option<BindTo>,
+    /// Every address of each upstream given as a hostname that resolved to
+    /// several, keyed by the first, which stands for it in the balancer;
+    /// they are raced when it is picked
+    alternates: HashMap<SocketAddr, Vec<BasicPeer>>,
+    options: ProxyOptions,
+}
+
+/// Where a listener sends its connections
+// Only one exists per listener, so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum Upstream {
+    /// Every connection goes to the mapping's upstream
+    Fixed(BasicPeer),
+    /// Each connection goes to one of the mapping's upstreams
+    Pool(UpstreamPool),
+    /// Like `Pool`, but the upstreams can be replaced while running
+    Discovered(Arc<DiscoveredUpstream>),
+    /// Each client names its target in an HTTP CONNECT request
+    Connect(ConnectAllowlist),
+    /// Each client names its target in a SOCKS5 handshake
+    Socks5(Socks5Config),
+    /// Each connection goes where the client was headed before being redirected here
+    Transparent,
+    /// No upstream: each connection is held open this long, or not at all, then closed
+    Sink(Option<Duration>),
+    /// Each connection goes where its TLS ClientHello's server name routes it
+    Sni(SniRoutes),
 }
 
-enum DuplexEvent {
-    DownstreamRead(usize),
-    UpstreamRead(usize),
+/// Optional per-connection state handed to `duplex`
+#[derive(Default)]
+pub struct DuplexExtras {
+    /// Held for the connection's lifetime so the admin API can see it
+    pub registration: Option<Registration>,
+    pub mirror: Option<Mirror>,
+    /// Downstream bytes already read (e.g. for protocol detection) that must
+    /// be forwarded before anything else
+    pub preamble: Vec<u8>,
+    /// Pool key the upstream connection is kept under if the client closes
+    /// first, for `ProxyOptions::upstream_reuse`
+    pub reuse_key: Option<u64>,
 }
 
 impl ProxyApp {
     pub fn new(proxy_to: BasicPeer, listen_addr: String, id_manager: Arc<ConnectionIdManager>) -> Self {
+        Self::with_options(proxy_to, listen_addr, id_manager, ProxyOptions::default())
+    }
+
+    pub fn with_options(
+        proxy_to: BasicPeer,
+        listen_addr: String,
+        id_manager: Arc<ConnectionIdManager>,
+        options: ProxyOptions,
+    ) -> Self {
+        Self::build(Upstream::Fixed(proxy_to), listen_addr, id_manager, options)
+    }
+
+    /// Balances connections over several upstreams
+    pub fn with_pool(
+        pool: UpstreamPool,
+        listen_addr: String,
+        id_manager: Arc<ConnectionIdManager>,
+        options: ProxyOptions,
+    ) -> Self {
+        Self::build(Upstream::Pool(pool), listen_addr, id_manager, options)
+    }
+
+    /// Balances connections over upstreams that are looked up at runtime
+    pub fn discovered(
+        upstream: Arc<DiscoveredUpstream>,
+        listen_addr: String,
+        id_manager: Arc<ConnectionIdManager>,
+        options: ProxyOptions,
+    ) -> Self {
+        Self::build(Upstream::Discovered(upstream), listen_addr, id_manager, options)
+    }
+
+    /// HTTP CONNECT forward proxy restricted to the targets in `allowlist`
+    pub fn connect(
+        listen_addr: String,
+        id_manager: Arc<ConnectionIdManager>,
+        allowlist: ConnectAllowlist,
+        options: ProxyOptions,
+    ) -> Self {
+        Self::build(Upstream::Connect(allowlist), listen_addr, id_manager, options)
+    }
+
+    /// SOCKS5 proxy accepting CONNECT commands for the configured targets
+    pub fn socks5(
+        listen_addr: String,
+        id_manager: Arc<ConnectionIdManager>,
+        config: Socks5Config,
+        options: ProxyOptions,
+    ) -> Self {
+        Self::build(Upstream::Socks5(config), listen_addr, id_manager, options)
+    }
+
+    /// Relays each redirected connection to its original destination
+    pub fn transparent(listen_addr: String, id_manager: Arc<ConnectionIdManager>, options: ProxyOptions) -> Self {
+        Self::build(Upstream::Transparent, listen_addr, id_manager, options)
+    }
+
+    /// Accepts connections without dialing anything, holding each open for
+    /// `hold` (closing it at once without one), for testing client timeouts
+    pub fn sink(listen_addr: String, id_manager: Arc<ConnectionIdManager>, hold: Option<Duration>, options: ProxyOptions) -> Self {
+        Self::build(Upstream::Sink(hold), listen_addr, id_manager, options)
+    }
+
+    /// Relays each TLS connection, still encrypted, to the upstream its
+    /// ClientHello's server name routes it to
+    pub fn sni(listen_addr: String, id_manager: Arc<ConnectionIdManager>, routes: SniRoutes, options: ProxyOptions) -> Self {
+        Self::build(Upstream::Sni(routes), listen_addr, id_manager, options)
+    }
+
+    fn build(
+        mut upstream: Upstream,
+        listen_addr: String,
+        id_manager: Arc<ConnectionIdManager>,
+        options: ProxyOptions,
+    ) -> Self {
+        let name = options.name.clone().unwrap_or_else(|| listen_addr.clone());
+        let mut http_to = options.http_upstream.map(|addr| BasicPeer::new(&addr.to_string()));
+        let mut mirror_to = options.mirror.map(|addr| BasicPeer::new(&addr.to_string()));
+        let mut socks5_via = options.upstream_socks5.as_ref().map(|socks5| BasicPeer::new(&socks5.addr.to_string()));
+        let bind_to = (options.bind_source.is_some() || options.source_ports.is_some()).then(|| {
+            let mut bind_to = BindTo::default();
+            bind_to.addr = options.bind_source.map(|source| SocketAddr::new(source, 0));
+            if let Some(ports) = options.source_ports {
+                bind_to
+                    .set_port_range(Some((ports.low(), ports.high())))
+                    .expect("SourcePorts is a valid range");
+            }
+            bind_to
+        });
+        let proxy_to: Vec<&mut BasicPeer> = match &mut upstream {
+            Upstream::Fixed(proxy_to) => vec![proxy_to],
+            Upstream::Pool(pool) => pool.peers_mut().collect(),
+            Upstream::Sni(routes) => routes.peers_mut().collect(),
+            // These peers get their options when they are picked
+            Upstream::Discovered(_) | Upstream::Connect(_) | Upstream::Socks5(_) | Upstream::Transparent | Upstream::Sink(_) => Vec::new(),
+        };
+        for peer in proxy_to.into_iter().chain(http_to.as_mut()).chain(mirror_to.as_mut()).chain(socks5_via.as_mut()) {
+            peer.options.bind_to = bind_to.clone();
+            peer.options.connection_timeout = options.connect_timeout;
+            peer.options.dscp = options.dscp.map(|dscp| dscp.tos());
+        }
+        // Per-connection mirror tasks must not linger on an unreachable mirror
+        if let Some(mirror_to) = mirror_to.as_mut() {
+            mirror_to.options.connection_timeout = Some(options.connect_timeout.unwrap_or(mirror::MIRROR_CONNECT_TIMEOUT));
+        }
+        let paused = match &options.registry {
+            Some(registry) => registry.register_listener(&listen_addr),
+            None => Arc::new(AtomicBool::new(false)),
+        };
+        let traffic = TrafficCounters::default();
         ProxyApp {
-            client_connector: TransportConnector::new(None),
-            proxy_to,
+            client_connector: TransportConnector::new(
+                options.upstream_reuse.map(|reuse| ConnectorOptions::new(reuse.pool_size)),
+            ),
+            upstream,
+            http_to,
+            mirror_to,
+            socks5_via,
             listen_addr,
-            active_connections: Arc::new(AtomicU64::new(0)),
+            name,
+            active_connections: traffic.active_counter(),
+            traffic,
+            paused,
+            shedding: AtomicBool::new(false),
+            admission: options.admission_ratio.map(AdmissionControl::new),
+            accept_pacer: options.accept_rate.map(AcceptPacer::new),
             id_manager,
+            bind_to,
+            alternates: HashMap::new(),
+            options,
+        }
+    }
+
+    /// Whether a new connection should be shed, entering or leaving the
+    /// shedding state as the active count crosses the marks
+    fn shedding(&self) -> bool {
+        let Some(marks) = self.options.shed else { return false };
+        let active = self.active_connections.load(Ordering::Relaxed);
+        if self.shedding.load(Ordering::Relaxed) {
+            if active >= marks.low() {
+                return true;
+            }
+            if self.shedding.swap(false, Ordering::Relaxed) {
+                info!("[{}] {} active connections, below the low-water mark of {}: accepting again", self.name, active, marks.low());
+            }
+            false
+        } else if active >= marks.high() {
+            if !self.shedding.swap(true, Ordering::Relaxed) {
+                warn!("[{}] {} active connections, at the high-water mark: shedding new connections", self.name, active);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Cumulative `(sent, received)` bytes over all connections so far
+    pub fn total_bytes(&self) -> (u64, u64) {
+        (self.traffic.bytes_sent(), self.traffic.bytes_received())
+    }
+
+    /// Number of connections relayed so far
+    pub fn total_connections(&self) -> u64 {
+        self.traffic.connections()
+    }
+
+    /// A handle to the counters that stays valid once the app has been
+    /// moved into its service
+    pub fn traffic(&self) -> TrafficCounters {
+        self.traffic.clone()
+    }
+
+    /// Reads and validates a CONNECT request, returning the peers its
+    /// target resolved to and any bytes the client sent after the request head
+    async fn accept_connect(
+        &self,
+        io: &mut Stream,
+        allowlist: &ConnectAllowlist,
+    ) -> std::result::Result<(Vec<BasicPeer>, Vec<u8>), ConnectRejection> {
+        let request = connect::read_connect_request(io, allowlist, self.max_handshake()).await?;
+        let targets = connect::resolve_target(&request.host, request.port).await?;
+        let targets = allowlist
+            .allowed_addrs(&request.host, request.port, targets)
+            .map_err(ConnectRejection::Forbidden)?;
+
+        Ok((self.tunnel_peers(targets), request.leftover))
+    }
+
+    /// Runs the SOCKS5 handshake, returning the peers the client's target resolved to
+    async fn accept_socks5(&self, io: &mut Stream, config: &Socks5Config) -> std::result::Result<Vec<BasicPeer>, Socks5Error> {
+        let request = socks5::handshake(io, config, self.max_handshake()).await?;
+        let targets = connect::resolve_target(&request.host, request.port)
+            .await
+            .map_err(|rejection| Socks5Error {
+                reply: Some(socks5::REPLY_HOST_UNREACHABLE),
+                reason: rejection.reason().to_string(),
+            })?;
+        let targets = config
+            .allowlist
+            .allowed_addrs(&request.host, request.port, targets)
+            .map_err(|reason| Socks5Error { reply: Some(socks5::REPLY_NOT_ALLOWED), reason })?;
+        Ok(self.tunnel_peers(targets))
+    }
+
+    fn max_handshake(&self) -> usize {
+        self.options.max_handshake.unwrap_or(connect::DEFAULT_MAX_HANDSHAKE)
+    }
+
+    fn tunnel_peers(&self, targets: Vec<SocketAddr>) -> Vec<BasicPeer> {
+        targets
+            .into_iter()
+            .map(|target| self.with_peer_options(BasicPeer::new(&target.to_string())))
+            .collect()
+    }
+
+    /// Applies the mapping's source binding and connect timeout to a peer
+    /// that was not known when the app was built
+    fn with_peer_options(&self, mut peer: BasicPeer) -> BasicPeer {
+        peer.options.bind_to = self.bind_to.clone();
+        peer.options.connection_timeout = self.options.connect_timeout;
+        peer.options.dscp = self.options.dscp.map(|dscp| dscp.tos());
+        peer
+    }
+
+    /// Whether `target` is this listener's own address, as it is for a
+    /// connection made to a transparent listener without being redirected
+    fn is_listener(&self, target: SocketAddr) -> bool {
+        self.listen_addr.parse::<SocketAddr>().is_ok_and(|listen| {
+            listen.port() == target.port() && (listen.ip().is_unspecified() || listen.ip() == target.ip())
+        })
+    }
+
+    /// The configured upstream for a connection from `client`
+    fn backend_for(&self, client: IpAddr) -> Cow<'_, BasicPeer> {
+        match &self.upstream {
+            Upstream::Fixed(proxy_to) => Cow::Borrowed(proxy_to),
+            Upstream::Pool(pool) => Cow::Borrowed(pool.select(client)),
+            Upstream::Discovered(upstream) => Cow::Owned(self.with_peer_options(upstream.current().select(client).clone())),
+            Upstream::Connect(_) | Upstream::Socks5(_) | Upstream::Transparent | Upstream::Sink(_) | Upstream::Sni(_) => {
+                unreachable!("forward, transparent and TLS proxies pick their own targets, sinks have none")
+            }
+        }
+    }
+
+    /// The failover tier `peer` was picked from, if the mapping has several,
+    /// logging a move to another tier the first time a connection is served by it
+    fn served_tier(&self, peer: &BasicPeer) -> Option<String> {
+        let Upstream::Pool(pool) = &self.upstream else { return None };
+        if pool.tiers() < 2 {
+            return None;
+        }
+        let tier = pool.tier_of(peer)?;
+        match pool.record_served(tier) {
+            Some(previous) if previous < tier => warn!(
+                "[{}] Failing over from the {} tier to the {} tier",
+                self.name, balancer::tier_name(previous), balancer::tier_name(tier)
+            ),
+            Some(previous) => info!(
+                "[{}] Recovered from the {} tier to the {} tier",
+                self.name, balancer::tier_name(previous), balancer::tier_name(tier)
+            ),
+            None => {}
+        }
+        Some(balancer::tier_name(tier))
+    }
+
+    /// Whether a connection may dial `peer`, logging as the upstream's
+    /// shedding starts and stops
+    fn admitted(&self, peer: &BasicPeer, client: &ClientAddr) -> bool {
+        let Some(admission) = &self.admission else { return true };
+        let decision = admission.admit(&peer._address.to_string());
+        match decision.shedding_changed {
+            Some(true) => warn!(
+                "[{}] {:.0}% of recent connects to {} succeeded, below {:.0}%: shedding new connections",
+                self.name, decision.success_ratio * 100.0, peer._address, admission.threshold() * 100.0
+            ),
+            Some(false) => info!("[{}] Connects to {} are succeeding again: accepting new connections", self.name, peer._address),
+            None => {}
+        }
+        if !decision.admitted {
+            debug!("[{}] Shedding connection from {}: connects to {} are failing", self.name, client, peer._address);
+        }
+        decision.admitted
+    }
+
+    /// A slot for one more connection to `peer` under the per-backend limit,
+    /// or the error to answer the client with when the backend is full
+    async fn backend_slot(&self, peer: &BasicPeer, client: &ClientAddr) -> pingora_core::Result<Option<OwnedSemaphorePermit>> {
+        let Some(limiter) = &self.options.backend_limiter else { return Ok(None) };
+        match limiter.acquire(&peer._address.to_string()).await {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                warn!(
+                    "[{}] Rejecting connection from {}: backend {} is at its limit of {} connections",
+                    self.name, client, peer._address, limiter.limit()
+                );
+                Err(pingora_core::Error::explain(pingora_core::ErrorType::ConnectRefused, "backend connection limit reached"))
+            }
+        }
+    }
+
+    /// Takes a balanced upstream out of rotation after a failed connect
+    fn mark_failed(&self, peer: &BasicPeer) {
+        match &self.upstream {
+            Upstream::Pool(pool) => pool.mark_failed(peer),
+            Upstream::Discovered(upstream) => upstream.current().mark_failed(peer),
+            Upstream::Fixed(_) | Upstream::Connect(_) | Upstream::Socks5(_) | Upstream::Transparent | Upstream::Sink(_) | Upstream::Sni(_) => {}
+        }
+    }
+
+    /// Dials `peer`, retrying per the connect retry options. A balanced peer
+    /// is re-picked for each retry, so `peer` ends up as the last one tried.
+    /// The connect timeout bounds all the attempts together.
+    async fn connect_upstream<'a>(
+        &'a self,
+        peer: &mut Cow<'a, BasicPeer>,
+        candidates: &[BasicPeer],
+        balanced: bool,
+        client: IpAddr,
+    ) -> pingora_core::Result<Stream> {
+        let retry = self.options.connect_retry;
+        let connect_started = tokio::time::Instant::now();
+        let mut attempt = 1;
+        let attempts = async {
+            loop {
+                let result = self.dial(peer, candidates).await;
+                if let Some(admission) = &self.admission {
+                    admission.record(&peer._address.to_string(), result.is_ok());
+                }
+                let e = match result {
+                    Ok(client_session) => return Ok(client_session),
+                    Err(e) => e,
+                };
+                let delay = retry.delay(attempt);
+                // No retry starts once it would run past the connect timeout
+                let out_of_time = self.options.connect_timeout.is_some_and(|limit| connect_started.elapsed() + delay >= limit);
+                if attempt >= retry.attempts || out_of_time {
+                    return Err(e);
+                }
+                self.mark_failed(peer);
+                debug!(
+                    "Connect to {} failed (attempt {}/{}), retrying in {:?}: {}",
+                    peer._address, attempt, retry.attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                if balanced {
+                    *peer = self.backend_for(client);
+                }
+            }
+        };
+        match self.options.connect_timeout {
+            Some(limit) => tokio::time::timeout_at(connect_started + limit, attempts).await.unwrap_or_else(|_| {
+                Err(pingora_core::Error::explain(
+                    pingora_core::ErrorType::ConnectTimedout,
+                    format!("no connect to {} within {:?} ({} attempts)", peer._address, limit, attempt),
+                ))
+            }),
+            None => attempts.await,
+        }
+    }
+
+    /// Connects to `peer`, or races `candidates` (Happy Eyeballs) when a
+    /// target resolved to several addresses, making the winner `peer`. An
+    /// upstream hostname's addresses are raced the same way. The connect
+    /// timeout bounds the whole race.
+    async fn dial<'a>(&'a self, peer: &mut Cow<'a, BasicPeer>, candidates: &[BasicPeer]) -> pingora_core::Result<Stream> {
+        let candidates = match peer._address.as_inet().and_then(|addr| self.alternates.get(addr)) {
+            Some(alternates) if candidates.len() < 2 => alternates.as_slice(),
+            _ => candidates,
+        };
+        if candidates.len() < 2 {
+            return self.open(peer.as_ref()).await;
+        }
+        let race = eyeballs::race(candidates.len(), eyeballs::CONNECTION_ATTEMPT_DELAY, |i| self.open(&candidates[i]));
+        let raced = match self.options.connect_timeout {
+            Some(limit) => tokio::time::timeout(limit, race).await.unwrap_or_else(|_| {
+                Err(pingora_core::Error::explain(
+                    pingora_core::ErrorType::ConnectTimedout,
+                    format!("none of {} addresses connected within {:?}", candidates.len(), limit),
+                ))
+            }),
+            None => race.await,
+        };
+        let (winner, stream) = raced?;
+        *peer = Cow::Owned(candidates[winner].clone());
+        Ok(stream)
+    }
+
+    /// Connects to `peer`, through the outbound SOCKS5 proxy if there is one.
+    /// The connect timeout then bounds the proxy's handshake as well.
+    async fn open(&self, peer: &BasicPeer) -> pingora_core::Result<Stream> {
+        let (Some(proxy), Some(socks5)) = (&self.socks5_via, &self.options.upstream_socks5) else {
+            if self.options.upstream_reuse.is_none() {
+                return self.client_connector.new_stream(peer).await;
+            }
+            let (stream, reused) = self.client_connector.get_stream(peer).await?;
+            if reused {
+                debug!("[{}] Reusing a pooled connection to {}", self.name, peer._address);
+            }
+            return Ok(stream);
+        };
+        let Some(target) = peer._address.as_inet().copied() else {
+            return Err(pingora_core::Error::explain(
+                pingora_core::ErrorType::ConnectError,
+                format!("{} can't be reached through a SOCKS5 proxy", peer._address),
+            ));
+        };
+        let tunnel = async {
+            let mut stream = self.client_connector.new_stream(proxy).await?;
+            socks5::open_tunnel(&mut stream, target, socks5.credentials.as_ref()).await.map_err(|e| {
+                let etype = match e.reply {
+                    Some(socks5::REPLY_CONNECTION_REFUSED) => pingora_core::ErrorType::ConnectRefused,
+                    _ => pingora_core::ErrorType::ConnectError,
+                };
+                pingora_core::Error::explain(etype, format!("SOCKS5 proxy {}: {}", proxy._address, e.reason))
+            })?;
+            Ok(stream)
+        };
+        match self.options.connect_timeout {
+            Some(limit) => tokio::time::timeout(limit, tunnel).await.unwrap_or_else(|_| {
+                Err(pingora_core::Error::explain(
+                    pingora_core::ErrorType::ConnectTimedout,
+                    format!("no tunnel to {} through SOCKS5 proxy {} within {:?}", target, proxy._address, limit),
+                ))
+            }),
+            None => tunnel.await,
+        }
+    }
+
+    /// Tells a CONNECT or SOCKS5 client whether its tunnel is up; a no-op
+    /// for fixed upstreams, whose clients don't expect a reply
+    async fn answer_tunnel(
+        &self,
+        io: &mut Stream,
+        result: std::result::Result<(), &pingora_core::Error>,
+    ) -> std::io::Result<()> {
+        match (&self.upstream, result) {
+            (Upstream::Fixed(_) | Upstream::Pool(_) | Upstream::Discovered(_) | Upstream::Transparent | Upstream::Sink(_) | Upstream::Sni(_), _) => Ok(()),
+            (Upstream::Connect(_), Ok(())) => connect::send_response(io, connect::ESTABLISHED_RESPONSE).await,
+            (Upstream::Connect(_), Err(e)) => {
+                connect::send_response(io, ConnectRejection::BadGateway(e.to_string()).response()).await
+            }
+            (Upstream::Socks5(_), Ok(())) => socks5::send_reply(io, socks5::REPLY_SUCCEEDED).await,
+            (Upstream::Socks5(_), Err(e)) => socks5::send_reply(io, socks5::reply_for_connect_error(e)).await,
+        }
+    }
+
+    /// Tells the HTTP upstream who the client is by adding it to the first
+    /// request's `X-Forwarded-For`; later requests on the connection pass as-is.
+    /// `None` means the client went away.
+    async fn add_forwarded_for(&self, io: &mut Stream, head: &mut Vec<u8>, client: &ClientAddr) -> Option<()> {
+        // Without an IP there is nothing to add
+        let Some(ip) = client.ip() else { return Some(()) };
+        if let Err(e) = detect::read_header_block(io, head, MAX_FORWARDED_HEAD, detect::DETECT_TIMEOUT).await {
+            debug!("Failed to read the request head from {}: {}", client, e);
+            return None;
+        }
+        if !detect::add_forwarded_for(head, ip) {
+            debug!("[{}] Request head from {} is incomplete, forwarding it without X-Forwarded-For", self.name, client);
+        }
+        Some(())
+    }
+
+    /// Writes the configured fail response to a client of a fixed upstream;
+    /// tunnel clients get their protocol's refusal from `answer_tunnel` instead
+    async fn send_fail_response(&self, io: &mut Stream) {
+        let Some(response) = &self.options.fail_response else { return };
+        if matches!(self.upstream, Upstream::Connect(_) | Upstream::Socks5(_)) {
+            return;
+        }
+        if let Err(e) = connect::send_response(io, response.bytes()).await {
+            debug!("Failed to send the fail response: {}", e);
+            return;
+        }
+        // Closing with unread client bytes would reset the connection and
+        // could discard the response, so read until the client closes too
+        let _ = io.shutdown().await;
+        let _ = tokio::time::timeout(FAIL_RESPONSE_LINGER, async {
+            let mut buf = [0u8; 1024];
+            while matches!(io.read(&mut buf).await, Ok(n) if n > 0) {}
+        })
+        .await;
+    }
+
+    /// Relays between the client and upstream until either closes, logging
+    /// the connection's start and end around `relay::relay`
+    pub async fn duplex(
+        &self,
+        server_session: Stream,
+        client_session: Stream,
+        conn_info: ConnectionInfo,
+        active_connections: Arc<AtomicU64>,
+        extras: DuplexExtras,
+    ) {
+        let DuplexExtras { registration, mirror, preamble, reuse_key } = extras;
+        let options = RelayOptions {
+            flush_mode: self.options.flush_mode,
+            adaptive_buffer: self.options.adaptive_buffer,
+            inject_delay: self.options.inject_delay,
+            max_lifetime: self.options.max_lifetime,
+            long_lived_threshold: self.options.long_conn_threshold,
+            started_at: Some(conn_info.start_instant),
+            idle_timeout: self.options.idle_timeout,
+            adaptive_idle: self.options.adaptive_idle,
+            write_timeout: self.options.write_timeout,
+            max_up_bytes: self.options.max_up_bytes,
+            max_down_bytes: self.options.max_down_bytes,
+            slow_io_threshold: self.options.slow_io_threshold,
+            backpressure_threshold: self.options.backpressure_threshold,
+            peek_bytes: self.options.peek_bytes,
+            preamble,
+            mirror,
+            registration,
+            traffic: Some(self.traffic.clone()),
+            label: format!("Conn #{}", conn_info.display_id),
+            keep_upstream: reuse_key.is_some(),
+            self_check: self.options.self_check,
+            ..Default::default()
+        };
+        
+        conn_info.log_start();
+        let mut result = relay::relay(server_session, client_session, options).await;
+        if let (Some(key), Some(upstream), Some(reuse)) = (reuse_key, result.upstream.take(), self.options.upstream_reuse) {
+            debug!("Conn #{} returning its upstream connection to the pool", conn_info.display_id);
+            self.client_connector.release_stream(upstream, key, Some(reuse.idle_timeout));
+        }
+        let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
+        if let Some(concurrency) = &self.options.concurrency {
+            concurrency.closed();
+        }
+        if let (CloseReason::UpstreamError, Some(error)) = (result.reason, &result.error) {
+            self.record_error(&format!("{}: {}", conn_info.backend_addr, error));
+        }
+        if let (true, false, Some(registry)) = (result.stats.is_empty(), result.reason.is_error(), &self.options.registry) {
+            registry.record_empty(&self.listen_addr);
+        }
+        if let (Some(kind), Some(registry)) = (result.reason.close_kind(), &self.options.registry) {
+            registry.record_close(&self.listen_addr, kind);
+        }
+        conn_info.log_end(&result.stats, result.reason, result.error.as_deref(), remaining);
+    }
+
+    /// Holds a sink connection for `hold`, or until the client leaves or the
+    /// server shuts down, discarding what the client sends, then closes it
+    async fn hold_sink(&self, mut io: Stream, client_addr: &ClientAddr, hold: Option<Duration>, shutdown: &ShutdownWatch) {
+        self.traffic.add_connection();
+        let Some(hold) = hold else {
+            info!("[{}] Sink connection from {} closed at once", self.name, client_addr);
+            return;
+        };
+        info!("[{}] Sink connection from {}, holding it for {:?}", self.name, client_addr, hold);
+        let started = std::time::Instant::now();
+        let held = tokio::time::sleep(hold);
+        tokio::pin!(held);
+        let mut shutdown = shutdown.clone();
+        let mut buf = [0u8; 1024];
+        let mut discarded = 0;
+        let ended = loop {
+            select! {
+                _ = &mut held => break "hold elapsed",
+                read = io.read(&mut buf) => match read {
+                    Ok(0) | Err(_) => break "client closed",
+                    Ok(n) => discarded += n as u64,
+                },
+                _ = shutdown.changed() => break "shutting down",
+            }
+        };
+        info!(
+            "[{}] Sink connection from {} closed after {:.1}s ({}), {} discarded",
+            self.name, client_addr, started.elapsed().as_secs_f64(), ended, connection::format_bytes(discarded)
+        );
+    }
+
+    /// Keeps `error` as this listener's last upstream failure, for the admin API
+    fn record_error(&self, error: &str) {
+        if let Some(registry) = &self.options.registry {
+            registry.record_error(&self.listen_addr, error);
+        }
+    }
+}
+
+/// Where `io` came from. A Unix socket client is normally unnamed, so it is
+/// shown as the path of the listener's socket instead.
+fn client_addr_of(io: &Stream) -> ClientAddr {
+    use pingora_core::protocols::l4::socket::SocketAddr as PeerAddr;
+    let Some(digest) = io.get_socket_digest() else { return ClientAddr::Unknown };
+    match digest.peer_addr() {
+        Some(PeerAddr::Inet(addr)) => ClientAddr::from(*addr),
+        #[cfg(unix)]
+        Some(PeerAddr::Unix(addr)) => addr
+            .as_pathname()
+            .or_else(|| digest.local_addr().and_then(PeerAddr::as_unix).and_then(|local| local.as_pathname()))
+            .map_or(ClientAddr::Unknown, |path| ClientAddr::Unix(path.display().to_string())),
+        None => ClientAddr::Unknown,
+    }
+}
+
+/// Marks the client socket's outgoing packets with `dscp`
+fn mark_downstream(io: &Stream, dscp: Dscp) {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsRawFd;
+        // Only plain TCP streams have a socket of their own to mark
+        if let Some(stream) = io.as_any().downcast_ref::<pingora_core::protocols::l4::stream::Stream>() {
+            if let Err(e) = pingora_core::protocols::l4::ext::set_dscp(stream.as_raw_fd(), dscp.tos()) {
+                debug!("Failed to mark client socket with DSCP {}: {}", dscp.value(), e);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (io, dscp);
+}
+
+#[async_trait]
+impl ServerApp for ProxyApp {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut io: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let client_addr = client_addr_of(&io);
+        // Clients without an IP all balance as one
+        let client_ip = client_addr.ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        if self.options.log_accept {
+            debug!("[{}] Accepted connection from {} on {}", self.name, client_addr, self.listen_addr);
+        }
+        
+        // Connections already relayed carry on; only new ones are turned away
+        if self.paused.load(Ordering::Relaxed) {
+            info!("[{}] Refusing connection from {}: listener is paused", self.name, client_addr);
+            return None;
+        }
+        if self.shedding() {
+            debug!("[{}] Shedding connection from {}", self.name, client_addr);
+            return None;
+        }
+        if let Some(pacer) = &self.accept_pacer {
+            let delay = pacer.delay();
+            if !delay.is_zero() {
+                debug!("[{}] Pacing connection from {} by {:?}", self.name, client_addr, delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+        if let Some(rate) = &self.options.connection_rate {
+            rate.record();
+        }
+        if let (Some(dscp), true) = (self.options.dscp, self.options.dscp_downstream) {
+            mark_downstream(&io, dscp);
+        }
+        
+        let mut preamble = Vec::new();
+        // Whether the peer came from the balancer, which may pick another on retry
+        let mut balanced = false;
+        // Every address a tunnel target resolved to, raced when there are several
+        let mut candidates = Vec::new();
+        let mut original_dst = None;
+        let mut peer: Cow<BasicPeer> = match (&self.upstream, &self.http_to) {
+            // With an HTTP upstream configured, sniff the request line to pick the backend
+            (Upstream::Fixed(_) | Upstream::Pool(_) | Upstream::Discovered(_), Some(http_to)) => {
+                preamble = match detect::read_preamble(&mut io, 1024, detect::DETECT_TIMEOUT).await {
+                    Ok(preamble) => preamble,
+                    Err(e) => {
+                        debug!("Failed to read from {} during protocol detection: {}", client_addr, e);
+                        return None;
+                    }
+                };
+                if detect::looks_like_http(&preamble) {
+                    if self.options.forwarded_for {
+                        self.add_forwarded_for(&mut io, &mut preamble, &client_addr).await?;
+                    }
+                    Cow::Borrowed(http_to)
+                } else {
+                    balanced = true;
+                    self.backend_for(client_ip)
+                }
+            }
+            (Upstream::Fixed(_) | Upstream::Pool(_) | Upstream::Discovered(_), None) => {
+                balanced = true;
+                self.backend_for(client_ip)
+            }
+            (Upstream::Connect(allowlist), _) => match self.accept_connect(&mut io, allowlist).await {
+                Ok((peers, leftover)) => {
+                    preamble = leftover;
+                    candidates = peers;
+                    Cow::Owned(candidates[0].clone())
+                }
+                Err(rejection) => {
+                    warn!("Rejected CONNECT from {}: {}", client_addr, rejection.reason());
+                    let _ = connect::send_response(&mut io, rejection.response()).await;
+                    return None;
+                }
+            },
+            (Upstream::Socks5(config), _) => match self.accept_socks5(&mut io, config).await {
+                Ok(peers) => {
+                    candidates = peers;
+                    Cow::Owned(candidates[0].clone())
+                }
+                Err(e) => {
+                    warn!("Rejected SOCKS5 request from {}: {}", client_addr, e.reason);
+                    if let Some(reply) = e.reply {
+                        let _ = socks5::send_reply(&mut io, reply).await;
+                    }
+                    return None;
+                }
+            },
+            (Upstream::Transparent, _) => match transparent::original_destination(&io) {
+                Some(target) if self.is_listener(target) => {
+                    warn!("[{}] Refusing connection from {} made directly to the transparent listener", self.name, client_addr);
+                    return None;
+                }
+                Some(target) => {
+                    original_dst = Some(target);
+                    Cow::Owned(self.with_peer_options(BasicPeer::new(&target.to_string())))
+                }
+                None => {
+                    warn!("[{}] No original destination for connection from {}", self.name, client_addr);
+                    return None;
+                }
+            },
+            (Upstream::Sink(hold), _) => {
+                self.hold_sink(io, &client_addr, *hold, shutdown).await;
+                return None;
+            }
+            (Upstream::Sni(routes), _) => {
+                let server_name = match sni::read_client_hello(&mut io, self.max_handshake()).await {
+                    Ok((hello, ClientHello::ServerName(server_name))) => {
+                        preamble = hello;
+                        server_name
+                    }
+                    Ok(_) => {
+                        warn!("[{}] Refusing connection from {}: it did not start with a TLS ClientHello", self.name, client_addr);
+                        return None;
+                    }
+                    Err(e) => {
+                        debug!("Failed to read the TLS ClientHello from {}: {}", client_addr, e);
+                        return None;
+                    }
+                };
+                match routes.route(server_name.as_deref()) {
+                    Some(peer) => {
+                        debug!("[{}] Routing {} by server name {} to {}",
+                               self.name, client_addr, server_name.as_deref().unwrap_or("(none)"), peer._address);
+                        Cow::Borrowed(peer)
+                    }
+                    None => {
+                        warn!("[{}] Refusing connection from {}: no route for server name {}",
+                              self.name, client_addr, server_name.as_deref().unwrap_or("(none)"));
+                        return None;
+                    }
+                }
+            }
+        };
+        
+        if !self.admitted(&peer, &client_addr) {
+            let e = pingora_core::Error::explain(pingora_core::ErrorType::ConnectRefused, "upstream is failing");
+            let _ = self.answer_tunnel(&mut io, Err(e.as_ref())).await;
+            return None;
+        }
+        
+        // Held until the connection ends, like the backend slot below
+        let _connection_slot = match &self.options.connection_limiter {
+            Some(limiter) => match limiter.acquire().await {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!(
+                        "[{}] Rejecting connection from {}: the proxy is at its limit of {} connections",
+                        self.name, client_addr, limiter.limit()
+                    );
+                    let e = pingora_core::Error::explain(pingora_core::ErrorType::ConnectRefused, "connection limit reached");
+                    let _ = self.answer_tunnel(&mut io, Err(e.as_ref())).await;
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        // Held until the connection ends, freeing the backend slot for the next one
+        let mut backend_slot = match self.backend_slot(&peer, &client_addr).await {
+            Ok(slot) => slot,
+            Err(e) => {
+                let _ = self.answer_tunnel(&mut io, Err(e.as_ref())).await;
+                return None;
+            }
+        };
+        let slotted = peer._address.clone();
+
+        // The client may hang up while a slow upstream is still being dialed;
+        // watch for that so the dial is abandoned instead of relayed to nobody
+        let connect_started = std::time::Instant::now();
+        let client_session = {
+            let connect = self.connect_upstream(&mut peer, &candidates, balanced, client_ip);
+            tokio::pin!(connect);
+            let mut buf = [0u8; 1024];
+            let mut watching = true;
+            loop {
+                select! {
+                    result = &mut connect => break result,
+                    read = io.read(&mut buf), if watching => match read {
+                        Ok(0) | Err(_) => {
+                            info!("[{}] Client {} disconnected while connecting upstream", self.name, client_addr);
+                            // Nothing was relayed: a health check that hung up before the dial finished
+                            if let Some(registry) = &self.options.registry {
+                                registry.record_empty(&self.listen_addr);
+                            }
+                            return None;
+                        }
+                        // Early data is relayed once connected; past that point
+                        // a hangup is noticed by the relay itself
+                        Ok(n) => {
+                            preamble.extend_from_slice(&buf[..n]);
+                            watching = false;
+                        }
+                    },
+                }
+            }
+        };
+
+        match client_session {
+            Ok(client_session) => {
+                // A retry or Happy Eyeballs may have connected to another
+                // backend, which needs a slot of its own
+                if backend_slot.is_some() && peer._address != slotted {
+                    drop(backend_slot.take());
+                    match self.backend_slot(&peer, &client_addr).await {
+                        Ok(slot) => backend_slot = slot,
+                        Err(e) => {
+                            let _ = self.answer_tunnel(&mut io, Err(e.as_ref())).await;
+                            return None;
+                        }
+                    }
+                }
+                let _backend_slot = backend_slot;
+                let connect_time = connect_started.elapsed();
+                if let Some(latency) = &self.options.connect_latency {
+                    latency.observe(connect_time);
+                }
+                if let Err(e) = self.answer_tunnel(&mut io, Ok(())).await {
+                    debug!("Failed to confirm tunnel to {}: {}", client_addr, e);
+                    return None;
+                }
+                
+                // Lets a replacement of discovered upstreams say how many connections it leaves behind
+                let _upstream_lease = match &self.upstream {
+                    Upstream::Discovered(upstream) => Some(upstream.lease(&peer._address.to_string())),
+                    _ => None,
+                };
+                
+                // Increment active connections counter
+                let current_connections = self.active_connections.fetch_add(1, Ordering::Relaxed) + 1;
+                if let Some(concurrency) = &self.options.concurrency {
+                    concurrency.opened();
+                }
+                self.traffic.add_connection();
+                
+                let conn_info = ConnectionInfo::new(
+                    client_addr,
+                    &self.listen_addr,
+                    &peer._address.to_string(),
+                    current_connections,
+                    &self.id_manager
+                ).with_name(&self.name)
+                .with_original_dst(original_dst)
+                .with_tier(self.served_tier(&peer))
+                .with_connect_time(connect_time)
+                .with_statsd(self.options.statsd.clone())
+                .with_client_subnet(self.options.client_subnets)
+                .with_observer(self.options.observer.clone())
+                .with_backend_traffic(self.options.backend_traffic.clone())
+                .with_connection_sizes(self.options.connection_sizes.clone())
+                .with_log_levels(self.options.conn_log_levels);
+                
+                // Dropped when duplex returns, removing the connection from the registry
+                let registration = self.options.registry.as_ref().map(|registry| registry.register(&conn_info));
+                
+                // The mirror connects on its own task, so a missing mirror never blocks the primary connection
+                let mirror = self.mirror_to.clone().map(|mirror_to| {
+                    let app = self.clone();
+                    let mirror_addr = mirror_to._address.to_string();
+                    Mirror::spawn(async move { app.open(&mirror_to).await }, mirror_addr, conn_info.display_id)
+                });
+                
+                // SOCKS5 tunnels are keyed by the proxy, not the upstream, so they aren't pooled
+                let reuse_key = (self.options.upstream_reuse.is_some() && self.socks5_via.is_none()).then(|| peer.reuse_hash());
+                let extras = DuplexExtras { registration, mirror, preamble, reuse_key };
+                let span = conn_info.span();
+                self.duplex(io, client_session, conn_info, self.active_connections.clone(), extras)
+                    .instrument(span)
+                    .await;
+                None
+            }
+            Err(e) => {
+                let _ = self.answer_tunnel(&mut io, Err(e.as_ref())).await;
+                self.send_fail_response(&mut io).await;
+                self.mark_failed(&peer);
+                match self.options.bind_source {
+                    Some(source) => warn!(
+                        "Failed to create client session to {} from source {}: {}",
+                        peer._address, source, e
+                    ),
+                    None => warn!("Failed to create client session to {}: {}", peer._address, e),
+                }
+                let no_free_port = e.root_cause().downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::AddrNotAvailable);
+                if let Some(ports) = self.options.source_ports.filter(|_| no_free_port) {
+                    warn!("[{}] No source port in {} is free to connect to {}", self.name, ports, peer._address);
+                }
+                // The attempt still gets a numbered end line, but was never counted as active
+                let current_connections = self.active_connections.load(Ordering::Relaxed);
+                let conn_info = ConnectionInfo::new(
+                    client_addr,
+                    &self.listen_addr,
+                    &peer._address.to_string(),
+                    current_connections,
+                    &self.id_manager
+                ).with_name(&self.name)
+                .with_statsd(self.options.statsd.clone())
+                .with_client_subnet(self.options.client_subnets)
+                .with_log_levels(self.options.conn_log_levels);
+                let err = ProxyError::ConnectionFailed(e.root_cause().to_string());
+                self.record_error(&format!("{}: {}", peer._address, err));
+                conn_info.log_end(&ConnectionStats::new(), CloseReason::ConnectFailed, Some(&err.to_string()), current_connections);
+                None
+            }
         }
     }
+}
+
+pub fn proxy_service(addr: &str, proxy_addr: &str, id_manager: Arc<ConnectionIdManager>) -> Service<ProxyApp> {
+    proxy_service_with_options(addr, proxy_addr, id_manager, ProxyOptions::default())
+}
+
+/// Panics if an upstream hostname doesn't resolve; use `proxy_app` to
+/// handle that instead
+pub fn proxy_service_with_options(
+    addr: &str,
+    proxy_addr: &str,
+    id_manager: Arc<ConnectionIdManager>,
+    options: ProxyOptions,
+) -> Service<ProxyApp> {
+    let app = proxy_app(addr, proxy_addr, id_manager, options).unwrap_or_else(|e| panic!("Invalid upstream {}: {}", proxy_addr, e));
+    Service::with_listeners("Proxy Service".to_string(), Listeners::tcp(addr), app)
+}
+
+/// The app behind `proxy_service_with_options`, for running it on a listener
+/// of its own. Fails if an upstream hostname doesn't resolve.
+pub fn proxy_app(addr: &str, proxy_addr: &str, id_manager: Arc<ConnectionIdManager>, options: ProxyOptions) -> std::io::Result<ProxyApp> {
+    Ok(tiered_app(addr.to_string(), upstream_tiers(proxy_addr)?, id_manager, options))
+}
+
+/// Proxy service for embedders that resolve their own upstreams: listens on
+/// `listen` and balances over `peers` with `options.lb_strategy`, without
+/// parsing any addresses. Panics if `peers` is empty.
+///
+/// ```
+/// use std::sync::Arc;
+/// use pingora_core::server::Server;
+/// use pingora_core::upstreams::peer::BasicPeer;
+/// use pj::id_manager::ConnectionIdManager;
+/// use pj::{proxy_service_with_peers, ProxyOptions};
+///
+/// // Addresses from the embedder's own discovery
+/// let peers = vec![BasicPeer::new("10.0.0.1:80"), BasicPeer::new("10.0.0.2:80")];
+/// let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+/// let service = proxy_service_with_peers("127.0.0.1:8080".parse().unwrap(), peers, id_manager, ProxyOptions::default());
+/// assert_eq!(service.app_logic().unwrap().total_connections(), 0);
+///
+/// let mut server = Server::new(None).unwrap();
+/// server.add_service(service);
+/// ```
+pub fn proxy_service_with_peers(
+    listen: SocketAddr,
+    peers: Vec<BasicPeer>,
+    id_manager: Arc<ConnectionIdManager>,
+    options: ProxyOptions,
+) -> Service<ProxyApp> {
+    assert!(!peers.is_empty(), "A proxy service needs at least one peer");
+    let tier = peers.into_iter().map(|peer| (vec![peer], 1)).collect();
+    let addr = listen.to_string();
+    Service::with_listeners(
+        "Proxy Service".to_string(),
+        Listeners::tcp(&addr),
+        tiered_app(addr, vec![tier], id_manager, options),
+    )
+}
+
+fn tiered_app(addr: String, tiers: Vec<UpstreamTier>, id_manager: Arc<ConnectionIdManager>, options: ProxyOptions) -> ProxyApp {
+    let mut alternates = HashMap::new();
+    let mut tiers: Vec<Vec<(BasicPeer, u32)>> = tiers
+        .into_iter()
+        .map(|tier| {
+            tier.into_iter()
+                .map(|(peers, weight)| {
+                    if let (true, Some(addr)) = (peers.len() > 1, peers[0]._address.as_inet()) {
+                        alternates.insert(*addr, peers.clone());
+                    }
+                    (peers[0].clone(), weight)
+                })
+                .collect()
+        })
+        .collect();
+    let mut app = if tiers.len() == 1 && tiers[0].len() == 1 {
+        ProxyApp::with_options(tiers.remove(0).remove(0).0, addr, id_manager, options)
+    } else {
+        let pool = UpstreamPool::with_tiers(tiers, options.lb_strategy);
+        ProxyApp::with_pool(pool, addr, id_manager, options)
+    };
+    app.alternates = alternates
+        .into_iter()
+        .map(|(addr, peers)| (addr, peers.into_iter().map(|peer| app.with_peer_options(peer)).collect()))
+        .collect();
+    app
+}
+
+/// Peers of a mapping's `proxy_addr`: several upstreams are separated by
+/// '|', each with an optional "*weight". The peers of every failover tier
+/// are included, a hostname as the first address it resolved to.
+pub fn upstream_peers(proxy_addr: &str) -> std::io::Result<Vec<(BasicPeer, u32)>> {
+    Ok(upstream_tiers(proxy_addr)?
+        .into_iter()
+        .flatten()
+        .map(|(mut peers, weight)| (peers.swap_remove(0), weight))
+        .collect())
+}
+
+/// The upstreams of one failover tier, each as its peers and weight
+pub type UpstreamTier = Vec<(Vec<BasicPeer>, u32)>;
+
+/// Peers of a mapping's `proxy_addr` grouped in failover tiers, which are
+/// separated by '>' with the primaries first. Each upstream has a peer for
+/// every address it resolved to, the families interleaved for Happy
+/// Eyeballs; an IP address has just the one.
+pub fn upstream_tiers(proxy_addr: &str) -> std::io::Result<Vec<UpstreamTier>> {
+    proxy_addr
+        .split('>')
+        .map(|tier| {
+            tier.split('|')
+                .map(|upstream| {
+                    let (upstream, weight) = balancer::parse_upstream(upstream).unwrap_or((upstream, 1));
+                    let peers = resolve_upstream(upstream)?.iter().map(|addr| BasicPeer::new(&addr.to_string())).collect();
+                    Ok((peers, weight))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Addresses of an upstream given as `host:port`
+fn resolve_upstream(upstream: &str) -> std::io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = upstream.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let addrs: Vec<SocketAddr> = upstream
+        .to_socket_addrs()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to resolve {}: {}", upstream, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} did not resolve to any address", upstream)));
+    }
+    Ok(eyeballs::interleave_families(addrs))
+}
+
+/// Proxy service whose upstreams come from `upstream`, which may be
+/// replaced while the service runs (see `discovery::UpstreamCommand`)
+pub fn discovered_service(
+    addr: &str,
+    upstream: Arc<DiscoveredUpstream>,
+    id_manager: Arc<ConnectionIdManager>,
+    options: ProxyOptions,
+) -> Service<ProxyApp> {
+    Service::with_listeners(
+        "Proxy Service".to_string(),
+        Listeners::tcp(addr),
+        ProxyApp::discovered(upstream, addr.to_string(), id_manager, options),
+    )
+}
+
+pub fn connect_service(
+    addr: &str,
+    id_manager: Arc<ConnectionIdManager>,
+    allowlist: ConnectAllowlist,
+    options: ProxyOptions,
+) -> Service<ProxyApp> {
+    Service::with_listeners(
+        "Connect Service".to_string(),
+        Listeners::tcp(addr),
+        ProxyApp::connect(addr.to_string(), id_manager, allowlist, options),
+    )
+}
+
+pub fn transparent_service(addr: &str, id_manager: Arc<ConnectionIdManager>, options: ProxyOptions) -> Service<ProxyApp> {
+    Service::with_listeners(
+        "Transparent Service".to_string(),
+        Listeners::tcp(addr),
+        ProxyApp::transparent(addr.to_string(), id_manager, options),
+    )
+}
+
+pub fn socks5_service(
+    addr: &str,
+    id_manager: Arc<ConnectionIdManager>,
+    config: Socks5Config,
+    options: ProxyOptions,
+) -> Service<ProxyApp> {
+    Service::with_listeners(
+        "SOCKS5 Service".to_string(),
+        Listeners::tcp(addr),
+        ProxyApp::socks5(addr.to_string(), id_manager, config, options),
+    )
+}
+
+/// How a mapping's listener picks the upstream for each connection
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ListenMode {
+    /// Relay to the mapping's `proxy_addr`
+    #[default]
+    Forward,
+    /// HTTP CONNECT forward proxy; `proxy_addr` is unused
+    Connect,
+    /// SOCKS5 proxy; `proxy_addr` is unused
+    Socks5,
+    /// Relay each connection to its original destination; `proxy_addr` is unused
+    Transparent,
+    /// Accept connections and never dial anything; `proxy_addr` is unused
+    Sink,
+    /// Route TLS connections by their server name to `sni_routes`; `proxy_addr` is unused
+    Tls,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProxyMapping {
+    pub listen_addr: String,
+    pub proxy_addr: String,
+    pub mode: ListenMode,
+    pub name: Option<String>,
+    pub bind_source: Option<IpAddr>,
+    pub mirror: Option<SocketAddr>,
+    pub http_upstream: Option<SocketAddr>,
+    pub idle_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// Network interface the listener is bound to (Linux only)
+    pub interface: Option<String>,
+    /// Idle upstream connections kept for later clients to reuse
+    pub reuse: Option<usize>,
+    /// How long each of them is kept, `options::DEFAULT_REUSE_IDLE` when unset
+    pub reuse_idle: Option<Duration>,
+    /// How long a sink mapping holds each connection before closing it
+    pub hold: Option<Duration>,
+    /// Where a tls:// mapping relays each server name, `sni::FALLBACK` for any other
+    pub sni_routes: Vec<(String, SocketAddr)>,
+    /// Reset this mapping's connection IDs on their own, after this long
+    pub reset_interval: Option<Duration>,
+    /// Reset this mapping's connection IDs on their own, after this many
+    pub reset_count: Option<u64>,
+}
+
+impl ProxyMapping {
+    /// Options for this mapping's service: its own settings, with `defaults`
+    /// (from the environment) filling in whatever it leaves unset
+    pub fn options(&self, defaults: &ProxyOptions) -> ProxyOptions {
+        ProxyOptions {
+            name: self.name.clone(),
+            bind_source: self.bind_source.or(defaults.bind_source),
+            mirror: self.mirror,
+            http_upstream: self.http_upstream,
+            idle_timeout: self.idle_timeout.or(defaults.idle_timeout),
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+            upstream_reuse: match self.reuse {
+                Some(pool_size) => Some(UpstreamReuse {
+                    pool_size,
+                    idle_timeout: self.reuse_idle.unwrap_or(options::DEFAULT_REUSE_IDLE),
+                }),
+                None => defaults.upstream_reuse,
+            },
+            ..defaults.clone()
+        }
+    }
+
+    /// The ID manager for this mapping's connections: `shared` unless the
+    /// mapping sets a reset policy of its own, then one counting for it alone
+    pub fn id_manager(&self, shared: &Arc<ConnectionIdManager>) -> Arc<ConnectionIdManager> {
+        if self.reset_interval.is_none() && self.reset_count.is_none() {
+            return shared.clone();
+        }
+        let label = self.name.as_deref().unwrap_or(&self.listen_addr);
+        Arc::new(shared.for_mapping(label, self.reset_interval, self.reset_count))
+    }
+}
+
+/// Replaces each `${VAR}` in `s` with the value of that environment variable.
+/// A variable that is not set is an error naming it.
+pub fn expand_env_vars(s: &str) -> std::result::Result<String, String> {
+    expand_vars(s, |name| std::env::var(name).ok())
+}
+
+fn expand_vars(s: &str, lookup: impl Fn(&str) -> Option<String>) -> std::result::Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("Unterminated '${{' in '{}'", s))?;
+        let name = &after[..end];
+        if name.is_empty() {
+            return Err(format!("Empty variable name in '{}'", s));
+        }
+        let value = lookup(name).ok_or_else(|| format!("Environment variable '{}' is not set", name))?;
+        expanded.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Parses `listen_ip:listen_port:proxy_ip:proxy_port` (or `connect://listen_ip:listen_port`
+/// and `socks5://listen_ip:listen_port` for forward proxies, `transparent://listen_ip:listen_port`
+/// for redirected traffic, `sink://listen_ip:listen_port` for no upstream at all, `tls://listen_ip:listen_port`
+/// to route TLS by server name), optionally followed by `?key=value` settings for the mapping (`name`, `bind`,
+/// `mirror`, `http`, `idle`, `connect`, `iface`, `reuse`, `reuse_idle`, `hold`, `sni`, `reset_interval`,
+/// `reset_count`). `${VAR}` references are expanded from the environment first.
+pub fn parse_proxy_mapping(s: &str) -> std::result::Result<ProxyMapping, String> {
+    let mut mappings = parse_mapping_entry(s)?;
+    if mappings.len() != 1 {
+        return Err(format!("Expected a single listen address, not {}", mappings.len()));
+    }
+    Ok(mappings.remove(0))
+}
+
+/// Parses one mapping entry into the mappings it stands for: a mapping as
+/// [`parse_proxy_mapping`] takes, or a fan-in group
+/// `listen=<addr>,<addr>... -> proxy_ip:proxy_port[?settings]` giving each
+/// listen address (`[::]:8080` for IPv6) a mapping to the same upstreams.
+pub fn parse_mapping_entry(s: &str) -> std::result::Result<Vec<ProxyMapping>, String> {
+    let s = expand_env_vars(s)?;
+    let (addrs, settings) = match s.split_once('?') {
+        Some((addrs, settings)) => (addrs, Some(settings)),
+        None => (s.as_str(), None),
+    };
+
+    if let Some(group) = addrs.trim().strip_prefix("listen=") {
+        let (listens, upstreams) = group
+            .split_once("->")
+            .ok_or("Invalid listen group format. Expected format: listen=listen_ip:listen_port,... -> proxy_ip:proxy_port")?;
+        let mut base = ProxyMapping { proxy_addr: parse_upstreams(upstreams.trim())?, ..Default::default() };
+        apply_mapping_settings(&mut base, settings)?;
+        let mut mappings = Vec::new();
+        for listen in listens.split(',').map(str::trim).filter(|listen| !listen.is_empty()) {
+            if listen.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err()) {
+                return Err(format!("Invalid listen address '{}'. Expected listen_ip:listen_port", listen));
+            }
+            let mapping = ProxyMapping { listen_addr: listen.to_string(), ..base.clone() };
+            check_mapping_settings(&mapping)?;
+            mappings.push(mapping);
+        }
+        if mappings.is_empty() {
+            return Err("Listen group needs at least one listen address".to_string());
+        }
+        return Ok(mappings);
+    }
+
+    let forward_proxy = [
+        ("connect://", ListenMode::Connect),
+        ("socks5://", ListenMode::Socks5),
+        ("transparent://", ListenMode::Transparent),
+        ("sink://", ListenMode::Sink),
+        ("tls://", ListenMode::Tls),
+    ]
+        .into_iter()
+        .find_map(|(scheme, mode)| addrs.strip_prefix(scheme).map(|listen| (scheme, mode, listen)));
+
+    let mut mapping = match forward_proxy {
+        Some((scheme, mode, listen)) => {
+            let parts: Vec<&str> = listen.split(':').collect();
+            if parts.len() != 2 {
+                return Err(format!("Invalid {} mapping format. Expected format: {}listen_ip:listen_port", scheme, scheme));
+            }
+            ProxyMapping {
+                listen_addr: listen.to_string(),
+                mode,
+                ..Default::default()
+            }
+        }
+        None => {
+            let parts: Vec<&str> = addrs.splitn(3, ':').collect();
+            if parts.len() != 3 {
+                return Err(INVALID_FORWARD_MAPPING.to_string());
+            }
+            ProxyMapping {
+                listen_addr: format!("{}:{}", parts[0], parts[1]),
+                proxy_addr: parse_upstreams(parts[2])?,
+                ..Default::default()
+            }
+        }
+    };
+
+    apply_mapping_settings(&mut mapping, settings)?;
+    check_mapping_settings(&mapping)?;
+    Ok(vec![mapping])
+}
+
+const INVALID_FORWARD_MAPPING: &str = "Invalid proxy mapping format. Expected format: listen_ip:listen_port:proxy_ip:proxy_port, with further upstreams joined by '|' and failover tiers by '>'";
+
+/// Checks a mapping's upstreams, `proxy_ip:proxy_port` joined by `|` and `>`
+fn parse_upstreams(upstreams: &str) -> std::result::Result<String, String> {
+    for upstream in upstreams.split(['|', '>']) {
+        if balancer::parse_upstream(upstream)?.0.split(':').count() != 2 {
+            return Err(INVALID_FORWARD_MAPPING.to_string());
+        }
+    }
+    Ok(upstreams.to_string())
+}
+
+/// Applies the `key=value` settings after a mapping's `?`
+fn apply_mapping_settings(mapping: &mut ProxyMapping, settings: Option<&str>) -> std::result::Result<(), String> {
+    for setting in settings.into_iter().flat_map(|s| s.split('&')) {
+        match setting.split_once('=') {
+            Some(("name", name)) if !name.is_empty() => mapping.name = Some(name.to_string()),
+            Some(("name", _)) => return Err("Mapping name must not be empty".to_string()),
+            Some(("bind", source)) => {
+                let source = source.parse()
+                    .map_err(|_| format!("Invalid bind source IP '{}'", source))?;
+                mapping.bind_source = Some(source);
+            }
+            Some(("mirror", mirror)) => {
+                let mirror = mirror.parse()
+                    .map_err(|_| format!("Invalid mirror address '{}'. Expected ip:port", mirror))?;
+                mapping.mirror = Some(mirror);
+            }
+            Some(("http", http)) => {
+                let http = http.parse()
+                    .map_err(|_| format!("Invalid HTTP upstream address '{}'. Expected ip:port", http))?;
+                mapping.http_upstream = Some(http);
+            }
+            Some(("idle", idle)) => {
+                let idle = id_manager::parse_duration(idle)
+                    .map_err(|e| format!("Invalid idle timeout '{}': {}", idle, e))?;
+                mapping.idle_timeout = Some(idle);
+            }
+            Some(("connect", connect)) => {
+                let connect = id_manager::parse_duration(connect)
+                    .map_err(|e| format!("Invalid connect timeout '{}': {}", connect, e))?;
+                mapping.connect_timeout = Some(connect);
+            }
+            Some(("iface", _)) if !cfg!(target_os = "linux") => {
+                return Err("iface=<name> is only supported on Linux".to_string());
+            }
+            Some(("iface", iface)) => {
+                // The kernel's IFNAMSIZ, less the terminating NUL
+                if iface.is_empty() || iface.len() > 15 {
+                    return Err(format!("Invalid interface name '{}'. Expected 1 to 15 bytes", iface));
+                }
+                mapping.interface = Some(iface.to_string());
+            }
+            Some(("reuse", size)) => {
+                let reuse = size.parse().map_err(|_| "not a number".to_string()).and_then(UpstreamReuse::new)
+                    .map_err(|e| format!("Invalid upstream pool size '{}': {}", size, e))?;
+                mapping.reuse = Some(reuse.pool_size);
+            }
+            Some(("reuse_idle", idle)) => {
+                let idle = id_manager::parse_duration(idle)
+                    .map_err(|e| format!("Invalid upstream pool idle timeout '{}': {}", idle, e))?;
+                mapping.reuse_idle = Some(idle);
+            }
+            Some(("hold", hold)) => {
+                let hold = id_manager::parse_duration(hold)
+                    .map_err(|e| format!("Invalid sink hold '{}': {}", hold, e))?;
+                mapping.hold = Some(hold);
+            }
+            Some(("sni", route)) => {
+                let invalid = || format!("Invalid SNI route '{}'. Expected <server name>=<ip:port>, or *=<ip:port> for any other", route);
+                let (server_name, upstream) = route.split_once('=').ok_or_else(invalid)?;
+                let upstream = upstream.parse().map_err(|_| invalid())?;
+                if server_name.is_empty() {
+                    return Err(invalid());
+                }
+                if mapping.sni_routes.iter().any(|(name, _)| name.eq_ignore_ascii_case(server_name)) {
+                    return Err(format!("Server name '{}' is routed more than once", server_name));
+                }
+                mapping.sni_routes.push((server_name.to_string(), upstream));
+            }
+            Some(("reset_interval", interval)) => {
+                let interval = id_manager::parse_duration(interval)
+                    .map_err(|e| format!("Invalid connection ID reset interval '{}': {}", interval, e))?;
+                mapping.reset_interval = Some(interval);
+            }
+            Some(("reset_count", count)) => {
+                let count = id_manager::parse_count(count)
+                    .map_err(|e| format!("Invalid connection ID reset count '{}': {}", count, e))?;
+                mapping.reset_count = Some(count);
+            }
+            _ => return Err(format!(
+                "Unknown mapping setting '{}'. Supported: name=<name>, bind=<ip>, mirror=<ip:port>, http=<ip:port>, idle=<duration>, connect=<duration>, iface=<name>, reuse=<pool size>, reuse_idle=<duration>, hold=<duration>, sni=<server name>=<ip:port>, reset_interval=<duration>, reset_count=<count>",
+                setting
+            )),
+        }
+    }
+    Ok(())
+}
+
+/// Rejects settings that don't fit the mapping they were given to
+fn check_mapping_settings(mapping: &ProxyMapping) -> std::result::Result<(), String> {
+    if mapping.interface.is_some() && mapping.listen_addr.parse::<SocketAddr>().is_err() {
+        return Err(format!("iface=<name> needs a listen address of ip:port, not '{}'", mapping.listen_addr));
+    }
+
+    if mapping.mode != ListenMode::Forward && mapping.http_upstream.is_some() {
+        return Err("http=<ip:port> cannot be used with a CONNECT, SOCKS5, transparent, sink or TLS mapping".to_string());
+    }
+
+    if mapping.mode == ListenMode::Tls && mapping.sni_routes.is_empty() {
+        return Err("A tls:// mapping needs at least one sni=<server name>=<ip:port> route".to_string());
+    }
+
+    if !mapping.sni_routes.is_empty() && mapping.mode != ListenMode::Tls {
+        return Err("sni=<server name>=<ip:port> only applies to a tls:// mapping".to_string());
+    }
+
+    if mapping.hold.is_some() && mapping.mode != ListenMode::Sink {
+        return Err("hold=<duration> only applies to a sink:// mapping".to_string());
+    }
+
+    if mapping.reuse_idle.is_some() && mapping.reuse.is_none() {
+        return Err("reuse_idle=<duration> needs reuse=<pool size> as well".to_string());
+    }
+
+    Ok(())
+}
+
+/// Splits a list of mapping entries separated by commas or semicolons. The
+/// commas between a `listen=` group's addresses don't end the entry; its `->` does.
+pub fn mapping_list_entries(s: &str) -> Vec<String> {
+    let mut entries: Vec<String> = Vec::new();
+    for part in s.split([',', ';']).map(str::trim).filter(|part| !part.is_empty()) {
+        match entries.last_mut() {
+            Some(group) if group.starts_with("listen=") && !group.contains("->") => {
+                group.push(',');
+                group.push_str(part);
+            }
+            _ => entries.push(part.to_string()),
+        }
+    }
+    entries
+}
+
+/// Parses one or more mappings separated by commas or semicolons, as
+/// `PJ_PROXIES` and a single `--proxy` value accept. An invalid entry fails
+/// the whole list, naming the entry.
+pub fn parse_proxy_mappings(s: &str) -> std::result::Result<Vec<ProxyMapping>, String> {
+    let mut mappings = Vec::new();
+    for entry in mapping_list_entries(s) {
+        mappings.extend(parse_mapping_entry(&entry).map_err(|e| format!("'{}': {}", entry, e))?);
+    }
+    if mappings.is_empty() {
+        return Err("Expected at least one proxy mapping".to_string());
+    }
+    Ok(mappings)
+}
+
+/// The mappings in a mappings file, one per line with its line number.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn mapping_file_entries(contents: &str) -> Vec<(usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// Rejects a set of mappings that could not all be served as written: a
+/// listen or upstream address with a blank host or port, or a listen address
+/// used by more than one mapping (only one of them would bind). Port 0 is
+/// exempt, as each listener on it is given a free port.
+pub fn check_mappings(mappings: &[ProxyMapping]) -> std::result::Result<(), String> {
+    let blank = |addr: &str| match addr.rsplit_once(':') {
+        Some((host, port)) => host.trim().is_empty() || port.trim().is_empty(),
+        None => true,
+    };
+    let mut listeners = std::collections::HashSet::new();
+    for mapping in mappings {
+        if blank(&mapping.listen_addr) {
+            return Err(format!("Mapping has a blank listen address '{}'", mapping.listen_addr));
+        }
+        if mapping.mode == ListenMode::Forward {
+            let mut upstreams = mapping
+                .proxy_addr
+                .split(['|', '>'])
+                .map(|upstream| balancer::parse_upstream(upstream).map_or(upstream, |(addr, _)| addr));
+            if let Some(upstream) = upstreams.find(|upstream| blank(upstream)) {
+                return Err(format!("Mapping for {} has a blank upstream address '{}'", mapping.listen_addr, upstream));
+            }
+        }
+        // Compare parsed addresses so spellings of the same one still collide
+        let parsed = mapping.listen_addr.parse::<SocketAddr>();
+        if parsed.as_ref().is_ok_and(|addr| addr.port() == 0) {
+            // Each port 0 listener gets a port of its own
+            continue;
+        }
+        let key = parsed.map_or_else(|_| mapping.listen_addr.trim().to_string(), |addr| addr.to_string());
+        if !listeners.insert(key) {
+            return Err(format!("Listen address {} is used by more than one mapping", mapping.listen_addr));
+        }
+    }
+    Ok(())
+}
+
+/// Rejects mappings whose upstream is the proxy's own listener, which would
+/// make every connection reconnect to itself until resources run out.
+///
+/// Addresses that fail to resolve are left for the listener / connector to
+/// report.
+pub fn check_proxy_loop(mapping: &ProxyMapping) -> std::result::Result<(), String> {
+    let resolve = |addr: &str| -> Vec<SocketAddr> {
+        addr.to_socket_addrs().map(|addrs| addrs.collect()).unwrap_or_default()
+    };
+    let listen_addrs = resolve(&mapping.listen_addr);
+    let upstream_addrs: Vec<SocketAddr> = mapping
+        .proxy_addr
+        .split(['|', '>'])
+        .filter_map(|upstream| balancer::parse_upstream(upstream).ok())
+        .flat_map(|(addr, _)| resolve(addr))
+        .collect();
+
+    for listen in &listen_addrs {
+        for upstream in &upstream_addrs {
+            // A wildcard listener also accepts connections made to loopback
+            let same_host = listen.ip() == upstream.ip()
+                || (listen.ip().is_unspecified() && (upstream.ip().is_loopback() || upstream.ip().is_unspecified()));
+            if same_host && listen.port() == upstream.port() {
+                return Err(format!(
+                    "Proxy loop: {} forwards to {} which is its own listen address",
+                    mapping.listen_addr, mapping.proxy_addr
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::services::Service as ServiceTrait;
+
+    #[test]
+    fn test_parse_proxy_mapping_valid() {
+        let input = "127.0.0.1:8080:192.168.1.1:9090";
+        let result = parse_proxy_mapping(input);
+        
+        assert!(result.is_ok());
+        let mapping = result.expect("Failed to parse valid mapping");
+        assert_eq!(mapping.listen_addr, "127.0.0.1:8080");
+        assert_eq!(mapping.proxy_addr, "192.168.1.1:9090");
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_multiple_upstreams() {
+        let mapping = parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80|10.0.0.2:80?name=web")
+            .expect("Failed to parse mapping with several upstreams");
+        assert_eq!(mapping.listen_addr, "0.0.0.0:8080");
+        assert_eq!(mapping.proxy_addr, "10.0.0.1:80|10.0.0.2:80");
+        assert_eq!(mapping.name.as_deref(), Some("web"));
+
+        assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80|10.0.0.2").is_err());
+        assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80|").is_err());
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_weighted_upstreams() {
+        let mapping = parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:9000*3|10.0.0.2:9000")
+            .expect("Failed to parse weighted mapping");
+        assert_eq!(mapping.proxy_addr, "10.0.0.1:9000*3|10.0.0.2:9000");
+
+        assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:9000*0|10.0.0.2:9000").is_err());
+        assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:9000*x").is_err());
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_failover_tiers() {
+        let mapping = parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80|10.0.0.2:80*2>10.0.1.1:80")
+            .expect("Failed to parse mapping with failover tiers");
+        assert_eq!(mapping.proxy_addr, "10.0.0.1:80|10.0.0.2:80*2>10.0.1.1:80");
+
+        let tiers: Vec<Vec<(String, u32)>> = upstream_tiers(&mapping.proxy_addr)
+            .unwrap()
+            .iter()
+            .map(|tier| tier.iter().map(|(peers, weight)| (peers[0]._address.to_string(), *weight)).collect())
+            .collect();
+        assert_eq!(
+            tiers,
+            vec![
+                vec![("10.0.0.1:80".to_string(), 1), ("10.0.0.2:80".to_string(), 2)],
+                vec![("10.0.1.1:80".to_string(), 1)],
+            ]
+        );
+        assert_eq!(upstream_peers(&mapping.proxy_addr).unwrap().len(), 3);
+
+        assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80>").is_err());
+        assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80>>10.0.1.1:80").is_err());
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_localhost() {
+        let input = "localhost:8080:localhost:9090";
+        let result = parse_proxy_mapping(input);
+        
+        assert!(result.is_ok());
+        let mapping = result.expect("Failed to parse localhost mapping");
+        assert_eq!(mapping.listen_addr, "localhost:8080");
+        assert_eq!(mapping.proxy_addr, "localhost:9090");
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_zeros() {
+        let input = "0.0.0.0:80:10.0.0.1:8080";
+        let result = parse_proxy_mapping(input);
+        
+        assert!(result.is_ok());
+        let mapping = result.expect("Failed to parse zeros mapping");
+        assert_eq!(mapping.listen_addr, "0.0.0.0:80");
+        assert_eq!(mapping.proxy_addr, "10.0.0.1:8080");
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_invalid_format() {
+        let test_cases = vec![
+            "127.0.0.1:8080",
+            "127.0.0.1:8080:192.168.1.1",
+            "127.0.0.1",
+            "",
+            "127.0.0.1:8080:192.168.1.1:9090:extra",
+        ];
+
+        for input in test_cases {
+            let result = parse_proxy_mapping(input);
+            assert!(result.is_err(), "Expected error for input: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_proxy_mappings_list() {
+        let mappings = parse_proxy_mappings("127.0.0.1:8080:10.0.0.1:9000?name=web&idle=5m, 127.0.0.1:8081:10.0.0.2:9000;socks5://127.0.0.1:1080")
+            .expect("Failed to parse mapping list");
+        let listen: Vec<&str> = mappings.iter().map(|m| m.listen_addr.as_str()).collect();
+        assert_eq!(listen, vec!["127.0.0.1:8080", "127.0.0.1:8081", "127.0.0.1:1080"]);
+        assert_eq!(mappings[0].name.as_deref(), Some("web"));
+        assert_eq!(mappings[2].mode, ListenMode::Socks5);
+
+        let err = parse_proxy_mappings("127.0.0.1:8080:10.0.0.1:9000,127.0.0.1:8081").unwrap_err();
+        assert!(err.starts_with("'127.0.0.1:8081': "), "Should name the bad entry: {}", err);
+        assert!(parse_proxy_mappings(" , ").is_err());
+    }
+
+    #[test]
+    fn test_parse_listen_group() {
+        let mappings = parse_proxy_mappings("listen=0.0.0.0:8080, [::]:8080 -> 10.0.0.1:9000|10.0.0.2:9000?name=web&idle=5m;127.0.0.1:8081:10.0.0.3:9000")
+            .expect("Failed to parse listen group");
+        let listen: Vec<&str> = mappings.iter().map(|m| m.listen_addr.as_str()).collect();
+        assert_eq!(listen, vec!["0.0.0.0:8080", "[::]:8080", "127.0.0.1:8081"]);
+        for mapping in &mappings[..2] {
+            assert_eq!(mapping.proxy_addr, "10.0.0.1:9000|10.0.0.2:9000");
+            assert_eq!(mapping.name.as_deref(), Some("web"));
+            assert_eq!(mapping.idle_timeout, Some(Duration::from_secs(300)));
+        }
+        assert_eq!(mappings[2].proxy_addr, "10.0.0.3:9000");
+        assert!(check_mappings(&mappings).is_ok(), "The listeners differ by address family");
+
+        assert_eq!(
+            mapping_list_entries("listen=127.0.0.1:80,[::1]:80 -> 10.0.0.1:80, 127.0.0.1:81:10.0.0.1:81"),
+            vec!["listen=127.0.0.1:80,[::1]:80 -> 10.0.0.1:80", "127.0.0.1:81:10.0.0.1:81"]
+        );
+        assert_eq!(parse_mapping_entry("127.0.0.1:80:10.0.0.1:80").unwrap().len(), 1);
+        assert!(parse_proxy_mapping("listen=127.0.0.1:80,[::1]:80 -> 10.0.0.1:80").is_err(), "Expects a single mapping");
+        assert_eq!(parse_proxy_mapping("listen=[::1]:80 -> 10.0.0.1:80").unwrap().listen_addr, "[::1]:80");
+        for input in [
+            "listen=127.0.0.1:80",
+            "listen= -> 10.0.0.1:80",
+            "listen=127.0.0.1 -> 10.0.0.1:80",
+            "listen=127.0.0.1:80 -> 10.0.0.1",
+            "listen=127.0.0.1:80 -> 10.0.0.1:80?bogus=1",
+        ] {
+            assert!(parse_mapping_entry(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+
+    #[test]
+    fn test_mapping_file_entries() {
+        let contents = "# web tier\n127.0.0.1:8080:10.0.0.1:9000?name=web\n\n   \n  # ssh\n  socks5://127.0.0.1:1080  \n";
+        assert_eq!(
+            mapping_file_entries(contents),
+            vec![(2, "127.0.0.1:8080:10.0.0.1:9000?name=web"), (6, "socks5://127.0.0.1:1080")]
+        );
+        assert!(mapping_file_entries("# nothing here\n").is_empty());
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let lookup = |name: &str| match name {
+            "BACKEND_HOST" => Some("10.0.0.7".to_string()),
+            "PORT" => Some("9000".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_vars("0.0.0.0:8080:${BACKEND_HOST}:${PORT}", lookup).unwrap(),
+            "0.0.0.0:8080:10.0.0.7:9000"
+        );
+        assert_eq!(expand_vars("0.0.0.0:8080:10.0.0.1:80", lookup).unwrap(), "0.0.0.0:8080:10.0.0.1:80");
+
+        assert_eq!(
+            expand_vars("0.0.0.0:8080:${MISSING}:9000", lookup).unwrap_err(),
+            "Environment variable 'MISSING' is not set"
+        );
+        assert!(expand_vars("0.0.0.0:8080:${BACKEND_HOST:9000", lookup).is_err());
+        assert!(expand_vars("0.0.0.0:8080:${}:9000", lookup).is_err());
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_expands_env() {
+        std::env::set_var("PJ_TEST_EXPAND_BACKEND", "10.0.0.9");
+        let mapping = parse_proxy_mapping("0.0.0.0:8080:${PJ_TEST_EXPAND_BACKEND}:9000?name=web")
+            .expect("Failed to parse mapping with a variable");
+        assert_eq!(mapping.proxy_addr, "10.0.0.9:9000");
+
+        let err = parse_proxy_mappings("0.0.0.0:8080:${PJ_TEST_EXPAND_UNSET}:9000").unwrap_err();
+        assert!(err.contains("'PJ_TEST_EXPAND_UNSET' is not set"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_name() {
+        let mapping = parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:9000?name=web")
+            .expect("Failed to parse named mapping");
+        assert_eq!(mapping.listen_addr, "127.0.0.1:8080");
+        assert_eq!(mapping.proxy_addr, "10.0.0.1:9000");
+        assert_eq!(mapping.name.as_deref(), Some("web"));
+
+        let unnamed = parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:9000").expect("Failed to parse mapping");
+        assert_eq!(unnamed.name, None);
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_bind_source() {
+        let mapping = parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:9000?name=web&bind=10.0.0.5")
+            .expect("Failed to parse mapping with bind source");
+        assert_eq!(mapping.name.as_deref(), Some("web"));
+        assert_eq!(mapping.bind_source, Some("10.0.0.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_mirror() {
+        let mapping = parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:9000?mirror=10.0.0.2:9000")
+            .expect("Failed to parse mapping with mirror");
+        assert_eq!(mapping.proxy_addr, "10.0.0.1:9000");
+        assert_eq!(mapping.mirror, Some("10.0.0.2:9000".parse().unwrap()));
+
+        assert!(parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:9000?mirror=10.0.0.2").is_err());
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_http_upstream() {
+        let mapping = parse_proxy_mapping("0.0.0.0:443:10.0.0.1:22?http=10.0.0.2:80")
+            .expect("Failed to parse mapping with HTTP upstream");
+        assert_eq!(mapping.proxy_addr, "10.0.0.1:22");
+        assert_eq!(mapping.http_upstream, Some("10.0.0.2:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_timeouts() {
+        let mapping = parse_proxy_mapping("0.0.0.0:22:10.0.0.1:22?idle=1h&connect=5s")
+            .expect("Failed to parse mapping with timeouts");
+        assert_eq!(mapping.idle_timeout, Some(Duration::from_secs(3600)));
+        assert_eq!(mapping.connect_timeout, Some(Duration::from_secs(5)));
+
+        let mapping = parse_proxy_mapping("connect://0.0.0.0:3128?idle=30s").unwrap();
+        assert_eq!(mapping.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(mapping.connect_timeout, None);
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_reuse() {
+        let defaults = ProxyOptions::default();
+        let mapping = parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80?reuse_idle=10s&reuse=32").expect("Failed to parse mapping with reuse");
+        assert_eq!(
+            mapping.options(&defaults).upstream_reuse,
+            Some(UpstreamReuse { pool_size: 32, idle_timeout: Duration::from_secs(10) })
+        );
+        let mapping = parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80?reuse=8").unwrap();
+        assert_eq!(mapping.options(&defaults).upstream_reuse, Some(UpstreamReuse::new(8).unwrap()));
+        assert_eq!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80").unwrap().options(&defaults).upstream_reuse, None);
+
+        for input in ["reuse=0", "reuse=many", "reuse_idle=10s", "reuse=8&reuse_idle=10"] {
+            assert!(parse_proxy_mapping(&format!("0.0.0.0:8080:10.0.0.1:80?{}", input)).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_with_interface() {
+        if cfg!(target_os = "linux") {
+            let mapping = parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80?iface=eth0").expect("Failed to parse mapping with interface");
+            assert_eq!(mapping.interface.as_deref(), Some("eth0"));
+            assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80?iface=").is_err());
+            assert!(parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80?iface=a-very-long-interface").is_err());
+            assert!(parse_proxy_mapping("localhost:8080:10.0.0.1:80?iface=eth0").is_err());
+        } else {
+            let err = parse_proxy_mapping("0.0.0.0:8080:10.0.0.1:80?iface=eth0").unwrap_err();
+            assert!(err.contains("only supported on Linux"), "Unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_mapping_timeouts_override_defaults() {
+        let defaults = ProxyOptions {
+            idle_timeout: Some(Duration::from_secs(600)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        let ssh = parse_proxy_mapping("0.0.0.0:22:10.0.0.1:22?idle=1h").unwrap().options(&defaults);
+        assert_eq!(ssh.idle_timeout, Some(Duration::from_secs(3600)));
+        assert_eq!(ssh.connect_timeout, Some(Duration::from_secs(10)));
+
+        let api = parse_proxy_mapping("0.0.0.0:80:10.0.0.2:80?idle=30s&connect=2s").unwrap().options(&defaults);
+        assert_eq!(api.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(api.connect_timeout, Some(Duration::from_secs(2)));
+
+        let plain = parse_proxy_mapping("0.0.0.0:8080:10.0.0.3:80").unwrap().options(&defaults);
+        assert_eq!(plain.idle_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(plain.connect_timeout, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_connect_timeout_sets_peer_timeout() {
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let options = ProxyOptions { connect_timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let app = ProxyApp::with_options(BasicPeer::new("127.0.0.1:8080"), "0.0.0.0:8787".to_string(), id_manager, options);
+
+        let Upstream::Fixed(proxy_to) = &app.upstream else { panic!("Expected a fixed upstream") };
+        assert_eq!(proxy_to.options.connection_timeout, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_bind_source_sets_peer_bind_to() {
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let options = ProxyOptions { bind_source: Some("127.0.0.2".parse().unwrap()), ..Default::default() };
+        let app = ProxyApp::with_options(BasicPeer::new("127.0.0.1:8080"), "0.0.0.0:8787".to_string(), id_manager, options);
+
+        let Upstream::Fixed(proxy_to) = &app.upstream else { panic!("Expected a fixed upstream") };
+        let bind_to = proxy_to.options.bind_to.clone().expect("bind_to should be set");
+        assert_eq!(bind_to.addr, Some("127.0.0.2:0".parse().unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dscp_marks_upstream_and_client_sockets() {
+        use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+
+        fn tos(fd: RawFd) -> libc::c_int {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let rc = unsafe { libc::getsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, &mut value as *mut _ as *mut libc::c_void, &mut len) };
+            assert_eq!(rc, 0, "getsockopt(IP_TOS) failed");
+            value
+        }
+
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        let (upstream_tx, upstream_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, proxy_side) = echo.accept().await.unwrap();
+            let _ = upstream_tx.send(proxy_side);
+            let (mut reader, mut writer) = socket.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let options = ProxyOptions { dscp: Some(Dscp::parse("EF").unwrap()), dscp_downstream: true, ..Default::default() };
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let app = Arc::new(ProxyApp::with_options(BasicPeer::new(&echo_addr.to_string()), "127.0.0.1:0".to_string(), id_manager, options));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let client_fd = accepted.as_raw_fd();
+        let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+
+        let relay = {
+            let app = app.clone();
+            tokio::spawn(async move {
+                let (_tx, shutdown) = tokio::sync::watch::channel(false);
+                app.process_new(io, &shutdown).await
+            })
+        };
+
+        client.write_all(b"qos").await.unwrap();
+        let mut echoed = [0u8; 3];
+        client.read_exact(&mut echoed).await.unwrap();
+
+        assert_eq!(tos(client_fd), 184, "EF is 46, shifted into the upper six bits");
+
+        // The proxy's upstream socket lives in this process; find it by its local address
+        let upstream_local = upstream_rx.await.unwrap();
+        let upstream_fd = std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+            .find(|&fd| {
+                let socket = std::mem::ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd) });
+                socket.local_addr().ok() == Some(upstream_local)
+            })
+            .expect("Upstream socket not found");
+        assert_eq!(tos(upstream_fd), 184);
+
+        drop(client);
+        relay.await.unwrap();
+    }
 
-    pub async fn duplex(&self, mut server_session: Stream, mut client_session: Stream, conn_info: ConnectionInfo, active_connections: Arc<AtomicU64>) {
-        let mut upstream_buf = [0; 1024];
-        let mut downstream_buf = [0; 1024];
-        let mut stats = ConnectionStats::new();
-        
-        conn_info.log_start();
-        
-        loop {
-            let downstream_read = server_session.read(&mut upstream_buf);
-            let upstream_read = client_session.read(&mut downstream_buf);
-            let event: DuplexEvent;
-            select! {
-                n = downstream_read => {
-                    match n {
-                        Ok(n) => event = DuplexEvent::DownstreamRead(n),
-                        Err(e) => {
-                            warn!("Downstream read error: {}", e);
-                            let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                            conn_info.log_end(stats.bytes_sent, stats.bytes_received, Some(&e.to_string()), remaining);
-                            return;
-                        }
-                    }
-                }
-                n = upstream_read => {
-                    match n {
-                        Ok(n) => event = DuplexEvent::UpstreamRead(n),
-                        Err(e) => {
-                            warn!("Upstream read error: {}", e);
-                            let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                            conn_info.log_end(stats.bytes_sent, stats.bytes_received, Some(&e.to_string()), remaining);
-                            return;
-                        }
-                    }
-                }
-            }
-            match event {
-                DuplexEvent::DownstreamRead(0) => {
-                    debug!("Downstream session closing");
-                    let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                    conn_info.log_end(stats.bytes_sent, stats.bytes_received, None, remaining);
-                    return;
-                }
-                DuplexEvent::UpstreamRead(0) => {
-                    debug!("Upstream session closing");
-                    let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                    conn_info.log_end(stats.bytes_sent, stats.bytes_received, None, remaining);
-                    return;
-                }
-                DuplexEvent::DownstreamRead(n) => {
-                    stats.add_received(n);
-                    if let Err(e) = client_session.write_all(&upstream_buf[0..n]).await {
-                        warn!("Failed to write to client session: {}", e);
-                        let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                        conn_info.log_end(stats.bytes_sent, stats.bytes_received, Some(&e.to_string()), remaining);
-                        return;
-                    }
-                    if let Err(e) = client_session.flush().await {
-                        warn!("Failed to flush client session: {}", e);
-                        let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                        conn_info.log_end(stats.bytes_sent, stats.bytes_received, Some(&e.to_string()), remaining);
-                        return;
-                    }
-                }
-                DuplexEvent::UpstreamRead(n) => {
-                    stats.add_sent(n);
-                    if let Err(e) = server_session.write_all(&downstream_buf[0..n]).await {
-                        warn!("Failed to write to server session: {}", e);
-                        let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                        conn_info.log_end(stats.bytes_sent, stats.bytes_received, Some(&e.to_string()), remaining);
-                        return;
-                    }
-                    if let Err(e) = server_session.flush().await {
-                        warn!("Failed to flush server session: {}", e);
-                        let remaining = active_connections.fetch_sub(1, Ordering::Relaxed) - 1;
-                        conn_info.log_end(stats.bytes_sent, stats.bytes_received, Some(&e.to_string()), remaining);
-                        return;
-                    }
-                }
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_source_ports_bind_upstream_within_range() {
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let (peers_tx, mut peers_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, peer)) = backend.accept().await {
+                let _ = peers_tx.send(peer);
+                held.push(socket);
             }
-        }
-    }
-}
+        });
 
-#[async_trait]
-impl ServerApp for ProxyApp {
-    async fn process_new(
-        self: &Arc<Self>,
-        io: Stream,
-        _shutdown: &ShutdownWatch,
-    ) -> Option<Stream> {
-        // Try to get client address from the stream's socket digest
-        let client_socket_addr = {
-            use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-            
-            io.get_socket_digest()
-                .and_then(|digest| digest.peer_addr.get().cloned())
-                .and_then(|opt_addr| opt_addr)
-                .and_then(|addr| {
-                    addr.as_inet().map(|inet| {
-                        let ip = IpAddr::from(inet.ip().to_canonical());
-                        SocketAddr::new(ip, inet.port())
-                    })
-                })
-                .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))
+        let options = ProxyOptions { source_ports: Some(SourcePorts::parse("40100-40101").unwrap()), ..Default::default() };
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let app = Arc::new(ProxyApp::with_options(BasicPeer::new(&backend_addr.to_string()), "127.0.0.1:0".to_string(), id_manager, options));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = || async {
+            let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+            let app = app.clone();
+            let relay = tokio::spawn(async move {
+                let (_tx, shutdown) = tokio::sync::watch::channel(false);
+                app.process_new(io, &shutdown).await
+            });
+            (client, relay)
         };
-        
-        let client_session = self.client_connector.new_stream(&self.proxy_to).await;
 
-        match client_session {
-            Ok(client_session) => {
-                // Increment active connections counter
-                let current_connections = self.active_connections.fetch_add(1, Ordering::Relaxed) + 1;
-                
-                let conn_info = ConnectionInfo::new(
-                    client_socket_addr,
-                    &self.listen_addr,
-                    &self.proxy_to._address.to_string(),
-                    current_connections,
-                    &self.id_manager
-                );
-                
-                self.duplex(io, client_session, conn_info, self.active_connections.clone()).await;
-                None
-            }
-            Err(e) => {
-                warn!("Failed to create client session to {}: {}", self.proxy_to._address, e);
-                None
-            }
+        // Each upstream connection takes one of the two ports while it is open
+        let mut ports = Vec::new();
+        let mut open = Vec::new();
+        for _ in 0..2 {
+            open.push(connect().await);
+            let peer = tokio::time::timeout(Duration::from_secs(5), peers_rx.recv()).await.unwrap().unwrap();
+            ports.push(peer.port());
         }
+        ports.sort();
+        assert_eq!(ports, [40100, 40101]);
+
+        // With the range exhausted the next client is turned away
+        let (mut client, relay) = connect().await;
+        tokio::time::timeout(Duration::from_secs(5), relay).await.expect("Connect should fail").unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert!(peers_rx.try_recv().is_err(), "Nothing should reach the backend");
     }
-}
 
-pub fn proxy_service(addr: &str, proxy_addr: &str, id_manager: Arc<ConnectionIdManager>) -> Service<ProxyApp> {
-    let proxy_to = BasicPeer::new(proxy_addr);
+    #[tokio::test]
+    async fn test_dial_falls_back_to_working_family() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v4 = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _accepted = listener.accept().await;
+            std::future::pending::<()>().await;
+        });
 
-    Service::with_listeners(
-        "Proxy Service".to_string(),
-        Listeners::tcp(addr),
-        ProxyApp::new(proxy_to, addr.to_string(), id_manager),
-    )
-}
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let options = ProxyOptions { connect_timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let app = ProxyApp::with_options(BasicPeer::new(&v4.to_string()), "127.0.0.1:0".to_string(), id_manager, options);
 
-#[derive(Debug, Clone)]
-pub struct ProxyMapping {
-    pub listen_addr: String,
-    pub proxy_addr: String,
-}
+        // 100::/64 is the discard prefix, so that address never answers
+        let candidates: Vec<BasicPeer> = ["[100::1]:9".to_string(), v4.to_string()]
+            .iter()
+            .map(|addr| app.with_peer_options(BasicPeer::new(addr)))
+            .collect();
+        let mut peer = Cow::Owned(candidates[0].clone());
+        let started = std::time::Instant::now();
+        app.dial(&mut peer, &candidates).await.expect("Should connect over IPv4");
+        assert!(started.elapsed() < Duration::from_secs(1), "Took {:?}", started.elapsed());
+        assert_eq!(peer._address.to_string(), v4.to_string());
+    }
 
-pub fn parse_proxy_mapping(s: &str) -> std::result::Result<ProxyMapping, String> {
-    let parts: Vec<&str> = s.split(':').collect();
-    if parts.len() != 4 {
-        return Err("Invalid proxy mapping format. Expected format: listen_ip:listen_port:proxy_ip:proxy_port".to_string());
+    #[tokio::test]
+    async fn test_hostname_upstream_falls_back_to_working_family() {
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v4 = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = backend.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        // A hostname builds an app instead of panicking, with each of its addresses
+        let tiers = upstream_tiers(&format!("localhost:{}", v4.port())).expect("localhost should resolve");
+        assert!(tiers[0][0].0.iter().any(|peer| peer._address.to_string() == v4.to_string()), "{:?}", tiers[0][0].0);
+        assert!(upstream_tiers("nonexistent.invalid:80").is_err());
+
+        // A host whose IPv6 address never answers (100::/64 is the discard
+        // prefix) and whose IPv4 one works is reached over IPv4 without waiting out the first
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let options = ProxyOptions { connect_timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let resolved = vec![BasicPeer::new(&format!("[100::1]:{}", v4.port())), BasicPeer::new(&v4.to_string())];
+        let app = Arc::new(tiered_app("127.0.0.1:0".to_string(), vec![vec![(resolved, 1)]], id_manager, options));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let started = std::time::Instant::now();
+        let (mut client, relay) = relay_one(&app, &listener).await;
+        assert!(echoes(&mut client).await, "Should relay through the IPv4 address");
+        assert!(started.elapsed() < Duration::from_secs(1), "Took {:?}", started.elapsed());
+        drop(client);
+        relay.await.unwrap();
     }
 
-    let listen_addr = format!("{}:{}", parts[0], parts[1]);
-    let proxy_addr = format!("{}:{}", parts[2], parts[3]);
+    #[test]
+    fn test_parse_connect_mapping() {
+        let mapping = parse_proxy_mapping("connect://0.0.0.0:3128?name=egress")
+            .expect("Failed to parse CONNECT mapping");
+        assert_eq!(mapping.mode, ListenMode::Connect);
+        assert_eq!(mapping.listen_addr, "0.0.0.0:3128");
+        assert_eq!(mapping.name.as_deref(), Some("egress"));
 
-    Ok(ProxyMapping {
-        listen_addr,
-        proxy_addr,
-    })
-}
+        assert_eq!(parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:9000").unwrap().mode, ListenMode::Forward);
+        assert!(parse_proxy_mapping("connect://0.0.0.0").is_err());
+        assert!(parse_proxy_mapping("connect://0.0.0.0:3128:10.0.0.1:80").is_err());
+        assert!(parse_proxy_mapping("connect://0.0.0.0:3128?http=10.0.0.1:80").is_err());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use pingora_core::services::Service as ServiceTrait;
+    #[test]
+    fn test_parse_socks5_mapping() {
+        let mapping = parse_proxy_mapping("socks5://0.0.0.0:1080").expect("Failed to parse SOCKS5 mapping");
+        assert_eq!(mapping.mode, ListenMode::Socks5);
+        assert_eq!(mapping.listen_addr, "0.0.0.0:1080");
+
+        assert!(parse_proxy_mapping("socks5://0.0.0.0").is_err());
+        assert!(parse_proxy_mapping("socks4://0.0.0.0:1080").is_err());
+    }
 
     #[test]
-    fn test_parse_proxy_mapping_valid() {
-        let input = "127.0.0.1:8080:192.168.1.1:9090";
-        let result = parse_proxy_mapping(input);
-        
-        assert!(result.is_ok());
-        let mapping = result.expect("Failed to parse valid mapping");
-        assert_eq!(mapping.listen_addr, "127.0.0.1:8080");
-        assert_eq!(mapping.proxy_addr, "192.168.1.1:9090");
+    fn test_parse_transparent_mapping() {
+        let mapping = parse_proxy_mapping("transparent://0.0.0.0:15001?name=egress").expect("Failed to parse transparent mapping");
+        assert_eq!(mapping.mode, ListenMode::Transparent);
+        assert_eq!(mapping.listen_addr, "0.0.0.0:15001");
+        assert_eq!(mapping.name.as_deref(), Some("egress"));
+
+        assert!(parse_proxy_mapping("transparent://0.0.0.0").is_err());
+        assert!(parse_proxy_mapping("transparent://0.0.0.0:15001?http=10.0.0.1:80").is_err());
     }
 
     #[test]
-    fn test_parse_proxy_mapping_with_localhost() {
-        let input = "localhost:8080:localhost:9090";
-        let result = parse_proxy_mapping(input);
-        
-        assert!(result.is_ok());
-        let mapping = result.expect("Failed to parse localhost mapping");
-        assert_eq!(mapping.listen_addr, "localhost:8080");
-        assert_eq!(mapping.proxy_addr, "localhost:9090");
+    fn test_parse_sink_mapping() {
+        let mapping = parse_proxy_mapping("sink://0.0.0.0:8080?hold=30s").expect("Failed to parse sink mapping");
+        assert_eq!(mapping.mode, ListenMode::Sink);
+        assert_eq!(mapping.listen_addr, "0.0.0.0:8080");
+        assert_eq!(mapping.hold, Some(Duration::from_secs(30)));
+        assert_eq!(parse_proxy_mapping("sink://0.0.0.0:8080").unwrap().hold, None);
+
+        assert!(parse_proxy_mapping("sink://0.0.0.0:8080?hold=forever").is_err());
+        assert!(parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:80?hold=30s").is_err(), "Only sinks hold connections");
     }
 
     #[test]
-    fn test_parse_proxy_mapping_with_zeros() {
-        let input = "0.0.0.0:80:10.0.0.1:8080";
-        let result = parse_proxy_mapping(input);
-        
-        assert!(result.is_ok());
-        let mapping = result.expect("Failed to parse zeros mapping");
-        assert_eq!(mapping.listen_addr, "0.0.0.0:80");
-        assert_eq!(mapping.proxy_addr, "10.0.0.1:8080");
+    fn test_parse_tls_mapping() {
+        let mapping = parse_proxy_mapping("tls://0.0.0.0:443?sni=a.example.com=10.0.0.1:443&sni=*=10.0.0.9:443&name=edge")
+            .expect("Failed to parse TLS mapping");
+        assert_eq!(mapping.mode, ListenMode::Tls);
+        assert_eq!(mapping.listen_addr, "0.0.0.0:443");
+        assert_eq!(mapping.name.as_deref(), Some("edge"));
+        assert_eq!(
+            mapping.sni_routes,
+            vec![("a.example.com".to_string(), "10.0.0.1:443".parse().unwrap()), ("*".to_string(), "10.0.0.9:443".parse().unwrap())]
+        );
+
+        assert!(parse_proxy_mapping("tls://0.0.0.0:443").is_err(), "Routes are required");
+        assert!(parse_proxy_mapping("tls://0.0.0.0:443?sni=a.example.com").is_err());
+        assert!(parse_proxy_mapping("tls://0.0.0.0:443?sni=a.example.com=backend").is_err());
+        assert!(parse_proxy_mapping("tls://0.0.0.0:443?sni=a.example.com=10.0.0.1:443&sni=A.example.com=10.0.0.2:443").is_err());
+        assert!(parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:80?sni=a.example.com=10.0.0.1:443").is_err(), "Only TLS mappings route by name");
     }
 
     #[test]
-    fn test_parse_proxy_mapping_invalid_format() {
+    fn test_mappings_reset_connection_ids_independently() {
+        let mappings = parse_proxy_mappings(
+            "127.0.0.1:8001:127.0.0.1:9001?name=web&reset_count=2,127.0.0.1:8002:127.0.0.1:9002?reset_count=3,127.0.0.1:8003:127.0.0.1:9003",
+        )
+        .unwrap();
+        assert_eq!(mappings[0].reset_count, Some(2));
+        assert!(parse_proxy_mapping("127.0.0.1:8001:127.0.0.1:9001?reset_count=0").is_err());
+        assert!(parse_proxy_mapping("127.0.0.1:8001:127.0.0.1:9001?reset_interval=5").is_err());
+
+        let shared = Arc::new(ConnectionIdManager::new(None, None));
+        let ids: Vec<_> = mappings.iter().map(|mapping| mapping.id_manager(&shared)).collect();
+        assert!(Arc::ptr_eq(&ids[2], &shared), "Mappings without a policy share the IDs");
+        let next = |ids: &ConnectionIdManager, n| (0..n).map(|_| ids.next_id()).collect::<Vec<_>>();
+        assert_eq!(next(&ids[0], 5), vec![0, 1, 0, 1, 0]);
+        assert_eq!(next(&ids[1], 5), vec![0, 1, 2, 0, 1]);
+        assert_eq!(next(&ids[2], 5), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_parse_proxy_mapping_invalid_settings() {
         let test_cases = vec![
-            "127.0.0.1:8080",
-            "127.0.0.1:8080:192.168.1.1",
-            "127.0.0.1",
-            "",
-            "127.0.0.1:8080:192.168.1.1:9090:extra",
+            "127.0.0.1:8080:10.0.0.1:9000?bind=not-an-ip",
+            "127.0.0.1:8080:10.0.0.1:9000?name=",
+            "127.0.0.1:8080:10.0.0.1:9000?colour=red",
+            "127.0.0.1:8080:10.0.0.1:9000?name",
+            "127.0.0.1:8080:10.0.0.1:9000?idle=5",
+            "127.0.0.1:8080:10.0.0.1:9000?connect=0s",
         ];
 
         for input in test_cases {
-            let result = parse_proxy_mapping(input);
-            assert!(result.is_err(), "Expected error for input: {}", input);
+            assert!(parse_proxy_mapping(input).is_err(), "Expected error for input: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_proxy_app_name_defaults_to_listen_addr() {
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let unnamed = ProxyApp::new(BasicPeer::new("127.0.0.1:8080"), "0.0.0.0:8787".to_string(), id_manager.clone());
+        assert_eq!(unnamed.name, "0.0.0.0:8787");
+
+        let options = ProxyOptions { name: Some("ssh".to_string()), ..Default::default() };
+        let named = ProxyApp::with_options(BasicPeer::new("127.0.0.1:8080"), "0.0.0.0:8787".to_string(), id_manager, options);
+        assert_eq!(named.name, "ssh");
+    }
+
+    #[test]
+    fn test_check_proxy_loop() {
+        let looped = parse_proxy_mapping("127.0.0.1:8080:127.0.0.1:8080").unwrap();
+        assert!(check_proxy_loop(&looped).is_err());
+
+        let wildcard = parse_proxy_mapping("0.0.0.0:8080:127.0.0.1:8080").unwrap();
+        assert!(check_proxy_loop(&wildcard).is_err());
+
+        let resolved = parse_proxy_mapping("127.0.0.1:8080:localhost:8080").unwrap();
+        assert!(check_proxy_loop(&resolved).is_err());
+
+        let other_port = parse_proxy_mapping("127.0.0.1:8080:127.0.0.1:8081").unwrap();
+        assert!(check_proxy_loop(&other_port).is_ok());
+
+        let other_host = parse_proxy_mapping("127.0.0.1:8080:10.0.0.1:8080").unwrap();
+        assert!(check_proxy_loop(&other_host).is_ok());
+    }
+
+    #[test]
+    fn test_check_mappings() {
+        let mappings = parse_proxy_mappings("127.0.0.1:8080:10.0.0.1:80,127.0.0.1:8081:10.0.0.1:80|10.0.0.2:80*2").unwrap();
+        assert!(check_mappings(&mappings).is_ok());
+
+        let duplicate = parse_proxy_mappings("127.0.0.1:8080:10.0.0.1:80,socks5://127.0.0.1:8080").unwrap();
+        let err = check_mappings(&duplicate).unwrap_err();
+        assert!(err.contains("127.0.0.1:8080"), "Should name the duplicate: {}", err);
+
+        // The same address spelled differently
+        let respelled = parse_proxy_mappings("127.0.0.1:8080:10.0.0.1:80,127.0.0.1:08080:10.0.0.2:80").unwrap();
+        assert!(check_mappings(&respelled).is_err());
+
+        let ephemeral = parse_proxy_mappings("127.0.0.1:0:10.0.0.1:80,127.0.0.1:0:10.0.0.2:80").unwrap();
+        assert!(check_mappings(&ephemeral).is_ok(), "Port 0 listeners should not collide");
+
+        for blank in ["   :   :   :   ", " :8080:10.0.0.1:80", "127.0.0.1:8080:10.0.0.1: ", "127.0.0.1:8080:10.0.0.1:80| :80"] {
+            let mapping = parse_proxy_mapping(blank).unwrap();
+            assert!(check_mappings(&[mapping]).is_err(), "Expected a blank address error for '{}'", blank);
         }
     }
 
@@ -268,7 +2318,8 @@ mod tests {
         let id_manager = Arc::new(ConnectionIdManager::new(None, None));
         let proxy_app = ProxyApp::new(peer.clone(), listen_addr.clone(), id_manager);
         
-        assert_eq!(proxy_app.proxy_to._address, peer._address);
+        let Upstream::Fixed(proxy_to) = &proxy_app.upstream else { panic!("Expected a fixed upstream") };
+        assert_eq!(proxy_to._address, peer._address);
         assert_eq!(proxy_app.listen_addr, listen_addr);
     }
 
@@ -288,6 +2339,7 @@ mod tests {
         let mapping = ProxyMapping {
             listen_addr: "127.0.0.1:8080".to_string(),
             proxy_addr: "192.168.1.1:9090".to_string(),
+            ..Default::default()
         };
         
         let cloned = mapping.clone();
@@ -300,6 +2352,7 @@ mod tests {
         let mapping = ProxyMapping {
             listen_addr: "127.0.0.1:8080".to_string(),
             proxy_addr: "192.168.1.1:9090".to_string(),
+            ..Default::default()
         };
         
         let debug_str = format!("{:?}", mapping);
@@ -307,26 +2360,620 @@ mod tests {
         assert!(debug_str.contains("192.168.1.1:9090"));
     }
 
-    #[test]
-    fn test_duplex_event_downstream_read() {
-        let event = DuplexEvent::DownstreamRead(100);
-        match event {
-            DuplexEvent::DownstreamRead(n) => assert_eq!(n, 100),
-            _ => panic!("Wrong event type"),
+    #[tokio::test]
+    async fn test_traffic_counters_after_transfer() {
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let app = Arc::new(ProxyApp::new(BasicPeer::new(&echo_addr.to_string()), "127.0.0.1:0".to_string(), id_manager));
+        let traffic = app.traffic();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+
+        let relay = {
+            let app = app.clone();
+            tokio::spawn(async move {
+                let (_tx, shutdown) = tokio::sync::watch::channel(false);
+                app.process_new(io, &shutdown).await
+            })
+        };
+
+        let payload = b"hello counters";
+        client.write_all(payload).await.unwrap();
+        let mut echoed = vec![0u8; payload.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, payload);
+        drop(client);
+        relay.await.unwrap();
+
+        let len = payload.len() as u64;
+        assert_eq!(app.total_bytes(), (len, len));
+        assert_eq!(app.total_connections(), 1);
+        assert_eq!(traffic.bytes_sent(), len);
+        assert_eq!(traffic.connections(), 1);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Start(String),
+        End(u64, u64, Option<String>),
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: std::sync::Mutex<Vec<Event>>,
+    }
+
+    impl ConnectionObserver for RecordingObserver {
+        fn on_start(&self, info: &ConnectionInfo) {
+            self.events.lock().unwrap().push(Event::Start(info.backend_addr.clone()));
+        }
+
+        fn on_end(&self, _info: &ConnectionInfo, stats: &ConnectionStats, error: Option<&str>) {
+            self.events.lock().unwrap().push(Event::End(stats.bytes_sent(), stats.bytes_received(), error.map(String::from)));
         }
     }
 
-    #[test]
-    fn test_duplex_event_upstream_read() {
-        let event = DuplexEvent::UpstreamRead(200);
-        match event {
-            DuplexEvent::UpstreamRead(n) => assert_eq!(n, 200),
-            _ => panic!("Wrong event type"),
+    #[tokio::test]
+    async fn test_observer_sees_start_and_end() {
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let observer = Arc::new(RecordingObserver::default());
+        let options = ProxyOptions { observer: Some(observer.clone()), ..Default::default() };
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new(&echo_addr.to_string()),
+            "127.0.0.1:0".to_string(),
+            id_manager,
+            options,
+        ));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+
+        let relay = {
+            let app = app.clone();
+            tokio::spawn(async move {
+                let (_tx, shutdown) = tokio::sync::watch::channel(false);
+                app.process_new(io, &shutdown).await
+            })
+        };
+
+        let payload = b"observed";
+        client.write_all(payload).await.unwrap();
+        let mut echoed = vec![0u8; payload.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        drop(client);
+        relay.await.unwrap();
+
+        let len = payload.len() as u64;
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![Event::Start(echo_addr.to_string()), Event::End(len, len, None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_counts_delivered_bytes() {
+        // The client takes 1500 of the 2048 bytes the upstream sends, then its connection breaks
+        let client: Stream = Box::new(
+            tokio_test::io::Builder::new()
+                .write(&[b'x'; 1500])
+                .write_error(std::io::ErrorKind::ConnectionReset.into())
+                .build(),
+        );
+        let upstream: Stream = Box::new(
+            tokio_test::io::Builder::new()
+                .read(&[b'x'; 2048])
+                .wait(Duration::from_secs(60))
+                .build(),
+        );
+
+        let observer = Arc::new(RecordingObserver::default());
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let app = ProxyApp::new(BasicPeer::new("127.0.0.1:1"), "127.0.0.1:0".to_string(), id_manager.clone());
+        let conn_info = ConnectionInfo::new(ClientAddr::Inet("127.0.0.1:40000".parse().unwrap()), "127.0.0.1:0", "127.0.0.1:1", 1, &id_manager)
+            .with_observer(Some(observer.clone()));
+        let extras = DuplexExtras { registration: None, mirror: None, preamble: Vec::new(), reuse_key: None };
+        app.duplex(client, upstream, conn_info, Arc::new(AtomicU64::new(1)), extras).await;
+
+        let events = observer.events.lock().unwrap();
+        let Some(Event::End(sent, received, Some(_))) = events.last() else {
+            panic!("Should end with the write error: {:?}", events);
+        };
+        // Only the first read was flushed before the write failed
+        assert_eq!((*sent, *received), (1024, 0));
+        assert_eq!(app.traffic.bytes_sent(), 1024);
+    }
+
+    async fn echo_backend() -> SocketAddr {
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_backend_traffic_tracked_per_peer() {
+        let (first, second) = (echo_backend().await, echo_backend().await);
+        let backend_traffic = Arc::new(BackendTraffic::default());
+        let options = ProxyOptions { backend_traffic: Some(backend_traffic.clone()), ..Default::default() };
+        let pool = UpstreamPool::new(upstream_peers(&format!("{}|{}", first, second)).unwrap(), balancer::LbStrategy::RoundRobin);
+        let app = Arc::new(ProxyApp::with_pool(pool, "127.0.0.1:0".to_string(), Arc::new(ConnectionIdManager::new(None, None)), options));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // Round robin sends these to the first, second and first backend again
+        for payload in [&b"one"[..], &b"second backend"[..], &b"three"[..]] {
+            let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+            let relay = {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    let (_tx, shutdown) = tokio::sync::watch::channel(false);
+                    app.process_new(io, &shutdown).await
+                })
+            };
+            client.write_all(payload).await.unwrap();
+            let mut echoed = vec![0u8; payload.len()];
+            client.read_exact(&mut echoed).await.unwrap();
+            drop(client);
+            relay.await.unwrap();
         }
+
+        let totals = backend_traffic.snapshot();
+        assert_eq!(totals.len(), 2);
+        let first_totals = &totals[&first.to_string()];
+        assert_eq!((first_totals.connections, first_totals.bytes_sent, first_totals.bytes_received), (2, 8, 8));
+        let second_totals = &totals[&second.to_string()];
+        assert_eq!((second_totals.connections, second_totals.bytes_sent, second_totals.bytes_received), (1, 14, 14));
     }
 
-    #[test]
-    fn test_duplex_event_sizes() {
-        assert_eq!(std::mem::size_of::<DuplexEvent>(), 16);
+    #[tokio::test]
+    async fn test_sink_holds_then_closes() {
+        async fn sink_connection(hold: Option<Duration>) -> Duration {
+            let app = Arc::new(ProxyApp::sink("127.0.0.1:0".to_string(), Arc::new(ConnectionIdManager::new(None, None)), hold, ProxyOptions::default()));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+            let started = std::time::Instant::now();
+            tokio::spawn(async move {
+                let (_tx, shutdown) = tokio::sync::watch::channel(false);
+                app.process_new(io, &shutdown).await
+            });
+            // What a held client sends goes nowhere, and nothing comes back before the close
+            if hold.is_some() {
+                client.write_all(b"anyone there?").await.unwrap();
+            }
+            let mut received = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+                .await
+                .expect("The sink should close the connection")
+                .unwrap();
+            assert!(received.is_empty(), "{:?}", received);
+            started.elapsed()
+        }
+
+        let hold = Duration::from_millis(300);
+        let held = sink_connection(Some(hold)).await;
+        assert!(held >= hold && held < hold * 3, "Should close once the hold is over: {:?}", held);
+        let closed = sink_connection(None).await;
+        assert!(closed < hold, "Without a hold the sink closes at once: {:?}", closed);
+    }
+
+    #[tokio::test]
+    async fn test_shedding_between_marks() {
+        let backend = echo_backend().await;
+        let options = ProxyOptions { shed: Some(ShedMarks::new(2, 1).unwrap()), ..Default::default() };
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new(&backend.to_string()),
+            "127.0.0.1:0".to_string(),
+            Arc::new(ConnectionIdManager::new(None, None)),
+            options,
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // Returns the client once the proxy has either relayed a byte through it or closed it
+        let open = || async {
+            let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+            let app = app.clone();
+            let relay = tokio::spawn(async move {
+                let (_tx, shutdown) = tokio::sync::watch::channel(false);
+                app.process_new(io, &shutdown).await
+            });
+            let _ = client.write_all(b"x").await;
+            let mut echoed = [0u8; 1];
+            let relayed = matches!(client.read(&mut echoed).await, Ok(1));
+            (client, relay, relayed)
+        };
+
+        let (first, first_relay, relayed) = open().await;
+        assert!(relayed);
+        let (second, second_relay, relayed) = open().await;
+        assert!(relayed);
+
+        // At the high-water mark new connections are closed straight away
+        let (_, _, relayed) = open().await;
+        assert!(!relayed, "Should shed at the high-water mark");
+
+        // One left is not below the low-water mark yet
+        drop(first);
+        first_relay.await.unwrap();
+        let (_, _, relayed) = open().await;
+        assert!(!relayed, "Should keep shedding until below the low-water mark");
+
+        drop(second);
+        second_relay.await.unwrap();
+        let (_, _, relayed) = open().await;
+        assert!(relayed, "Should accept again below the low-water mark");
+    }
+
+    /// Hands one client connection to `app`, returning the client and the relay task
+    async fn relay_one(app: &Arc<ProxyApp>, listener: &tokio::net::TcpListener) -> (tokio::net::TcpStream, tokio::task::JoinHandle<Option<Stream>>) {
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+        let app = app.clone();
+        let relay = tokio::spawn(async move {
+            let (_tx, shutdown) = tokio::sync::watch::channel(false);
+            app.process_new(io, &shutdown).await
+        });
+        (client, relay)
+    }
+
+    async fn echoes(client: &mut tokio::net::TcpStream) -> bool {
+        let _ = client.write_all(b"x").await;
+        let mut echoed = [0u8; 1];
+        matches!(client.read(&mut echoed).await, Ok(1))
+    }
+
+    #[tokio::test]
+    async fn test_upstream_reuse_keeps_connection_for_next_client() {
+        // Echoes on every connection it accepts, counting them
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let accepts = Arc::new(AtomicU64::new(0));
+        tokio::spawn({
+            let accepts = accepts.clone();
+            async move {
+                while let Ok((mut socket, _)) = backend.accept().await {
+                    accepts.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let (mut reader, mut writer) = socket.split();
+                        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                    });
+                }
+            }
+        });
+        let app_with = |upstream_reuse| {
+            let options = ProxyOptions { upstream_reuse, ..Default::default() };
+            let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+            Arc::new(ProxyApp::with_options(BasicPeer::new(&backend_addr.to_string()), "127.0.0.1:0".to_string(), id_manager, options))
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let pooled = app_with(Some(UpstreamReuse::new(4).unwrap()));
+        for _ in 0..3 {
+            let (mut client, relay) = relay_one(&pooled, &listener).await;
+            assert!(echoes(&mut client).await);
+            drop(client);
+            relay.await.unwrap();
+        }
+        assert_eq!(accepts.load(Ordering::SeqCst), 1, "Later clients should reuse the first upstream connection");
+
+        // Without reuse every client gets its own upstream connection
+        let unpooled = app_with(None);
+        for _ in 0..2 {
+            let (mut client, relay) = relay_one(&unpooled, &listener).await;
+            assert!(echoes(&mut client).await);
+            drop(client);
+            relay.await.unwrap();
+        }
+        assert_eq!(accepts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries_bounded_by_connect_timeout() {
+        // A SOCKS5 proxy that drops the first handshake late and stalls every later one
+        let socks = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_addr = socks.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (first, _) = socks.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            drop(first);
+            let mut stalled = Vec::new();
+            while let Ok((socket, _)) = socks.accept().await {
+                stalled.push(socket);
+            }
+        });
+        let options = ProxyOptions {
+            upstream_socks5: Some(socks5::Socks5Upstream { addr: socks_addr, credentials: None }),
+            connect_timeout: Some(Duration::from_millis(400)),
+            connect_retry: ConnectRetry { attempts: 3, base_delay: Duration::from_millis(20), max_delay: Duration::from_millis(20) },
+            ..Default::default()
+        };
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new("127.0.0.1:9"),
+            "127.0.0.1:0".to_string(),
+            Arc::new(ConnectionIdManager::new(None, None)),
+            options,
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // The retry starts in time, but may only use what is left of the 400ms
+        let started = std::time::Instant::now();
+        let (_client, relay) = relay_one(&app, &listener).await;
+        relay.await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(600), "Retries should stop at the connect timeout: {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_mirror_does_not_delay_primary() {
+        let backend = echo_backend().await;
+        // A listener that never accepts stops answering SYNs once its backlog is full
+        let mirror = tokio::net::TcpSocket::new_v4().unwrap();
+        mirror.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mirror = mirror.listen(0).unwrap();
+        let mirror_addr = mirror.local_addr().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(200), tokio::net::TcpStream::connect(mirror_addr)).await {
+            queued.push(stream);
+        }
+        let options = ProxyOptions { mirror: Some(mirror_addr), ..Default::default() };
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new(&backend.to_string()),
+            "127.0.0.1:0".to_string(),
+            Arc::new(ConnectionIdManager::new(None, None)),
+            options,
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let started = std::time::Instant::now();
+        let (mut client, relay) = relay_one(&app, &listener).await;
+        assert!(echoes(&mut client).await);
+        assert!(started.elapsed() < Duration::from_secs(1), "The primary should not wait for the mirror: {:?}", started.elapsed());
+        drop(client);
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backend_limit_refuses_over_cap() {
+        let backend = echo_backend().await;
+        let limiter = Arc::new(limiter::BackendLimiter::new(1, None).unwrap());
+        let options = ProxyOptions { backend_limiter: Some(limiter.clone()), ..Default::default() };
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new(&backend.to_string()),
+            "127.0.0.1:0".to_string(),
+            Arc::new(ConnectionIdManager::new(None, None)),
+            options,
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (mut first, first_relay) = relay_one(&app, &listener).await;
+        assert!(echoes(&mut first).await);
+        assert_eq!(limiter.in_use(&backend.to_string()), 1);
+
+        let (mut second, second_relay) = relay_one(&app, &listener).await;
+        assert!(!echoes(&mut second).await, "Should close a connection over the backend's limit");
+        second_relay.await.unwrap();
+
+        // Closing the first frees its slot
+        drop(first);
+        first_relay.await.unwrap();
+        assert_eq!(limiter.in_use(&backend.to_string()), 0);
+        let (mut third, _) = relay_one(&app, &listener).await;
+        assert!(echoes(&mut third).await, "Should accept once the backend has a free slot");
+    }
+
+    #[tokio::test]
+    async fn test_backend_limit_follows_retried_backend() {
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let backend = echo_backend().await;
+        let limiter = Arc::new(limiter::BackendLimiter::new(1, None).unwrap());
+        let options = ProxyOptions {
+            backend_limiter: Some(limiter.clone()),
+            connect_retry: ConnectRetry { attempts: 2, ..Default::default() },
+            ..Default::default()
+        };
+        // Round robin picks the dead backend first, the retry moves to the live one
+        let pool = UpstreamPool::new(upstream_peers(&format!("{}|{}", dead, backend)).unwrap(), balancer::LbStrategy::RoundRobin);
+        let app = Arc::new(ProxyApp::with_pool(pool, "127.0.0.1:0".to_string(), Arc::new(ConnectionIdManager::new(None, None)), options));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (mut client, relay) = relay_one(&app, &listener).await;
+        assert!(echoes(&mut client).await);
+        assert_eq!(limiter.in_use(&dead.to_string()), 0, "The dead backend's slot should be given back");
+        assert_eq!(limiter.in_use(&backend.to_string()), 1, "The slot should be held on the backend actually connected");
+
+        drop(client);
+        relay.await.unwrap();
+        assert_eq!(limiter.in_use(&backend.to_string()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_backend_limit_queues_for_wait() {
+        let backend = echo_backend().await;
+        let limiter = Arc::new(limiter::BackendLimiter::new(1, Some(Duration::from_secs(10))).unwrap());
+        let options = ProxyOptions { backend_limiter: Some(limiter), ..Default::default() };
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new(&backend.to_string()),
+            "127.0.0.1:0".to_string(),
+            Arc::new(ConnectionIdManager::new(None, None)),
+            options,
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (mut first, first_relay) = relay_one(&app, &listener).await;
+        assert!(echoes(&mut first).await);
+
+        // The second waits for the first's slot instead of being refused
+        let (mut second, _) = relay_one(&app, &listener).await;
+        let queued = tokio::spawn(async move { echoes(&mut second).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!queued.is_finished(), "Should hold the second connection while the backend is full");
+
+        drop(first);
+        first_relay.await.unwrap();
+        let relayed = tokio::time::timeout(Duration::from_secs(5), queued).await.unwrap().unwrap();
+        assert!(relayed, "Should relay the queued connection once the slot is free");
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_queues_across_listeners() {
+        let backend = echo_backend().await;
+        let limiter = Arc::new(limiter::ConnectionLimiter::new(1, Some(Duration::from_secs(10))).unwrap());
+        let listener_app = || {
+            let options = ProxyOptions { connection_limiter: Some(limiter.clone()), ..Default::default() };
+            Arc::new(ProxyApp::with_options(
+                BasicPeer::new(&backend.to_string()),
+                "127.0.0.1:0".to_string(),
+                Arc::new(ConnectionIdManager::new(None, None)),
+                options,
+            ))
+        };
+        let (web, ssh) = (listener_app(), listener_app());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (mut first, first_relay) = relay_one(&web, &listener).await;
+        assert!(echoes(&mut first).await);
+        assert_eq!(limiter.in_use(), 1);
+
+        // The limit is shared, so the other listener's client waits for the slot
+        let (mut second, _) = relay_one(&ssh, &listener).await;
+        let queued = tokio::spawn(async move { echoes(&mut second).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!queued.is_finished(), "Should hold the second connection while the proxy is full");
+
+        drop(first);
+        first_relay.await.unwrap();
+        let relayed = tokio::time::timeout(Duration::from_secs(5), queued).await.unwrap().unwrap();
+        assert!(relayed, "Should relay the queued connection once the slot is free");
+    }
+
+    #[tokio::test]
+    async fn test_admission_sheds_dials_to_failing_upstream() {
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let options = ProxyOptions { admission_ratio: Some(0.5), ..Default::default() };
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new(&refused.to_string()),
+            "127.0.0.1:0".to_string(),
+            Arc::new(ConnectionIdManager::new(None, None)),
+            options,
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // Accepting everything would dial once per client
+        for _ in 0..100 {
+            let (_client, relay) = relay_one(&app, &listener).await;
+            assert!(relay.await.unwrap().is_none());
+        }
+        let attempts = app.admission.as_ref().unwrap().attempts(&refused.to_string());
+        assert!((10..=40).contains(&attempts), "Should dial far fewer than 100 times, dialed {}", attempts);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_accept_rate_paces_a_burst() {
+        let backend = echo_backend().await;
+        let options = ProxyOptions { accept_rate: Some(50.0), ..Default::default() };
+        let app = Arc::new(ProxyApp::with_options(
+            BasicPeer::new(&backend.to_string()),
+            "127.0.0.1:0".to_string(),
+            Arc::new(ConnectionIdManager::new(None, None)),
+            options,
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let started = std::time::Instant::now();
+        let mut clients = Vec::new();
+        for _ in 0..25 {
+            let (client, _relay) = relay_one(&app, &listener).await;
+            clients.push(tokio::spawn(async move {
+                let mut client = client;
+                assert!(echoes(&mut client).await, "Paced connections are delayed, not dropped");
+                started.elapsed()
+            }));
+        }
+        let mut relayed_at = Vec::new();
+        for client in clients {
+            relayed_at.push(client.await.unwrap());
+        }
+        relayed_at.sort();
+
+        // Five pass at once, then the other twenty come 20ms apart
+        assert!(relayed_at[4] < Duration::from_millis(100), "The bucket's burst should pass at once: {:?}", relayed_at);
+        let last = relayed_at[24];
+        assert!(last >= Duration::from_millis(350) && last < Duration::from_millis(1000), "Should take about 400ms: {:?}", relayed_at);
+    }
+
+    #[tokio::test]
+    async fn test_transparent_relays_to_original_destination() {
+        // Without a redirect the original destination is the accepted socket's
+        // own address, so the relayed connection comes back to this listener
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let app = Arc::new(ProxyApp::transparent("127.0.0.1:0".to_string(), Arc::new(ConnectionIdManager::new(None, None)), ProxyOptions::default()));
+
+        let mut client = tokio::net::TcpStream::connect(target).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+        let relay = tokio::spawn(async move {
+            let (_tx, shutdown) = tokio::sync::watch::channel(false);
+            app.process_new(io, &shutdown).await
+        });
+        let (mut upstream, _) = listener.accept().await.unwrap();
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        upstream.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        drop(client);
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transparent_refuses_direct_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Arc::new(ProxyApp::transparent(addr.to_string(), Arc::new(ConnectionIdManager::new(None, None)), ProxyOptions::default()));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(accepted));
+        let (_tx, shutdown) = tokio::sync::watch::channel(false);
+        assert!(app.process_new(io, &shutdown).await.is_none());
+
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0, "Should be closed instead of relayed to itself");
+    }
+}
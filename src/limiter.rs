@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Tunnels reach arbitrary targets; past this many backends the idle ones are dropped
+const PRUNE_AT: usize = 1024;
+
+/// Caps how many connections are open to each backend address at once,
+/// across every listener sharing the limiter. A connection holds its
+/// backend's permit until it ends.
+#[derive(Debug)]
+pub struct BackendLimiter {
+    limit: usize,
+    wait: Option<Duration>,
+    backends: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl BackendLimiter {
+    /// Allows `limit` connections per backend. With `wait`, a connection over
+    /// the limit queues that long for one to end; without, it is refused at once.
+    pub fn new(limit: usize, wait: Option<Duration>) -> Result<Self, String> {
+        if limit == 0 || limit > Semaphore::MAX_PERMITS {
+            return Err(format!("Backend connection limit must be between 1 and {}", Semaphore::MAX_PERMITS));
+        }
+        Ok(BackendLimiter { limit, wait, backends: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn wait(&self) -> Option<Duration> {
+        self.wait
+    }
+
+    /// A slot for one connection to `backend`, or `None` if none came free in time
+    pub async fn acquire(&self, backend: &str) -> Option<OwnedSemaphorePermit> {
+        let semaphore = {
+            let mut backends = self.backends.lock().unwrap_or_else(PoisonError::into_inner);
+            if backends.len() >= PRUNE_AT && !backends.contains_key(backend) {
+                backends.retain(|_, semaphore| semaphore.available_permits() < self.limit);
+            }
+            backends
+                .entry(backend.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+                .clone()
+        };
+        acquire_within(semaphore, self.wait).await
+    }
+
+    /// Connections open to `backend` right now
+    pub fn in_use(&self, backend: &str) -> usize {
+        let backends = self.backends.lock().unwrap_or_else(PoisonError::into_inner);
+        backends.get(backend).map_or(0, |semaphore| self.limit - semaphore.available_permits())
+    }
+}
+
+/// Caps how many connections the proxy relays at once, over every listener
+/// sharing the limiter. A connection holds its permit until it ends.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    limit: usize,
+    wait: Option<Duration>,
+    slots: Arc<Semaphore>,
+}
+
+impl ConnectionLimiter {
+    /// Allows `limit` connections at once. With `wait`, a connection over the
+    /// limit queues that long for one to end; without, it is refused at once.
+    pub fn new(limit: usize, wait: Option<Duration>) -> Result<Self, String> {
+        if limit == 0 || limit > Semaphore::MAX_PERMITS {
+            return Err(format!("Connection limit must be between 1 and {}", Semaphore::MAX_PERMITS));
+        }
+        Ok(ConnectionLimiter { limit, wait, slots: Arc::new(Semaphore::new(limit)) })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn wait(&self) -> Option<Duration> {
+        self.wait
+    }
+
+    /// A slot for one connection, or `None` if none came free in time
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        acquire_within(self.slots.clone(), self.wait).await
+    }
+
+    /// Connections holding a slot right now
+    pub fn in_use(&self) -> usize {
+        self.limit - self.slots.available_permits()
+    }
+}
+
+async fn acquire_within(semaphore: Arc<Semaphore>, wait: Option<Duration>) -> Option<OwnedSemaphorePermit> {
+    match wait {
+        Some(wait) => tokio::time::timeout(wait, semaphore.acquire_owned()).await.ok()?.ok(),
+        None => semaphore.try_acquire_owned().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refuses_over_limit() {
+        let limiter = BackendLimiter::new(2, None).unwrap();
+        let first = limiter.acquire("10.0.0.1:80").await.expect("Should get a slot");
+        let _second = limiter.acquire("10.0.0.1:80").await.expect("Should get a slot");
+        assert!(limiter.acquire("10.0.0.1:80").await.is_none(), "Should refuse a third connection");
+        assert_eq!(limiter.in_use("10.0.0.1:80"), 2);
+
+        // Other backends have limits of their own
+        assert!(limiter.acquire("10.0.0.2:80").await.is_some());
+
+        drop(first);
+        assert!(limiter.acquire("10.0.0.1:80").await.is_some(), "A finished connection frees its slot");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queues_for_wait() {
+        let limiter = Arc::new(BackendLimiter::new(1, Some(Duration::from_secs(5))).unwrap());
+        let held = limiter.acquire("10.0.0.1:80").await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        assert!(limiter.acquire("10.0.0.1:80").await.is_none(), "Should give up after the wait");
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("10.0.0.1:80").await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        drop(held);
+        assert!(queued.await.unwrap(), "Should get the slot once it is released");
+
+        assert!(BackendLimiter::new(0, None).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_limit() {
+        let limiter = ConnectionLimiter::new(1, Some(Duration::from_secs(2))).unwrap();
+        let held = limiter.acquire().await.expect("Should get a slot");
+        assert_eq!(limiter.in_use(), 1);
+
+        let started = tokio::time::Instant::now();
+        assert!(limiter.acquire().await.is_none(), "Should give up after the wait");
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+
+        drop(held);
+        assert!(limiter.acquire().await.is_some());
+        assert!(ConnectionLimiter::new(1, None).unwrap().acquire().await.is_some());
+        assert!(ConnectionLimiter::new(0, None).is_err());
+    }
+}
@@ -1,15 +1,61 @@
+#[cfg(feature = "jemalloc")]
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+// Builds without the `jemalloc` feature (static musl targets, say) use the system allocator
+#[cfg(not(feature = "jemalloc"))]
+#[global_allocator]
+static GLOBAL: std::alloc::System = std::alloc::System;
+
 use clap::{CommandFactory, Parser};
 use pingora_core::server::{configuration::Opt, Server};
+use pingora_core::listeners::Listeners;
+use pingora_core::services::background::background_service;
+use pingora_core::services::listening::Service;
 use std::env;
+use std::io::IsTerminal;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
 use std::process;
 use std::sync::Arc;
-use tracing::{error, info};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+#[cfg(feature = "otlp")]
+use tracing::Level;
+use tracing_subscriber::filter::EnvFilter;
+#[cfg(feature = "otlp")]
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use pj::{check_mappings, check_proxy_loop, upstream_peers, upstream_tiers, mapping_file_entries, mapping_list_entries, parse_mapping_entry, parse_proxy_mappings, proxy_app, AdaptiveBuffer, AdaptiveIdle, BackendTraffic, BindFailure, ClientSubnets, ConnectRetry, DelayDirection, Dscp, FailResponse, FlushMode, InjectedDelay, ListenMode, ProxyApp, ProxyMapping, ProxyOptions, ShedMarks, SourcePorts};
+use pj::connect::ConnectAllowlist;
+use pj::connection::ConnLogLevels;
+use pj::sni::SniRoutes;
+use pj::socks5::{parse_credentials, Socks5Config, Socks5Upstream};
+use pj::admin::{admin_service, MappingInfo};
+use pj::admission::parse_threshold;
+use pj::balancer::LbStrategy;
+use pj::limiter::{BackendLimiter, ConnectionLimiter};
+use pj::metrics::{LatencyHistogram, SizeHistogram};
+use pj::options::DEFAULT_IDLE_RTTS;
+use pj::relay::RELAY_BUFFER_SIZE;
+use pj::discovery::{DiscoveredUpstream, UpstreamCommand};
+use pj::id_manager::{ConnectionIdManager, parse_duration, parse_count, parse_number};
+use pj::rate::{parse_rate, ConnectionRate, RateReporter, RATE_WINDOW_SECS};
+use pj::heartbeat::{Heartbeat, DEFAULT_HEARTBEAT_INTERVAL};
+use pj::rebind::RebindingListener;
+use pj::registry::ConnectionRegistry;
+use pj::stats_port::StatsPort;
+use pj::statsd::StatsdClient;
+use pj::summary::{Concurrency, ShutdownSummary};
+use pj::telemetry::{log_layer, LogColor, LogTime, SPAN_TARGET};
+#[cfg(feature = "otlp")]
+use pj::telemetry::{otlp_layer, SpanFlush};
 
-use pj::{parse_proxy_mapping, proxy_service, ProxyMapping};
-use pj::id_manager::{ConnectionIdManager, parse_duration, parse_count};
+/// Mappings started when PJ_MAX_MAPPINGS isn't set; a list longer than this is
+/// more likely a templating mistake than a real configuration
+const DEFAULT_MAX_MAPPINGS: u64 = 1024;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -19,6 +65,10 @@ use pj::id_manager::{ConnectionIdManager, parse_duration, parse_count};
     after_help = "ENVIRONMENT VARIABLES:
   PJ_PROXY    Single proxy mapping (same format as --proxy)
   PJ_PROXIES  Multiple proxy mappings, comma or semicolon separated
+              (${VAR} in any mapping is expanded from the environment)
+              @<path> reads them from a file instead, one per line; lines starting with # are comments
+  PJ_MAX_MAPPINGS  Refuse to start with more mappings than this, from whichever source
+              Default: 1024
   PJ_LOG      Set logging level (error, warn, info, debug, trace)
               Default: info
               Examples: 
@@ -26,16 +76,300 @@ use pj::id_manager::{ConnectionIdManager, parse_duration, parse_count};
                 PJ_LOG=pj=trace - Trace logging for pj module only
                 PJ_LOG=warn,pj=info - Warn globally, info for pj
               Note: Falls back to RUST_LOG if PJ_LOG is not set
+  PJ_LOG_COLOR  Color log lines: auto (only on a terminal), always, never
+              Default: auto
+  PJ_LOG_TIME   Timestamp on log lines: rfc3339 (wall clock), uptime (since start), none
+              Default: rfc3339
   
   PJ_CONN_ID_RESET_INTERVAL  Time interval for connection ID reset
-              Format: [number][unit] (d=days, h=hours, m=minutes, s=seconds)
+              Format: [number][unit] (w=weeks, d=days, h=hours, m=minutes, s=seconds, ms=milliseconds)
+              Each unit may appear once
               Default: None (no reset by time)
-              Examples: 6h, 30m, 1d, 1d12h
+              Examples: 6h, 30m, 1d, 1d12h, 2w
   
   PJ_CONN_ID_RESET_COUNT     Count threshold for connection ID reset
-              Format: number or with units (k=thousand, m=million, g=billion)
+              Format: number or with units (k=thousand, m=million, g=billion, t=trillion)
               Default: None (no reset by count)
-              Examples: 100k, 10m, 1g, 500000
+              Examples: 100k, 1.5m, 10m, 1g, 500000
+  
+  PJ_CONN_ID_OFFSET          First connection ID, and where IDs go back to on reset,
+              to tell instances apart in merged logs
+              Format: same as PJ_CONN_ID_RESET_COUNT, or 0
+              Default: 0
+              Example: 1m
+  
+  PJ_CONN_ID_WIDTH           Zero-pad connection IDs in logs to this many digits
+              Default: None (no padding)
+              Example: 8
+  
+  PJ_MAX_LIFETIME            Close each connection once it has been open this long
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (no limit)
+              Examples: 1h, 30m
+  
+  PJ_LONG_CONN_THRESHOLD     Log each connection still open after this long, and count it in the admin
+              API's pj_long_connections_total and StatsD's connections.long_lived; it is left open
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (disabled)
+              Example: 12h
+  
+  PJ_MAX_UP_BYTES            Close a connection once its client has sent this many bytes
+              Format: same as PJ_CONN_ID_RESET_COUNT
+              Default: None (no limit; 0 also means none)
+              Example: 10g
+  
+  PJ_MAX_DOWN_BYTES          Close a connection once it has relayed this many bytes to the client
+              Format: same as PJ_CONN_ID_RESET_COUNT
+              Default: None (no limit; 0 also means none)
+  
+  PJ_SHED_HIGH               Stop accepting on a listener once it has this many active connections;
+  PJ_SHED_LOW                new ones are closed at once until fewer than PJ_SHED_LOW remain
+              Format: same as PJ_CONN_ID_RESET_COUNT; set both or neither
+              Default: None (never shed)
+              Example: PJ_SHED_HIGH=1000 PJ_SHED_LOW=800
+  
+  PJ_ADMISSION_RATIO         Shed new connections for an upstream while fewer than this share of its
+              connects over the last 10-20 seconds succeed; the further below, the more are shed
+              Format: a ratio above 0 and at most 1
+              Default: None (every connection dials its upstream)
+              Example: 0.5
+  
+  PJ_ACCEPT_RATE             Take at most this many new connections per second on each listener;
+              faster arrivals wait their turn instead of being refused, smoothing bursts
+              Format: <count>/s or <count>/m (bursts of a tenth of a second's worth pass at once)
+              Default: None (no pacing)
+              Example: 500/s
+  
+  PJ_MAX_CONNECTIONS         Relay at most this many connections at once, across all listeners
+              Format: same as PJ_CONN_ID_RESET_COUNT
+              Default: None (no limit)
+  
+  PJ_QUEUE_TIMEOUT           How long a connection over PJ_MAX_CONNECTIONS waits for a slot before it is closed
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (closed at once)
+              Example: PJ_MAX_CONNECTIONS=10000 PJ_QUEUE_TIMEOUT=2s
+  
+  PJ_MAX_BACKEND_CONNS       Open at most this many connections to each backend address, across all listeners
+              Format: same as PJ_CONN_ID_RESET_COUNT
+              Default: None (no limit)
+  
+  PJ_BACKEND_QUEUE_TIMEOUT   How long a connection over PJ_MAX_BACKEND_CONNS waits for a slot before it is closed
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (closed at once)
+              Example: PJ_MAX_BACKEND_CONNS=100 PJ_BACKEND_QUEUE_TIMEOUT=5s
+  
+  PJ_HEARTBEAT_INTERVAL      How often to log active/total connections and bytes relayed
+              Format: same as PJ_CONN_ID_RESET_INTERVAL, or 0 to turn it off
+              Default: 60s
+  
+  PJ_REBIND_INTERVAL         Check this often that listeners on a specific IP still have their address,
+              closing one whose address went away (DHCP, failover) and rebinding it once it returns
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (listeners are bound once at startup)
+              Example: 5s
+  
+  PJ_BIND_FAILURE            What to do when a listener can't be bound at startup (Unix): abort exits with
+              an error, skip logs it and starts the other mappings, exiting only if none bind
+              Format: abort | skip
+              Default: None (a failure is logged and the rest keep running)
+              Example: skip
+  
+  PJ_IDLE_TIMEOUT            Close connections with no traffic in either direction for this long
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (no limit)
+              Override per mapping with ?idle=<duration>
+              Examples: 1h, 30s
+  
+  PJ_IDLE_TIMEOUT_MIN        Scale each connection's idle timeout with its round trip (client read to the
+  PJ_IDLE_TIMEOUT_MAX        upstream's next read): PJ_IDLE_TIMEOUT_RTTS smoothed round trips, kept between
+  PJ_IDLE_TIMEOUT_RTTS       the bounds; until one is measured, PJ_IDLE_TIMEOUT within them (or the maximum)
+              Format: same as PJ_CONN_ID_RESET_INTERVAL; PJ_IDLE_TIMEOUT_RTTS a whole number; set both bounds or neither
+              Default: None (the fixed PJ_IDLE_TIMEOUT); PJ_IDLE_TIMEOUT_RTTS 100
+              Example: PJ_IDLE_TIMEOUT_MIN=30s PJ_IDLE_TIMEOUT_MAX=2h
+  
+  PJ_WRITE_TIMEOUT           Close a connection when relaying data to one side (the write and its flush)
+              takes longer than this, e.g. a client that stopped reading while the upstream sends
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (writes wait as long as they need)
+              Example: 30s
+  
+  PJ_CONNECT_TIMEOUT         Give up on an upstream connect after this long
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (the OS default)
+              Override per mapping with ?connect=<duration>
+              Example: 5s
+  
+  PJ_CONNECT_ATTEMPTS        Upstream connect attempts per connection, including the first
+              Retries back off exponentially with jitter, within PJ_CONNECT_TIMEOUT
+              Default: 1 (no retries)
+              Example: 3
+  
+  PJ_RETRY_BASE_MS           Delay before the first retry in milliseconds, doubled for each next one
+              Default: 50
+  
+  PJ_RETRY_MAX_MS            Upper bound for the delay between retries in milliseconds
+              Default: 1000
+  
+  PJ_FAIL_RESPONSE           Bytes sent to the client before closing it when the upstream connect fails
+              Values: http502, http503, or raw text with \\r \\n \\t \\\\ \\xHH escapes
+              Default: None (the connection is just closed)
+              Example: 'SSH-2.0-unavailable\\r\\n'
+  
+  PJ_FORWARDED_FOR           Add the client's IP to X-Forwarded-For on connections ?http=<ip:port> routes
+              to its HTTP upstream (1 or true); only each connection's first request is rewritten
+  
+  PJ_LB_STRATEGY             How mappings with several upstreams (joined by |) pick one
+              Values: round_robin (weighted), ip_hash (same client IP, same upstream)
+              Default: round_robin
+  
+  PJ_FLUSH_MODE              When relayed bytes are flushed to the other side
+              Values: immediate (after every write), coalesce (batch full reads for up to 5ms),
+              buffered (collect up to 64 KB and flush only once the sender pauses or the connection closes)
+              Default: immediate
+  
+  PJ_BUFFER_MAX              Grow each connection's read buffers up to this many bytes while reads keep
+  PJ_BUFFER_MIN              filling them, shrinking back towards PJ_BUFFER_MIN while they don't;
+  PJ_BUFFER_GROWTH           each step grows or shrinks them PJ_BUFFER_GROWTH times
+              Format: same as PJ_CONN_ID_RESET_COUNT; PJ_BUFFER_MIN and PJ_BUFFER_GROWTH need PJ_BUFFER_MAX
+              Default: None (fixed 1024 byte buffers); PJ_BUFFER_MIN 1024, PJ_BUFFER_GROWTH 2
+              Example: PJ_BUFFER_MAX=256k
+  
+  PJ_DSCP                    DSCP to mark upstream connections with, for QoS
+              Values: 0-63, EF, CS0-CS7, AF11-AF43
+              Default: None (left to the OS)
+  
+  PJ_DSCP_DOWNSTREAM         Mark client connections with PJ_DSCP as well (1 or true)
+  
+  PJ_BIND_SOURCE             Local IP to bind upstream connections to
+              Default: None (chosen by the OS)
+              Override per mapping with ?bind=<ip>
+              Example: 10.0.0.5
+  
+  PJ_SOURCE_PORT_RANGE       Local port range upstream connections are bound within (Linux 6.3+)
+              Format: low-high, inside net.ipv4.ip_local_port_range
+              Default: None (chosen by the OS)
+              Connects fail once no port in the range is free for an upstream
+              Example: 40000-41000
+  
+  PJ_CONNECT_ALLOW           Targets connect:// and socks5:// listeners may reach, comma separated
+              Format: host:port, host may be *, *.domain or a CIDR block, port may be * or a range
+              Default: None (every CONNECT request is refused)
+              Example: *.example.com:443,127.0.0.1:*,10.1.0.0/16:8000-8999
+  
+  PJ_CONNECT_DENY            Targets refused even where PJ_CONNECT_ALLOW admits them
+              Format: same as PJ_CONNECT_ALLOW; CIDR blocks also apply to the addresses names resolve to
+              Default: None
+              Example: 10.0.0.0/8:*,172.16.0.0/12:*,192.168.0.0/16:*,*:25
+  
+  PJ_MAX_HANDSHAKE_BYTES     Close connect://, socks5:// and tls:// clients that send this many bytes without
+              completing their CONNECT request head, SOCKS5 handshake or TLS ClientHello
+              Format: same as PJ_CONN_ID_RESET_COUNT
+              Default: 8192
+              Example: 4k
+  
+  PJ_SOCKS5_AUTH             Username and password required by socks5:// listeners
+              Format: user:pass
+              Default: None (no authentication)
+  
+  PJ_UPSTREAM_SOCKS5         SOCKS5 proxy that upstream connections are tunneled through
+              Format: host:port; PJ_CONNECT_TIMEOUT also covers the proxy's handshake
+              Default: None (upstreams are dialed directly)
+              Example: egress.corp:1080
+  
+  PJ_UPSTREAM_SOCKS5_AUTH    Username and password for PJ_UPSTREAM_SOCKS5
+              Format: user:pass
+              Default: None (no authentication)
+  
+  PJ_PEEK_BYTES              Hex dump up to N bytes of each connection's first read
+              Logged at debug level (requires PJ_LOG=debug)
+              Default: None (disabled)
+              Example: 64
+  
+  PJ_SLOW_IO_THRESHOLD       Log each relayed write (and its flush) that takes longer than this
+              Logged at debug level (requires PJ_LOG=debug)
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (disabled)
+              Example: 2s
+  
+  PJ_BACKPRESSURE_THRESHOLD  Mark a connection backpressured when a relayed write (and its flush)
+              waits longer than this on a side that isn't draining; reading from the
+              other side stops meanwhile, so memory per connection stays at the read buffer.
+              Shown in its close line and counted as connections.backpressured in StatsD
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (disabled)
+              Example: 500ms
+  
+  PJ_SELF_CHECK              Check each connection at close for bytes the relay dropped or duplicated,
+              logging an error on a mismatch; a harness for tests, debug builds only (1 or true)
+  
+  PJ_INJECT_DELAY            Sleep this long before forwarding each relayed read, to test clients against
+              a slow network; never set it in production
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (disabled)
+              Example: 50ms
+  
+  PJ_INJECT_DELAY_DIRECTION  Which traffic PJ_INJECT_DELAY slows: both, up (client to upstream), down
+              Default: both
+  
+  PJ_LOG_ACCEPT              Log each client the moment it is accepted, before the upstream is dialed (1 or true)
+              Logged at debug level (requires PJ_LOG=debug)
+  
+  PJ_LOG_CONN_LEVEL          Level of each connection's estab and close lines, to cut log volume
+              Format: one level for all, or start=<level>,end=<level>,empty=<level>
+              empty= is for close lines of connections that relayed nothing (health checks, port scans); it follows end unless set
+              Levels: trace, debug, info, warn, error, off; ends with an error are always logged at warn
+              Default: info
+              Example: start=debug,end=info,empty=debug
+  
+  PJ_UPSTREAM_CMD            Shell command printing the upstreams for the (single) forward mapping
+              Output: host:port entries, optionally *weight, separated by |, commas or whitespace
+              On failure the previous upstreams (at first, the mapping's own) are kept
+              Open connections keep their upstream; a change logs how many remain on dropped addresses
+              Default: None (the mapping's upstreams are used)
+              Example: \"consul-template -once -template upstreams.tpl:/dev/stdout\"
+  
+  PJ_UPSTREAM_CMD_INTERVAL   Re-run PJ_UPSTREAM_CMD this often
+              Format: same as PJ_CONN_ID_RESET_INTERVAL
+              Default: None (run once at startup)
+              Example: 30s
+  
+  PJ_STATSD_ADDR             StatsD server that connection metrics are sent to over UDP
+              Metrics: pj.connections.accepted, pj.connections.failed (counters),
+                       pj.connections.active (gauge), pj.connection.duration (timing)
+              Default: None (metrics disabled)
+              Example: 127.0.0.1:8125
+  
+  PJ_STATSD_TAGS             Add DogStatsD name/backend tags to the metrics (1 or true)
+              Default: false
+  
+  PJ_STATSD_CLIENT_SUBNET    Also tag connection and byte metrics with the client's subnet at these
+              prefix lengths, IPv4 then optionally IPv6; needs PJ_STATSD_TAGS
+              Default: None (no client_subnet tag); IPv6 defaults to /48
+              Example: /24,/48
+  
+  LISTEN_FDS / LISTEN_PID    Set by systemd socket activation; the inherited sockets are used
+              for the mappings in the order given instead of binding their addresses
+  
+  PJ_ADMIN_ADDR              Listen address for the admin HTTP API
+              Default: None (admin API disabled)
+              Endpoints: GET /connections - list active connections
+                         DELETE /connections/{key} - close a connection by its listed key
+                         GET /stats - connection rate over the last minute
+                         GET /info - version, build commit, uptime and mappings
+                         GET /listeners - listeners, whether each is paused and its last upstream error
+                         GET /metrics - Prometheus metrics, including upstream connect times
+              Example: 127.0.0.1:9900
+  
+  PJ_STATS_PORT              Port answering each line sent to it with the proxy's counters in Graphite
+              plaintext (key value timestamp) and closing; a port alone binds 127.0.0.1
+              Counters: pj.active_connections, pj.connections_total, pj.bytes_sent, pj.bytes_received
+              Default: None (disabled)
+              Example: 2003 or 0.0.0.0:2003
+  
+  PJ_OTLP_ENDPOINT           OpenTelemetry collector to export connection spans to (OTLP/HTTP);
+              needs a build with the otlp feature
+              Default: None (spans disabled)
+              Example: http://localhost:4318
 
 EXAMPLES:
   # Using command line arguments
@@ -49,51 +383,208 @@ EXAMPLES:
   PJ_LOG=debug pj --proxy 0.0.0.0:8787:127.0.0.1:22
   
   # With connection ID reset settings
-  PJ_CONN_ID_RESET_INTERVAL=6h PJ_CONN_ID_RESET_COUNT=100k pj --proxy 0.0.0.0:8787:127.0.0.1:22"
+  PJ_CONN_ID_RESET_INTERVAL=6h PJ_CONN_ID_RESET_COUNT=100k pj --proxy 0.0.0.0:8787:127.0.0.1:22
+  
+  # With the admin API enabled
+  PJ_ADMIN_ADDR=127.0.0.1:9900 pj --proxy 0.0.0.0:8787:127.0.0.1:22
+  
+  # Check the configuration without starting
+  pj --check --proxy 0.0.0.0:8787:127.0.0.1:22"
 )]
 struct Args {
-    /// Proxy mapping in format "listen_ip:listen_port:proxy_ip:proxy_port"
-    /// Can be specified multiple times for multiple mappings
-    #[arg(short, long, value_parser = parse_proxy_mapping)]
-    proxy: Vec<ProxyMapping>,
+    /// Proxy mapping in format "listen_ip:listen_port:proxy_ip:proxy_port",
+    /// or "connect://listen_ip:listen_port" / "socks5://listen_ip:listen_port" for forward proxies,
+    /// or "transparent://listen_ip:listen_port" to relay redirected connections to their original destination
+    /// or "sink://listen_ip:listen_port" to accept connections with no upstream and close them,
+    /// after hold=<duration> if given,
+    /// or "tls://listen_ip:listen_port" to relay TLS, still encrypted, by the server name in its ClientHello:
+    /// each sni=<server name>=<ip:port> setting routes one name, sni=*=<ip:port> any other (refused without it)
+    /// Join several upstreams with "|" to balance connections over them (see PJ_LB_STRATEGY);
+    /// suffix one with "*weight" to give it a larger share, e.g. "10.0.0.1:9000*3|10.0.0.2:9000"
+    /// Separate failover tiers with ">": later tiers only serve while every upstream before them is down,
+    /// e.g. "10.0.0.1:80|10.0.0.2:80>10.0.1.1:80"
+    /// Append settings after "?", joined with "&": name=<name> labels the mapping in logs,
+    /// bind=<ip> picks the upstream source IP, mirror=<ip:port> copies client bytes to a second upstream,
+    /// http=<ip:port> sends connections that start with an HTTP request there instead,
+    /// idle=<duration> and connect=<duration> override PJ_IDLE_TIMEOUT and PJ_CONNECT_TIMEOUT,
+    /// iface=<name> only accepts connections arriving on that network interface (Linux),
+    /// reuse=<pool size> keeps upstream connections whose client closed for later clients, for
+    /// request/response protocols like HTTP/1.1 keep-alive only; reuse_idle=<duration> is how long
+    /// each is kept (default 60s), reset_interval=<duration> and reset_count=<count> give the mapping
+    /// connection IDs of its own, reset on that policy instead of PJ_CONN_ID_RESET_INTERVAL/COUNT
+    /// Fan several listen addresses in to one upstream with "listen=<addr>,<addr> -> proxy_ip:proxy_port",
+    /// e.g. "listen=0.0.0.0:8080,[::]:8080 -> 10.0.0.1:9000"
+    /// Can be specified multiple times, or given several mappings separated by "," or ";"
+    #[arg(short, long, value_parser = parse_proxy_mappings)]
+    proxy: Vec<Vec<ProxyMapping>>,
+    
+    /// Validate the mappings and PJ_* settings, print what would be started and exit
+    /// without listening; any invalid setting fails the check
+    #[arg(long)]
+    check: bool,
+    
+    /// Print the mappings that would start, taken from --proxy, PJ_PROXIES or PJ_PROXY as at
+    /// startup, one "listen -> upstream" per line, and exit without listening; log lines go to
+    /// stderr so the list is all that is printed to stdout
+    #[arg(long)]
+    list_mappings: bool,
+}
+
+/// Reports a setting that is ignored when invalid. Under --check it fails the check instead.
+fn invalid_setting(check: bool, message: String) {
+    error!("{}", message);
+    if check {
+        process::exit(1);
+    }
+}
+
+/// The system's ephemeral port range, which bounds the ports PJ_SOURCE_PORT_RANGE can hand out
+fn ephemeral_ports() -> Option<(u16, u16)> {
+    let range = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok()?;
+    let mut ports = range.split_whitespace().map(|port| port.parse().ok());
+    Some((ports.next()??, ports.next()??))
+}
+
+/// Binds a mapping's listener up front when pingora can't bind it as needed:
+/// on port 0, where the service needs the port the OS picked, to an
+/// interface, or on IPv6, which pingora would let take the IPv4 port too.
+/// With `all`, as for the admin API, every mapping on an IP address is bound
+/// here so it is known which are up; with `resolve` too, as for
+/// PJ_BIND_FAILURE, a host name is bound to its first address so any failure
+/// is known before the server starts. Returns the socket and its address;
+/// other mappings are left to pingora.
+#[cfg(unix)]
+fn prebind(mapping: &ProxyMapping, all: bool, resolve: bool) -> std::io::Result<Option<(TcpListener, SocketAddr)>> {
+    let addr = match mapping.listen_addr.parse::<SocketAddr>() {
+        Ok(addr) if all || resolve || addr.port() == 0 || mapping.interface.is_some() || addr.is_ipv6() => addr,
+        Err(_) if resolve => mapping.listen_addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "the address resolved to nothing")
+        })?,
+        _ => return Ok(None),
+    };
+    let listener = pj::activation::bind_listener(addr, mapping.interface.as_deref())?;
+    let bound = listener.local_addr()?;
+    Ok(Some((listener, bound)))
+}
+
+/// One line per mapping for the --check summary
+fn describe_mapping(mapping: &ProxyMapping, upstream_cmd: bool) -> String {
+    let label = mapping.name.as_ref().map(|name| format!("'{}' ", name)).unwrap_or_default();
+    match mapping.mode {
+        ListenMode::Forward if upstream_cmd => format!("{}{} -> upstreams from PJ_UPSTREAM_CMD", label, mapping.listen_addr),
+        ListenMode::Forward => format!("{}{} -> {}", label, mapping.listen_addr, mapping.proxy_addr),
+        ListenMode::Connect => format!("{}{} (CONNECT proxy)", label, mapping.listen_addr),
+        ListenMode::Socks5 => format!("{}{} (SOCKS5 proxy)", label, mapping.listen_addr),
+        ListenMode::Transparent => format!("{}{} (transparent proxy)", label, mapping.listen_addr),
+        ListenMode::Sink => format!("{}{} (sink)", label, mapping.listen_addr),
+        ListenMode::Tls => format!("{}{} -> {} (by TLS server name)", label, mapping.listen_addr, MappingInfo::from(mapping).backend),
+    }
 }
 
 fn main() {
+    let started = Instant::now();
+    let args = Args::parse();
+    
     // Initialize tracing with PJ_LOG (fallback to RUST_LOG) environment variable support
     // Default to "info" if neither is set
     let filter = env::var("PJ_LOG")
         .or_else(|_| env::var("RUST_LOG"))
         .unwrap_or_else(|_| "info".to_string());
     
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::new(filter)
-        )
-        .init();
+    // Connection spans only exist for OTLP export; keep them out of the log lines
+    let log_filter = EnvFilter::new(filter).add_directive(
+        format!("{}=off", SPAN_TARGET).parse().expect("Valid span directive"),
+    );
     
-    let args = Args::parse();
+    let otlp_endpoint = env::var("PJ_OTLP_ENDPOINT").ok().filter(|s| !s.is_empty());
+    #[cfg(feature = "otlp")]
+    let (otlp, tracer_provider, otlp_error) = match otlp_endpoint.as_deref().map(otlp_layer) {
+        Some(Ok((layer, provider))) => (Some(layer.with_filter(Targets::new().with_target("pj", Level::INFO))), Some(provider), None),
+        Some(Err(e)) => (None, None, Some(e)),
+        None => (None, None, None),
+    };
+    
+    // Bad format settings fall back to the defaults until logging is up to report them
+    let log_color = env::var("PJ_LOG_COLOR").ok().filter(|s| !s.is_empty()).map(|s| LogColor::parse(&s));
+    let log_time = env::var("PJ_LOG_TIME").ok().filter(|s| !s.is_empty()).map(|s| LogTime::parse(&s));
+    let color = match &log_color {
+        Some(Ok(mode)) => *mode,
+        _ => LogColor::Auto,
+    };
+    let time = match &log_time {
+        Some(Ok(format)) => *format,
+        _ => LogTime::Rfc3339,
+    };
+    // Color depends on whether the stream the log lines go to is a terminal
+    let terminal = match args.list_mappings {
+        true => std::io::stderr().is_terminal(),
+        false => std::io::stdout().is_terminal(),
+    };
+    let log = log_layer(color.enabled(terminal), time, args.list_mappings);
+    
+    let subscriber = tracing_subscriber::registry().with(log.with_filter(log_filter));
+    #[cfg(feature = "otlp")]
+    let subscriber = subscriber.with(otlp);
+    subscriber.init();
+    
+    #[cfg(feature = "otlp")]
+    if let Some(e) = otlp_error {
+        error!("Invalid PJ_OTLP_ENDPOINT: {}", e);
+        process::exit(1);
+    }
+    if let Some(Err(e)) = log_color {
+        error!("Invalid PJ_LOG_COLOR: {}", e);
+        process::exit(1);
+    }
+    if let Some(Err(e)) = log_time {
+        error!("Invalid PJ_LOG_TIME: {}", e);
+        process::exit(1);
+    }
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = otlp_endpoint {
+        info!("Exporting connection spans to {}", endpoint);
+    }
+    #[cfg(not(feature = "otlp"))]
+    if otlp_endpoint.is_some() {
+        warn!("PJ_OTLP_ENDPOINT is set but pj was built without the otlp feature, ignoring it");
+    }
+    
+    let check = args.check;
     
     // Collect proxy mappings from command line or environment variables
     let mut proxy_mappings = Vec::new();
     
     // Priority 1: Command line arguments
     if !args.proxy.is_empty() {
-        proxy_mappings = args.proxy;
+        proxy_mappings = args.proxy.into_iter().flatten().collect();
         info!("Using proxy mappings from command line arguments");
     } 
     // Priority 2: PJ_PROXIES environment variable (multiple mappings)
-    else if let Ok(env_mappings) = env::var("PJ_PROXIES") {
-        for mapping_str in env_mappings.split(|c| c == ',' || c == ';') {
-            let trimmed = mapping_str.trim();
-            if !trimmed.is_empty() {
-                match parse_proxy_mapping(trimmed) {
-                    Ok(mapping) => {
-                        proxy_mappings.push(mapping);
-                    },
-                    Err(e) => {
-                        error!("Failed to parse proxy mapping '{}': {}", trimmed, e);
+    else if let Some(path) = env::var("PJ_PROXIES").ok().and_then(|s| s.strip_prefix('@').map(str::to_string)) {
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for (line, entry) in mapping_file_entries(&contents) {
+                    match parse_mapping_entry(entry) {
+                        Ok(mappings) => proxy_mappings.extend(mappings),
+                        Err(e) => invalid_setting(check, format!("Failed to parse proxy mapping '{}' on line {} of {}: {}", entry, line, path, e)),
                     }
                 }
+                if !proxy_mappings.is_empty() {
+                    info!("Using {} proxy mappings from {}", proxy_mappings.len(), path);
+                }
+            }
+            Err(e) => invalid_setting(check, format!("Failed to read PJ_PROXIES mappings file '{}': {}", path, e)),
+        }
+    }
+    else if let Ok(env_mappings) = env::var("PJ_PROXIES") {
+        for entry in mapping_list_entries(&env_mappings) {
+            match parse_mapping_entry(&entry) {
+                Ok(mappings) => {
+                    proxy_mappings.extend(mappings);
+                },
+                Err(e) => {
+                    invalid_setting(check, format!("Failed to parse proxy mapping '{}': {}", entry, e));
+                }
             }
         }
         if !proxy_mappings.is_empty() {
@@ -102,13 +593,13 @@ fn main() {
     }
     // Priority 3: PJ_PROXY environment variable (single mapping)
     else if let Ok(env_proxy) = env::var("PJ_PROXY") {
-        match parse_proxy_mapping(&env_proxy) {
-            Ok(mapping) => {
-                proxy_mappings.push(mapping);
+        match parse_mapping_entry(&env_proxy) {
+            Ok(mappings) => {
+                proxy_mappings.extend(mappings);
                 info!("Using proxy mapping from PJ_PROXY environment variable");
             },
             Err(e) => {
-                error!("Failed to parse PJ_PROXY environment variable '{}': {}", env_proxy, e);
+                invalid_setting(check, format!("Failed to parse PJ_PROXY environment variable '{}': {}", env_proxy, e));
             }
         }
     }
@@ -124,8 +615,66 @@ fn main() {
         process::exit(1);
     }
     
+    let max_mappings = match env::var("PJ_MAX_MAPPINGS").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_count(&s) {
+            Ok(max) if max > 0 => max,
+            Ok(_) => {
+                error!("Invalid PJ_MAX_MAPPINGS '{}': must be at least 1", s);
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Invalid PJ_MAX_MAPPINGS '{}': {}", s, e);
+                process::exit(1);
+            }
+        },
+        None => DEFAULT_MAX_MAPPINGS,
+    };
+    if proxy_mappings.len() as u64 > max_mappings {
+        error!("{} mappings exceed PJ_MAX_MAPPINGS ({}), refusing to start; raise it if that many are intended",
+               proxy_mappings.len(), max_mappings);
+        process::exit(1);
+    }
+    
+    if let Err(e) = check_mappings(&proxy_mappings) {
+        error!("{}", e);
+        process::exit(1);
+    }
+    
+    // Refuse to start rather than proxy connections back into ourselves
+    for mapping in &proxy_mappings {
+        if let Err(e) = check_proxy_loop(mapping) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+    
     let proxy_count = proxy_mappings.len();
     
+    // Upstreams looked up by a command instead of taken from the mapping
+    let upstream_cmd = env::var("PJ_UPSTREAM_CMD").ok().filter(|cmd| !cmd.trim().is_empty());
+    if upstream_cmd.is_some() && proxy_mappings.iter().filter(|m| m.mode == ListenMode::Forward).count() != 1 {
+        error!("PJ_UPSTREAM_CMD needs exactly one forward (listen_ip:port:upstream) mapping");
+        process::exit(1);
+    }
+    
+    if args.list_mappings {
+        for mapping in &proxy_mappings {
+            let info = MappingInfo::from(mapping);
+            match &upstream_cmd {
+                Some(_) if mapping.mode == ListenMode::Forward => println!("{} -> PJ_UPSTREAM_CMD", info.listen_addr),
+                _ => println!("{} -> {}", info.listen_addr, info.backend),
+            }
+        }
+        process::exit(0);
+    }
+    let upstream_cmd_interval = env::var("PJ_UPSTREAM_CMD_INTERVAL").ok().map(|s| match parse_duration(&s) {
+        Ok(duration) => duration,
+        Err(e) => {
+            error!("Invalid PJ_UPSTREAM_CMD_INTERVAL '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
     // Parse connection ID reset settings from environment variables
     let reset_interval = env::var("PJ_CONN_ID_RESET_INTERVAL")
         .ok()
@@ -136,7 +685,7 @@ fn main() {
                     Some(duration)
                 }
                 Err(e) => {
-                    error!("Invalid PJ_CONN_ID_RESET_INTERVAL '{}': {}", s, e);
+                    invalid_setting(check, format!("Invalid PJ_CONN_ID_RESET_INTERVAL '{}': {}", s, e));
                     None
                 }
             }
@@ -151,7 +700,7 @@ fn main() {
                     Some(count)
                 }
                 Err(e) => {
-                    error!("Invalid PJ_CONN_ID_RESET_COUNT '{}': {}", s, e);
+                    invalid_setting(check, format!("Invalid PJ_CONN_ID_RESET_COUNT '{}': {}", s, e));
                     None
                 }
             }
@@ -165,8 +714,694 @@ fn main() {
         (Some(_), Some(_)) => info!("Connection ID reset by time interval or count threshold"),
     }
     
+    let id_offset = env::var("PJ_CONN_ID_OFFSET").ok().map(|s| match parse_number(&s) {
+        Ok(offset) => offset,
+        Err(e) => {
+            error!("Invalid PJ_CONN_ID_OFFSET '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    let id_width = env::var("PJ_CONN_ID_WIDTH").ok().map(|s| match s.trim().parse::<usize>() {
+        Ok(width) if width <= 20 => width,
+        _ => {
+            error!("Invalid PJ_CONN_ID_WIDTH '{}': expected a number of digits up to 20", s);
+            process::exit(1);
+        }
+    });
+    if let Some(offset) = id_offset {
+        info!("Connection IDs start at {}", offset);
+    }
+    
     // Create shared ID manager
-    let id_manager = Arc::new(ConnectionIdManager::new(reset_interval, reset_count));
+    let id_manager = Arc::new(
+        ConnectionIdManager::new(reset_interval, reset_count)
+            .with_offset(id_offset.unwrap_or(0))
+            .with_width(id_width.unwrap_or(0)),
+    );
+    
+    // The connection registry is only maintained when the admin API is enabled
+    let admin_addr = env::var("PJ_ADMIN_ADDR").ok().filter(|addr| !addr.trim().is_empty());
+    let registry = admin_addr.as_ref().map(|_| Arc::new(ConnectionRegistry::new()));
+    // A bare port is bound on localhost only, the socket itself is bound after --check
+    let stats_addr = env::var("PJ_STATS_PORT").ok().filter(|s| !s.trim().is_empty()).map(|s| {
+        let parsed = match s.trim().parse::<u16>() {
+            Ok(port) => Some(SocketAddr::from(([127, 0, 0, 1], port))),
+            Err(_) => s.trim().to_socket_addrs().ok().and_then(|mut addrs| addrs.next()),
+        };
+        match parsed {
+            Some(addr) => addr,
+            None => {
+                error!("Invalid PJ_STATS_PORT '{}': expected a port or an address like 0.0.0.0:2003", s);
+                process::exit(1);
+            }
+        }
+    });
+    // Optional local IP for all upstream connections, overridable per mapping with ?bind=
+    let bind_source = match env::var("PJ_BIND_SOURCE").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match s.trim().parse() {
+            Ok(ip) => {
+                info!("Binding upstream connections to source {}", ip);
+                Some(ip)
+            }
+            Err(_) => {
+                error!("Invalid PJ_BIND_SOURCE '{}': expected an IP address", s);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // Optional local port range for all upstream connections
+    let source_ports = match env::var("PJ_SOURCE_PORT_RANGE").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match SourcePorts::parse(&s) {
+            Ok(ports) => {
+                info!("Binding upstream connections to source ports {}", ports);
+                if !cfg!(target_os = "linux") {
+                    warn!("PJ_SOURCE_PORT_RANGE only works on Linux, upstream source ports are chosen by the OS");
+                }
+                if let Some((low, high)) = ephemeral_ports().filter(|&(low, high)| ports.low() < low || ports.high() > high) {
+                    warn!(
+                        "PJ_SOURCE_PORT_RANGE {} reaches outside net.ipv4.ip_local_port_range {}-{}, only the ports inside both are used",
+                        ports, low, high
+                    );
+                }
+                Some(ports)
+            }
+            Err(e) => {
+                error!("Invalid PJ_SOURCE_PORT_RANGE: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    
+    let lb_strategy = match env::var("PJ_LB_STRATEGY").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match LbStrategy::parse(&s) {
+            Ok(strategy) => strategy,
+            Err(e) => {
+                error!("Invalid PJ_LB_STRATEGY: {}", e);
+                process::exit(1);
+            }
+        },
+        None => LbStrategy::default(),
+    };
+    
+    let bind_failure = match env::var("PJ_BIND_FAILURE").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match BindFailure::parse(&s) {
+            Ok(mode) => Some(mode),
+            Err(e) => {
+                error!("Invalid PJ_BIND_FAILURE: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if bind_failure.is_some() {
+        warn!("PJ_BIND_FAILURE is only supported on Unix, ignoring it");
+    }
+    
+    let flush_mode = match env::var("PJ_FLUSH_MODE").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match FlushMode::parse(&s) {
+            Ok(mode) => mode,
+            Err(e) => {
+                error!("Invalid PJ_FLUSH_MODE: {}", e);
+                process::exit(1);
+            }
+        },
+        None => FlushMode::default(),
+    };
+    
+    let buffer_setting = |var: &str| match env::var(var).ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_count(&s).map(usize::try_from) {
+            Ok(Ok(value)) => Some(value),
+            _ => {
+                error!("Invalid {} '{}': expected a number of bytes", var, s);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let adaptive_buffer = match (buffer_setting("PJ_BUFFER_MIN"), buffer_setting("PJ_BUFFER_MAX"), buffer_setting("PJ_BUFFER_GROWTH")) {
+        (min, Some(max), growth) => match AdaptiveBuffer::new(min.unwrap_or(RELAY_BUFFER_SIZE), max, growth.unwrap_or(2)) {
+            Ok(adaptive) => {
+                info!("Read buffers grow from {} up to {} bytes with the traffic, {} times at a step",
+                      adaptive.min, adaptive.max, adaptive.growth);
+                Some(adaptive)
+            }
+            Err(e) => {
+                error!("Invalid PJ_BUFFER_MIN/PJ_BUFFER_MAX/PJ_BUFFER_GROWTH: {}", e);
+                process::exit(1);
+            }
+        },
+        (None, None, None) => None,
+        _ => {
+            error!("PJ_BUFFER_MIN and PJ_BUFFER_GROWTH need PJ_BUFFER_MAX");
+            process::exit(1);
+        }
+    };
+    
+    let dscp = match env::var("PJ_DSCP").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match Dscp::parse(&s) {
+            Ok(dscp) => Some(dscp),
+            Err(e) => {
+                error!("Invalid PJ_DSCP: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let conn_log_levels = match env::var("PJ_LOG_CONN_LEVEL").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match ConnLogLevels::parse(&s) {
+            Ok(levels) => levels,
+            Err(e) => {
+                error!("Invalid PJ_LOG_CONN_LEVEL: {}", e);
+                process::exit(1);
+            }
+        },
+        None => ConnLogLevels::default(),
+    };
+    let fail_response = match env::var("PJ_FAIL_RESPONSE").ok().filter(|s| !s.is_empty()) {
+        Some(s) => match FailResponse::parse(&s) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                error!("Invalid PJ_FAIL_RESPONSE: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let forwarded_for = env::var("PJ_FORWARDED_FOR")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    let log_accept = env::var("PJ_LOG_ACCEPT")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    let self_check = env::var("PJ_SELF_CHECK")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    if self_check && !cfg!(debug_assertions) {
+        warn!("PJ_SELF_CHECK only works in debug builds, ignoring it");
+    }
+    let inject_delay = match env::var("PJ_INJECT_DELAY").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => {
+            let delay = parse_duration(&s).unwrap_or_else(|e| {
+                error!("Invalid PJ_INJECT_DELAY '{}': {}", s, e);
+                process::exit(1);
+            });
+            let direction = match env::var("PJ_INJECT_DELAY_DIRECTION").ok().filter(|s| !s.trim().is_empty()) {
+                Some(direction) => DelayDirection::parse(&direction).unwrap_or_else(|e| {
+                    error!("Invalid PJ_INJECT_DELAY_DIRECTION: {}", e);
+                    process::exit(1);
+                }),
+                None => DelayDirection::default(),
+            };
+            warn!("Injecting {} of latency into relayed traffic ({}); for testing only", s, direction.as_str());
+            Some(InjectedDelay { delay, direction })
+        }
+        None => None,
+    };
+    let dscp_downstream = env::var("PJ_DSCP_DOWNSTREAM")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    if let Some(dscp) = dscp {
+        info!("Marking {} traffic with DSCP {}", if dscp_downstream { "upstream and client" } else { "upstream" }, dscp.value());
+    }
+    
+    // Targets CONNECT listeners may tunnel to; nothing is allowed by default
+    let connect_allowlist = match ConnectAllowlist::parse(&env::var("PJ_CONNECT_ALLOW").unwrap_or_default()) {
+        Ok(allowlist) => allowlist,
+        Err(e) => {
+            error!("Invalid PJ_CONNECT_ALLOW: {}", e);
+            process::exit(1);
+        }
+    };
+    let connect_allowlist = match connect_allowlist.with_denied(&env::var("PJ_CONNECT_DENY").unwrap_or_default()) {
+        Ok(allowlist) => allowlist,
+        Err(e) => {
+            error!("Invalid PJ_CONNECT_DENY: {}", e);
+            process::exit(1);
+        }
+    };
+    
+    let socks5_credentials = match env::var("PJ_SOCKS5_AUTH").ok().filter(|s| !s.is_empty()) {
+        Some(s) => match parse_credentials(&s) {
+            Ok(credentials) => Some(credentials),
+            Err(e) => {
+                error!("Invalid PJ_SOCKS5_AUTH: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    
+    let upstream_socks5 = match env::var("PJ_UPSTREAM_SOCKS5").ok().filter(|s| !s.is_empty()) {
+        Some(addr) => {
+            let resolved = addr.trim().to_socket_addrs().map_err(|e| e.to_string()).and_then(|mut addrs| {
+                addrs.next().ok_or_else(|| format!("{} did not resolve", addr))
+            });
+            let credentials = match env::var("PJ_UPSTREAM_SOCKS5_AUTH").ok().filter(|s| !s.is_empty()) {
+                Some(s) => match parse_credentials(&s) {
+                    Ok(credentials) => Some(credentials),
+                    Err(e) => {
+                        error!("Invalid PJ_UPSTREAM_SOCKS5_AUTH: {}", e);
+                        process::exit(1);
+                    }
+                },
+                None => None,
+            };
+            match resolved {
+                Ok(resolved) => {
+                    info!("Reaching upstreams through SOCKS5 proxy {}{}", addr,
+                          if credentials.is_some() { " with username/password" } else { "" });
+                    Some(Socks5Upstream { addr: resolved, credentials })
+                }
+                Err(e) => {
+                    error!("Invalid PJ_UPSTREAM_SOCKS5 '{}': {}", addr, e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    
+    let peek_bytes = env::var("PJ_PEEK_BYTES").ok().and_then(|s| match s.trim().parse::<usize>() {
+        Ok(0) => None,
+        Ok(n) => {
+            info!("Logging the first {} bytes of each connection at debug level", n);
+            Some(n)
+        }
+        Err(_) => {
+            error!("Invalid PJ_PEEK_BYTES '{}': expected a number of bytes", s);
+            process::exit(1);
+        }
+    });
+    
+    let max_handshake = env::var("PJ_MAX_HANDSHAKE_BYTES").ok().map(|s| match parse_count(&s) {
+        Ok(0) | Err(_) => {
+            error!("Invalid PJ_MAX_HANDSHAKE_BYTES '{}': expected a number of bytes above 0", s);
+            process::exit(1);
+        }
+        Ok(n) => n as usize,
+    });
+    
+    let max_lifetime = env::var("PJ_MAX_LIFETIME").ok().map(|s| match parse_duration(&s) {
+        Ok(duration) => {
+            info!("Connections are closed after {}", s);
+            duration
+        }
+        Err(e) => {
+            error!("Invalid PJ_MAX_LIFETIME '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    let long_conn_threshold = env::var("PJ_LONG_CONN_THRESHOLD").ok().map(|s| match parse_duration(&s) {
+        Ok(threshold) => {
+            info!("Logging connections still open after {}", s);
+            threshold
+        }
+        Err(e) => {
+            error!("Invalid PJ_LONG_CONN_THRESHOLD '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    let slow_io_threshold = env::var("PJ_SLOW_IO_THRESHOLD").ok().map(|s| match parse_duration(&s) {
+        Ok(threshold) => {
+            info!("Logging relayed writes slower than {} at debug level", s);
+            threshold
+        }
+        Err(e) => {
+            error!("Invalid PJ_SLOW_IO_THRESHOLD '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    let backpressure_threshold = env::var("PJ_BACKPRESSURE_THRESHOLD").ok().map(|s| match parse_duration(&s) {
+        Ok(threshold) => {
+            info!("Marking connections backpressured when a write waits longer than {}", s);
+            threshold
+        }
+        Err(e) => {
+            error!("Invalid PJ_BACKPRESSURE_THRESHOLD '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    let idle_timeout = env::var("PJ_IDLE_TIMEOUT").ok().map(|s| match parse_duration(&s) {
+        Ok(duration) => {
+            info!("Idle connections are closed after {}", s);
+            duration
+        }
+        Err(e) => {
+            error!("Invalid PJ_IDLE_TIMEOUT '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    let idle_bound = |var: &str| match env::var(var).ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_duration(&s) {
+            Ok(duration) => Some(duration),
+            Err(e) => {
+                error!("Invalid {} '{}': {}", var, s, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let idle_rtts = match env::var("PJ_IDLE_TIMEOUT_RTTS").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match s.trim().parse::<u32>() {
+            Ok(rtts) => Some(rtts),
+            Err(_) => {
+                error!("Invalid PJ_IDLE_TIMEOUT_RTTS '{}': expected a whole number of round trips", s);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let adaptive_idle = match (idle_bound("PJ_IDLE_TIMEOUT_MIN"), idle_bound("PJ_IDLE_TIMEOUT_MAX"), idle_rtts) {
+        (Some(min), Some(max), rtts) => match AdaptiveIdle::new(min, max, rtts.unwrap_or(DEFAULT_IDLE_RTTS)) {
+            Ok(adaptive) => {
+                info!("Idle timeouts scale to {} round trips, between {:?} and {:?}", adaptive.rtts, adaptive.min, adaptive.max);
+                Some(adaptive)
+            }
+            Err(e) => {
+                error!("Invalid PJ_IDLE_TIMEOUT_MIN/PJ_IDLE_TIMEOUT_MAX/PJ_IDLE_TIMEOUT_RTTS: {}", e);
+                process::exit(1);
+            }
+        },
+        (None, None, None) => None,
+        _ => {
+            error!("PJ_IDLE_TIMEOUT_MIN and PJ_IDLE_TIMEOUT_MAX must be set together, and PJ_IDLE_TIMEOUT_RTTS needs them");
+            process::exit(1);
+        }
+    };
+    
+    let write_timeout = env::var("PJ_WRITE_TIMEOUT").ok().map(|s| match parse_duration(&s) {
+        Ok(duration) => {
+            info!("Connections are closed when a write stalls for {}", s);
+            duration
+        }
+        Err(e) => {
+            error!("Invalid PJ_WRITE_TIMEOUT '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    let rebind_interval = env::var("PJ_REBIND_INTERVAL").ok().map(|s| match parse_duration(&s) {
+        Ok(interval) => interval,
+        Err(e) => {
+            error!("Invalid PJ_REBIND_INTERVAL '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    // 0 leaves a direction uncapped
+    let byte_cap = |var: &str| {
+        env::var(var).ok().and_then(|s| match parse_count(&s) {
+            Ok(0) => None,
+            Ok(cap) => {
+                info!("Connections are closed after {} bytes ({})", cap, var);
+                Some(cap)
+            }
+            Err(e) => {
+                error!("Invalid {} '{}': {}", var, s, e);
+                process::exit(1);
+            }
+        })
+    };
+    let max_up_bytes = byte_cap("PJ_MAX_UP_BYTES");
+    let max_down_bytes = byte_cap("PJ_MAX_DOWN_BYTES");
+    
+    let shed_mark = |var: &str| match env::var(var).ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_count(&s) {
+            Ok(mark) => Some(mark),
+            Err(e) => {
+                error!("Invalid {} '{}': {}", var, s, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let shed = match (shed_mark("PJ_SHED_HIGH"), shed_mark("PJ_SHED_LOW")) {
+        (Some(high), Some(low)) => match ShedMarks::new(high, low) {
+            Ok(marks) => {
+                info!("Listeners shed new connections from {} active until fewer than {} remain", high, low);
+                Some(marks)
+            }
+            Err(e) => {
+                error!("Invalid PJ_SHED_HIGH/PJ_SHED_LOW: {}", e);
+                process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            error!("PJ_SHED_HIGH and PJ_SHED_LOW must be set together");
+            process::exit(1);
+        }
+    };
+    
+    let admission_ratio = match env::var("PJ_ADMISSION_RATIO").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_threshold(&s) {
+            Ok(ratio) => {
+                info!("Shedding some new connections for upstreams with under {}% of recent connects succeeding", ratio * 100.0);
+                Some(ratio)
+            }
+            Err(e) => {
+                error!("Invalid PJ_ADMISSION_RATIO: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    
+    let accept_rate = match env::var("PJ_ACCEPT_RATE").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_rate(&s) {
+            Ok(rate) => {
+                info!("Pacing each listener to {} new connections per second", rate);
+                Some(rate)
+            }
+            Err(e) => {
+                error!("Invalid PJ_ACCEPT_RATE: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    
+    let queue_timeout = match env::var("PJ_QUEUE_TIMEOUT").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_duration(&s) {
+            Ok(wait) => Some(wait),
+            Err(e) => {
+                error!("Invalid PJ_QUEUE_TIMEOUT '{}': {}", s, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let connection_limiter = match shed_mark("PJ_MAX_CONNECTIONS") {
+        Some(limit) => match ConnectionLimiter::new(limit as usize, queue_timeout) {
+            Ok(limiter) => {
+                match queue_timeout {
+                    Some(wait) => info!("Relaying at most {} connections at once; others wait up to {:?}", limit, wait),
+                    None => info!("Relaying at most {} connections at once; others are refused", limit),
+                }
+                Some(Arc::new(limiter))
+            }
+            Err(e) => {
+                error!("Invalid PJ_MAX_CONNECTIONS: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let backend_queue_timeout = match env::var("PJ_BACKEND_QUEUE_TIMEOUT").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match parse_duration(&s) {
+            Ok(wait) => Some(wait),
+            Err(e) => {
+                error!("Invalid PJ_BACKEND_QUEUE_TIMEOUT '{}': {}", s, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let backend_limiter = match shed_mark("PJ_MAX_BACKEND_CONNS") {
+        Some(limit) => match BackendLimiter::new(limit as usize, backend_queue_timeout) {
+            Ok(limiter) => {
+                match backend_queue_timeout {
+                    Some(wait) => info!("Backends take at most {} connections each; others wait up to {:?}", limit, wait),
+                    None => info!("Backends take at most {} connections each; others are refused", limit),
+                }
+                Some(Arc::new(limiter))
+            }
+            Err(e) => {
+                error!("Invalid PJ_MAX_BACKEND_CONNS: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    
+    let connect_timeout = env::var("PJ_CONNECT_TIMEOUT").ok().map(|s| match parse_duration(&s) {
+        Ok(duration) => duration,
+        Err(e) => {
+            error!("Invalid PJ_CONNECT_TIMEOUT '{}': {}", s, e);
+            process::exit(1);
+        }
+    });
+    
+    let mut connect_retry = ConnectRetry::default();
+    for (var, setting) in [
+        ("PJ_CONNECT_ATTEMPTS", "attempts"),
+        ("PJ_RETRY_BASE_MS", "base"),
+        ("PJ_RETRY_MAX_MS", "max"),
+    ] {
+        let Ok(s) = env::var(var) else { continue };
+        match (setting, s.trim().parse::<u32>()) {
+            ("attempts", Ok(n)) if n > 0 => connect_retry.attempts = n,
+            ("base", Ok(ms)) => connect_retry.base_delay = Duration::from_millis(ms.into()),
+            ("max", Ok(ms)) => connect_retry.max_delay = Duration::from_millis(ms.into()),
+            _ => {
+                error!("Invalid {} '{}': expected a positive number", var, s);
+                process::exit(1);
+            }
+        }
+    }
+    if connect_retry.attempts > 1 {
+        info!("Upstream connects are tried up to {} times, backing off from {:?} to at most {:?}",
+              connect_retry.attempts, connect_retry.base_delay, connect_retry.max_delay);
+    }
+    
+    // 0 turns the heartbeat off
+    let heartbeat_interval = match env::var("PJ_HEARTBEAT_INTERVAL").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) if s.trim() == "0" => None,
+        Some(s) => match parse_duration(&s) {
+            Ok(interval) => Some(interval),
+            Err(e) => {
+                error!("Invalid PJ_HEARTBEAT_INTERVAL '{}': {}", s, e);
+                process::exit(1);
+            }
+        },
+        None => Some(DEFAULT_HEARTBEAT_INTERVAL),
+    };
+    let statsd_tags = env::var("PJ_STATSD_TAGS")
+        .map(|s| matches!(s.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    let statsd = match env::var("PJ_STATSD_ADDR").ok().filter(|s| !s.is_empty()) {
+        Some(addr) => {
+            let tags = statsd_tags;
+            match StatsdClient::new(&addr, tags) {
+                Ok(client) => {
+                    info!("Sending StatsD metrics to {}{}", addr, if tags { " with DogStatsD tags" } else { "" });
+                    Some(Arc::new(client.with_mapping_names(proxy_count > 1)))
+                }
+                Err(e) => {
+                    error!("Invalid PJ_STATSD_ADDR '{}': {}", addr, e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    let client_subnets = match env::var("PJ_STATSD_CLIENT_SUBNET").ok().filter(|s| !s.trim().is_empty()) {
+        Some(s) => match ClientSubnets::parse(&s) {
+            Ok(subnets) if statsd.is_some() && statsd_tags => {
+                info!("Tagging StatsD metrics with client subnets of {}", subnets);
+                Some(subnets)
+            }
+            Ok(_) => {
+                warn!("PJ_STATSD_CLIENT_SUBNET needs PJ_STATSD_ADDR and PJ_STATSD_TAGS to tag metrics, ignoring it");
+                None
+            }
+            Err(e) => {
+                error!("Invalid PJ_STATSD_CLIENT_SUBNET: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    
+    let connection_rate = Arc::new(ConnectionRate::default());
+    let backend_traffic = Arc::new(BackendTraffic::default());
+    let concurrency = Arc::new(Concurrency::default());
+    let connect_latency = Arc::new(LatencyHistogram::default());
+    let connection_sizes = Arc::new(SizeHistogram::default());
+    
+    let options = ProxyOptions {
+        registry: registry.clone(),
+        bind_source,
+        source_ports,
+        peek_bytes,
+        statsd,
+        client_subnets,
+        max_lifetime,
+        lb_strategy,
+        flush_mode,
+        adaptive_buffer,
+        idle_timeout,
+        adaptive_idle,
+        write_timeout,
+        connect_timeout,
+        connect_retry,
+        connection_rate: Some(connection_rate.clone()),
+        concurrency: Some(concurrency.clone()),
+        backend_traffic: Some(backend_traffic.clone()),
+        connect_latency: Some(connect_latency.clone()),
+        connection_sizes: Some(connection_sizes.clone()),
+        fail_response,
+        forwarded_for,
+        log_accept,
+        self_check,
+        inject_delay,
+        conn_log_levels,
+        upstream_socks5,
+        dscp,
+        dscp_downstream,
+        max_up_bytes,
+        max_down_bytes,
+        shed,
+        admission_ratio,
+        accept_rate,
+        slow_io_threshold,
+        backpressure_threshold,
+        long_conn_threshold,
+        backend_limiter,
+        connection_limiter,
+        max_handshake,
+        ..Default::default()
+    };
+    
+    if check {
+        // Upstreams are resolved as they would be at startup, so a name that doesn't resolve fails here
+        for mapping in proxy_mappings.iter().filter(|mapping| mapping.mode == ListenMode::Forward) {
+            if let Err(e) = upstream_tiers(&mapping.proxy_addr) {
+                error!("Invalid upstream for {}: {}", mapping.listen_addr, e);
+                process::exit(1);
+            }
+        }
+        println!("Configuration OK, would start {} mappings:", proxy_count);
+        for mapping in &proxy_mappings {
+            println!("  {}", describe_mapping(mapping, upstream_cmd.is_some()));
+        }
+        if let Some(addr) = &admin_addr {
+            println!("  admin API on {}", addr.trim());
+        }
+        if let Some(addr) = stats_addr {
+            println!("  stats port on {}", addr);
+        }
+        process::exit(0);
+    }
+    
+    let stats_socket = stats_addr.map(|addr| match TcpListener::bind(addr) {
+        Ok(socket) => {
+            info!("Graphite stats port listening on {}", addr);
+            socket
+        }
+        Err(e) => {
+            error!("Failed to bind PJ_STATS_PORT {}: {}", addr, e);
+            process::exit(1);
+        }
+    });
     
     let opt = Some(Opt::default());
     let mut server = match Server::new(opt) {
@@ -179,14 +1414,210 @@ fn main() {
     
     server.bootstrap();
     
-    for mapping in proxy_mappings {
-        let proxy = proxy_service(&mapping.listen_addr, &mapping.proxy_addr, id_manager.clone());
-        server.add_service(proxy);
+    // Sockets from systemd socket activation go to the mappings in order
+    #[cfg(unix)]
+    let mut inherited_fds = pj::activation::take_listen_fds().into_iter();
+    server.add_service(background_service(
+        "connection rate",
+        RateReporter::new(connection_rate.clone(), options.statsd.clone(), Duration::from_secs(RATE_WINDOW_SECS)),
+    ));
+    
+    #[cfg(unix)]
+    if inherited_fds.len() > 0 {
+        info!("Socket activation: inherited {} listening sockets", inherited_fds.len());
+    }
+    
+    let mut listener_traffic = Vec::new();
+    let mut mapping_info = Vec::new();
+    // Mappings whose listener failed to bind and were left out
+    let mut skipped = 0;
+    for mut mapping in proxy_mappings {
+        #[cfg(unix)]
+        let inherited_fd = inherited_fds.next();
+        // Port 0 and interface listeners are bound here; for port 0 the
+        // service is then built with the port the OS picked. With the admin
+        // API on every listener on an IP address is, so /healthz knows which
+        // are up. Only PJ_BIND_FAILURE=abort stops the proxy over a failure.
+        #[cfg(unix)]
+        let prebound = match inherited_fd {
+            Some(_) => None,
+            None => match prebind(&mapping, registry.is_some(), bind_failure.is_some()) {
+                Ok(prebound) => prebound,
+                Err(e) if bind_failure == Some(BindFailure::Abort) => {
+                    error!("Failed to bind {}: {}", mapping.listen_addr, e);
+                    process::exit(1);
+                }
+                Err(e) => {
+                    error!("Failed to bind {}, skipping its mapping: {}", mapping.listen_addr, e);
+                    // Listed in /listeners as not bound
+                    if let Some(registry) = &registry {
+                        registry.register_listener(&mapping.listen_addr);
+                    }
+                    skipped += 1;
+                    continue;
+                }
+            },
+        };
+        let mut listening = mapping.listen_addr.clone();
+        #[cfg(unix)]
+        if let Some((_, bound)) = &prebound {
+            let requested_port = mapping.listen_addr.parse::<SocketAddr>().map_or(0, |addr| addr.port());
+            listening = match requested_port {
+                0 => format!("{} (requested :0)", bound),
+                _ => bound.to_string(),
+            };
+            mapping.listen_addr = bound.to_string();
+        }
+        if let Some(interface) = &mapping.interface {
+            listening.push_str(&format!(" on interface {}", interface));
+        }
+        mapping_info.push(MappingInfo::from(&mapping));
+        let mapping_options = mapping.options(&options);
+        let label = mapping.name.as_ref().map(|name| format!("'{}' ", name)).unwrap_or_default();
+        let mapping_ids = mapping.id_manager(&id_manager);
+        if !Arc::ptr_eq(&mapping_ids, &id_manager) {
+            info!("Mapping {}on {} numbers its connections on its own, resetting{}{}", label, mapping.listen_addr,
+                  mapping.reset_interval.map(|interval| format!(" every {:?}", interval)).unwrap_or_default(),
+                  mapping.reset_count.map(|count| format!(" after {} connections", count)).unwrap_or_default());
+        }
         
-        info!("Adding proxy mapping - listening on {}, proxying to {}", 
-              mapping.listen_addr, mapping.proxy_addr);
+        let (service_name, app) = match mapping.mode {
+            ListenMode::Forward => match &upstream_cmd {
+                Some(cmd) => {
+                    // The mapping's own upstreams stand in until the command succeeds
+                    let peers = upstream_peers(&mapping.proxy_addr).unwrap_or_else(|e| {
+                        error!("Invalid upstream for {}: {}", mapping.listen_addr, e);
+                        process::exit(1);
+                    });
+                    let upstream = Arc::new(DiscoveredUpstream::new(peers, options.lb_strategy));
+                    let command = UpstreamCommand::new(cmd, upstream.clone());
+                    if let Err(e) = command.refresh() {
+                        error!("Upstream command failed, using {} for now: {}", mapping.proxy_addr, e);
+                    }
+                    info!("Adding proxy mapping {}- listening on {}, proxying to the upstreams from PJ_UPSTREAM_CMD",
+                          label, listening);
+                    if let Some(interval) = upstream_cmd_interval {
+                        server.add_service(background_service("upstream command", command.every(interval)));
+                    }
+                    ("Proxy Service", ProxyApp::discovered(upstream, mapping.listen_addr.clone(), mapping_ids, mapping_options))
+                }
+                None => {
+                    info!("Adding proxy mapping {}- listening on {}, proxying to {}{}", 
+                          label, listening, mapping.proxy_addr,
+                          mapping.mirror.map(|mirror| format!(", mirroring to {}", mirror)).unwrap_or_default());
+                    let app = proxy_app(&mapping.listen_addr, &mapping.proxy_addr, mapping_ids, mapping_options).unwrap_or_else(|e| {
+                        error!("Invalid upstream for {}: {}", mapping.listen_addr, e);
+                        process::exit(1);
+                    });
+                    ("Proxy Service", app)
+                }
+            },
+            ListenMode::Connect => {
+                if connect_allowlist.is_empty() {
+                    warn!("PJ_CONNECT_ALLOW is not set, {} will refuse every CONNECT request", mapping.listen_addr);
+                }
+                info!("Adding CONNECT proxy {}- listening on {}", label, listening);
+                ("Connect Service", ProxyApp::connect(mapping.listen_addr.clone(), mapping_ids, connect_allowlist.clone(), mapping_options))
+            }
+            ListenMode::Socks5 => {
+                if connect_allowlist.is_empty() {
+                    warn!("PJ_CONNECT_ALLOW is not set, {} will refuse every SOCKS5 request", mapping.listen_addr);
+                }
+                let config = Socks5Config {
+                    allowlist: connect_allowlist.clone(),
+                    credentials: socks5_credentials.clone(),
+                };
+                info!("Adding SOCKS5 proxy {}- listening on {}{}", label, listening,
+                      if socks5_credentials.is_some() { " (username/password required)" } else { "" });
+                ("SOCKS5 Service", ProxyApp::socks5(mapping.listen_addr.clone(), mapping_ids, config, mapping_options))
+            }
+            ListenMode::Transparent => {
+                info!("Adding transparent proxy {}- listening on {}, relaying to each connection's original destination",
+                      label, listening);
+                ("Transparent Service", ProxyApp::transparent(mapping.listen_addr.clone(), mapping_ids, mapping_options))
+            }
+            ListenMode::Sink => {
+                info!("Adding sink {}- listening on {}, {} each connection without an upstream", label, listening,
+                      mapping.hold.map(|hold| format!("holding for {:?} then closing", hold)).unwrap_or_else(|| "closing".to_string()));
+                ("Sink Service", ProxyApp::sink(mapping.listen_addr.clone(), mapping_ids, mapping.hold, mapping_options))
+            }
+            ListenMode::Tls => {
+                info!("Adding TLS passthrough {}- listening on {}, routing by server name to {}", label, listening,
+                      MappingInfo::from(&mapping).backend);
+                let routes = SniRoutes::new(&mapping.sni_routes);
+                ("TLS Service", ProxyApp::sni(mapping.listen_addr.clone(), mapping_ids, routes, mapping_options))
+            }
+        };
+        listener_traffic.push(app.traffic());
+        // With the admin API on, Unix listeners on an IP address are bound by
+        // now; host names, and listeners elsewhere, pingora binds as the
+        // server starts
+        if let Some(registry) = &registry {
+            registry.record_bound(&mapping.listen_addr);
+        }
+        
+        // Listeners on an address of their own are watched for it going away
+        #[cfg(unix)]
+        let watched = rebind_interval
+            .filter(|_| inherited_fd.is_none())
+            .zip(mapping.listen_addr.parse::<SocketAddr>().ok().filter(|addr| !addr.ip().is_unspecified()));
+        #[cfg(unix)]
+        if let Some((interval, addr)) = watched {
+            info!("Watching {} and rebinding it every {:?} while it is unavailable", addr, interval);
+            let mut listener = RebindingListener::new(app, addr, mapping.interface.as_deref(), interval);
+            if let Some(registry) = &registry {
+                listener = listener.with_registry(registry.clone());
+            }
+            match prebound {
+                Some((socket, _)) => server.add_service(listener.with_listener(socket)),
+                None => server.add_service(listener),
+            }
+            continue;
+        }
+        let proxy = Service::with_listeners(service_name.to_string(), Listeners::tcp(&mapping.listen_addr), app);
+        #[cfg(unix)]
+        if let Some(fd) = inherited_fd {
+            server.add_service(pj::activation::InheritedListener::new(proxy, &mapping.listen_addr, fd));
+            continue;
+        }
+        #[cfg(unix)]
+        if let Some((listener, _)) = prebound {
+            server.add_service(pj::activation::InheritedListener::with_listener(proxy, &mapping.listen_addr, listener));
+            continue;
+        }
+        server.add_service(proxy);
     }
     
-    info!("Starting proxy server with {} mappings", proxy_count);
+    if let Some(interval) = heartbeat_interval {
+        server.add_service(background_service("heartbeat", Heartbeat::new(listener_traffic.clone(), interval).with_backends(backend_traffic.clone()).with_connection_sizes(connection_sizes.clone())));
+    }
+    if let Some(socket) = stats_socket {
+        server.add_service(background_service("stats port", StatsPort::new(socket, listener_traffic.clone())));
+    }
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = tracer_provider {
+        server.add_service(background_service("span flush", SpanFlush::new(provider, concurrency.clone())));
+    }
+    server.add_service(background_service("shutdown summary", ShutdownSummary::new(listener_traffic, concurrency, started)));
+    
+    #[cfg(unix)]
+    if inherited_fds.len() > 0 {
+        warn!("{} inherited sockets have no mapping and are unused", inherited_fds.len());
+    }
+    
+    if let (Some(addr), Some(registry)) = (admin_addr, registry) {
+        server.add_service(admin_service(addr.trim(), registry, connection_rate.clone(), backend_traffic.clone(), connect_latency, connection_sizes.clone(), started, mapping_info));
+        info!("Admin API listening on {}", addr.trim());
+    }
+    
+    if skipped == proxy_count && bind_failure == Some(BindFailure::Skip) {
+        error!("None of the {} mappings could be bound, exiting", proxy_count);
+        process::exit(1);
+    }
+    if skipped > 0 {
+        warn!("Starting proxy server with {} of {} mappings, {} failed to bind", proxy_count - skipped, proxy_count, skipped);
+    } else {
+        info!("Starting proxy server with {} mappings", proxy_count);
+    }
     server.run_forever();
 }
\ No newline at end of file
@@ -0,0 +1,226 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the buckets upstream connect times fall into
+pub const CONNECT_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A fixed-bucket histogram of durations, rendered in the Prometheus text format.
+///
+/// Each observation bumps one counter, so recording never blocks and
+/// memory stays fixed.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    bounds: &'static [f64],
+    // One per bound, then one for observations above the last
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram::new(&CONNECT_BUCKETS)
+    }
+}
+
+impl LatencyHistogram {
+    /// `bounds` must be in increasing order
+    pub fn new(bounds: &'static [f64]) -> Self {
+        LatencyHistogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = self.bounds.iter().position(|bound| secs <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// The histogram as Prometheus exposition lines for the metric `name`
+    pub fn render(&self, name: &str, help: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        // Prometheus buckets are cumulative
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum().as_secs_f64());
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+        out
+    }
+}
+
+// Each power of two is split into this many buckets, bounding the error of
+// a percentile to half a bucket: 1/32 of the value
+const SUB_BUCKETS: u64 = 16;
+const SIZE_BUCKETS: usize = (64 - 3) * SUB_BUCKETS as usize;
+
+/// The percentiles the heartbeat and `/metrics` report
+pub const SIZE_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Distribution of byte counts over log-linear buckets, so percentiles stay
+/// within a few percent however many values are recorded, in fixed memory
+#[derive(Debug)]
+pub struct SizeHistogram {
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        SizeHistogram {
+            buckets: (0..SIZE_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SizeHistogram {
+    pub fn observe(&self, bytes: u64) {
+        self.buckets[size_bucket(bytes)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    /// The value below which a `quantile` (0 to 1) of the recorded values fall,
+    /// or `None` before anything is recorded
+    pub fn percentile(&self, quantile: f64) -> Option<u64> {
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((quantile * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        counts.iter().position(|count| {
+            seen += count;
+            seen >= rank
+        })
+        .map(bucket_midpoint)
+    }
+
+    /// The distribution as a Prometheus summary for the metric `name`
+    pub fn render(&self, name: &str, help: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} summary", name);
+        for quantile in SIZE_QUANTILES {
+            let value = self.percentile(quantile).map_or_else(|| "NaN".to_string(), |value| value.to_string());
+            let _ = writeln!(out, "{}{{quantile=\"{}\"}} {}", name, quantile, value);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum());
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+        out
+    }
+}
+
+/// Values below `SUB_BUCKETS` get a bucket each; above, each power of two
+/// gets `SUB_BUCKETS` buckets of equal width
+fn size_bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros() as u64;
+    let sub = (value >> (exponent - 4)) & (SUB_BUCKETS - 1);
+    ((exponent - 3) * SUB_BUCKETS + sub) as usize
+}
+
+fn bucket_midpoint(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let exponent = bucket / SUB_BUCKETS + 3;
+    let width = 1u64 << (exponent - 4);
+    ((SUB_BUCKETS + bucket % SUB_BUCKETS) << (exponent - 4)) + width / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_is_cumulative() {
+        let histogram = LatencyHistogram::new(&[0.01, 0.1, 1.0]);
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(10));
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_secs(3));
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(3065));
+
+        assert_eq!(
+            histogram.render("pj_upstream_connect_seconds", "Time to connect upstream"),
+            "# HELP pj_upstream_connect_seconds Time to connect upstream\n\
+             # TYPE pj_upstream_connect_seconds histogram\n\
+             pj_upstream_connect_seconds_bucket{le=\"0.01\"} 2\n\
+             pj_upstream_connect_seconds_bucket{le=\"0.1\"} 3\n\
+             pj_upstream_connect_seconds_bucket{le=\"1\"} 3\n\
+             pj_upstream_connect_seconds_bucket{le=\"+Inf\"} 4\n\
+             pj_upstream_connect_seconds_sum 3.065\n\
+             pj_upstream_connect_seconds_count 4\n"
+        );
+    }
+
+    #[test]
+    fn test_size_percentiles() {
+        let sizes = SizeHistogram::default();
+        assert_eq!(sizes.percentile(0.5), None);
+
+        // 1 KB to 100 KB in 1 KB steps: p50 is 50 KB, p90 90 KB, p99 99 KB
+        for kb in 1..=100u64 {
+            sizes.observe(kb * 1024);
+        }
+        assert_eq!(sizes.count(), 100);
+        assert_eq!(sizes.sum(), 5050 * 1024);
+        for (quantile, expected) in [(0.5, 50.0 * 1024.0), (0.9, 90.0 * 1024.0), (0.99, 99.0 * 1024.0)] {
+            let value = sizes.percentile(quantile).unwrap() as f64;
+            assert!((value - expected).abs() / expected < 0.04, "p{} was {}, expected about {}", quantile * 100.0, value, expected);
+        }
+
+        // Small values are exact, and the extremes still fit
+        let small = SizeHistogram::default();
+        for bytes in [0, 3, 3, 7] {
+            small.observe(bytes);
+        }
+        assert_eq!(small.percentile(0.5), Some(3));
+        assert_eq!(small.percentile(1.0), Some(7));
+        small.observe(u64::MAX);
+        assert!(small.percentile(1.0).unwrap() > u64::MAX / 2);
+    }
+
+    #[test]
+    fn test_render_size_summary() {
+        let sizes = SizeHistogram::default();
+        assert!(sizes.render("pj_connection_bytes", "Bytes per connection").contains("pj_connection_bytes{quantile=\"0.5\"} NaN\n"));
+        sizes.observe(10);
+        let rendered = sizes.render("pj_connection_bytes", "Bytes per connection");
+        assert!(rendered.contains("# TYPE pj_connection_bytes summary\n"), "{}", rendered);
+        assert!(rendered.contains("pj_connection_bytes{quantile=\"0.99\"} 10\n"), "{}", rendered);
+        assert!(rendered.ends_with("pj_connection_bytes_sum 10\npj_connection_bytes_count 1\n"), "{}", rendered);
+    }
+}
@@ -0,0 +1,80 @@
+use std::future::Future;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use pingora_core::protocols::Stream;
+
+use crate::id_manager::DisplayId;
+
+// Chunks queued for the mirror before new ones are dropped
+const MIRROR_QUEUE_CHUNKS: usize = 256;
+
+/// How long a mirror gets to connect when the mapping sets no connect timeout
+pub const MIRROR_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Best-effort copy of a connection's downstream bytes to a secondary
+/// upstream. Connecting and writing happen on a separate task, so a slow,
+/// unreachable or broken mirror never holds up the primary relay; chunks
+/// queue while it connects and are dropped when it falls behind.
+pub struct Mirror {
+    sender: mpsc::Sender<Bytes>,
+    conn_id: DisplayId,
+    dropping: bool,
+}
+
+impl Mirror {
+    pub fn spawn<F>(connect: F, mirror_addr: String, conn_id: DisplayId) -> Self
+    where
+        F: Future<Output = pingora_core::Result<Stream>> + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(MIRROR_QUEUE_CHUNKS);
+
+        tokio::spawn(async move {
+            let mut stream = match connect.await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // Dropping the receiver makes send() skip everything from here on
+                    warn!("Conn #{} failed to connect to mirror {}: {}", conn_id, mirror_addr, e);
+                    return;
+                }
+            };
+            while let Some(chunk) = receiver.recv().await {
+                if let Err(e) = stream.write_all(&chunk).await {
+                    warn!("Conn #{} mirror write failed: {}", conn_id, e);
+                    return;
+                }
+                if let Err(e) = stream.flush().await {
+                    warn!("Conn #{} mirror flush failed: {}", conn_id, e);
+                    return;
+                }
+            }
+            let _ = stream.shutdown().await;
+        });
+
+        Mirror {
+            sender,
+            conn_id,
+            dropping: false,
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8]) {
+        match self.sender.try_send(Bytes::copy_from_slice(data)) {
+            Ok(()) => self.dropping = false,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                // Only log the start of each overflow to avoid flooding the logs
+                if !self.dropping {
+                    warn!("Conn #{} mirror is falling behind, dropping data", self.conn_id);
+                    self.dropping = true;
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                debug!("Conn #{} mirror closed, skipping {} bytes", self.conn_id, data.len());
+            }
+        }
+    }
+}
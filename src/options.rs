@@ -0,0 +1,654 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::balancer::LbStrategy;
+use crate::connection::{canonical_ip, BackendTraffic, ConnLogLevels, ConnectionObserver};
+use crate::limiter::{BackendLimiter, ConnectionLimiter};
+use crate::metrics::{LatencyHistogram, SizeHistogram};
+use crate::rate::ConnectionRate;
+use crate::registry::ConnectionRegistry;
+use crate::socks5::Socks5Upstream;
+use crate::statsd::StatsdClient;
+use crate::summary::Concurrency;
+
+/// When relayed bytes are flushed to the other side
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FlushMode {
+    /// Flush after every write
+    #[default]
+    Immediate,
+    /// Hold back writes from full reads for a moment so more can join them;
+    /// a short read or the timer flushes
+    Coalesce,
+    /// Collect writes in a buffer of their own, only flushing once the
+    /// sender pauses or the connection closes; for bulk transfers
+    Buffered,
+}
+
+impl FlushMode {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "immediate" => Ok(FlushMode::Immediate),
+            "coalesce" => Ok(FlushMode::Coalesce),
+            "buffered" => Ok(FlushMode::Buffered),
+            other => Err(format!("Unknown flush mode '{}'. Supported: immediate, coalesce, buffered", other)),
+        }
+    }
+}
+
+/// What happens at startup when a mapping's listener can't be bound
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BindFailure {
+    /// Exit with an error
+    Abort,
+    /// Log it and start the mappings that did bind, exiting only if none did
+    Skip,
+}
+
+impl BindFailure {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "abort" => Ok(BindFailure::Abort),
+            "skip" => Ok(BindFailure::Skip),
+            other => Err(format!("Unknown bind failure mode '{}'. Supported: abort, skip", other)),
+        }
+    }
+}
+
+/// Sizes each direction's read buffer to the connection's traffic, so many
+/// quiet connections hold little memory while busy ones get large reads.
+///
+/// A buffer starts at `min` bytes and grows `growth` times, up to `max`,
+/// once reads keep filling it. It shrinks back by the same factor, releasing
+/// the memory, once reads keep fitting in the smaller size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveBuffer {
+    pub min: usize,
+    pub max: usize,
+    pub growth: usize,
+}
+
+impl AdaptiveBuffer {
+    pub fn new(min: usize, max: usize, growth: usize) -> Result<Self, String> {
+        if min == 0 || min > max {
+            return Err(format!("Minimum buffer size {} must be between 1 and the maximum {}", min, max));
+        }
+        if growth < 2 {
+            return Err(format!("Buffer growth factor {} must be at least 2", growth));
+        }
+        Ok(AdaptiveBuffer { min, max, growth })
+    }
+}
+
+/// Round trips an adaptive idle timeout waits unless told otherwise
+pub const DEFAULT_IDLE_RTTS: u32 = 100;
+
+/// Scales each connection's idle timeout with its measured round trip, the
+/// time from a client read to the upstream's next read, for WAN tunnels a
+/// single fixed timeout suits badly.
+///
+/// The timeout is `rtts` smoothed round trips, kept between `min` and `max`.
+/// Until a round trip has been measured it is the fixed idle timeout, or
+/// `max` without one, within the same bounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveIdle {
+    pub min: Duration,
+    pub max: Duration,
+    pub rtts: u32,
+}
+
+impl AdaptiveIdle {
+    pub fn new(min: Duration, max: Duration, rtts: u32) -> Result<Self, String> {
+        if min > max {
+            return Err(format!("Minimum idle timeout {:?} must not be above the maximum {:?}", min, max));
+        }
+        if rtts == 0 {
+            return Err("Round trips per idle timeout must be at least 1".to_string());
+        }
+        Ok(AdaptiveIdle { min, max, rtts })
+    }
+
+    /// The idle timeout for a smoothed round trip of `rtt`
+    pub fn timeout(&self, rtt: Duration) -> Duration {
+        rtt.saturating_mul(self.rtts).clamp(self.min, self.max)
+    }
+
+    /// The idle timeout before any round trip is measured
+    pub fn initial(&self, fixed: Option<Duration>) -> Duration {
+        fixed.unwrap_or(self.max).clamp(self.min, self.max)
+    }
+}
+
+/// Prefix lengths that client addresses are grouped by in metric labels,
+/// so clients can be broken down by network without a label per address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientSubnets {
+    v4: u8,
+    v6: u8,
+}
+
+impl ClientSubnets {
+    /// Accepts an IPv4 prefix length, optionally followed by an IPv6 one,
+    /// such as `/24` or `/24,/48`; IPv6 clients are grouped by /48 unless given
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid client subnet '{}'. Expected IPv4 and optionally IPv6 prefix lengths, such as /24 or /24,/48", s.trim());
+        let prefix = |part: &str, max: u8| match part.trim().trim_start_matches('/').parse::<u8>() {
+            Ok(len) if len <= max => Ok(len),
+            _ => Err(invalid()),
+        };
+        match s.split(',').collect::<Vec<_>>().as_slice() {
+            [v4] => Ok(ClientSubnets { v4: prefix(v4, 32)?, v6: 48 }),
+            [v4, v6] => Ok(ClientSubnets { v4: prefix(v4, 32)?, v6: prefix(v6, 128)? }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The subnet `ip` falls in, such as `10.1.2.0/24`; IPv4-mapped IPv6
+    /// addresses are grouped as IPv4
+    pub fn bucket(&self, ip: IpAddr) -> String {
+        match canonical_ip(ip) {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.v4)).unwrap_or(0);
+                format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), self.v4)
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.v6)).unwrap_or(0);
+                format!("{}/{}", Ipv6Addr::from(u128::from(ip) & mask), self.v6)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ClientSubnets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{} (IPv4), /{} (IPv6)", self.v4, self.v6)
+    }
+}
+
+/// Which way relayed traffic is delayed by an `InjectedDelay`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DelayDirection {
+    #[default]
+    Both,
+    /// Only what the client sends upstream
+    Up,
+    /// Only what the upstream sends back to the client
+    Down,
+}
+
+impl DelayDirection {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "both" => Ok(DelayDirection::Both),
+            "up" => Ok(DelayDirection::Up),
+            "down" => Ok(DelayDirection::Down),
+            other => Err(format!("Unknown delay direction '{}'. Supported: both, up, down", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DelayDirection::Both => "both",
+            DelayDirection::Up => "up",
+            DelayDirection::Down => "down",
+        }
+    }
+
+    pub fn up(&self) -> bool {
+        *self != DelayDirection::Down
+    }
+
+    pub fn down(&self) -> bool {
+        *self != DelayDirection::Up
+    }
+}
+
+/// Artificial latency added before each relayed read is forwarded, for
+/// testing how clients cope with a slow network. Never set unless asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InjectedDelay {
+    pub delay: Duration,
+    pub direction: DelayDirection,
+}
+
+/// Local ports upstream connections are bound within, so firewalls that key
+/// on source ports let them through.
+///
+/// The kernel picks a free port in the range for each connect
+/// (`IP_LOCAL_PORT_RANGE`, Linux 6.3 and later), using only ports that are
+/// also inside `net.ipv4.ip_local_port_range`. Once none is free for an
+/// upstream, connects to it fail rather than leaving the range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourcePorts {
+    low: u16,
+    high: u16,
+}
+
+impl SourcePorts {
+    /// Accepts `low-high`, such as `40000-41000`, with `low` below `high`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid port range '{}'. Expected low-high with low below high, such as 40000-41000", s.trim());
+        let (low, high) = s.trim().split_once('-').ok_or_else(invalid)?;
+        match (low.trim().parse::<u16>(), high.trim().parse::<u16>()) {
+            (Ok(low), Ok(high)) if low > 0 && low < high => Ok(SourcePorts { low, high }),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn low(&self) -> u16 {
+        self.low
+    }
+
+    pub fn high(&self) -> u16 {
+        self.high
+    }
+}
+
+impl fmt::Display for SourcePorts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.low, self.high)
+    }
+}
+
+/// DiffServ code point that relayed traffic is marked with, so routers can
+/// prioritize it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// Accepts a code point from 0 to 63 or a class name: `EF`, `CS0`-`CS7`
+    /// or `AF11`-`AF43`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let name = s.trim().to_uppercase();
+        let value = match name.as_str() {
+            "EF" => Some(46),
+            _ => {
+                if let Some(class) = name.strip_prefix("CS") {
+                    class.parse::<u8>().ok().filter(|class| *class <= 7).map(|class| class << 3)
+                } else if let Some(af) = name.strip_prefix("AF") {
+                    match af.as_bytes() {
+                        [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => Some(((class - b'0') << 3) | ((drop - b'0') << 1)),
+                        _ => None,
+                    }
+                } else {
+                    name.parse::<u8>().ok().filter(|value| *value <= 63)
+                }
+            }
+        };
+        value
+            .map(Dscp)
+            .ok_or_else(|| format!("Invalid DSCP '{}'. Expected 0-63, EF, CS0-CS7 or AF11-AF43", s.trim()))
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+
+    /// The IP ToS / traffic class byte, which carries the code point in its upper six bits
+    pub fn tos(&self) -> u8 {
+        self.0 << 2
+    }
+}
+
+/// Bytes written to a client before closing it when its upstream connect fails
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailResponse(Vec<u8>);
+
+impl FailResponse {
+    /// Accepts a template name (`http502`, `http503`) or raw text, where
+    /// `\r`, `\n`, `\t`, `\\` and `\xHH` stand for the bytes they escape
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let bytes = match s.trim().to_lowercase().as_str() {
+            "http502" => b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+            "http503" => b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+            _ => unescape(s)?,
+        };
+        if bytes.is_empty() {
+            return Err("Fail response is empty".to_string());
+        }
+        Ok(FailResponse(bytes))
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = Some(&hex)
+                    .filter(|hex| hex.len() == 2 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("Invalid escape '\\x{}'. Expected two hex digits", hex))?;
+                bytes.push(byte);
+            }
+            Some(other) => return Err(format!("Unknown escape '\\{}'. Supported: \\r \\n \\t \\\\ \\xHH", other)),
+            None => return Err("Trailing '\\' in fail response".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Retries of a failed upstream connect, spaced by exponential backoff
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectRetry {
+    /// Connect attempts per connection, including the first
+    pub attempts: u32,
+    /// Delay before the first retry; each later one doubles it
+    pub base_delay: Duration,
+    /// Upper bound for any single delay
+    pub max_delay: Duration,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        ConnectRetry {
+            attempts: 1,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ConnectRetry {
+    /// Delay before retry number `retry` (1 for the first). Half of it is
+    /// random so clients that failed together don't all retry together.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        let jitter = RandomState::new().build_hasher().finish() % (half.as_nanos() as u64 + 1);
+        half + Duration::from_nanos(jitter)
+    }
+}
+
+/// Active connection counts that drive a listener's shedding: once `high`
+/// are open new connections are closed straight away, until fewer than
+/// `low` are left. The gap keeps it from flapping around a single limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShedMarks {
+    high: u64,
+    low: u64,
+}
+
+impl ShedMarks {
+    pub fn new(high: u64, low: u64) -> Result<Self, String> {
+        if low == 0 || low > high {
+            return Err(format!("Low-water mark {} must be between 1 and the high-water mark {}", low, high));
+        }
+        Ok(ShedMarks { high, low })
+    }
+
+    pub fn high(&self) -> u64 {
+        self.high
+    }
+
+    pub fn low(&self) -> u64 {
+        self.low
+    }
+}
+
+/// How long a pooled upstream connection waits for its next client unless
+/// `reuse_idle=` says otherwise
+pub const DEFAULT_REUSE_IDLE: Duration = Duration::from_secs(60);
+
+/// Keeps upstream connections open after their client leaves, so the next
+/// client to the same upstream is relayed over one instead of a new connect.
+///
+/// Raw TCP carries no message boundaries, so this is only safe for upstreams
+/// that treat each connection as a run of independent requests, such as
+/// HTTP/1.1 with keep-alive, and whose clients close only after their last
+/// response. A connection is pooled only when its client closed cleanly and
+/// everything was delivered; the upstream closing it or sending anything
+/// while it waits takes it out of the pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpstreamReuse {
+    /// Most idle connections kept, over all upstreams of the listener
+    pub pool_size: usize,
+    /// How long an idle connection is kept before it is closed
+    pub idle_timeout: Duration,
+}
+
+impl UpstreamReuse {
+    pub fn new(pool_size: usize) -> Result<Self, String> {
+        if pool_size == 0 {
+            return Err("Upstream pool size must be at least 1".to_string());
+        }
+        Ok(UpstreamReuse { pool_size, idle_timeout: DEFAULT_REUSE_IDLE })
+    }
+}
+
+/// Optional settings for a proxy service beyond its addresses.
+///
+/// `ProxyOptions::default()` gives the plain relay behavior.
+#[derive(Clone, Default)]
+pub struct ProxyOptions {
+    /// Registry that live connections are published to (used by the admin API)
+    pub registry: Option<Arc<ConnectionRegistry>>,
+    /// Friendly name used in logs, falling back to the listen address
+    pub name: Option<String>,
+    /// Local IP that upstream connections are bound to before connecting
+    pub bind_source: Option<IpAddr>,
+    /// Local ports upstream connections are bound within
+    pub source_ports: Option<SourcePorts>,
+    /// Secondary upstream that receives a copy of the client's bytes
+    pub mirror: Option<SocketAddr>,
+    /// Upstream for connections that open with an HTTP request line; the
+    /// mapping's main upstream becomes the fallback for everything else
+    pub http_upstream: Option<SocketAddr>,
+    /// Log a hex dump of up to this many bytes of each connection's first read
+    pub peek_bytes: Option<usize>,
+    /// StatsD client that connection counters, gauges and timings go to
+    pub statsd: Option<Arc<StatsdClient>>,
+    /// Tag StatsD metrics with the client's subnet at these prefix lengths
+    pub client_subnets: Option<ClientSubnets>,
+    /// Close connections once they have been open this long, busy or not
+    pub max_lifetime: Option<Duration>,
+    /// How mappings with several upstreams pick one per connection
+    pub lb_strategy: LbStrategy,
+    /// Whether writes are flushed right away or batched
+    pub flush_mode: FlushMode,
+    /// Size read buffers to the traffic instead of a fixed size
+    pub adaptive_buffer: Option<AdaptiveBuffer>,
+    /// Delay relayed reads before forwarding them, for chaos testing
+    pub inject_delay: Option<InjectedDelay>,
+    /// Close connections that see no traffic in either direction for this long
+    pub idle_timeout: Option<Duration>,
+    /// Scale the idle timeout with each connection's round trip instead
+    pub adaptive_idle: Option<AdaptiveIdle>,
+    /// Close connections when relaying a read to the other side takes longer than this
+    pub write_timeout: Option<Duration>,
+    /// Give up on an upstream connect after this long
+    pub connect_timeout: Option<Duration>,
+    /// How failed upstream connects are retried; no retries by default
+    pub connect_retry: ConnectRetry,
+    /// Rolling rate of new connections, shared by all listeners
+    pub connection_rate: Option<Arc<ConnectionRate>>,
+    /// Called as relayed connections start and end
+    pub observer: Option<Arc<dyn ConnectionObserver>>,
+    /// Mark upstream connections with this DSCP
+    pub dscp: Option<Dscp>,
+    /// Mark the client side with `dscp` as well
+    pub dscp_downstream: bool,
+    /// Close connections once the client has sent this many bytes upstream
+    pub max_up_bytes: Option<u64>,
+    /// Close connections once the upstream has sent this many bytes to the client
+    pub max_down_bytes: Option<u64>,
+    /// Per-backend totals, shared by all listeners
+    pub backend_traffic: Option<Arc<BackendTraffic>>,
+    /// Shed new connections while this listener is this busy
+    pub shed: Option<ShedMarks>,
+    /// Shed some new connections for an upstream while fewer than this share
+    /// (0 to 1) of its recent connects succeed
+    pub admission_ratio: Option<f64>,
+    /// New connections each listener takes per second; faster arrivals wait their turn
+    pub accept_rate: Option<f64>,
+    /// Log relayed writes (with their flush) that take longer than this, at debug level
+    pub slow_io_threshold: Option<Duration>,
+    /// Mark connections backpressured when a relayed write (with its flush)
+    /// waits longer than this on a side that isn't draining
+    pub backpressure_threshold: Option<Duration>,
+    /// Log and count connections still open after this long, without closing them
+    pub long_conn_threshold: Option<Duration>,
+    /// Caps connections per backend address, shared by all listeners
+    pub backend_limiter: Option<Arc<BackendLimiter>>,
+    /// Caps connections relayed at once, shared by all listeners
+    pub connection_limiter: Option<Arc<ConnectionLimiter>>,
+    /// Upstream connect times, shared by all listeners
+    pub connect_latency: Option<Arc<LatencyHistogram>>,
+    /// Bytes relayed per finished connection, shared by all listeners
+    pub connection_sizes: Option<Arc<SizeHistogram>>,
+    /// Sent to clients of fixed upstreams whose upstream connect fails, before closing
+    pub fail_response: Option<FailResponse>,
+    /// Add the client's address to `X-Forwarded-For` on requests routed to `http_upstream`
+    pub forwarded_for: bool,
+    /// Log each client at debug level as soon as it is accepted, before the upstream is dialed
+    pub log_accept: bool,
+    /// Levels of each connection's start and end lines
+    pub conn_log_levels: ConnLogLevels,
+    /// Reach upstreams through this SOCKS5 proxy instead of dialing them directly
+    pub upstream_socks5: Option<Socks5Upstream>,
+    /// Most bytes read from a CONNECT request head or SOCKS5 handshake,
+    /// `connect::DEFAULT_MAX_HANDSHAKE` when unset
+    pub max_handshake: Option<usize>,
+    /// Pool upstream connections whose client closed, for later clients to reuse
+    pub upstream_reuse: Option<UpstreamReuse>,
+    /// Open connections over all listeners and their peak, shared by all listeners
+    pub concurrency: Option<Arc<Concurrency>>,
+    /// Check each relay for dropped or duplicated bytes, in debug builds
+    pub self_check: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dscp() {
+        for (input, value) in [("46", 46), ("0", 0), ("63", 63), ("ef", 46), ("CS0", 0), ("CS5", 40), ("cs7", 56), ("AF11", 10), ("AF43", 38)] {
+            assert_eq!(Dscp::parse(input).unwrap().value(), value, "{}", input);
+        }
+        assert_eq!(Dscp::parse("EF").unwrap().tos(), 184);
+        for input in ["64", "-1", "CS8", "AF14", "AF51", "AF1", "", "best"] {
+            assert!(Dscp::parse(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off_with_jitter() {
+        let retry = ConnectRetry { attempts: 5, ..Default::default() };
+        for (n, full) in [(1, 50), (2, 100), (3, 200), (4, 400), (5, 800), (6, 1000), (40, 1000)] {
+            let full = Duration::from_millis(full);
+            for _ in 0..20 {
+                let delay = retry.delay(n);
+                assert!(delay >= full / 2 && delay <= full, "retry {}: {:?} outside {:?}..={:?}", n, delay, full / 2, full);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_fail_response() {
+        assert!(FailResponse::parse("HTTP502").unwrap().bytes().starts_with(b"HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(FailResponse::parse("http503").unwrap().bytes().starts_with(b"HTTP/1.1 503 "));
+        assert_eq!(FailResponse::parse(r"busy\r\n").unwrap().bytes(), b"busy\r\n");
+        assert_eq!(FailResponse::parse(r"\x00\xffa\\b\t").unwrap().bytes(), b"\x00\xffa\\b\t");
+        for input in ["", r"\q", r"\x1", r"\x+f", r"\xzz", "trailing\\"] {
+            assert!(FailResponse::parse(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_bind_failure() {
+        assert_eq!(BindFailure::parse("abort").unwrap(), BindFailure::Abort);
+        assert_eq!(BindFailure::parse(" Skip ").unwrap(), BindFailure::Skip);
+        assert!(BindFailure::parse("retry").is_err());
+    }
+
+    #[test]
+    fn test_parse_source_ports() {
+        let ports = SourcePorts::parse(" 40000-41000 ").unwrap();
+        assert_eq!((ports.low(), ports.high()), (40000, 41000));
+        assert_eq!(ports.to_string(), "40000-41000");
+        for input in ["40000", "41000-40000", "40000-40000", "0-100", "40000-70000", "a-b", ""] {
+            assert!(SourcePorts::parse(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+
+    #[test]
+    fn test_adaptive_buffer_validated() {
+        assert!(AdaptiveBuffer::new(1024, 65536, 2).is_ok());
+        assert!(AdaptiveBuffer::new(4096, 4096, 4).is_ok());
+        assert!(AdaptiveBuffer::new(0, 65536, 2).is_err());
+        assert!(AdaptiveBuffer::new(8192, 4096, 2).is_err());
+        assert!(AdaptiveBuffer::new(1024, 65536, 1).is_err());
+    }
+
+    #[test]
+    fn test_adaptive_idle_bounds() {
+        let secs = Duration::from_secs;
+        let adaptive = AdaptiveIdle::new(secs(30), secs(3600), 100).unwrap();
+        assert_eq!(adaptive.timeout(Duration::from_millis(200)), secs(30), "A LAN round trip gets the minimum");
+        assert_eq!(adaptive.timeout(Duration::from_millis(800)), secs(80));
+        assert_eq!(adaptive.timeout(secs(60)), secs(3600));
+        assert_eq!(adaptive.initial(Some(secs(5))), secs(30));
+        assert_eq!(adaptive.initial(Some(secs(300))), secs(300));
+        assert_eq!(adaptive.initial(None), secs(3600));
+        assert!(AdaptiveIdle::new(secs(60), secs(30), 100).is_err());
+        assert!(AdaptiveIdle::new(secs(30), secs(60), 0).is_err());
+    }
+
+    #[test]
+    fn test_client_subnet_buckets() {
+        let subnets = ClientSubnets::parse("/24").unwrap();
+        assert_eq!(subnets.bucket("10.1.2.3".parse().unwrap()), "10.1.2.0/24");
+        assert_eq!(subnets.bucket("::ffff:10.1.2.3".parse().unwrap()), "10.1.2.0/24");
+        assert_eq!(subnets.bucket("2001:db8:aa:bb::1".parse().unwrap()), "2001:db8:aa::/48");
+        let subnets = ClientSubnets::parse(" 16 , /64 ").unwrap();
+        assert_eq!(subnets.bucket("10.1.2.3".parse().unwrap()), "10.1.0.0/16");
+        assert_eq!(subnets.bucket("2001:db8:aa:bb::1".parse().unwrap()), "2001:db8:aa:bb::/64");
+        let everything = ClientSubnets::parse("0,0").unwrap();
+        assert_eq!(everything.bucket("10.1.2.3".parse().unwrap()), "0.0.0.0/0");
+        assert_eq!(ClientSubnets::parse("32,128").unwrap().bucket("::1".parse().unwrap()), "::1/128");
+        for input in ["", "/33", "24,/129", "24,48,64", "abc"] {
+            assert!(ClientSubnets::parse(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_delay_direction() {
+        assert_eq!(DelayDirection::parse(" Up ").unwrap(), DelayDirection::Up);
+        let both = DelayDirection::parse("both").unwrap();
+        assert!(both.up() && both.down());
+        let down = DelayDirection::parse("down").unwrap();
+        assert!(!down.up() && down.down());
+        assert!(DelayDirection::parse("sideways").is_err());
+    }
+
+    #[test]
+    fn test_shed_marks_validated() {
+        assert!(ShedMarks::new(10, 8).is_ok());
+        assert!(ShedMarks::new(10, 10).is_ok());
+        assert!(ShedMarks::new(10, 11).is_err());
+        assert!(ShedMarks::new(10, 0).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+
+use crate::statsd::StatsdClient;
+
+/// Seconds of history the connection rate is averaged over
+pub const RATE_WINDOW_SECS: u64 = 60;
+
+/// New connections per second over the last minute, across all listeners.
+///
+/// Arrivals are counted in one bucket per second, so memory stays the same
+/// however many connections a burst brings.
+#[derive(Debug)]
+pub struct ConnectionRate {
+    origin: Instant,
+    // (second since origin, connections in that second), indexed by second % window
+    buckets: Mutex<[(u64, u64); RATE_WINDOW_SECS as usize]>,
+}
+
+impl Default for ConnectionRate {
+    fn default() -> Self {
+        ConnectionRate::new(Instant::now())
+    }
+}
+
+impl ConnectionRate {
+    pub fn new(origin: Instant) -> Self {
+        ConnectionRate {
+            origin,
+            buckets: Mutex::new([(0, 0); RATE_WINDOW_SECS as usize]),
+        }
+    }
+
+    pub fn record(&self) {
+        self.record_at(Instant::now());
+    }
+
+    pub fn record_at(&self, at: Instant) {
+        let second = at.saturating_duration_since(self.origin).as_secs();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = &mut buckets[(second % RATE_WINDOW_SECS) as usize];
+        if bucket.0 != second {
+            // The slot last held a second that has left the window
+            *bucket = (second, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.per_second_at(Instant::now())
+    }
+
+    pub fn per_second_at(&self, now: Instant) -> f64 {
+        let now = now.saturating_duration_since(self.origin).as_secs();
+        let buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let total: u64 = buckets
+            .iter()
+            .filter(|(second, _)| *second <= now && now - second < RATE_WINDOW_SECS)
+            .map(|(_, count)| count)
+            .sum();
+        total as f64 / RATE_WINDOW_SECS as f64
+    }
+}
+
+/// Paces how fast a listener takes new connections: a token bucket refilled
+/// at `rate` per second that holds a tenth of a second's worth, so short
+/// bursts pass at once. A connection that finds the bucket empty waits for
+/// its token instead of being refused, spreading a burst out rather than
+/// dropping it.
+#[derive(Debug)]
+pub struct AcceptPacer {
+    rate: f64,
+    capacity: f64,
+    // (tokens, when last refilled); goes negative while connections wait their turn
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl AcceptPacer {
+    /// Accepts `rate` (above 0) connections per second
+    pub fn new(rate: f64) -> Self {
+        let capacity = (rate / 10.0).max(1.0);
+        AcceptPacer { rate, capacity, bucket: Mutex::new((capacity, Instant::now())) }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Takes a token for a connection arriving now, returning how long it
+    /// has to wait for it
+    pub fn delay(&self) -> Duration {
+        self.delay_at(Instant::now())
+    }
+
+    pub fn delay_at(&self, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let (tokens, refilled) = *bucket;
+        let tokens = (tokens + now.saturating_duration_since(refilled).as_secs_f64() * self.rate).min(self.capacity) - 1.0;
+        *bucket = (tokens, now.max(refilled));
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+}
+
+/// Parses a rate as `PJ_ACCEPT_RATE` takes it: a count per second (`500/s`
+/// or just `500`) or per minute (`6000/m`)
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let (count, per) = match s.split_once('/') {
+        Some((count, unit)) => match unit.trim() {
+            "s" => (count, 1.0),
+            "m" => (count, 60.0),
+            _ => return Err(format!("Unknown rate unit '{}'. Supported: s, m", unit.trim())),
+        },
+        None => (s, 1.0),
+    };
+    match count.trim().parse::<f64>() {
+        Ok(count) if count > 0.0 && count.is_finite() => Ok(count / per),
+        _ => Err(format!("'{}' is not a rate above 0, e.g. 500/s", s)),
+    }
+}
+
+/// Logs the connection rate periodically, and sends it to StatsD when configured
+pub struct RateReporter {
+    rate: Arc<ConnectionRate>,
+    statsd: Option<Arc<StatsdClient>>,
+    interval: Duration,
+}
+
+impl RateReporter {
+    pub fn new(rate: Arc<ConnectionRate>, statsd: Option<Arc<StatsdClient>>, interval: Duration) -> Self {
+        RateReporter { rate, statsd, interval }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for RateReporter {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = shutdown.changed() => return,
+            }
+            let rate = self.rate.per_second();
+            info!("Connection rate: {:.2}/s over the last {}s", rate, RATE_WINDOW_SECS);
+            if let Some(statsd) = &self.statsd {
+                statsd.gauge("connections.per_second", format!("{:.2}", rate), &[]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_over_window() {
+        let start = Instant::now();
+        let rate = ConnectionRate::new(start);
+        // 120 connections spread over the first 30 seconds
+        for i in 0..120 {
+            rate.record_at(start + Duration::from_millis(i * 250));
+        }
+        assert_eq!(rate.per_second_at(start + Duration::from_secs(30)), 2.0);
+
+        // Those seconds leave the window one by one
+        assert_eq!(rate.per_second_at(start + Duration::from_secs(74)), 1.0);
+        assert_eq!(rate.per_second_at(start + Duration::from_secs(90)), 0.0);
+    }
+
+    #[test]
+    fn test_burst_reuses_buckets() {
+        let start = Instant::now();
+        let rate = ConnectionRate::new(start);
+        for _ in 0..10_000 {
+            rate.record_at(start + Duration::from_secs(5));
+        }
+        // A minute later the same slot is reused and the burst is forgotten
+        rate.record_at(start + Duration::from_secs(65));
+        assert_eq!(rate.per_second_at(start + Duration::from_secs(65)), 1.0 / 60.0);
+    }
+
+    #[test]
+    fn test_accept_pacer_spreads_a_burst() {
+        let pacer = AcceptPacer::new(100.0);
+        let start = Instant::now();
+        *pacer.bucket.lock().unwrap() = (pacer.capacity, start);
+
+        // A tenth of a second's worth passes at once
+        for _ in 0..10 {
+            assert_eq!(pacer.delay_at(start), Duration::ZERO);
+        }
+        // Then each connection waits 10ms longer than the one before it
+        let millis: Vec<u64> = (0..3).map(|_| (pacer.delay_at(start).as_secs_f64() * 1000.0).round() as u64).collect();
+        assert_eq!(millis, vec![10, 20, 30]);
+
+        // Once the waiters have had their turn, the bucket refills up to its capacity
+        assert_eq!(pacer.delay_at(start + Duration::from_millis(40)), Duration::ZERO);
+        assert_eq!(pacer.delay_at(start + Duration::from_secs(60)), Duration::ZERO);
+        for _ in 0..9 {
+            pacer.delay_at(start + Duration::from_secs(60));
+        }
+        assert!(pacer.delay_at(start + Duration::from_secs(60)) > Duration::ZERO);
+
+        assert_eq!(parse_rate("500/s").unwrap(), 500.0);
+        assert_eq!(parse_rate(" 250 ").unwrap(), 250.0);
+        assert_eq!(parse_rate("6000/m").unwrap(), 100.0);
+        for input in ["0/s", "-5", "fast", "500/h", "/s"] {
+            assert!(parse_rate(input).is_err(), "Expected an error for '{}'", input);
+        }
+    }
+}
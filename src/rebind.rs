@@ -0,0 +1,319 @@
+use async_trait::async_trait;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use pingora_core::apps::ServerApp;
+#[cfg(unix)]
+use pingora_core::server::ListenFds;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::protocols::l4::listener::Listener;
+use pingora_core::services::listening::Service as ListeningService;
+use pingora_core::services::Service;
+
+use crate::activation::bind_listener;
+use crate::registry::ConnectionRegistry;
+
+/// How a rebinding listener binds its address and tells whether it went away
+pub trait Bind: Send + Sync {
+    /// A listening socket on `addr`
+    fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener>;
+
+    /// Whether `addr`'s IP address is still assigned to this host
+    fn available(&self, addr: SocketAddr) -> bool;
+}
+
+/// Binds with `activation::bind_listener`, optionally to an interface
+pub struct SystemBind {
+    interface: Option<String>,
+}
+
+impl SystemBind {
+    pub fn new(interface: Option<&str>) -> Self {
+        SystemBind { interface: interface.map(str::to_string) }
+    }
+}
+
+impl Bind for SystemBind {
+    fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        bind_listener(addr, self.interface.as_deref())
+    }
+
+    fn available(&self, addr: SocketAddr) -> bool {
+        // Any port on the address binds as long as the address is still assigned
+        !matches!(TcpListener::bind((addr.ip(), 0)), Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable)
+    }
+}
+
+/// Runs a listening app on an address that may come and go, such as one
+/// assigned by DHCP or moved on failover.
+///
+/// Every `interval` the listener checks that its address is still assigned.
+/// Once it isn't, the listener is closed and rebinding is tried every
+/// `interval` until it succeeds; connections already relayed and other
+/// listeners carry on meanwhile. The socket is the listener's own, so it
+/// isn't handed on at a graceful upgrade.
+pub struct RebindingListener<A> {
+    app: Arc<A>,
+    addr: SocketAddr,
+    interval: Duration,
+    bind: Box<dyn Bind>,
+    listener: Option<TcpListener>,
+    registry: Option<Arc<ConnectionRegistry>>,
+    name: String,
+}
+
+impl<A> RebindingListener<A> {
+    pub fn new(app: A, addr: SocketAddr, interface: Option<&str>, interval: Duration) -> Self {
+        RebindingListener {
+            app: Arc::new(app),
+            addr,
+            interval,
+            bind: Box::new(SystemBind::new(interface)),
+            listener: None,
+            registry: None,
+            name: format!("Rebinding {}", addr),
+        }
+    }
+
+    /// Start with a socket bound before the server starts instead of binding at startup
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Bind and check the address with `bind` instead of the system calls
+    pub fn with_bind(mut self, bind: impl Bind + 'static) -> Self {
+        self.bind = Box::new(bind);
+        self
+    }
+
+    /// Keep the listener's bound flag in `registry` up to date as the address
+    /// goes away and comes back
+    pub fn with_registry(mut self, registry: Arc<ConnectionRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    fn set_bound(&self, bound: bool) {
+        if let Some(registry) = &self.registry {
+            let listen_addr = self.addr.to_string();
+            match bound {
+                true => registry.record_bound(&listen_addr),
+                false => registry.record_unbound(&listen_addr),
+            }
+        }
+    }
+
+    /// Binds the address, logging the outcome of the `attempt`th try
+    fn rebind(&self, attempt: u32) -> Option<Listener> {
+        match self.bind.bind(self.addr).and_then(into_listener) {
+            Ok(listener) if attempt == 0 => Some(listener),
+            Ok(listener) => {
+                info!("Rebound {} after {} attempts", self.addr, attempt);
+                Some(listener)
+            }
+            Err(e) if attempt == 0 => {
+                warn!("Failed to bind {}, retrying every {:?}: {}", self.addr, self.interval, e);
+                None
+            }
+            Err(e) => {
+                info!("Rebinding {} failed (attempt {}): {}", self.addr, attempt, e);
+                None
+            }
+        }
+    }
+}
+
+fn into_listener(listener: TcpListener) -> io::Result<Listener> {
+    listener.set_nonblocking(true)?;
+    Ok(tokio::net::TcpListener::from_std(listener)?.into())
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> Service for RebindingListener<A> {
+    async fn start_service(&mut self, #[cfg(unix)] _fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        let mut listener = match self.listener.take() {
+            Some(listener) => into_listener(listener)
+                .map_err(|e| warn!("Failed to use the socket for {}: {}", self.addr, e))
+                .ok(),
+            None => None,
+        };
+        if listener.is_none() {
+            listener = self.rebind(0);
+        }
+        self.set_bound(listener.is_some());
+        let mut attempts = 0;
+        let mut checks = tokio::time::interval(self.interval);
+        checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        checks.tick().await;
+
+        loop {
+            let Some(accepting) = &listener else {
+                tokio::select! {
+                    _ = checks.tick() => {
+                        attempts += 1;
+                        listener = self.rebind(attempts);
+                        if listener.is_some() {
+                            self.set_bound(true);
+                        }
+                    }
+                    _ = shutdown.changed() => break,
+                }
+                continue;
+            };
+            tokio::select! {
+                accepted = accepting.accept() => match accepted {
+                    Ok(io) => {
+                        let (app, shutdown) = (self.app.clone(), shutdown.clone());
+                        tokio::spawn(ListeningService::handle_event(Box::new(io), app, shutdown));
+                    }
+                    Err(e) if !self.bind.available(self.addr) => {
+                        warn!("Accept on {} failed and the address is gone, rebinding every {:?}: {}", self.addr, self.interval, e);
+                        (listener, attempts) = (None, 0);
+                        self.set_bound(false);
+                    }
+                    Err(e) => {
+                        warn!("Accept on {} failed: {}", self.addr, e);
+                        // Out of file descriptors, accept fails at once until some are freed
+                        if e.raw_os_error() == Some(24) {
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                },
+                _ = checks.tick() => {
+                    if !self.bind.available(self.addr) {
+                        warn!("Listen address {} is no longer available, closing its listener and rebinding every {:?}",
+                              self.addr, self.interval);
+                        (listener, attempts) = (None, 0);
+                        self.set_bound(false);
+                    }
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+        info!("Shutting down {}", self.addr);
+        self.app.cleanup().await;
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::watch;
+
+    use pingora_core::protocols::Stream;
+
+    /// Greets each connection and closes it
+    struct Greeter;
+
+    #[async_trait]
+    impl ServerApp for Greeter {
+        async fn process_new(self: &Arc<Self>, mut io: Stream, _shutdown: &ShutdownWatch) -> Option<Stream> {
+            let _ = io.write_all(b"hi").await;
+            let _ = io.flush().await;
+            None
+        }
+    }
+
+    /// An address that can be taken away and given back
+    struct FlakyBind {
+        up: Arc<AtomicBool>,
+    }
+
+    impl Bind for FlakyBind {
+        fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+            match self.up.load(Ordering::SeqCst) {
+                true => bind_listener(addr, None),
+                false => Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+            }
+        }
+
+        fn available(&self, _addr: SocketAddr) -> bool {
+            self.up.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn greets(addr: SocketAddr) -> bool {
+        use tokio::io::AsyncReadExt;
+        let Ok(mut client) = tokio::net::TcpStream::connect(addr).await else { return false };
+        let mut greeting = Vec::new();
+        let _ = client.read_to_end(&mut greeting).await;
+        greeting == b"hi"
+    }
+
+    async fn eventually(addr: SocketAddr, expected: bool) {
+        for _ in 0..100 {
+            if greets(addr).await == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} never {}", addr, if expected { "came back" } else { "stopped listening" });
+    }
+
+    #[tokio::test]
+    async fn test_rebinds_when_address_returns() {
+        let interval = Duration::from_millis(50);
+        let up = Arc::new(AtomicBool::new(true));
+        let socket = bind_listener("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let registry = Arc::new(ConnectionRegistry::new());
+        registry.register_listener(&addr.to_string());
+        let bound = {
+            let registry = registry.clone();
+            move || registry.listeners()[0].bound
+        };
+        let mut service = RebindingListener::new(Greeter, addr, None, interval)
+            .with_listener(socket)
+            .with_bind(FlakyBind { up: up.clone() })
+            .with_registry(registry.clone());
+        let (stop, shutdown) = watch::channel(false);
+        let running = tokio::spawn(async move {
+            service.start_service(#[cfg(unix)] None, shutdown).await;
+        });
+
+        assert!(greets(addr).await);
+        assert!(bound());
+
+        // The address goes away: the listener closes
+        up.store(false, Ordering::SeqCst);
+        eventually(addr, false).await;
+        assert!(!bound(), "/healthz should see the listener is down");
+
+        // It comes back: the listener is bound again
+        up.store(true, Ordering::SeqCst);
+        eventually(addr, true).await;
+        assert!(bound());
+
+        stop.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), running).await.expect("Should stop on shutdown").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_binds_once_address_appears() {
+        // An address that isn't there at startup is bound once it is
+        let up = Arc::new(AtomicBool::new(false));
+        let addr = bind_listener("127.0.0.1:0".parse().unwrap(), None).unwrap().local_addr().unwrap();
+        let mut service = RebindingListener::new(Greeter, addr, None, Duration::from_millis(50))
+            .with_bind(FlakyBind { up: up.clone() });
+        let (_stop, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            service.start_service(#[cfg(unix)] None, shutdown).await;
+        });
+
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(!greets(addr).await);
+        up.store(true, Ordering::SeqCst);
+        eventually(addr, true).await;
+    }
+}
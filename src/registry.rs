@@ -0,0 +1,413 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+use crate::connection::{ByteCounters, ClientAddr, CloseKind, ConnectionInfo};
+
+/// Live connections currently being proxied, shared between the proxy
+/// services and the admin API.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    // Connection IDs can repeat after an ID reset, so entries are keyed by
+    // a registry-local sequence instead
+    next_key: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<ActiveConnection>>>,
+    // Pause flag and last upstream error of each listener, keyed by listen address
+    listeners: Mutex<HashMap<String, ListenerState>>,
+    shutting_down: AtomicBool,
+}
+
+#[derive(Default)]
+struct ListenerState {
+    paused: Arc<AtomicBool>,
+    bound: bool,
+    last_error: Option<(String, SystemTime)>,
+    empty_connections: u64,
+    graceful_closes: u64,
+    reset_closes: u64,
+    long_connections: u64,
+}
+
+pub struct ActiveConnection {
+    info: ConnectionInfo,
+    bytes: ByteCounters,
+    close_requested: Notify,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerSnapshot {
+    pub listen_addr: String,
+    pub paused: bool,
+    /// Whether its socket was bound
+    pub bound: bool,
+    /// The listener's most recent upstream connect or transfer failure
+    pub last_error: Option<String>,
+    /// When it happened, in seconds since the Unix epoch
+    pub last_error_at: Option<f64>,
+    /// Connections that closed without relaying a byte either way
+    pub empty_connections: u64,
+    /// Connections a side closed with a FIN
+    pub graceful_closes: u64,
+    /// Connections a side reset with an RST
+    pub reset_closes: u64,
+    /// Connections that stayed open past the long-lived threshold
+    pub long_connections: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionSnapshot {
+    /// Unique for the life of the process, unlike `id`, which repeats after
+    /// an ID reset or across mappings counting their own IDs
+    pub key: u64,
+    pub id: u64,
+    pub name: String,
+    pub client_addr: ClientAddr,
+    pub proxy_addr: String,
+    pub backend_addr: String,
+    pub duration_secs: f64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Handle held by `duplex` for the lifetime of a connection. Dropping it
+/// removes the connection from the registry, so every exit path cleans up.
+pub struct Registration {
+    registry: Arc<ConnectionRegistry>,
+    key: u64,
+    connection: Arc<ActiveConnection>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(self: &Arc<Self>, info: &ConnectionInfo) -> Registration {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(ActiveConnection {
+            info: info.clone(),
+            bytes: ByteCounters::default(),
+            close_requested: Notify::new(),
+        });
+
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, connection.clone());
+
+        Registration {
+            registry: self.clone(),
+            key,
+            connection,
+        }
+    }
+
+    /// Snapshot of all live connections, ordered by connection ID
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        let mut snapshots: Vec<ConnectionSnapshot> = self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(key, conn)| conn.snapshot(*key))
+            .collect();
+        snapshots.sort_by_key(|s| (s.id, s.key));
+        snapshots
+    }
+
+    /// Ask the live connection with the given registry key to shut down.
+    /// Returns false if there is none.
+    pub fn close(&self, key: u64) -> bool {
+        match self.connections.lock().unwrap_or_else(PoisonError::into_inner).get(&key) {
+            Some(conn) => {
+                // notify_one stores a permit, so the request isn't lost if duplex
+                // is busy writing rather than waiting in select!
+                conn.close_requested.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the pause flag for the listener on `listen_addr`, which the
+    /// proxy checks before accepting each connection
+    pub fn register_listener(&self, listen_addr: &str) -> Arc<AtomicBool> {
+        self.listeners
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(listen_addr.to_string())
+            .or_default()
+            .paused
+            .clone()
+    }
+
+    /// Records that the listener on `listen_addr` has its socket bound
+    pub fn record_bound(&self, listen_addr: &str) {
+        if let Some(listener) = self.listeners.lock().unwrap_or_else(PoisonError::into_inner).get_mut(listen_addr) {
+            listener.bound = true;
+        }
+    }
+
+    /// Records that the listener on `listen_addr` lost its socket
+    pub fn record_unbound(&self, listen_addr: &str) {
+        if let Some(listener) = self.listeners.lock().unwrap_or_else(PoisonError::into_inner).get_mut(listen_addr) {
+            listener.bound = false;
+        }
+    }
+
+    /// Records that a graceful shutdown has begun
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Records `error` as the latest upstream failure of the listener on `listen_addr`
+    pub fn record_error(&self, listen_addr: &str, error: &str) {
+        self.record_error_at(listen_addr, error, SystemTime::now());
+    }
+
+    pub fn record_error_at(&self, listen_addr: &str, error: &str, at: SystemTime) {
+        if let Some(listener) = self.listeners.lock().unwrap_or_else(PoisonError::into_inner).get_mut(listen_addr) {
+            listener.last_error = Some((error.to_string(), at));
+        }
+    }
+
+    /// Counts a connection on `listen_addr` that closed without relaying anything
+    pub fn record_empty(&self, listen_addr: &str) {
+        if let Some(listener) = self.listeners.lock().unwrap_or_else(PoisonError::into_inner).get_mut(listen_addr) {
+            listener.empty_connections += 1;
+        }
+    }
+
+    /// Counts how a side closed a connection on `listen_addr`
+    pub fn record_close(&self, listen_addr: &str, kind: CloseKind) {
+        if let Some(listener) = self.listeners.lock().unwrap_or_else(PoisonError::into_inner).get_mut(listen_addr) {
+            match kind {
+                CloseKind::Graceful => listener.graceful_closes += 1,
+                CloseKind::Reset => listener.reset_closes += 1,
+            }
+        }
+    }
+
+    /// Counts a connection on `listen_addr` that stayed open past the long-lived threshold
+    pub fn record_long_lived(&self, listen_addr: &str) {
+        if let Some(listener) = self.listeners.lock().unwrap_or_else(PoisonError::into_inner).get_mut(listen_addr) {
+            listener.long_connections += 1;
+        }
+    }
+
+    /// Pauses or resumes the listener on `listen_addr`. Returns false if no
+    /// listener has that address.
+    pub fn set_paused(&self, listen_addr: &str, paused: bool) -> bool {
+        match self.listeners.lock().unwrap_or_else(PoisonError::into_inner).get(listen_addr) {
+            Some(listener) => {
+                listener.paused.store(paused, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn listeners(&self) -> Vec<ListenerSnapshot> {
+        let listeners = self.listeners.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut snapshot: Vec<ListenerSnapshot> = listeners
+            .iter()
+            .map(|(addr, listener)| ListenerSnapshot {
+                listen_addr: addr.clone(),
+                paused: listener.paused.load(Ordering::Relaxed),
+                bound: listener.bound,
+                last_error: listener.last_error.as_ref().map(|(error, _)| error.clone()),
+                last_error_at: listener.last_error.as_ref().map(|(_, at)| {
+                    at.duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64())
+                }),
+                empty_connections: listener.empty_connections,
+                graceful_closes: listener.graceful_closes,
+                reset_closes: listener.reset_closes,
+                long_connections: listener.long_connections,
+            })
+            .collect();
+        snapshot.sort_by(|a, b| a.listen_addr.cmp(&b.listen_addr));
+        snapshot
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&self, key: u64) {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key);
+    }
+}
+
+impl ActiveConnection {
+    fn snapshot(&self, key: u64) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            key,
+            id: self.info.id,
+            name: self.info.name.clone(),
+            client_addr: self.info.client_addr.clone(),
+            proxy_addr: self.info.proxy_addr.clone(),
+            backend_addr: self.info.backend_addr.clone(),
+            duration_secs: self.info.start_instant.elapsed().as_secs_f64(),
+            bytes_sent: self.bytes.bytes_sent(),
+            bytes_received: self.bytes.bytes_received(),
+        }
+    }
+}
+
+impl Registration {
+    /// The counters the connection's `ConnectionStats` should count into,
+    /// so snapshots show its live totals
+    pub fn counters(&self) -> ByteCounters {
+        self.connection.bytes.clone()
+    }
+
+    /// Counts the connection as long-lived on its listener
+    pub fn record_long_lived(&self) {
+        self.registry.record_long_lived(&self.connection.info.proxy_addr);
+    }
+
+    /// Resolves once the admin API asks for this connection to be closed
+    pub async fn close_requested(&self) {
+        self.connection.close_requested.notified().await;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.remove(self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{CloseReason, ConnectionStats};
+    use crate::id_manager::ConnectionIdManager;
+
+    fn test_info(id_manager: &Arc<ConnectionIdManager>) -> ConnectionInfo {
+        ConnectionInfo::new(
+            ClientAddr::Inet("127.0.0.1:50000".parse().unwrap()),
+            "127.0.0.1:8080",
+            "127.0.0.1:9090",
+            1,
+            id_manager,
+        )
+    }
+
+    #[test]
+    fn test_register_and_snapshot() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+
+        let registration = registry.register(&test_info(&id_manager));
+        let mut stats = ConnectionStats::with_counters(registration.counters());
+        stats.add_sent(100);
+        stats.add_received(42);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].id, 0);
+        assert_eq!(snapshot[0].name, "127.0.0.1:8080");
+        assert_eq!(snapshot[0].backend_addr, "127.0.0.1:9090");
+        assert_eq!(snapshot[0].bytes_sent, 100);
+        assert_eq!(snapshot[0].bytes_received, 42);
+    }
+
+    #[test]
+    fn test_drop_removes_connection() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+
+        let first = registry.register(&test_info(&id_manager));
+        let second = registry.register(&test_info(&id_manager));
+        assert_eq!(registry.len(), 2);
+
+        drop(first);
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].id, 1);
+
+        drop(second);
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_close_signals_matching_connection() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+
+        let first = registry.register(&test_info(&id_manager));
+        let _second = registry.register(&test_info(&id_manager));
+
+        assert!(registry.close(registry.snapshot()[0].key));
+        assert!(!registry.close(42));
+
+        // The permit is stored even though nobody was waiting yet
+        tokio::time::timeout(std::time::Duration::from_secs(1), first.close_requested())
+            .await
+            .expect("close request should be observed");
+    }
+
+    #[tokio::test]
+    async fn test_repeated_ids_are_tracked_separately() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        // Threshold of 1 resets the ID manager on every connection
+        let id_manager = Arc::new(ConnectionIdManager::new(None, Some(1)));
+
+        let first = registry.register(&test_info(&id_manager));
+        let second = registry.register(&test_info(&id_manager));
+
+        let snapshot = registry.snapshot();
+        let ids: Vec<u64> = snapshot.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![0, 0]);
+        assert_ne!(snapshot[0].key, snapshot[1].key);
+
+        // Closing by key reaches only that connection
+        assert!(registry.close(snapshot[1].key));
+        let wait = std::time::Duration::from_millis(50);
+        assert!(tokio::time::timeout(wait, second.close_requested()).await.is_ok());
+        assert!(tokio::time::timeout(wait, first.close_requested()).await.is_err());
+    }
+
+    #[test]
+    fn test_last_error_per_listener() {
+        let registry = ConnectionRegistry::new();
+        registry.register_listener("127.0.0.1:8080");
+        registry.register_listener("127.0.0.1:8081");
+        registry.record_error("127.0.0.1:9999", "not a listener");
+        assert!(registry.listeners().iter().all(|listener| listener.last_error.is_none()));
+
+        let at = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        registry.record_error_at("127.0.0.1:8080", "10.0.0.1:80: connection refused", at);
+        registry.record_error_at("127.0.0.1:8080", "10.0.0.1:80: connection reset", at + std::time::Duration::from_secs(5));
+        let listeners = registry.listeners();
+        assert_eq!(listeners[0].last_error.as_deref(), Some("10.0.0.1:80: connection reset"));
+        assert_eq!(listeners[0].last_error_at, Some(1_700_000_005.0));
+        assert_eq!((listeners[1].last_error.as_deref(), listeners[1].last_error_at), (None, None));
+
+        registry.record_empty("127.0.0.1:8081");
+        registry.record_empty("127.0.0.1:8081");
+        let empty: Vec<u64> = registry.listeners().iter().map(|listener| listener.empty_connections).collect();
+        assert_eq!(empty, vec![0, 2]);
+
+        registry.record_close("127.0.0.1:8080", CloseReason::UpstreamEof.close_kind().unwrap());
+        registry.record_close("127.0.0.1:8080", CloseReason::PeerReset.close_kind().unwrap());
+        registry.record_close("127.0.0.1:8080", CloseKind::Reset);
+        let closes: Vec<(u64, u64)> = registry.listeners().iter().map(|listener| (listener.graceful_closes, listener.reset_closes)).collect();
+        assert_eq!(closes, vec![(1, 2), (0, 0)]);
+        assert_eq!(CloseReason::Timeout.close_kind(), None, "pj closed it itself");
+    }
+}
@@ -0,0 +1,1126 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::select;
+use tracing::{debug, error, info, warn};
+
+use pingora_core::protocols::Stream;
+
+use crate::connection::{hex_dump, CloseReason, ConnectionStats, TrafficCounters};
+use crate::error::ProxyError;
+use crate::mirror::Mirror;
+use crate::options::{AdaptiveBuffer, AdaptiveIdle, FlushMode, InjectedDelay};
+use crate::registry::Registration;
+
+/// Bytes read from either side at a time unless `RelayOptions` says otherwise
+pub const RELAY_BUFFER_SIZE: usize = 1024;
+
+/// How long `FlushMode::Coalesce` holds written bytes before flushing
+const COALESCE_DELAY: Duration = Duration::from_millis(5);
+
+/// Bytes `FlushMode::Buffered` collects each way before writing them out
+const BUFFERED_WRITE_SIZE: usize = 64 * 1024;
+
+/// Reads in a row that fill an adaptive buffer before it grows
+const GROW_AFTER: u32 = 4;
+
+/// Reads in a row that would fit in a smaller adaptive buffer before it shrinks
+const SHRINK_AFTER: u32 = 16;
+
+/// How `relay` runs one connection. `RelayOptions::default()` relays until a
+/// side closes, flushing every write.
+pub struct RelayOptions {
+    /// Size of each direction's read buffer
+    pub buffer_size: usize,
+    /// Grow and shrink each read buffer with the traffic, in place of `buffer_size`
+    pub adaptive_buffer: Option<AdaptiveBuffer>,
+    /// Whether writes are flushed right away or batched
+    pub flush_mode: FlushMode,
+    /// Close once the connection has been open this long, busy or not
+    pub max_lifetime: Option<Duration>,
+    /// Log and count the connection once it has been open this long, without
+    /// closing it; counted from `started_at` like `max_lifetime`
+    pub long_lived_threshold: Option<Duration>,
+    /// When the connection was opened, which `max_lifetime` counts from;
+    /// `None` counts from the start of the relay
+    pub started_at: Option<Instant>,
+    /// Sleep this long before forwarding each read, in the delay's direction
+    pub inject_delay: Option<InjectedDelay>,
+    /// Close after this long without traffic in either direction
+    pub idle_timeout: Option<Duration>,
+    /// Scale `idle_timeout` with the connection's measured round trip
+    pub adaptive_idle: Option<AdaptiveIdle>,
+    /// Close when relaying a read to the other side (its write and flush)
+    /// takes longer than this, as when the reader has stopped reading
+    pub write_timeout: Option<Duration>,
+    /// Close once the client has sent this many bytes upstream
+    pub max_up_bytes: Option<u64>,
+    /// Close once the upstream has sent this many bytes to the client
+    pub max_down_bytes: Option<u64>,
+    /// Log writes (with their flush) that take longer than this, at debug level
+    pub slow_io_threshold: Option<Duration>,
+    /// Count writes (with their flush) held up longer than this by a side not
+    /// draining in `ConnectionStats::backpressured`
+    pub backpressure_threshold: Option<Duration>,
+    /// Log a hex dump of up to this many bytes of the client's first read, at debug level
+    pub peek_bytes: Option<usize>,
+    /// Client bytes already read (e.g. for protocol detection), relayed before anything else
+    pub preamble: Vec<u8>,
+    /// Gets a copy of everything the client sends
+    pub mirror: Option<Mirror>,
+    /// Publishes the live byte totals to the admin API and lets it close the connection
+    pub registration: Option<Registration>,
+    /// Listener-wide totals the relayed bytes are added to
+    pub traffic: Option<TrafficCounters>,
+    /// Names the connection in the relay's log lines, e.g. `Conn #000001`
+    pub label: String,
+    /// Hand the upstream connection back in `RelayResult::upstream` when the
+    /// client closes first and everything relayed was delivered
+    pub keep_upstream: bool,
+    /// At a clean close, check that every byte read from one side was written
+    /// to the other and counted, logging an error for any that weren't; a
+    /// correctness harness, only done in debug builds
+    pub self_check: bool,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        RelayOptions {
+            buffer_size: RELAY_BUFFER_SIZE,
+            adaptive_buffer: None,
+            flush_mode: FlushMode::default(),
+            max_lifetime: None,
+            long_lived_threshold: None,
+            started_at: None,
+            inject_delay: None,
+            idle_timeout: None,
+            adaptive_idle: None,
+            write_timeout: None,
+            max_up_bytes: None,
+            max_down_bytes: None,
+            slow_io_threshold: None,
+            backpressure_threshold: None,
+            peek_bytes: None,
+            preamble: Vec::new(),
+            mirror: None,
+            registration: None,
+            traffic: None,
+            label: "Relay".to_string(),
+            keep_upstream: false,
+            self_check: false,
+        }
+    }
+}
+
+/// How a relay ended
+#[derive(Debug)]
+pub struct RelayResult {
+    /// Bytes delivered each way: `bytes_sent` to the client, `bytes_received` from it
+    pub stats: ConnectionStats,
+    pub reason: CloseReason,
+    /// What went wrong, or why the relay closed the connection itself;
+    /// `None` when a side closed cleanly
+    pub error: Option<String>,
+    /// The still open upstream connection, with `RelayOptions::keep_upstream`
+    pub upstream: Option<Stream>,
+}
+
+enum DuplexEvent {
+    DownstreamRead(usize),
+    UpstreamRead(usize),
+    CloseRequested,
+    LifetimeExpired,
+    LongLived,
+    FlushDue,
+    IdleTimeout,
+}
+
+/// The leg of a relay an I/O failure happened on
+#[derive(Clone, Copy)]
+enum Side {
+    Downstream,
+    Upstream,
+}
+
+impl Side {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Side::Downstream => "downstream",
+            Side::Upstream => "upstream",
+        }
+    }
+}
+
+/// Why the relay loop stopped
+enum Stop {
+    /// A read, write or flush on the side failed
+    Failed(Side, &'static str, std::io::Error),
+    /// Writing towards the side outlasted the write timeout
+    WriteTimeout(Side),
+    /// A side closed or the relay ended the connection itself
+    Close(CloseReason, Option<&'static str>),
+}
+
+/// Relays between `server`, the client's connection, and `client`, the
+/// connection to the upstream, until either closes or `options` ends it.
+///
+/// Bytes are counted as they are written, and taken back when a failure
+/// means they were never flushed to their destination, so the totals only
+/// cover what was delivered.
+///
+/// ```
+/// use pj::relay::{relay, RelayOptions};
+/// use pj::CloseReason;
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (server, mut client) = tokio::io::duplex(64);
+/// let (upstream, mut backend) = tokio::io::duplex(64);
+/// let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), RelayOptions::default()));
+///
+/// client.write_all(b"ping").await.unwrap();
+/// let mut buf = [0u8; 4];
+/// backend.read_exact(&mut buf).await.unwrap();
+/// drop(client);
+///
+/// let result = relayed.await.unwrap();
+/// assert_eq!(result.reason, CloseReason::DownstreamEof);
+/// assert_eq!(result.stats.bytes_received(), 4);
+/// # }
+/// ```
+pub async fn relay(server_session: Stream, client_session: Stream, options: RelayOptions) -> RelayResult {
+    let RelayOptions {
+        buffer_size,
+        adaptive_buffer,
+        flush_mode,
+        max_lifetime,
+        long_lived_threshold,
+        started_at,
+        inject_delay,
+        idle_timeout,
+        adaptive_idle,
+        write_timeout,
+        max_up_bytes,
+        max_down_bytes,
+        slow_io_threshold,
+        backpressure_threshold,
+        peek_bytes,
+        preamble,
+        mut mirror,
+        registration,
+        traffic,
+        label,
+        keep_upstream,
+        self_check,
+    } = options;
+    let initial_size = adaptive_buffer.map_or(buffer_size, |adaptive| adaptive.min).max(1);
+    let mut upstream_buf = vec![0; initial_size];
+    let mut downstream_buf = vec![0; initial_size];
+    let mut upstream_sizer = adaptive_buffer.map(BufferSizer::new);
+    let mut downstream_sizer = adaptive_buffer.map(BufferSizer::new);
+    // Counting into the registration lets the admin API see live totals
+    let mut stats = match &registration {
+        Some(registration) => ConnectionStats::with_counters(registration.counters()),
+        None => ConnectionStats::new(),
+    };
+    let mut peek_pending = peek_bytes.is_some();
+    let mut preamble_offset = 0;
+    let started_at = started_at.unwrap_or_else(Instant::now);
+    let lifetime_deadline = max_lifetime.map(|max| tokio::time::Instant::from_std(started_at) + max);
+    // Cleared once passed, as a connection is only counted long-lived once
+    let mut long_lived_at = long_lived_threshold.map(|threshold| tokio::time::Instant::from_std(started_at) + threshold);
+    let coalesce = flush_mode == FlushMode::Coalesce;
+    let buffered = flush_mode == FlushMode::Buffered;
+    // Without FlushMode::Buffered the writers hold nothing and pass each write straight on
+    let write_buffer = if buffered { BUFFERED_WRITE_SIZE.max(initial_size) } else { 0 };
+    let mut server_session = BufWriter::with_capacity(write_buffer, Tally::new(server_session));
+    let mut client_session = BufWriter::with_capacity(write_buffer, Tally::new(client_session));
+    // Bytes counted each way but not flushed yet
+    let mut upstream_unflushed = 0;
+    let mut downstream_unflushed = 0;
+    let mut flush_deadline: Option<tokio::time::Instant> = None;
+    let mut idle_timeout = match adaptive_idle {
+        Some(adaptive) => Some(adaptive.initial(idle_timeout)),
+        None => idle_timeout,
+    };
+    let mut rtt = RttEstimator::default();
+    let idle_deadline = |idle: Option<Duration>, now: tokio::time::Instant| idle.map(|idle| now + idle);
+    let mut idle_at = idle_deadline(idle_timeout, tokio::time::Instant::now());
+    let write_deadline = || write_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let closed = |stats, reason, error: Option<&str>| RelayResult { stats, reason, error: error.map(str::to_string), upstream: None };
+
+    let stop = loop {
+        let event: DuplexEvent;
+        if preamble_offset < preamble.len() {
+            // Replay bytes consumed before the relay started, one buffer at a time
+            let n = (preamble.len() - preamble_offset).min(upstream_buf.len());
+            upstream_buf[..n].copy_from_slice(&preamble[preamble_offset..preamble_offset + n]);
+            preamble_offset += n;
+            event = DuplexEvent::DownstreamRead(n);
+        } else {
+            let downstream_read = server_session.read(&mut upstream_buf);
+            let upstream_read = client_session.read(&mut downstream_buf);
+            let close_requested = async {
+                match &registration {
+                    Some(registration) => registration.close_requested().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let lifetime_expired = async {
+                match lifetime_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let long_lived = async {
+                match long_lived_at {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let flush_due = async {
+                match flush_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            // Buffered writes are flushed once neither side has more to read right away
+            let drain = buffered && upstream_unflushed + downstream_unflushed > 0;
+            let reads_drained = async {
+                match drain {
+                    true => tokio::task::yield_now().await,
+                    false => std::future::pending().await,
+                }
+            };
+            let idle_expired = async {
+                match idle_at {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            select! {
+                n = downstream_read => {
+                    match n {
+                        Ok(n) => event = DuplexEvent::DownstreamRead(n),
+                        Err(e) => break Stop::Failed(Side::Downstream, "downstream read", e),
+                    }
+                }
+                n = upstream_read => {
+                    match n {
+                        Ok(n) => event = DuplexEvent::UpstreamRead(n),
+                        Err(e) => break Stop::Failed(Side::Upstream, "upstream read", e),
+                    }
+                }
+                _ = close_requested => event = DuplexEvent::CloseRequested,
+                _ = lifetime_expired => event = DuplexEvent::LifetimeExpired,
+                _ = long_lived => event = DuplexEvent::LongLived,
+                _ = flush_due => event = DuplexEvent::FlushDue,
+                _ = reads_drained => event = DuplexEvent::FlushDue,
+                _ = idle_expired => event = DuplexEvent::IdleTimeout,
+            }
+        }
+        match event {
+            DuplexEvent::CloseRequested => {
+                info!("{} closed via admin API", label);
+                break Stop::Close(CloseReason::AdminClose, Some("admin closed"));
+            }
+            DuplexEvent::LifetimeExpired => {
+                info!("{} reached its max lifetime, closing", label);
+                break Stop::Close(CloseReason::Timeout, Some("max lifetime reached"));
+            }
+            DuplexEvent::LongLived => {
+                long_lived_at = None;
+                info!("{} has been open for {:.1}s, past the long-lived threshold", label, started_at.elapsed().as_secs_f64());
+                stats.mark_long_lived();
+                if let Some(registration) = &registration {
+                    registration.record_long_lived();
+                }
+            }
+            DuplexEvent::IdleTimeout => {
+                info!("{} idle for too long, closing", label);
+                break Stop::Close(CloseReason::Timeout, Some("idle timeout"));
+            }
+            DuplexEvent::FlushDue => {
+                flush_deadline = None;
+                let deadline = write_deadline();
+                let flushed = within(deadline, Side::Upstream, "upstream flush", flush_pending(&mut client_session, &mut upstream_unflushed));
+                if let Err(stop) = flushed.await {
+                    break stop;
+                }
+                let flushed = within(deadline, Side::Downstream, "downstream flush", flush_pending(&mut server_session, &mut downstream_unflushed));
+                if let Err(stop) = flushed.await {
+                    break stop;
+                }
+            }
+            DuplexEvent::DownstreamRead(0) => {
+                debug!("Downstream session closing");
+                break Stop::Close(CloseReason::DownstreamEof, None);
+            }
+            DuplexEvent::UpstreamRead(0) => {
+                debug!("Upstream session closing");
+                break Stop::Close(CloseReason::UpstreamEof, None);
+            }
+            DuplexEvent::DownstreamRead(n) => {
+                let now = tokio::time::Instant::now();
+                idle_at = idle_deadline(idle_timeout, now);
+                if adaptive_idle.is_some() {
+                    rtt.downstream_read(now);
+                }
+                if peek_pending {
+                    peek_pending = false;
+                    let peeked = &upstream_buf[0..n.min(peek_bytes.unwrap_or(0))];
+                    debug!("{} first {} bytes:\n{}", label, peeked.len(), hex_dump(peeked));
+                }
+                // Only what fits under the cap is relayed before closing
+                let (n, over_cap) = cap_read(n, stats.bytes_received(), max_up_bytes);
+                stats.add_read(n, upstream_buf.len());
+                // Mirrored bytes are not counted in the connection stats
+                if let Some(mirror) = mirror.as_mut() {
+                    mirror.send(&upstream_buf[0..n]);
+                }
+                if let Some(injected) = inject_delay.filter(|injected| injected.direction.up()) {
+                    tokio::time::sleep(injected.delay).await;
+                }
+                let io_started = slow_io_threshold.or(backpressure_threshold).map(|_| Instant::now());
+                let deadline = write_deadline();
+                if let Err(stop) = within(deadline, Side::Upstream, "upstream write", client_session.write_all(&upstream_buf[0..n])).await {
+                    break stop;
+                }
+                count_relayed(&mut stats, traffic.as_ref(), Side::Upstream, n);
+                upstream_unflushed += n;
+                // A short read means the sender has paused, so nothing is coming to batch with
+                let batching = flush_mode != FlushMode::Immediate && n == upstream_buf.len() && !over_cap;
+                if let Some(sizer) = upstream_sizer.as_mut() {
+                    sizer.after_read(&mut upstream_buf, n);
+                }
+                if !batching {
+                    if let Err(stop) = within(deadline, Side::Upstream, "upstream flush", flush_pending(&mut client_session, &mut upstream_unflushed)).await {
+                        break stop;
+                    }
+                } else if coalesce {
+                    flush_deadline.get_or_insert_with(|| tokio::time::Instant::now() + COALESCE_DELAY);
+                }
+                trace_slow_io(&label, slow_io_threshold, Side::Upstream, n, io_started);
+                note_backpressure(&mut stats, &label, backpressure_threshold, Side::Upstream, io_started);
+                if over_cap {
+                    info!("{} sent more than its upstream byte cap, closing", label);
+                    break Stop::Close(CloseReason::ByteCap, Some("byte cap exceeded (upstream)"));
+                }
+            }
+            DuplexEvent::UpstreamRead(n) => {
+                let now = tokio::time::Instant::now();
+                if let Some((adaptive, smoothed)) = adaptive_idle.zip(rtt.upstream_read(now)) {
+                    let adapted = adaptive.timeout(smoothed);
+                    if idle_timeout != Some(adapted) {
+                        debug!("{} idle timeout is now {:?}, for a {:?} round trip", label, adapted, smoothed);
+                        idle_timeout = Some(adapted);
+                    }
+                }
+                idle_at = idle_deadline(idle_timeout, now);
+                let (n, over_cap) = cap_read(n, stats.bytes_sent(), max_down_bytes);
+                stats.add_read(n, downstream_buf.len());
+                if let Some(injected) = inject_delay.filter(|injected| injected.direction.down()) {
+                    tokio::time::sleep(injected.delay).await;
+                }
+                let io_started = slow_io_threshold.or(backpressure_threshold).map(|_| Instant::now());
+                let deadline = write_deadline();
+                if let Err(stop) = within(deadline, Side::Downstream, "downstream write", server_session.write_all(&downstream_buf[0..n])).await {
+                    break stop;
+                }
+                count_relayed(&mut stats, traffic.as_ref(), Side::Downstream, n);
+                downstream_unflushed += n;
+                // A short read means the sender has paused, so nothing is coming to batch with
+                let batching = flush_mode != FlushMode::Immediate && n == downstream_buf.len() && !over_cap;
+                if let Some(sizer) = downstream_sizer.as_mut() {
+                    sizer.after_read(&mut downstream_buf, n);
+                }
+                if !batching {
+                    if let Err(stop) = within(deadline, Side::Downstream, "downstream flush", flush_pending(&mut server_session, &mut downstream_unflushed)).await {
+                        break stop;
+                    }
+                } else if coalesce {
+                    flush_deadline.get_or_insert_with(|| tokio::time::Instant::now() + COALESCE_DELAY);
+                }
+                trace_slow_io(&label, slow_io_threshold, Side::Downstream, n, io_started);
+                note_backpressure(&mut stats, &label, backpressure_threshold, Side::Downstream, io_started);
+                if over_cap {
+                    info!("{} received more than its downstream byte cap, closing", label);
+                    break Stop::Close(CloseReason::ByteCap, Some("byte cap exceeded (downstream)"));
+                }
+            }
+        }
+    };
+
+    let (side, operation, error) = match stop {
+        Stop::Close(reason, error) => {
+            // Whatever was relayed is delivered, not left in a write buffer, before closing
+            let _ = within(write_deadline(), Side::Upstream, "upstream flush", flush_pending(&mut client_session, &mut upstream_unflushed)).await;
+            let _ = within(write_deadline(), Side::Downstream, "downstream flush", flush_pending(&mut server_session, &mut downstream_unflushed)).await;
+            retract_relayed(&mut stats, traffic.as_ref(), Side::Upstream, upstream_unflushed);
+            retract_relayed(&mut stats, traffic.as_ref(), Side::Downstream, downstream_unflushed);
+            let delivered = upstream_unflushed + downstream_unflushed == 0;
+            // Bytes over a byte cap are read but dropped on purpose
+            if self_check && cfg!(debug_assertions) && delivered && reason != CloseReason::ByteCap {
+                let (server, client) = (server_session.get_ref(), client_session.get_ref());
+                let up = check_relayed(&label, "client", "upstream", server.read + preamble_offset as u64, client.written, stats.bytes_received());
+                let down = check_relayed(&label, "upstream", "client", client.read, server.written, stats.bytes_sent());
+                if up && down {
+                    debug!("{} self-check passed: {} bytes up, {} down", label, client.written, server.written);
+                }
+            }
+            let reusable = keep_upstream && reason == CloseReason::DownstreamEof && delivered;
+            return RelayResult { upstream: reusable.then(|| client_session.into_inner().inner), ..closed(stats, reason, error) };
+        }
+        Stop::Failed(side, operation, error) => (side, operation, error),
+        Stop::WriteTimeout(side) => {
+            info!("{} {} write took longer than the write timeout, closing", label, side.as_str());
+            retract_relayed(&mut stats, traffic.as_ref(), Side::Upstream, upstream_unflushed);
+            retract_relayed(&mut stats, traffic.as_ref(), Side::Downstream, downstream_unflushed);
+            return closed(stats, CloseReason::Timeout, Some(&format!("write timeout ({})", side.as_str())));
+        }
+    };
+    let peer_reset = matches!(error.kind(), std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe);
+    let err = ProxyError::transfer(operation, error);
+    warn!("{} {}", label, err);
+    let reason = if peer_reset {
+        // The other side is still connected; hand it what was already relayed before closing
+        let delivered = match side {
+            Side::Downstream => within(write_deadline(), Side::Upstream, "upstream flush", flush_pending(&mut client_session, &mut upstream_unflushed)).await,
+            Side::Upstream => within(write_deadline(), Side::Downstream, "downstream flush", flush_pending(&mut server_session, &mut downstream_unflushed)).await,
+        };
+        match delivered {
+            Ok(()) | Err(Stop::Close(..)) => {}
+            Err(Stop::Failed(_, _, e)) => debug!("{} could not deliver the last relayed bytes after a reset: {}", label, e),
+            Err(Stop::WriteTimeout(_)) => debug!("{} could not deliver the last relayed bytes after a reset in time", label),
+        }
+        CloseReason::PeerReset
+    } else {
+        match side {
+            Side::Downstream => CloseReason::DownstreamError,
+            Side::Upstream => CloseReason::UpstreamError,
+        }
+    };
+    // Bytes still waiting on a flush went down with the connection
+    retract_relayed(&mut stats, traffic.as_ref(), Side::Upstream, upstream_unflushed);
+    retract_relayed(&mut stats, traffic.as_ref(), Side::Downstream, downstream_unflushed);
+    closed(stats, reason, Some(&err.to_string()))
+}
+
+/// Logs a relayed write begun at `started` if it took longer than the
+/// slow I/O threshold; `started` is only taken when one is set
+fn trace_slow_io(label: &str, threshold: Option<Duration>, side: Side, bytes: usize, started: Option<Instant>) {
+    let (Some(threshold), Some(started)) = (threshold, started) else { return };
+    let elapsed = started.elapsed();
+    if elapsed > threshold {
+        debug!("{} slow {} write: {} bytes took {:?}", label, side.as_str(), bytes, elapsed);
+    }
+}
+
+/// Counts a relayed write begun at `started` as backpressured if the side
+/// it went to took longer than `threshold` to take it; meanwhile nothing
+/// more was read from the other side, so its sender was held back
+fn note_backpressure(stats: &mut ConnectionStats, label: &str, threshold: Option<Duration>, side: Side, started: Option<Instant>) {
+    let (Some(threshold), Some(started)) = (threshold, started) else { return };
+    let elapsed = started.elapsed();
+    if elapsed > threshold {
+        if !stats.backpressured() {
+            debug!("{} backpressured: the {} side took {:?} to take a write", label, side.as_str(), elapsed);
+        }
+        stats.add_stall(elapsed);
+    }
+}
+
+/// Runs `operation`, a write or flush towards `side`, giving up at `deadline`
+async fn within(
+    deadline: Option<tokio::time::Instant>,
+    side: Side,
+    operation: &'static str,
+    io: impl Future<Output = std::io::Result<()>>,
+) -> Result<(), Stop> {
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, io).await.map_err(|_| Stop::WriteTimeout(side))?,
+        None => io.await,
+    };
+    result.map_err(|e| Stop::Failed(side, operation, e))
+}
+
+/// Counts `bytes` written towards `side`, to be taken back with
+/// `retract_relayed` if the flush that would deliver them fails
+fn count_relayed(stats: &mut ConnectionStats, traffic: Option<&TrafficCounters>, side: Side, bytes: usize) {
+    match side {
+        Side::Upstream => {
+            stats.add_received(bytes);
+            if let Some(traffic) = traffic {
+                traffic.add_received(bytes);
+            }
+        }
+        Side::Downstream => {
+            stats.add_sent(bytes);
+            if let Some(traffic) = traffic {
+                traffic.add_sent(bytes);
+            }
+        }
+    }
+}
+
+fn retract_relayed(stats: &mut ConnectionStats, traffic: Option<&TrafficCounters>, side: Side, bytes: usize) {
+    match side {
+        Side::Upstream => {
+            stats.retract_received(bytes);
+            if let Some(traffic) = traffic {
+                traffic.retract_received(bytes);
+            }
+        }
+        Side::Downstream => {
+            stats.retract_sent(bytes);
+            if let Some(traffic) = traffic {
+                traffic.retract_sent(bytes);
+            }
+        }
+    }
+}
+
+/// Logs an error if the bytes read from `from` differ from those written to
+/// `to`, or from those counted, meaning the relay dropped or duplicated some
+fn check_relayed(label: &str, from: &str, to: &str, read: u64, written: u64, counted: u64) -> bool {
+    let matched = read == written && written == counted;
+    if !matched {
+        error!(
+            "{} self-check failed: read {} bytes from the {}, wrote {} to the {} and counted {}",
+            label, read, from, written, to, counted
+        );
+    }
+    matched
+}
+
+/// Counts the bytes read from and written to a session, for `RelayOptions::self_check`
+struct Tally<S> {
+    inner: S,
+    read: u64,
+    written: u64,
+}
+
+impl<S> Tally<S> {
+    fn new(inner: S) -> Self {
+        Tally { inner, read: 0, written: 0 }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tally<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read += (buf.filled().len() - before) as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tally<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.written += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Smooths a connection's round trip, sampled as the time from a client
+/// read to the upstream's next read, the way TCP smooths its RTT
+#[derive(Default)]
+struct RttEstimator {
+    // When the client read that the next upstream read answers came in
+    sent_at: Option<tokio::time::Instant>,
+    smoothed: Option<Duration>,
+}
+
+impl RttEstimator {
+    fn downstream_read(&mut self, now: tokio::time::Instant) {
+        self.sent_at.get_or_insert(now);
+    }
+
+    /// The smoothed round trip, when this read answers a client read
+    fn upstream_read(&mut self, now: tokio::time::Instant) -> Option<Duration> {
+        let sample = now.duration_since(self.sent_at.take()?);
+        let smoothed = match self.smoothed {
+            Some(smoothed) => (smoothed * 7 + sample) / 8,
+            None => sample,
+        };
+        self.smoothed = Some(smoothed);
+        Some(smoothed)
+    }
+}
+
+/// Resizes one direction's read buffer with `AdaptiveBuffer`, from how
+/// full its recent reads were
+struct BufferSizer {
+    sizing: AdaptiveBuffer,
+    full_reads: u32,
+    small_reads: u32,
+}
+
+impl BufferSizer {
+    fn new(sizing: AdaptiveBuffer) -> Self {
+        BufferSizer { sizing, full_reads: 0, small_reads: 0 }
+    }
+
+    /// Grows or shrinks `buf` once a read of `n` bytes into it completes a streak
+    fn after_read(&mut self, buf: &mut Vec<u8>, n: usize) {
+        let AdaptiveBuffer { min, max, growth } = self.sizing;
+        let size = buf.len();
+        let smaller = (size / growth).max(min);
+        if n == size {
+            (self.full_reads, self.small_reads) = (self.full_reads + 1, 0);
+            if self.full_reads >= GROW_AFTER && size < max {
+                buf.resize(size.saturating_mul(growth).min(max), 0);
+                self.full_reads = 0;
+            }
+        } else if size > min && n <= smaller {
+            (self.full_reads, self.small_reads) = (0, self.small_reads + 1);
+            if self.small_reads >= SHRINK_AFTER {
+                buf.truncate(smaller);
+                buf.shrink_to_fit();
+                self.small_reads = 0;
+            }
+        } else {
+            (self.full_reads, self.small_reads) = (0, 0);
+        }
+    }
+}
+
+/// Trims a read of `n` bytes to what is left under `cap` once `relayed`
+/// bytes have gone the same way, and says whether the read went over it
+fn cap_read(n: usize, relayed: u64, cap: Option<u64>) -> (usize, bool) {
+    match cap {
+        Some(cap) if relayed + n as u64 > cap => (cap.saturating_sub(relayed) as usize, true),
+        _ => (n, false),
+    }
+}
+
+/// Flushes `session` if `pending` bytes have been written to it since the
+/// last flush. When the flush fails `pending` keeps them, as they may not
+/// have arrived.
+async fn flush_pending(session: &mut (impl AsyncWrite + Unpin), pending: &mut usize) -> std::io::Result<()> {
+    if *pending > 0 {
+        session.flush().await?;
+        *pending = 0;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use pingora_core::protocols::raw_connect::ProxyDigest;
+    use pingora_core::protocols::{GetProxyDigest, GetSocketDigest, GetTimingDigest, Peek, Shutdown, SocketDigest, Ssl, TimingDigest, UniqueID, UniqueIDType};
+
+    #[test]
+    fn test_cap_read() {
+        assert_eq!(cap_read(512, 0, None), (512, false));
+        assert_eq!(cap_read(512, 400, Some(1000)), (512, false));
+        assert_eq!(cap_read(512, 600, Some(1000)), (400, true));
+        assert_eq!(cap_read(512, 1000, Some(1000)), (0, true));
+    }
+
+    #[test]
+    fn test_buffer_sizer_grows_and_shrinks() {
+        let mut sizer = BufferSizer::new(AdaptiveBuffer::new(1024, 4096, 2).unwrap());
+        let mut buf = vec![0; 1024];
+        for _ in 0..GROW_AFTER * 3 {
+            let n = buf.len();
+            sizer.after_read(&mut buf, n);
+        }
+        assert_eq!(buf.len(), 4096, "Full reads grow it to the max");
+
+        // Reads that would still need the larger buffer keep it
+        for _ in 0..SHRINK_AFTER * 2 {
+            sizer.after_read(&mut buf, 3000);
+        }
+        assert_eq!(buf.len(), 4096);
+        for _ in 0..SHRINK_AFTER * 4 {
+            sizer.after_read(&mut buf, 100);
+        }
+        assert_eq!(buf.len(), 1024, "Small reads shrink it back to the min");
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_buffer_follows_throughput() {
+        let adaptive = AdaptiveBuffer::new(1024, 64 * 1024, 2).unwrap();
+        let pair = || (tokio::io::duplex(256 * 1024), tokio::io::duplex(256 * 1024));
+
+        // A bulk transfer keeps filling the buffer, which grows to the max
+        let ((server, mut client), (upstream, mut backend)) = pair();
+        let options = RelayOptions { adaptive_buffer: Some(adaptive), ..Default::default() };
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options));
+        let payload: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let sent = payload.clone();
+        tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+        });
+        let mut received = Vec::new();
+        backend.read_to_end(&mut received).await.unwrap();
+        let bulk = relayed.await.unwrap();
+        assert!(received == payload, "The payload should arrive intact");
+        assert_eq!(bulk.stats.largest_buffer(), 64 * 1024);
+
+        // A trickle of small messages never fills it, so it stays small
+        let ((server, mut client), (upstream, mut backend)) = pair();
+        let options = RelayOptions { adaptive_buffer: Some(adaptive), ..Default::default() };
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options));
+        for _ in 0..50 {
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            backend.read_exact(&mut buf).await.unwrap();
+        }
+        drop(client);
+        let trickle = relayed.await.unwrap();
+        assert_eq!(trickle.stats.largest_buffer(), 1024);
+        assert!(!trickle.stats.buffer_saturated());
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_marks_backpressure() {
+        let threshold = Duration::from_millis(50);
+        let options = || RelayOptions { backpressure_threshold: Some(threshold), ..Default::default() };
+
+        // The upstream takes its bytes straight away
+        let ((server, mut client), (upstream, mut backend)) = (tokio::io::duplex(64 * 1024), tokio::io::duplex(64 * 1024));
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options()));
+        client.write_all(&[7; 4096]).await.unwrap();
+        let mut received = vec![0; 4096];
+        backend.read_exact(&mut received).await.unwrap();
+        drop(client);
+        let fast = relayed.await.unwrap();
+        assert!(!fast.stats.backpressured());
+
+        // Its socket holds 64 bytes and it stops reading for a while
+        let ((server, mut client), (upstream, mut backend)) = (tokio::io::duplex(64 * 1024), tokio::io::duplex(64));
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options()));
+        client.write_all(&[7; 4096]).await.unwrap();
+        tokio::time::sleep(threshold * 4).await;
+        backend.read_exact(&mut received).await.unwrap();
+        drop(client);
+        let slow = relayed.await.unwrap();
+        assert!(slow.stats.backpressured());
+        assert!(slow.stats.longest_stall() >= threshold * 3, "Stalled for {:?}", slow.stats.longest_stall());
+        assert_eq!(slow.stats.bytes_received(), 4096);
+    }
+
+    #[tokio::test]
+    async fn test_long_lived_connections_counted() {
+        use crate::connection::{ClientAddr, ConnectionInfo};
+        use crate::id_manager::ConnectionIdManager;
+        use crate::registry::ConnectionRegistry;
+
+        let registry = Arc::new(ConnectionRegistry::new());
+        registry.register_listener("127.0.0.1:8080");
+        let id_manager = Arc::new(ConnectionIdManager::new(None, None));
+        let info = ConnectionInfo::new(ClientAddr::Unknown, "127.0.0.1:8080", "127.0.0.1:9090", 1, &id_manager);
+        let threshold = Duration::from_millis(100);
+        let run = |held: Duration| {
+            let options = RelayOptions { long_lived_threshold: Some(threshold), registration: Some(registry.register(&info)), ..Default::default() };
+            async move {
+                let ((server, client), (upstream, _backend)) = (tokio::io::duplex(1024), tokio::io::duplex(1024));
+                let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options));
+                tokio::time::sleep(held).await;
+                drop(client);
+                relayed.await.unwrap()
+            }
+        };
+
+        let short = run(Duration::from_millis(10)).await;
+        assert!(!short.stats.long_lived());
+        assert_eq!(registry.listeners()[0].long_connections, 0);
+
+        // Held past the threshold it is counted, once, and still closes as its client does
+        let long = run(threshold * 3).await;
+        assert!(long.stats.long_lived());
+        assert_eq!(long.reason, CloseReason::DownstreamEof);
+        assert_eq!(registry.listeners()[0].long_connections, 1);
+    }
+
+    #[test]
+    fn test_check_relayed() {
+        assert!(check_relayed("Conn #0", "client", "upstream", 4096, 4096, 4096));
+        assert!(!check_relayed("Conn #0", "client", "upstream", 4096, 4000, 4096), "Dropped bytes");
+        assert!(!check_relayed("Conn #0", "client", "upstream", 4096, 4096, 8192), "Counted twice");
+    }
+
+    #[test]
+    fn test_duplex_event_downstream_read() {
+        let event = DuplexEvent::DownstreamRead(100);
+        match event {
+            DuplexEvent::DownstreamRead(n) => assert_eq!(n, 100),
+            _ => panic!("Wrong event type"),
+        }
+    }
+
+    #[test]
+    fn test_duplex_event_upstream_read() {
+        let event = DuplexEvent::UpstreamRead(200);
+        match event {
+            DuplexEvent::UpstreamRead(n) => assert_eq!(n, 200),
+            _ => panic!("Wrong event type"),
+        }
+    }
+
+    #[test]
+    fn test_duplex_event_sizes() {
+        assert_eq!(std::mem::size_of::<DuplexEvent>(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_relay_over_duplex_streams() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        let (upstream, mut backend) = tokio::io::duplex(4096);
+        let traffic = TrafficCounters::default();
+        let options = RelayOptions {
+            buffer_size: 16,
+            preamble: b"GET ".to_vec(),
+            traffic: Some(traffic.clone()),
+            ..Default::default()
+        };
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options));
+
+        // The preamble goes first, and writes longer than the buffer are relayed in pieces
+        client.write_all(b"/ HTTP/1.1 with a longer tail").await.unwrap();
+        let mut request = vec![0u8; 33];
+        backend.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"GET / HTTP/1.1 with a longer tail");
+        backend.write_all(b"pong").await.unwrap();
+        let mut response = [0u8; 4];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"pong");
+        drop(backend);
+
+        let result = relayed.await.unwrap();
+        assert_eq!(result.reason, CloseReason::UpstreamEof);
+        assert_eq!(result.error, None);
+        assert_eq!((result.stats.bytes_sent(), result.stats.bytes_received()), (4, 33));
+        assert_eq!((traffic.bytes_sent(), traffic.bytes_received()), (4, 33));
+    }
+
+    #[tokio::test]
+    async fn test_keep_upstream_only_after_client_closes() {
+        let keep = || RelayOptions { keep_upstream: true, ..Default::default() };
+
+        let (server, mut client) = tokio::io::duplex(64);
+        let (upstream, mut backend) = tokio::io::duplex(64);
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), keep()));
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        backend.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        let result = relayed.await.unwrap();
+        assert_eq!(result.reason, CloseReason::DownstreamEof);
+        let mut upstream = result.upstream.expect("Should hand back the upstream");
+        // It is still the same, open connection
+        upstream.write_all(b"more").await.unwrap();
+        upstream.flush().await.unwrap();
+        backend.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"more");
+
+        // An upstream that closed is of no use
+        let (server, _client) = tokio::io::duplex(64);
+        let (upstream, backend) = tokio::io::duplex(64);
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), keep()));
+        drop(backend);
+        let result = relayed.await.unwrap();
+        assert_eq!(result.reason, CloseReason::UpstreamEof);
+        assert!(result.upstream.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_closes_on_idle_and_byte_cap() {
+        let (server, _client) = tokio::io::duplex(64);
+        let (upstream, _backend) = tokio::io::duplex(64);
+        let options = RelayOptions { idle_timeout: Some(Duration::from_secs(30)), ..Default::default() };
+        let result = relay(Box::new(server), Box::new(upstream), options).await;
+        assert_eq!(result.reason, CloseReason::Timeout);
+        assert_eq!(result.error.as_deref(), Some("idle timeout"));
+
+        let (server, mut client) = tokio::io::duplex(64);
+        let (upstream, mut backend) = tokio::io::duplex(64);
+        let options = RelayOptions { max_up_bytes: Some(3), ..Default::default() };
+        client.write_all(b"hello").await.unwrap();
+        let result = relay(Box::new(server), Box::new(upstream), options).await;
+        assert_eq!(result.reason, CloseReason::ByteCap);
+        assert_eq!(result.stats.bytes_received(), 3);
+        let mut relayed = [0u8; 3];
+        backend.read_exact(&mut relayed).await.unwrap();
+        assert_eq!(&relayed, b"hel");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout_adapts_to_round_trip() {
+        let secs = Duration::from_secs;
+        let adaptive = AdaptiveIdle::new(secs(2), secs(20), 10).unwrap();
+        // One request answered after `latency`, then silence until the relay gives up
+        let idle_close = |fixed: Duration, latency: Duration| async move {
+            let ((server, mut client), (upstream, mut backend)) = (tokio::io::duplex(64), tokio::io::duplex(64));
+            tokio::spawn(async move {
+                let mut request = [0u8; 4];
+                backend.read_exact(&mut request).await.unwrap();
+                tokio::time::sleep(latency).await;
+                backend.write_all(b"pong").await.unwrap();
+                std::future::pending::<()>().await;
+            });
+            let options = RelayOptions { idle_timeout: Some(fixed), adaptive_idle: Some(adaptive), ..Default::default() };
+            let started = tokio::time::Instant::now();
+            let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options));
+            client.write_all(b"ping").await.unwrap();
+            let result = relayed.await.unwrap();
+            assert_eq!(result.error.as_deref(), Some("idle timeout"));
+            started.elapsed() - latency
+        };
+
+        // A 500ms round trip outlasts the fixed 3s: ten of them make 5s
+        assert_eq!(idle_close(secs(3), Duration::from_millis(500)).await, secs(5));
+        // Never past the maximum, however slow the round trip
+        assert_eq!(idle_close(secs(10), secs(5)).await, secs(20));
+        // Nor below the minimum for a fast one
+        assert_eq!(idle_close(secs(3), Duration::from_millis(10)).await, secs(2));
+
+        // Without it the fixed timeout holds
+        let ((server, mut client), (upstream, mut backend)) = (tokio::io::duplex(64), tokio::io::duplex(64));
+        let options = RelayOptions { idle_timeout: Some(secs(3)), ..Default::default() };
+        let started = tokio::time::Instant::now();
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options));
+        client.write_all(b"ping").await.unwrap();
+        let mut request = [0u8; 4];
+        backend.read_exact(&mut request).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        backend.write_all(b"pong").await.unwrap();
+        relayed.await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(3500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_closes_on_write_timeout() {
+        // The client stops reading while the upstream keeps sending
+        let (server, _client) = tokio::io::duplex(64);
+        let (upstream, mut backend) = tokio::io::duplex(64);
+        let options = RelayOptions { write_timeout: Some(Duration::from_secs(2)), ..Default::default() };
+        tokio::spawn(async move { while backend.write_all(&[b'x'; 64]).await.is_ok() {} });
+
+        let started = tokio::time::Instant::now();
+        let result = relay(Box::new(server), Box::new(upstream), options).await;
+        assert_eq!(result.reason, CloseReason::Timeout);
+        assert_eq!(result.error.as_deref(), Some("write timeout (downstream)"));
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        // Only what fit in the client's buffer was delivered
+        assert_eq!(result.stats.bytes_sent(), 64);
+    }
+
+    /// A duplex stream that counts the flushes it gets
+    #[derive(Debug)]
+    struct CountingFlushes {
+        inner: tokio::io::DuplexStream,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl tokio::io::AsyncRead for CountingFlushes {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountingFlushes {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Shutdown for CountingFlushes {
+        async fn shutdown(&mut self) {}
+    }
+    impl UniqueID for CountingFlushes {
+        fn id(&self) -> UniqueIDType {
+            0
+        }
+    }
+    impl Ssl for CountingFlushes {}
+    impl GetTimingDigest for CountingFlushes {
+        fn get_timing_digest(&self) -> Vec<Option<TimingDigest>> {
+            vec![]
+        }
+    }
+    impl GetProxyDigest for CountingFlushes {
+        fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>> {
+            None
+        }
+    }
+    impl GetSocketDigest for CountingFlushes {
+        fn get_socket_digest(&self) -> Option<Arc<SocketDigest>> {
+            None
+        }
+    }
+    impl Peek for CountingFlushes {}
+
+    /// Relays `payload` from the client to the backend, returning what the
+    /// backend got and how often the upstream side was flushed
+    async fn relay_bulk(flush_mode: FlushMode, payload: &[u8]) -> (Vec<u8>, usize) {
+        let (server, mut client) = tokio::io::duplex(256 * 1024);
+        let (upstream, mut backend) = tokio::io::duplex(256 * 1024);
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let upstream = CountingFlushes { inner: upstream, flushes: flushes.clone() };
+        let options = RelayOptions { flush_mode, ..Default::default() };
+        let relayed = tokio::spawn(relay(Box::new(server), Box::new(upstream), options));
+
+        let sent = payload.to_vec();
+        tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+        });
+        let mut received = Vec::new();
+        backend.read_to_end(&mut received).await.unwrap();
+        let result = relayed.await.unwrap();
+        assert_eq!(result.reason, CloseReason::DownstreamEof);
+        assert_eq!(result.stats.bytes_received(), payload.len() as u64);
+        (received, flushes.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn test_buffered_flush_mode_bulk_transfer() {
+        // 8 MB that isn't one repeated byte, so reordering or loss would show
+        let payload: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+
+        // How reads are split depends on the duplex pipe, so only bounds are asserted
+        let chunks = payload.len() / RELAY_BUFFER_SIZE;
+
+        let (received, flushes) = relay_bulk(FlushMode::Buffered, &payload).await;
+        assert!(received == payload, "The payload should arrive intact");
+        assert!(flushes > 0 && flushes <= chunks, "Expected at most a flush per chunk, got {}", flushes);
+        assert!(flushes < 256, "Expected few flushes, got {}", flushes);
+
+        // Every read is flushed, and no read is larger than the buffer
+        let (received, immediate_flushes) = relay_bulk(FlushMode::Immediate, &payload).await;
+        assert!(received == payload, "The payload should arrive intact");
+        assert!(immediate_flushes >= chunks, "Expected a flush per chunk, got {}", immediate_flushes);
+    }
+}
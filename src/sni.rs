@@ -0,0 +1,287 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::time::timeout;
+
+use pingora_core::protocols::Stream;
+use pingora_core::upstreams::peer::BasicPeer;
+
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Bytes in a TLS record header: content type, version and length
+const RECORD_HEADER: usize = 5;
+
+/// How long a client has to send its ClientHello
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The route `SniRoutes` takes for a server name none of its routes name
+pub const FALLBACK: &str = "*";
+
+/// What the start of a connection says about its ClientHello
+#[derive(Debug, PartialEq)]
+pub enum ClientHello {
+    /// More bytes are needed to tell
+    Incomplete,
+    /// The bytes aren't a TLS ClientHello
+    NotTls,
+    /// A complete ClientHello, with the server name it asked for (lowercased)
+    ServerName(Option<String>),
+}
+
+/// Reads through a byte slice, failing once it runs out
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3).map(|b| usize::from(b[0]) << 16 | usize::from(b[1]) << 8 | usize::from(b[2]))
+    }
+
+    /// A field prefixed with its length in `width` bytes
+    fn prefixed(&mut self, width: usize) -> Option<Reader<'a>> {
+        let len = match width {
+            1 => usize::from(self.u8()?),
+            2 => usize::from(self.u16()?),
+            _ => self.u24()?,
+        };
+        self.take(len).map(|data| Reader { data })
+    }
+}
+
+/// Finds the server name in `data`, the first bytes a client sent, without
+/// decrypting anything: a ClientHello is sent in the clear. The handshake
+/// message may be split over several records.
+pub fn parse_client_hello(data: &[u8]) -> ClientHello {
+    // The handshake message, put back together from the records it was split into
+    let mut handshake = Vec::new();
+    let mut records = Reader { data };
+    loop {
+        let Some(header) = records.take(RECORD_HEADER) else {
+            // A partial header can still be told apart from TLS
+            return match is_handshake_record(records.data) {
+                true => ClientHello::Incomplete,
+                false => ClientHello::NotTls,
+            };
+        };
+        if !is_handshake_record(header) {
+            return ClientHello::NotTls;
+        }
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let Some(fragment) = records.take(len) else { return ClientHello::Incomplete };
+        handshake.extend_from_slice(fragment);
+        if handshake.first().is_some_and(|&kind| kind != HANDSHAKE_CLIENT_HELLO) {
+            return ClientHello::NotTls;
+        }
+        if handshake.len() >= 4 {
+            let hello_len = Reader { data: &handshake[1..4] }.u24().unwrap_or(0);
+            if handshake.len() >= 4 + hello_len {
+                return match server_name(&handshake[4..4 + hello_len]) {
+                    Some(name) => ClientHello::ServerName(name),
+                    None => ClientHello::NotTls,
+                };
+            }
+        }
+    }
+}
+
+/// Whether `header`, all or the start of a record header, is a handshake
+/// record's. Every TLS version so far, SSL 3.0 included, is 3.x on the record layer.
+fn is_handshake_record(header: &[u8]) -> bool {
+    header.first().is_none_or(|&content| content == CONTENT_HANDSHAKE) && header.get(1).is_none_or(|&major| major == 0x03)
+}
+
+/// The server name extension's host name, from a ClientHello's body;
+/// `None` when the body is malformed
+fn server_name(body: &[u8]) -> Option<Option<String>> {
+    let mut hello = Reader { data: body };
+    hello.take(2 + 32)?; // client version and random
+    hello.prefixed(1)?; // session id
+    hello.prefixed(2)?; // cipher suites
+    hello.prefixed(1)?; // compression methods
+    if hello.data.is_empty() {
+        // Extensions were optional before TLS 1.2
+        return Some(None);
+    }
+    let mut extensions = hello.prefixed(2)?;
+    while !extensions.data.is_empty() {
+        let kind = extensions.u16()?;
+        let mut extension = extensions.prefixed(2)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = extension.prefixed(2)?;
+        while !names.data.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.prefixed(2)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name.data).ok()?;
+                return Some(Some(normalize(name)));
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+/// A host name as routes compare them: lowercased, without a trailing dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Reads the start of a connection until it holds a whole ClientHello, is
+/// found not to be one, `limit` bytes are buffered, the client closes, or
+/// the hello timeout elapses. The returned bytes have been consumed from
+/// `stream` and must be replayed upstream.
+pub async fn read_client_hello(stream: &mut Stream, limit: usize) -> io::Result<(Vec<u8>, ClientHello)> {
+    let mut hello = Vec::new();
+    let read = async {
+        let mut chunk = [0u8; 1024];
+        while hello.len() < limit {
+            let n = stream.read(&mut chunk[..(limit - hello.len()).min(1024)]).await?;
+            if n == 0 {
+                break;
+            }
+            hello.extend_from_slice(&chunk[..n]);
+            if parse_client_hello(&hello) != ClientHello::Incomplete {
+                break;
+            }
+        }
+        Ok::<(), io::Error>(())
+    };
+    // Timing out leaves the hello incomplete, which is refused like any other
+    if let Ok(result) = timeout(HELLO_TIMEOUT, read).await {
+        result?;
+    }
+    let parsed = parse_client_hello(&hello);
+    Ok((hello, parsed))
+}
+
+/// Where a `tls://` listener relays each connection, by the server name
+/// its ClientHello asks for
+#[derive(Debug, Clone)]
+pub struct SniRoutes {
+    routes: Vec<(String, BasicPeer)>,
+    fallback: Option<BasicPeer>,
+}
+
+impl SniRoutes {
+    /// Routes from `(server name, upstream)` pairs; the `FALLBACK` name takes
+    /// any other server name, and clients that send none
+    pub fn new(routes: &[(String, SocketAddr)]) -> Self {
+        let peer = |addr: &SocketAddr| BasicPeer::new(&addr.to_string());
+        SniRoutes {
+            routes: routes
+                .iter()
+                .filter(|(name, _)| name != FALLBACK)
+                .map(|(name, addr)| (normalize(name), peer(addr)))
+                .collect(),
+            fallback: routes.iter().find(|(name, _)| name == FALLBACK).map(|(_, addr)| peer(addr)),
+        }
+    }
+
+    /// The upstream for `server_name`, or `None` to refuse the connection
+    pub fn route(&self, server_name: Option<&str>) -> Option<&BasicPeer> {
+        server_name
+            .and_then(|name| self.routes.iter().find(|(route, _)| route == name))
+            .map(|(_, peer)| peer)
+            .or(self.fallback.as_ref())
+    }
+
+    pub fn peers_mut(&mut self) -> impl Iterator<Item = &mut BasicPeer> {
+        self.routes.iter_mut().map(|(_, peer)| peer).chain(self.fallback.as_mut())
+    }
+}
+
+/// A ClientHello record asking for `server_name`, as a client would send it
+#[cfg(any(test, feature = "test-util"))]
+pub fn client_hello(server_name: &str) -> Vec<u8> {
+    let with_len = |width: usize, data: &[u8]| -> Vec<u8> {
+        let mut field = data.len().to_be_bytes()[8 - width..].to_vec();
+        field.extend_from_slice(data);
+        field
+    };
+    let name = [&[NAME_TYPE_HOST_NAME][..], &with_len(2, server_name.as_bytes())].concat();
+    let extension = [&EXTENSION_SERVER_NAME.to_be_bytes()[..], &with_len(2, &with_len(2, &name))].concat();
+    // supported_versions with TLS 1.3, so the SNI isn't the only extension
+    let versions = [&[0x00, 0x2b][..], &with_len(2, &with_len(1, &[0x03, 0x04]))].concat();
+    let body = [
+        &[0x03, 0x03][..],
+        &[0x42; 32],
+        &with_len(1, &[0x07; 32]),
+        &with_len(2, &[0x13, 0x01]),
+        &with_len(1, &[0x00]),
+        &with_len(2, &[versions, extension].concat()),
+    ]
+    .concat();
+    let handshake = [&[HANDSHAKE_CLIENT_HELLO][..], &with_len(3, &body)].concat();
+    [&[CONTENT_HANDSHAKE, 0x03, 0x01][..], &with_len(2, &handshake)].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_hello() {
+        let hello = client_hello("API.Example.com.");
+        assert_eq!(parse_client_hello(&hello), ClientHello::ServerName(Some("api.example.com".to_string())));
+        // Anything after the hello is left alone
+        assert_eq!(parse_client_hello(&[hello.as_slice(), b"more"].concat()), parse_client_hello(&hello));
+
+        for cut in [1, 4, RECORD_HEADER, 40, hello.len() - 1] {
+            assert_eq!(parse_client_hello(&hello[..cut]), ClientHello::Incomplete, "Cut at {}", cut);
+        }
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), ClientHello::NotTls);
+        assert_eq!(parse_client_hello(b"G"), ClientHello::NotTls);
+        assert_eq!(parse_client_hello(&[CONTENT_HANDSHAKE, 0x03, 0x01, 0x00, 0x04, 0x02, 0, 0, 0]), ClientHello::NotTls, "A ServerHello");
+
+        // The handshake split over two records
+        let handshake = &hello[RECORD_HEADER..];
+        let record = |fragment: &[u8]| [&[CONTENT_HANDSHAKE, 0x03, 0x01][..], &(fragment.len() as u16).to_be_bytes(), fragment].concat();
+        let split = [record(&handshake[..20]), record(&handshake[20..])].concat();
+        assert_eq!(parse_client_hello(&split), ClientHello::ServerName(Some("api.example.com".to_string())));
+        assert_eq!(parse_client_hello(&split[..30]), ClientHello::Incomplete);
+    }
+
+    #[test]
+    fn test_sni_routes() {
+        let routes = SniRoutes::new(&[
+            ("a.example.com".to_string(), "10.0.0.1:443".parse().unwrap()),
+            ("B.example.com".to_string(), "10.0.0.2:443".parse().unwrap()),
+        ]);
+        let upstream = |name| routes.route(name).map(|peer| peer._address.to_string());
+        assert_eq!(upstream(Some("a.example.com")).as_deref(), Some("10.0.0.1:443"));
+        assert_eq!(upstream(Some("b.example.com")).as_deref(), Some("10.0.0.2:443"));
+        assert_eq!(upstream(Some("c.example.com")), None, "Unknown names are refused");
+        assert_eq!(upstream(None), None);
+
+        let routes = SniRoutes::new(&[
+            ("a.example.com".to_string(), "10.0.0.1:443".parse().unwrap()),
+            (FALLBACK.to_string(), "10.0.0.9:443".parse().unwrap()),
+        ]);
+        assert_eq!(routes.route(Some("c.example.com")).unwrap()._address.to_string(), "10.0.0.9:443");
+        assert_eq!(routes.route(None).unwrap()._address.to_string(), "10.0.0.9:443");
+    }
+}
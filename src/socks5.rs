@@ -0,0 +1,365 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+use pingora_core::protocols::Stream;
+
+use crate::connect::ConnectAllowlist;
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// How long a client has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings for a `socks5://` listener
+#[derive(Debug, Clone, Default)]
+pub struct Socks5Config {
+    /// Targets clients may reach, shared with CONNECT listeners
+    pub allowlist: ConnectAllowlist,
+    /// Username and password required from clients; `None` allows no-auth
+    pub credentials: Option<(String, String)>,
+}
+
+/// An outbound SOCKS5 proxy that upstream connections are tunneled through
+#[derive(Debug, Clone)]
+pub struct Socks5Upstream {
+    pub addr: SocketAddr,
+    /// Username and password the proxy requires; `None` offers no-auth only
+    pub credentials: Option<(String, String)>,
+}
+
+/// A failed handshake. `reply` is the SOCKS5 reply code still owed to the
+/// client, if the protocol got far enough for one to be sent.
+#[derive(Debug)]
+pub struct Socks5Error {
+    pub reply: Option<u8>,
+    pub reason: String,
+}
+
+/// Target requested by the client
+#[derive(Debug, PartialEq)]
+pub struct Socks5Request {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Socks5Error {
+    fn protocol(reason: impl Into<String>) -> Self {
+        Socks5Error { reply: None, reason: reason.into() }
+    }
+
+    fn reply(reply: u8, reason: impl Into<String>) -> Self {
+        Socks5Error { reply: Some(reply), reason: reason.into() }
+    }
+}
+
+impl From<io::Error> for Socks5Error {
+    fn from(e: io::Error) -> Self {
+        Socks5Error::protocol(format!("read failed: {}", e))
+    }
+}
+
+/// Parses `user:pass` as used by `PJ_SOCKS5_AUTH`
+pub fn parse_credentials(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((user, pass)) if !user.is_empty() && user.len() <= 255 && pass.len() <= 255 => {
+            Ok((user.to_string(), pass.to_string()))
+        }
+        _ => Err("Expected user:pass with a non-empty user of at most 255 bytes".to_string()),
+    }
+}
+
+/// Negotiates authentication and reads the client's request, leaving the
+/// final reply to the caller once the upstream connection is known. A
+/// client that sends more than `max_bytes` before its request is complete
+/// is refused.
+pub async fn handshake(stream: &mut Stream, config: &Socks5Config, max_bytes: usize) -> Result<Socks5Request, Socks5Error> {
+    let mut client = HandshakeReader { stream, remaining: max_bytes, limit: max_bytes };
+    let request = timeout(HANDSHAKE_TIMEOUT, async {
+        negotiate_auth(&mut client, config).await?;
+        read_request(&mut client).await
+    })
+    .await
+    .map_err(|_| Socks5Error::protocol("timed out during handshake"))??;
+
+    if !config.allowlist.allows(&request.host, request.port) {
+        return Err(Socks5Error::reply(
+            REPLY_NOT_ALLOWED,
+            format!("{}:{} is not in the CONNECT allowlist", request.host, request.port),
+        ));
+    }
+    Ok(request)
+}
+
+async fn negotiate_auth(client: &mut HandshakeReader<'_>, config: &Socks5Config) -> Result<(), Socks5Error> {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(Socks5Error::protocol(format!("unsupported SOCKS version {}", header[0])));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    client.read_exact(&mut methods).await?;
+
+    let wanted = if config.credentials.is_some() { METHOD_USER_PASS } else { METHOD_NO_AUTH };
+    if !methods.contains(&wanted) {
+        write_flushed(client.stream, &[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(Socks5Error::protocol("client offered no acceptable authentication method"));
+    }
+    write_flushed(client.stream, &[VERSION, wanted]).await?;
+
+    if let Some((user, pass)) = &config.credentials {
+        let mut version = [0u8; 1];
+        client.read_exact(&mut version).await?;
+        if version[0] != AUTH_VERSION {
+            return Err(Socks5Error::protocol(format!("unsupported auth version {}", version[0])));
+        }
+        let username = client.read_length_prefixed().await?;
+        let password = client.read_length_prefixed().await?;
+
+        if username != user.as_bytes() || password != pass.as_bytes() {
+            write_flushed(client.stream, &[AUTH_VERSION, 0x01]).await?;
+            return Err(Socks5Error::protocol("invalid username or password"));
+        }
+        write_flushed(client.stream, &[AUTH_VERSION, 0x00]).await?;
+    }
+    Ok(())
+}
+
+async fn read_request(client: &mut HandshakeReader<'_>) -> Result<Socks5Request, Socks5Error> {
+    let mut header = [0u8; 4];
+    client.read_exact(&mut header).await?;
+    let [version, command, _reserved, address_type] = header;
+    if version != VERSION {
+        return Err(Socks5Error::protocol(format!("unsupported SOCKS version {}", version)));
+    }
+
+    // The address has to be consumed either way so the reply lines up
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            client.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            client.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let domain = client.read_length_prefixed().await?;
+            String::from_utf8(domain)
+                .map_err(|_| Socks5Error::reply(REPLY_GENERAL_FAILURE, "domain is not valid UTF-8"))?
+        }
+        other => {
+            return Err(Socks5Error::reply(
+                REPLY_ADDRESS_NOT_SUPPORTED,
+                format!("unsupported address type {}", other),
+            ))
+        }
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port).await?;
+
+    if command != CMD_CONNECT {
+        return Err(Socks5Error::reply(REPLY_COMMAND_NOT_SUPPORTED, format!("unsupported command {}", command)));
+    }
+
+    Ok(Socks5Request {
+        host,
+        port: u16::from_be_bytes(port),
+    })
+}
+
+/// Reads the client's side of the handshake, counting the bytes against a cap
+struct HandshakeReader<'a> {
+    stream: &'a mut Stream,
+    remaining: usize,
+    limit: usize,
+}
+
+impl HandshakeReader<'_> {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Socks5Error> {
+        if buf.len() > self.remaining {
+            return Err(Socks5Error::protocol(format!("handshake exceeds {} bytes", self.limit)));
+        }
+        self.stream.read_exact(buf).await?;
+        self.remaining -= buf.len();
+        Ok(())
+    }
+
+    async fn read_length_prefixed(&mut self) -> Result<Vec<u8>, Socks5Error> {
+        let mut len = [0u8; 1];
+        self.read_exact(&mut len).await?;
+        let mut value = vec![0u8; len[0] as usize];
+        self.read_exact(&mut value).await?;
+        Ok(value)
+    }
+}
+
+async fn read_length_prefixed(stream: &mut Stream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).await?;
+    let mut value = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut value).await?;
+    Ok(value)
+}
+
+// The client waits for each reply before sending more, so none can sit in the write buffer
+async fn write_flushed(stream: &mut Stream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+/// Sends the final reply to the client's request. The bound address is
+/// reported as 0.0.0.0:0, which clients ignore for CONNECT.
+pub async fn send_reply(stream: &mut Stream, reply: u8) -> io::Result<()> {
+    write_flushed(stream, &[VERSION, reply, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await
+}
+
+/// Asks the SOCKS5 proxy at the other end of `stream` for a tunnel to
+/// `target`, returning once it is open. On failure `reply` is the code the
+/// proxy refused the request with, if it got that far.
+pub async fn open_tunnel(
+    stream: &mut Stream,
+    target: SocketAddr,
+    credentials: Option<&(String, String)>,
+) -> Result<(), Socks5Error> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        offer_auth(stream, credentials).await?;
+        request_connect(stream, target).await
+    })
+    .await
+    .map_err(|_| Socks5Error::protocol("timed out during handshake"))?
+}
+
+async fn offer_auth(stream: &mut Stream, credentials: Option<&(String, String)>) -> Result<(), Socks5Error> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS],
+        None => &[VERSION, 1, METHOD_NO_AUTH],
+    };
+    write_flushed(stream, greeting).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(Socks5Error::protocol(format!("proxy answered with SOCKS version {}", choice[0])));
+    }
+    match (choice[1], credentials) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_USER_PASS, Some((user, pass))) => {
+            let mut auth = vec![AUTH_VERSION, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(pass.len() as u8);
+            auth.extend_from_slice(pass.as_bytes());
+            write_flushed(stream, &auth).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(Socks5Error::protocol("proxy rejected the username or password"));
+            }
+            Ok(())
+        }
+        _ => Err(Socks5Error::protocol("proxy accepted none of the offered authentication methods")),
+    }
+}
+
+async fn request_connect(stream: &mut Stream, target: SocketAddr) -> Result<(), Socks5Error> {
+    let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    write_flushed(stream, &request).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, reply, _reserved, address_type] = header;
+    if version != VERSION {
+        return Err(Socks5Error::protocol(format!("proxy answered with SOCKS version {}", version)));
+    }
+    if reply != REPLY_SUCCEEDED {
+        return Err(Socks5Error::reply(reply, format!("proxy refused the tunnel to {} (reply {})", target, reply)));
+    }
+    // The bound address is of no use, but relayed data only starts after it
+    match address_type {
+        ATYP_IPV4 => stream.read_exact(&mut [0u8; 4 + 2]).await?,
+        ATYP_IPV6 => stream.read_exact(&mut [0u8; 16 + 2]).await?,
+        ATYP_DOMAIN => {
+            read_length_prefixed(stream).await?;
+            stream.read_exact(&mut [0u8; 2]).await?
+        }
+        other => return Err(Socks5Error::protocol(format!("proxy replied with address type {}", other))),
+    };
+    Ok(())
+}
+
+/// Maps an upstream connection failure to the closest SOCKS5 reply code
+pub fn reply_for_connect_error(e: &pingora_core::Error) -> u8 {
+    if e.etype() == &pingora_core::ErrorType::ConnectRefused {
+        REPLY_CONNECTION_REFUSED
+    } else {
+        REPLY_HOST_UNREACHABLE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_credentials() {
+        assert_eq!(parse_credentials("alice:s3cret").unwrap(), ("alice".to_string(), "s3cret".to_string()));
+        assert_eq!(parse_credentials("bob:pa:ss").unwrap(), ("bob".to_string(), "pa:ss".to_string()));
+        assert!(parse_credentials("alice").is_err());
+        assert!(parse_credentials(":s3cret").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_stops_at_cap() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let mut io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(listener.accept().await.unwrap().0));
+
+        // A greeting offering 255 methods doesn't fit in 64 bytes
+        let mut greeting = vec![VERSION, 255];
+        greeting.resize(2 + 255, METHOD_NO_AUTH);
+        client.write_all(&greeting).await.unwrap();
+        let error = handshake(&mut io, &Socks5Config::default(), 64).await.unwrap_err();
+        assert_eq!((error.reply, error.reason.as_str()), (None, "handshake exceeds 64 bytes"));
+
+        // A complete handshake within the cap goes through
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let mut io: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(listener.accept().await.unwrap().0));
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH, VERSION, CMD_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80]).await.unwrap();
+        let config = Socks5Config { allowlist: ConnectAllowlist::parse("*:*").unwrap(), credentials: None };
+        let request = handshake(&mut io, &config, 13).await.unwrap();
+        assert_eq!(request, Socks5Request { host: "10.0.0.1".to_string(), port: 80 });
+    }
+}
@@ -0,0 +1,142 @@
+use async_trait::async_trait;
+use std::io;
+use std::net::TcpListener;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tracing::{debug, warn};
+
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+
+use crate::connection::TrafficCounters;
+
+/// How long a stats client has to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes read while waiting for the request line
+const MAX_REQUEST: u64 = 1024;
+
+/// Answers each client that sends a line with the proxy's counters in
+/// Graphite plaintext (`key value timestamp` lines), then closes, for
+/// monitoring that can't scrape HTTP. What the line says doesn't matter.
+pub struct StatsPort {
+    // Taken by `start`, which only runs once
+    listener: Mutex<Option<TcpListener>>,
+    listeners: Vec<TrafficCounters>,
+}
+
+impl StatsPort {
+    pub fn new(listener: TcpListener, listeners: Vec<TrafficCounters>) -> Self {
+        StatsPort { listener: Mutex::new(Some(listener)), listeners }
+    }
+
+    /// The counters totalled over all listeners, stamped with `timestamp`
+    /// (seconds since the Unix epoch)
+    pub fn lines(&self, timestamp: u64) -> String {
+        let total = |count: fn(&TrafficCounters) -> u64| self.listeners.iter().map(count).sum::<u64>();
+        [
+            ("pj.active_connections", total(TrafficCounters::active)),
+            ("pj.connections_total", total(TrafficCounters::connections)),
+            ("pj.bytes_sent", total(TrafficCounters::bytes_sent)),
+            ("pj.bytes_received", total(TrafficCounters::bytes_received)),
+        ]
+        .iter()
+        .map(|(key, value)| format!("{} {} {}\n", key, value, timestamp))
+        .collect()
+    }
+
+    async fn answer(&self, stream: tokio::net::TcpStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut request = Vec::new();
+        let mut reader = BufReader::new(reader.take(MAX_REQUEST));
+        match tokio::time::timeout(REQUEST_TIMEOUT, reader.read_until(b'\n', &mut request)).await {
+            Ok(Ok(_)) if request.ends_with(b"\n") => {}
+            Ok(Err(e)) => return Err(e),
+            _ => return Ok(()),
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        writer.write_all(self.lines(timestamp).as_bytes()).await?;
+        writer.shutdown().await
+    }
+}
+
+#[async_trait]
+impl BackgroundService for StatsPort {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(listener) = self.listener.lock().unwrap_or_else(PoisonError::into_inner).take() else { return };
+        let listener = match listener.set_nonblocking(true).and_then(|_| tokio::net::TcpListener::from_std(listener)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Failed to use the stats port socket: {}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    // Answered one at a time: clients are few and each answer is a few lines
+                    Ok((stream, peer)) => {
+                        if let Err(e) = self.answer(stream).await {
+                            debug!("Failed to answer stats client {}: {}", peer, e);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Accept on the stats port failed: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                },
+                _ = shutdown.changed() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_stats_port_answers_with_counters() {
+        let (web, ssh) = (TrafficCounters::default(), TrafficCounters::default());
+        web.add_connection();
+        web.add_sent(2048);
+        ssh.add_connection();
+        ssh.add_received(24);
+        ssh.active_counter().fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(StatsPort::new(listener, vec![web, ssh]));
+        let (_stop, shutdown) = watch::channel(false);
+        let running = stats.clone();
+        tokio::spawn(async move { running.start(shutdown).await });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"\n").await.unwrap();
+        let mut answer = String::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_string(&mut answer))
+            .await
+            .expect("Should answer and close")
+            .unwrap();
+
+        let lines: Vec<Vec<&str>> = answer.lines().map(|line| line.split(' ').collect()).collect();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for line in &lines {
+            let timestamp: u64 = line[2].parse().unwrap();
+            assert!(now - timestamp <= 5, "Timestamps should be the current time: {}", answer);
+        }
+        let values: Vec<(&str, &str)> = lines.iter().map(|line| (line[0], line[1])).collect();
+        assert_eq!(
+            values,
+            vec![
+                ("pj.active_connections", "1"),
+                ("pj.connections_total", "2"),
+                ("pj.bytes_sent", "2048"),
+                ("pj.bytes_received", "24"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Stays under the common 1500 byte MTU once IP/UDP headers are added
+const MAX_DATAGRAM: usize = 1432;
+// How long a metric may wait for others to share its datagram
+const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+// Metrics queued for the sender before new ones are dropped
+const QUEUE_METRICS: usize = 4096;
+
+/// Fire-and-forget StatsD client. Metrics are queued and sent from a
+/// background thread, several lines per datagram, so emitting one never
+/// blocks the relay; when the queue is full metrics are dropped.
+#[derive(Debug)]
+pub struct StatsdClient {
+    sender: SyncSender<String>,
+    tags: bool,
+    mapping_names: bool,
+}
+
+impl StatsdClient {
+    /// Connects to `addr`. With `tags` set, metrics carry DogStatsD
+    /// `|#key:value` tags; plain StatsD servers would reject those.
+    pub fn new(addr: &str, tags: bool) -> io::Result<Self> {
+        let target = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} did not resolve", addr)))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(target)?;
+
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_METRICS);
+        thread::Builder::new()
+            .name("pj-statsd".to_string())
+            .spawn(move || {
+                while let Ok(first) = receiver.recv() {
+                    let mut batch = first;
+                    let deadline = Instant::now() + FLUSH_INTERVAL;
+                    loop {
+                        let wait = deadline.saturating_duration_since(Instant::now());
+                        match receiver.recv_timeout(wait) {
+                            Ok(line) if batch.len() + 1 + line.len() > MAX_DATAGRAM => {
+                                let _ = socket.send(batch.as_bytes());
+                                batch = line;
+                            }
+                            Ok(line) => {
+                                batch.push('\n');
+                                batch.push_str(&line);
+                            }
+                            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    // Send errors (e.g. nothing listening yet) are not worth surfacing
+                    let _ = socket.send(batch.as_bytes());
+                }
+            })?;
+
+        Ok(StatsdClient { sender, tags, mapping_names: false })
+    }
+
+    /// Without tags, puts the mapping name into the names of per-mapping
+    /// gauges, so several mappings don't overwrite each other's value
+    pub fn with_mapping_names(mut self, mapping_names: bool) -> Self {
+        self.mapping_names = mapping_names;
+        self
+    }
+
+    /// A gauge of one mapping, tagged with its name or, without tags,
+    /// named after it when the proxy has several mappings
+    pub fn mapping_gauge(&self, metric: &str, mapping: &str, value: impl std::fmt::Display) {
+        if self.tags || !self.mapping_names {
+            return self.gauge(metric, value, &[("name", mapping)]);
+        }
+        // Dots and colons would split or end the metric name
+        let mapping: String = mapping
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.gauge(&format!("{}.{}", metric, mapping), value, &[]);
+    }
+
+    pub fn count(&self, metric: &str, value: i64, tags: &[(&str, &str)]) {
+        self.emit(metric, &value.to_string(), "c", tags);
+    }
+
+    pub fn gauge(&self, metric: &str, value: impl std::fmt::Display, tags: &[(&str, &str)]) {
+        self.emit(metric, &value.to_string(), "g", tags);
+    }
+
+    pub fn timing(&self, metric: &str, duration: Duration, tags: &[(&str, &str)]) {
+        self.emit(metric, &duration.as_millis().to_string(), "ms", tags);
+    }
+
+    fn emit(&self, metric: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+        let mut line = format!("pj.{}:{}|{}", metric, value, kind);
+        if self.tags && !tags.is_empty() {
+            let tags: Vec<String> = tags.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        // Metrics are best effort; drop rather than wait when the queue is full
+        let _ = self.sender.try_send(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_batched() {
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let client = StatsdClient::new(&sink.local_addr().unwrap().to_string(), true).unwrap();
+
+        client.count("connections.accepted", 1, &[("name", "web"), ("backend", "10.0.0.1:80")]);
+        client.gauge("connections.active", 3, &[("name", "web")]);
+        client.timing("connection.duration", Duration::from_millis(1500), &[]);
+
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let n = sink.recv(&mut buf).unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&buf[..n]).unwrap().lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "pj.connections.accepted:1|c|#name:web,backend:10.0.0.1:80",
+                "pj.connections.active:3|g|#name:web",
+                "pj.connection.duration:1500|ms",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tags_omitted_for_plain_statsd() {
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let client = StatsdClient::new(&sink.local_addr().unwrap().to_string(), false).unwrap();
+
+        client.count("connections.failed", 1, &[("name", "web")]);
+
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let n = sink.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"pj.connections.failed:1|c");
+    }
+
+    #[test]
+    fn test_mapping_gauge_named_per_mapping() {
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let addr = sink.local_addr().unwrap().to_string();
+
+        // One plain StatsD gauge per mapping once there are several
+        let client = StatsdClient::new(&addr, false).unwrap().with_mapping_names(true);
+        client.mapping_gauge("connections.active", "web", 2);
+        client.mapping_gauge("connections.active", "0.0.0.0:8080", 5);
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let n = sink.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"pj.connections.active.web:2|g\npj.connections.active.0_0_0_0_8080:5|g");
+
+        // Tags tell mappings apart on their own, and a single mapping needs nothing
+        StatsdClient::new(&addr, true).unwrap().with_mapping_names(true).mapping_gauge("connections.active", "web", 2);
+        let n = sink.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"pj.connections.active:2|g|#name:web");
+        StatsdClient::new(&addr, false).unwrap().mapping_gauge("connections.active", "web", 2);
+        let n = sink.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"pj.connections.active:2|g");
+    }
+}
@@ -0,0 +1,112 @@
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+
+use crate::connection::{format_bytes, TrafficCounters};
+
+/// How often a shutting down proxy checks whether its connections have drained
+pub(crate) const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Connections open over every listener at once, and the most there have been
+#[derive(Debug, Default)]
+pub struct Concurrency {
+    active: AtomicU64,
+    peak: AtomicU64,
+}
+
+impl Concurrency {
+    pub fn opened(&self) {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(active, Ordering::Relaxed);
+    }
+
+    pub fn closed(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Connections being relayed right now
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Most connections relayed at once since start
+    pub fn peak(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+/// Logs what the proxy did over its lifetime once it is shut down and its
+/// connections have drained, or straight away on Ctrl-C, which doesn't wait
+/// for them
+pub struct ShutdownSummary {
+    listeners: Vec<TrafficCounters>,
+    concurrency: Arc<Concurrency>,
+    started: Instant,
+}
+
+impl ShutdownSummary {
+    pub fn new(listeners: Vec<TrafficCounters>, concurrency: Arc<Concurrency>, started: Instant) -> Self {
+        ShutdownSummary { listeners, concurrency, started }
+    }
+
+    /// The line logged at shutdown, totalled over all listeners
+    pub fn summary(&self) -> String {
+        let total = |count: fn(&TrafficCounters) -> u64| self.listeners.iter().map(count).sum::<u64>();
+        format!(
+            "Shutdown summary: {} connections served | Sent: {} | Received: {} | Peak: {} concurrent | Uptime: {:.1}s",
+            total(TrafficCounters::connections),
+            format_bytes(total(TrafficCounters::bytes_sent)),
+            format_bytes(total(TrafficCounters::bytes_received)),
+            self.concurrency.peak(),
+            self.started.elapsed().as_secs_f64(),
+        )
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ShutdownSummary {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                while self.concurrency.active() > 0 {
+                    tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        info!("{}", self.summary());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_totals_listeners_and_peak() {
+        let (web, ssh) = (TrafficCounters::default(), TrafficCounters::default());
+        web.add_connection();
+        web.add_sent(2048);
+        ssh.add_connection();
+        ssh.add_received(24);
+        let concurrency = Arc::new(Concurrency::default());
+        concurrency.opened();
+        concurrency.opened();
+        concurrency.closed();
+        concurrency.opened();
+        concurrency.closed();
+        assert_eq!((concurrency.active(), concurrency.peak()), (1, 2));
+
+        let summary = ShutdownSummary::new(vec![web, ssh], concurrency, Instant::now()).summary();
+        assert!(
+            summary.starts_with("Shutdown summary: 2 connections served | Sent: 2.0 KB | Received: 24 B | Peak: 2 concurrent | Uptime: "),
+            "{}",
+            summary
+        );
+    }
+}
@@ -0,0 +1,196 @@
+#[cfg(feature = "otlp")]
+use async_trait::async_trait;
+#[cfg(feature = "otlp")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otlp")]
+use opentelemetry::KeyValue;
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::trace::SdkTracerProvider;
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::Resource;
+#[cfg(feature = "otlp")]
+use std::sync::Arc;
+#[cfg(feature = "otlp")]
+use tracing::{debug, warn};
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+#[cfg(feature = "otlp")]
+use pingora_core::server::ShutdownWatch;
+#[cfg(feature = "otlp")]
+use pingora_core::services::background::BackgroundService;
+
+#[cfg(feature = "otlp")]
+use crate::summary::{Concurrency, DRAIN_CHECK_INTERVAL};
+
+/// Target of the per-connection spans. Log output filters it out so the
+/// plain log format is unchanged; only the OTLP layer enables it.
+pub const SPAN_TARGET: &str = "pj::span";
+
+/// Builds a layer exporting connection spans over OTLP/HTTP to `endpoint`,
+/// the collector's base URL (e.g. `http://localhost:4318`), along with the
+/// provider to shut down on exit so the spans still batched are sent.
+#[cfg(feature = "otlp")]
+pub fn otlp_layer<S>(endpoint: &str) -> Result<(impl Layer<S>, SdkTracerProvider), String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let endpoint = endpoint.trim_end_matches('/');
+    let traces_endpoint = if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{}/v1/traces", endpoint)
+    };
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(traces_endpoint)
+        .build()
+        .map_err(|e| format!("Failed to build OTLP exporter: {}", e))?;
+
+    let resource = Resource::builder()
+        .with_service_name("pj")
+        .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+
+    // Exports from its own thread, so no runtime is needed this early in startup
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer("pj");
+    opentelemetry::global::set_tracer_provider(provider.clone());
+
+    Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+}
+
+/// Shuts the OTLP tracer provider down once the proxy is shut down and its
+/// connections have drained, or straight away on Ctrl-C, exporting the
+/// spans the batch exporter still holds
+#[cfg(feature = "otlp")]
+pub struct SpanFlush {
+    provider: SdkTracerProvider,
+    concurrency: Arc<Concurrency>,
+}
+
+#[cfg(feature = "otlp")]
+impl SpanFlush {
+    pub fn new(provider: SdkTracerProvider, concurrency: Arc<Concurrency>) -> Self {
+        SpanFlush { provider, concurrency }
+    }
+}
+
+#[cfg(feature = "otlp")]
+#[async_trait]
+impl BackgroundService for SpanFlush {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                while self.concurrency.active() > 0 {
+                    tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        // The exporter sends over a blocking client
+        let provider = self.provider.clone();
+        match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+            Ok(Ok(())) => debug!("Flushed connection spans"),
+            Ok(Err(e)) => warn!("Failed to flush connection spans: {}", e),
+            Err(e) => warn!("Failed to flush connection spans: {}", e),
+        }
+    }
+}
+
+/// When log lines are colored, from `PJ_LOG_COLOR`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogColor {
+    /// Only when logs go to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl LogColor {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(LogColor::Auto),
+            "always" => Ok(LogColor::Always),
+            "never" => Ok(LogColor::Never),
+            other => Err(format!("Unknown log color mode '{}'. Supported: auto, always, never", other)),
+        }
+    }
+
+    /// Whether to color output written to a terminal (`is_terminal`) or not
+    pub fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            LogColor::Auto => is_terminal,
+            LogColor::Always => true,
+            LogColor::Never => false,
+        }
+    }
+}
+
+/// What each log line is stamped with, from `PJ_LOG_TIME`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogTime {
+    None,
+    /// Wall-clock time, e.g. `2024-05-01T12:00:00.123456Z`
+    Rfc3339,
+    /// Time since the proxy started, e.g. `3.512s`
+    Uptime,
+}
+
+impl LogTime {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "none" => Ok(LogTime::None),
+            "rfc3339" => Ok(LogTime::Rfc3339),
+            "uptime" => Ok(LogTime::Uptime),
+            other => Err(format!("Unknown log time format '{}'. Supported: none, rfc3339, uptime", other)),
+        }
+    }
+}
+
+/// The human-readable log layer, colored or not and stamped per `time`,
+/// writing to stdout or, with `stderr`, to stderr
+pub fn log_layer<S>(color: bool, time: LogTime, stderr: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(color)
+        .with_writer(move || -> Box<dyn std::io::Write> {
+            match stderr {
+                true => Box::new(std::io::stderr()),
+                false => Box::new(std::io::stdout()),
+            }
+        });
+    match time {
+        LogTime::None => layer.without_time().boxed(),
+        LogTime::Rfc3339 => layer.boxed(),
+        LogTime::Uptime => layer.with_timer(tracing_subscriber::fmt::time::uptime()).boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!(LogColor::parse(" Never ").unwrap(), LogColor::Never);
+        assert!(LogColor::parse("sometimes").is_err());
+        assert!(LogColor::Auto.enabled(true));
+        assert!(!LogColor::Auto.enabled(false));
+        assert!(LogColor::Always.enabled(false));
+        assert!(!LogColor::Never.enabled(true));
+
+        assert_eq!(LogTime::parse("uptime").unwrap(), LogTime::Uptime);
+        assert_eq!(LogTime::parse("RFC3339").unwrap(), LogTime::Rfc3339);
+        assert!(LogTime::parse("local").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use pingora_core::protocols::Stream;
+
+// The IPv6 counterpart of SO_ORIGINAL_DST, which libc does not export
+#[cfg(target_os = "linux")]
+const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+/// Where a connection handed to a transparent listener was headed. NAT
+/// redirects (iptables `REDIRECT`/`DNAT`) record it as `SO_ORIGINAL_DST`;
+/// with `TPROXY` the accepted socket's own address is the original
+/// destination.
+pub fn original_destination(io: &Stream) -> Option<SocketAddr> {
+    nat_destination(io).or_else(|| local_address(io))
+}
+
+/// The destination recorded by a NAT redirect, if the connection had one
+#[cfg(target_os = "linux")]
+fn nat_destination(io: &Stream) -> Option<SocketAddr> {
+    let fd = raw_fd(io)?;
+    // The connection's family decides which table the redirect was made in
+    let (level, option) = match local_address(io)? {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST),
+    };
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockopt(fd, level, option, &mut storage as *mut _ as *mut libc::c_void, &mut len) };
+    if result != 0 {
+        return None;
+    }
+    parse_sockaddr(&storage)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn nat_destination(_io: &Stream) -> Option<SocketAddr> {
+    None
+}
+
+/// The address the client connected to, as this host sees it
+#[cfg(unix)]
+fn local_address(io: &Stream) -> Option<SocketAddr> {
+    let fd = raw_fd(io)?;
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+    if result != 0 {
+        return None;
+    }
+    parse_sockaddr(&storage)
+}
+
+#[cfg(not(unix))]
+fn local_address(_io: &Stream) -> Option<SocketAddr> {
+    None
+}
+
+/// The descriptor of a plain TCP stream; other streams have no socket of their own
+#[cfg(unix)]
+fn raw_fd(io: &Stream) -> Option<std::os::fd::RawFd> {
+    use std::os::fd::AsRawFd;
+    io.as_any()
+        .downcast_ref::<pingora_core::protocols::l4::stream::Stream>()
+        .map(|stream| stream.as_raw_fd())
+}
+
+/// Reads an IPv4 or IPv6 socket address filled in by the kernel. IPv4
+/// addresses mapped into IPv6 come back as plain IPv4.
+#[cfg(unix)]
+fn parse_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Some(match ip.to_ipv4_mapped() {
+                Some(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+                None => SocketAddr::V6(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id)),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn storage_of<T>(addr: T) -> libc::sockaddr_storage {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        unsafe { std::ptr::write(&mut storage as *mut _ as *mut T, addr) };
+        storage
+    }
+
+    #[test]
+    fn test_parse_sockaddr() {
+        let mut v4: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        v4.sin_family = libc::AF_INET as libc::sa_family_t;
+        v4.sin_port = 8080u16.to_be();
+        v4.sin_addr.s_addr = u32::from(std::net::Ipv4Addr::new(10, 0, 0, 7)).to_be();
+        assert_eq!(parse_sockaddr(&storage_of(v4)), Some("10.0.0.7:8080".parse().unwrap()));
+
+        let mut v6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        v6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        v6.sin6_port = 443u16.to_be();
+        v6.sin6_addr.s6_addr = "2001:db8::5".parse::<std::net::Ipv6Addr>().unwrap().octets();
+        assert_eq!(parse_sockaddr(&storage_of(v6)), Some("[2001:db8::5]:443".parse().unwrap()));
+
+        v6.sin6_addr.s6_addr = "::ffff:192.0.2.1".parse::<std::net::Ipv6Addr>().unwrap().octets();
+        assert_eq!(parse_sockaddr(&storage_of(v6)), Some("192.0.2.1:443".parse().unwrap()));
+
+        let unix: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        assert_eq!(parse_sockaddr(&unix), None);
+    }
+}
@@ -0,0 +1,579 @@
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+
+use pj::harness::TestProxy;
+use pj::registry::ConnectionRegistry;
+use pj::ProxyOptions;
+
+async fn start_echo_server(addr: &str) -> tokio::task::JoinHandle<()> {
+    let listener = TcpListener::bind(addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(_) => break,
+            };
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                loop {
+                    match socket.read(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            if socket.write_all(&buf[0..n]).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+    })
+}
+
+async fn http_request(addr: &str, method: &str, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.expect("Failed to connect to admin API");
+    let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", method, path, addr);
+    stream.write_all(request.as_bytes()).await.expect("Failed to send request");
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.expect("Failed to read response");
+    let response = String::from_utf8_lossy(&response).to_string();
+
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .expect("Malformed status line");
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string();
+    (status, body)
+}
+
+#[tokio::test]
+async fn test_admin_lists_active_connections() {
+    let echo_server_addr = "127.0.0.1:23001";
+    let proxy_listen_addr = "127.0.0.1:23002";
+    let admin_addr = "127.0.0.1:23003";
+
+    let _echo_handle = start_echo_server(echo_server_addr).await;
+
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    // No connections yet
+    let (status, body) = http_request(admin_addr, "GET", "/connections").await;
+    assert_eq!(status, 200);
+    assert_eq!(body.trim(), "[]");
+
+    // Open a connection and push some data through it
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let client_addr = client.local_addr().expect("Failed to get client address");
+    let test_data = b"Hello, admin!";
+    client.write_all(test_data).await.expect("Failed to write data");
+    let mut buffer = vec![0u8; test_data.len()];
+    client.read_exact(&mut buffer).await.expect("Failed to read response");
+
+    let (status, body) = http_request(admin_addr, "GET", "/connections").await;
+    assert_eq!(status, 200);
+    let connections: serde_json::Value = serde_json::from_str(&body).expect("Response should be JSON");
+    let connections = connections.as_array().expect("Response should be a list");
+    assert_eq!(connections.len(), 1, "Should list the open connection: {}", body);
+    assert_eq!(connections[0]["id"], 0);
+    assert_eq!(connections[0]["client_addr"], client_addr.to_string());
+    assert_eq!(connections[0]["backend_addr"], echo_server_addr);
+    assert_eq!(connections[0]["bytes_sent"], test_data.len());
+    assert_eq!(connections[0]["bytes_received"], test_data.len());
+    assert!(connections[0]["duration_secs"].as_f64().is_some());
+
+    // Closing the connection removes it from the listing
+    drop(client);
+    sleep(Duration::from_millis(500)).await;
+
+    let (status, body) = http_request(admin_addr, "GET", "/connections").await;
+    assert_eq!(status, 200);
+    assert_eq!(body.trim(), "[]", "Closed connection should disappear");
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_admin_cleans_up_failed_transfers() {
+    // Backend that resets the connection as soon as it reads anything
+    let backend = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind backend");
+    let backend_addr = backend.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = backend.accept().await {
+            let mut buf = [0; 64];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket.set_linger(Some(Duration::ZERO));
+            drop(socket);
+        }
+    });
+
+    // The registry is what /connections lists; this proxy has one of its own
+    let registry = Arc::new(ConnectionRegistry::new());
+    let options = ProxyOptions { registry: Some(registry.clone()), ..Default::default() };
+    let proxy = TestProxy::start(&format!("127.0.0.1:0:{}", backend_addr), options).await.expect("Failed to start proxy");
+    assert_eq!(registry.listeners()[0].listen_addr, proxy.addr().to_string());
+
+    let mut client = TcpStream::connect(proxy.addr()).await.expect("Failed to connect to proxy");
+    client.write_all(b"trigger reset").await.expect("Failed to write data");
+
+    // Once the proxy has torn the relay down the client is closed too
+    let mut buf = [0u8; 16];
+    let closed = timeout(Duration::from_secs(5), client.read(&mut buf)).await.expect("Proxy should close the client");
+    assert!(matches!(closed, Ok(0) | Err(_)), "Nothing should be relayed: {:?}", closed);
+
+    // The error path must still deregister the connection
+    for _ in 0..100 {
+        if registry.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert!(registry.snapshot().is_empty(), "Failed connection should be removed from the registry");
+
+    drop(client);
+    proxy.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_admin_closes_connection_by_key() {
+    let echo_server_addr = "127.0.0.1:23007";
+    let proxy_listen_addr = "127.0.0.1:23008";
+    let admin_addr = "127.0.0.1:23009";
+
+    let _echo_handle = start_echo_server(echo_server_addr).await;
+
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .env("PJ_LOG", "info")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    client.write_all(b"ping").await.expect("Failed to write data");
+    let mut buffer = [0u8; 4];
+    client.read_exact(&mut buffer).await.expect("Failed to read response");
+
+    // Unknown keys are rejected
+    let (status, _) = http_request(admin_addr, "DELETE", "/connections/999").await;
+    assert_eq!(status, 404);
+
+    let (_, body) = http_request(admin_addr, "GET", "/connections").await;
+    let connections: serde_json::Value = serde_json::from_str(&body).expect("Connections should be JSON");
+    let key = &connections[0]["key"];
+    let (status, body) = http_request(admin_addr, "DELETE", &format!("/connections/{}", key)).await;
+    assert_eq!(status, 200, "Close should succeed: {}", body);
+
+    // The client sees EOF promptly once the proxy tears the connection down
+    let mut buf = [0u8; 16];
+    let n = timeout(Duration::from_secs(2), client.read(&mut buf))
+        .await
+        .expect("Client should observe the close promptly")
+        .expect("Read should not error");
+    assert_eq!(n, 0, "Client should read EOF");
+
+    let (_, body) = http_request(admin_addr, "GET", "/connections").await;
+    assert_eq!(body.trim(), "[]");
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    println!("Proxy output:\n{}", combined);
+
+    assert!(combined.contains("admin closed"), "Should log an admin closed end event");
+}
+
+#[tokio::test]
+async fn test_admin_pauses_and_resumes_listener() {
+    let echo_server_addr = "127.0.0.1:23010";
+    let proxy_listen_addr = "127.0.0.1:23011";
+    let admin_addr = "127.0.0.1:23012";
+
+    let _echo_handle = start_echo_server(echo_server_addr).await;
+
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    async fn echoes(addr: &str) -> bool {
+        let Ok(mut client) = TcpStream::connect(addr).await else { return false };
+        if client.write_all(b"ping").await.is_err() {
+            return false;
+        }
+        let mut buffer = [0u8; 4];
+        matches!(timeout(Duration::from_secs(2), client.read_exact(&mut buffer)).await, Ok(Ok(_)))
+    }
+
+    // A connection opened before the pause survives it
+    let mut existing = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    existing.write_all(b"before").await.unwrap();
+    let mut buffer = [0u8; 6];
+    existing.read_exact(&mut buffer).await.unwrap();
+
+    let (status, body) = http_request(admin_addr, "POST", &format!("/listeners/{}/pause", proxy_listen_addr)).await;
+    assert_eq!(status, 200, "Unexpected response: {}", body);
+    assert!(!echoes(proxy_listen_addr).await, "New connections should be refused while paused");
+
+    existing.write_all(b"during").await.unwrap();
+    existing.read_exact(&mut buffer).await.expect("Existing connection should keep working");
+    assert_eq!(&buffer, b"during");
+
+    let (status, body) = http_request(admin_addr, "GET", "/listeners").await;
+    assert_eq!(status, 200);
+    assert!(body.contains(r#""paused":true"#), "Unexpected listeners: {}", body);
+
+    let (status, _) = http_request(admin_addr, "POST", &format!("/listeners/{}/resume", proxy_listen_addr)).await;
+    assert_eq!(status, 200);
+    assert!(echoes(proxy_listen_addr).await, "Connections should work again after resuming");
+
+    let (status, _) = http_request(admin_addr, "POST", "/listeners/127.0.0.1:1/pause").await;
+    assert_eq!(status, 404);
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined_output = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined_output.contains("listener is paused"), "Should log why connections were refused");
+}
+
+#[tokio::test]
+async fn test_admin_reports_port_zero_listener() {
+    let echo_server_addr = "127.0.0.1:23013";
+    let admin_addr = "127.0.0.1:23014";
+
+    let _echo_handle = start_echo_server(echo_server_addr).await;
+
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("127.0.0.1:0:{}", echo_server_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    let (status, body) = http_request(admin_addr, "GET", "/listeners").await;
+    assert_eq!(status, 200);
+    let listeners: serde_json::Value = serde_json::from_str(&body).expect("Response should be JSON");
+    let bound: std::net::SocketAddr = listeners[0]["listen_addr"]
+        .as_str()
+        .and_then(|addr| addr.parse().ok())
+        .unwrap_or_else(|| panic!("Should list the bound address: {}", body));
+    assert_ne!(bound.port(), 0, "Should report the port the OS picked");
+
+    let mut client = TcpStream::connect(bound).await.expect("Failed to connect to the bound port");
+    client.write_all(b"ping").await.expect("Failed to write data");
+    let mut buffer = [0u8; 4];
+    client.read_exact(&mut buffer).await.expect("Failed to read response");
+    assert_eq!(&buffer, b"ping");
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined_output = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(
+        combined_output.contains(&format!("listening on {} (requested :0)", bound)),
+        "Should log the bound address: {}",
+        combined_output
+    );
+}
+
+#[tokio::test]
+async fn test_admin_metrics_track_connect_latency() {
+    let echo_server_addr = "127.0.0.1:23015";
+    let proxy_listen_addr = "127.0.0.1:23016";
+    let admin_addr = "127.0.0.1:23017";
+
+    let _echo_handle = start_echo_server(echo_server_addr).await;
+
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    let (status, body) = http_request(admin_addr, "GET", "/metrics").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("pj_upstream_connect_seconds_count 0"), "No connects yet: {}", body);
+
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    client.write_all(b"ping").await.expect("Failed to write data");
+    let mut buffer = [0u8; 4];
+    client.read_exact(&mut buffer).await.expect("Failed to read response");
+
+    let (_, body) = http_request(admin_addr, "GET", "/metrics").await;
+    assert!(body.contains("pj_upstream_connect_seconds_count 1"), "Should count the connect: {}", body);
+    assert!(body.contains("pj_upstream_connect_seconds_bucket{le=\"+Inf\"} 1"), "{}", body);
+
+    drop(client);
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined_output = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    let estab = combined_output
+        .lines()
+        .find(|line| line.contains(" estab "))
+        .unwrap_or_else(|| panic!("Should log the connection: {}", combined_output));
+    assert!(estab.contains(" | Connect: ") && estab.ends_with("ms"), "estab line should carry the connect time: {}", estab);
+}
+
+#[tokio::test]
+async fn test_admin_reports_last_upstream_error() {
+    let refused_addr = "127.0.0.1:23018";  // Nothing listens here
+    let proxy_listen_addr = "127.0.0.1:23019";
+    let admin_addr = "127.0.0.1:23020";
+
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, refused_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    let (_, body) = http_request(admin_addr, "GET", "/listeners").await;
+    let listeners: serde_json::Value = serde_json::from_str(&body).expect("Listeners should be JSON");
+    assert_eq!(listeners[0]["last_error"], serde_json::Value::Null, "Nothing has failed yet: {}", body);
+
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let mut buffer = Vec::new();
+    let _ = timeout(Duration::from_secs(5), client.read_to_end(&mut buffer)).await;
+
+    let (status, body) = http_request(admin_addr, "GET", "/listeners").await;
+    assert_eq!(status, 200);
+    let listeners: serde_json::Value = serde_json::from_str(&body).expect("Listeners should be JSON");
+    let last_error = listeners[0]["last_error"].as_str().unwrap_or_else(|| panic!("Should record the failure: {}", body));
+    assert!(last_error.starts_with(&format!("{}: ", refused_addr)), "Should name the upstream: {}", last_error);
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+    let at = listeners[0]["last_error_at"].as_f64().expect("Should stamp the failure");
+    assert!(now - at >= 0.0 && now - at < 30.0, "The failure should be recent: {} vs now {}", at, now);
+
+    let (_, body) = http_request(admin_addr, "GET", "/metrics").await;
+    let gauge = body
+        .lines()
+        .find(|line| line.starts_with("pj_listener_last_error_timestamp_seconds{"))
+        .unwrap_or_else(|| panic!("Should expose the failure: {}", body));
+    assert!(gauge.contains(&format!("listen_addr=\"{}\"", proxy_listen_addr)), "{}", gauge);
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_admin_counts_empty_connections() {
+    let echo_server_addr = "127.0.0.1:23021";
+    let proxy_listen_addr = "127.0.0.1:23022";
+    let admin_addr = "127.0.0.1:23023";
+
+    let _echo_server = start_echo_server(echo_server_addr).await;
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    // Connect and hang up at once, like a health check
+    drop(TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy"));
+
+    // A connection that relays something isn't counted
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    client.write_all(b"ping").await.expect("Failed to write");
+    let mut buffer = [0u8; 4];
+    client.read_exact(&mut buffer).await.expect("Failed to read response");
+    drop(client);
+    sleep(Duration::from_millis(500)).await;
+
+    let (_, body) = http_request(admin_addr, "GET", "/metrics").await;
+    assert!(
+        body.contains(&format!("pj_empty_connections_total{{listen_addr=\"{}\"}} 1\n", proxy_listen_addr)),
+        "Should count the empty connection: {}",
+        body
+    );
+    let (_, body) = http_request(admin_addr, "GET", "/listeners").await;
+    let listeners: serde_json::Value = serde_json::from_str(&body).expect("Listeners should be JSON");
+    assert_eq!(listeners[0]["empty_connections"], 1, "{}", body);
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_admin_counts_reset_and_graceful_closes() {
+    let backend_addr = "127.0.0.1:23024";
+    let proxy_listen_addr = "127.0.0.1:23025";
+    let admin_addr = "127.0.0.1:23026";
+
+    // Resets connections whose first byte is 'r' and closes the rest with a FIN
+    let backend = TcpListener::bind(backend_addr).await.expect("Failed to bind backend");
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = backend.accept().await {
+            let mut first = [0u8; 1];
+            if socket.read_exact(&mut first).await.is_ok() && &first == b"r" {
+                socket.set_linger(Some(Duration::ZERO)).expect("Failed to set SO_LINGER");
+            }
+        }
+    });
+
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, backend_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(5)).await;
+
+    for first in [b"r", b"g"] {
+        let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+        client.write_all(first).await.expect("Failed to write");
+        let mut rest = Vec::new();
+        let _ = timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await.expect("Proxy should close the client");
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    let (_, body) = http_request(admin_addr, "GET", "/metrics").await;
+    for kind in ["reset", "graceful"] {
+        assert!(
+            body.contains(&format!("pj_closes_total{{listen_addr=\"{}\",kind=\"{}\"}} 1\n", proxy_listen_addr, kind)),
+            "Should count one {} close: {}",
+            kind,
+            body
+        );
+    }
+    let (_, body) = http_request(admin_addr, "GET", "/listeners").await;
+    let listeners: serde_json::Value = serde_json::from_str(&body).expect("Listeners should be JSON");
+    assert_eq!((listeners[0]["graceful_closes"].as_u64(), listeners[0]["reset_closes"].as_u64()), (Some(1), Some(1)), "{}", body);
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined_output = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined_output.contains("Reason: peer_reset | Close: reset"), "{}", combined_output);
+    assert!(combined_output.contains("Reason: upstream_eof | Close: graceful"), "{}", combined_output);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_healthz_through_shutdown() {
+    let echo_server_addr = "127.0.0.1:23027";
+    let proxy_listen_addr = "127.0.0.1:23028";
+    let admin_addr = "127.0.0.1:23029";
+
+    let _echo_handle = start_echo_server(echo_server_addr).await;
+
+    // Run the binary itself so the signal reaches the proxy, not cargo
+    let mut proxy_process = Command::new(env!("CARGO_BIN_EXE_pj"))
+        .args(["--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_ADMIN_ADDR", admin_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    sleep(Duration::from_secs(3)).await;
+
+    let (status, body) = http_request(admin_addr, "GET", "/healthz").await;
+    assert_eq!(status, 200, "{}", body);
+    let health: serde_json::Value = serde_json::from_str(&body).expect("Health should be JSON");
+    assert_eq!(health["listeners_bound"], 1, "{}", body);
+
+    // A connection still open keeps the proxy draining after SIGTERM
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    client.write_all(b"hello").await.expect("Failed to write data");
+    let mut buffer = [0u8; 5];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .expect("Timeout waiting for echo")
+        .expect("Failed to read echo");
+
+    unsafe { libc::kill(proxy_process.id() as i32, libc::SIGTERM) };
+    sleep(Duration::from_secs(1)).await;
+
+    let (status, body) = http_request(admin_addr, "GET", "/healthz").await;
+    assert_eq!(status, 503, "{}", body);
+    assert!(body.contains("shutting down"), "{}", body);
+
+    drop(client);
+    proxy_process.kill().expect("Failed to kill proxy");
+    let _ = proxy_process.wait_with_output();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_keeps_running_when_a_listener_fails_to_bind() {
+    // One mapping's port is already taken; the admin API gets a port of its own
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to hold a port");
+    let taken_addr = taken.local_addr().unwrap();
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+    let mut proxy_process = Command::new(env!("CARGO_BIN_EXE_pj"))
+        .args(["--proxy", &format!("{}:127.0.0.1:9", taken_addr), "--proxy", "127.0.0.1:0:127.0.0.1:9"])
+        .env("PJ_ADMIN_ADDR", &admin_addr)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start proxy");
+
+    let mut ready = false;
+    for _ in 0..300 {
+        if TcpStream::connect(&admin_addr).await.is_ok() {
+            ready = true;
+            break;
+        }
+        assert!(proxy_process.try_wait().unwrap().is_none(), "The proxy should keep running");
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(ready, "The admin API never came up");
+
+    let (status, body) = http_request(&admin_addr, "GET", "/healthz").await;
+    assert_eq!(status, 200, "{}", body);
+    let health: serde_json::Value = serde_json::from_str(&body).expect("Health should be JSON");
+    assert_eq!(health["listeners_bound"], 1, "{}", body);
+
+    // The one that failed is listed as not bound
+    let (_, body) = http_request(&admin_addr, "GET", "/listeners").await;
+    let listeners: serde_json::Value = serde_json::from_str(&body).expect("Listeners should be JSON");
+    let failed = listeners
+        .as_array()
+        .expect("Listeners should be a list")
+        .iter()
+        .find(|listener| listener["listen_addr"] == taken_addr.to_string())
+        .unwrap_or_else(|| panic!("The failed listener should be listed: {}", body));
+    assert_eq!(failed["bound"], false, "{}", body);
+
+    proxy_process.kill().expect("Failed to kill proxy");
+    let _ = proxy_process.wait();
+}
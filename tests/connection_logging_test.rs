@@ -1,9 +1,77 @@
-use std::process::{Command, Stdio};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time::sleep;
 
+/// A running `pj` whose log is collected as it is written
+struct ProxyRun {
+    process: Child,
+    log: Arc<Mutex<String>>,
+    reader: std::thread::JoinHandle<()>,
+}
+
+impl ProxyRun {
+    /// Starts the built binary on `mapping`, which listens on port 0, and
+    /// waits for it to log the port it was given
+    async fn start(mapping: &str, envs: &[(&str, &str)]) -> (ProxyRun, SocketAddr) {
+        let mut process = Command::new(env!("CARGO_BIN_EXE_pj"))
+            .args(["--proxy", mapping])
+            .envs(envs.iter().copied())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start proxy");
+        let stdout = process.stdout.take().expect("stdout is piped");
+        let log = Arc::new(Mutex::new(String::new()));
+        let (bound_tx, bound_rx) = tokio::sync::oneshot::channel();
+        let reader = std::thread::spawn({
+            let log = log.clone();
+            move || {
+                let mut bound_tx = Some(bound_tx);
+                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                    let bound = line.split_once("listening on ").and_then(|(_, rest)| rest.split_once(" (requested :0)"));
+                    if let Some((addr, _)) = bound {
+                        if let Some(bound_tx) = bound_tx.take() {
+                            let _ = bound_tx.send(addr.parse::<SocketAddr>().expect("Malformed listen address"));
+                        }
+                    }
+                    let mut log = log.lock().unwrap();
+                    log.push_str(&line);
+                    log.push('\n');
+                }
+            }
+        });
+        let bound = tokio::time::timeout(Duration::from_secs(30), bound_rx)
+            .await
+            .expect("Proxy never logged its listen address")
+            .expect("Proxy exited before listening");
+        (ProxyRun { process, log, reader }, bound)
+    }
+
+    /// Waits up to five seconds for the log to contain `text`, which lines
+    /// written as a connection ends may take a moment to
+    async fn wait_for_log(&self, text: &str) {
+        for _ in 0..100 {
+            if self.log.lock().unwrap().contains(text) {
+                return;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Stops the proxy, returning everything it logged
+    fn finish(mut self) -> String {
+        self.process.kill().expect("Failed to kill proxy");
+        let _ = self.process.wait();
+        self.reader.join().expect("Log reader panicked");
+        std::mem::take(&mut *self.log.lock().unwrap())
+    }
+}
+
 #[tokio::test]
 async fn test_connection_logging_basic() {
     let echo_server_addr = "127.0.0.1:21001";
@@ -28,7 +96,7 @@ async fn test_connection_logging_basic() {
     
     // Start proxy with stderr capture to get logs
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -66,8 +134,8 @@ async fn test_connection_logging_basic() {
             "Should log connection info");
     assert!(combined_output.contains("estab") || combined_output.contains("Adding proxy mapping"), 
             "Should log connection establishment or proxy setup");
-    assert!(combined_output.contains(&proxy_listen_addr), "Should log proxy address");
-    assert!(combined_output.contains(&echo_server_addr), "Should log backend address");
+    assert!(combined_output.contains(proxy_listen_addr), "Should log proxy address");
+    assert!(combined_output.contains(echo_server_addr), "Should log backend address");
     assert!(combined_output.contains("closed") || combined_output.contains("Duration:"), 
             "Should log connection close with stats");
 }
@@ -96,7 +164,7 @@ async fn test_connection_logging_with_stats() {
     
     // Start proxy
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -146,6 +214,9 @@ async fn test_connection_logging_with_stats() {
     assert!(combined_output.contains("Duration:"), "Should log connection duration");
     assert!(combined_output.contains("Sent:") && combined_output.contains("Received:"), 
             "Should log data transfer stats");
+    assert!(combined_output.contains("PeakTx:") && combined_output.contains("PeakRx:"), 
+            "Should log peak throughput per direction");
+    assert!(combined_output.contains("B/s"), "Should use human-readable rate format");
     // Check for human-readable format (B, KB, MB, GB)
     assert!(combined_output.contains(" B") || combined_output.contains(" KB") || 
             combined_output.contains(" MB") || combined_output.contains(" GB"), 
@@ -159,7 +230,7 @@ async fn test_connection_logging_with_error() {
     
     // Start proxy pointing to unreachable address
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -229,7 +300,7 @@ async fn test_multiple_connections_logging() {
     
     // Start proxy
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -280,4 +351,774 @@ async fn test_multiple_connections_logging() {
     assert!(combined_output.contains("Conn #0"), "Should have connection 0");
     assert!(combined_output.contains("Conn #1"), "Should have connection 1");
     assert!(combined_output.contains("Conn #2"), "Should have connection 2");
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_connection_logging_with_mapping_name() {
+    let echo_server_addr = "127.0.0.1:21008";
+    let proxy_listen_addr = "127.0.0.1:21009";
+    
+    // Start echo server
+    let echo_listener = TcpListener::bind(echo_server_addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        let (mut socket, _) = echo_listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        
+        loop {
+            match socket.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    socket.write_all(&buf[0..n]).await.unwrap();
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}?name=echo-svc", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_LOG", "info")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let test_data = b"Named mapping";
+    client.write_all(test_data).await.expect("Failed to write data");
+    
+    let mut buffer = vec![0u8; test_data.len()];
+    client.read_exact(&mut buffer).await.expect("Failed to read response");
+    
+    drop(client);
+    sleep(Duration::from_millis(500)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(combined_output.contains("[echo-svc] Conn #0 estab"), "Start line should carry the mapping name");
+    assert!(combined_output.contains("[echo-svc] Conn #0 close"), "End line should carry the mapping name");
+}
+
+#[tokio::test]
+async fn test_connection_logging_labels_transfer_errors() {
+    let backend_addr = "127.0.0.1:21010";
+    let proxy_listen_addr = "127.0.0.1:21011";
+    
+    // Backend that resets the connection as soon as it reads anything
+    let backend = TcpListener::bind(backend_addr).await.expect("Failed to bind backend");
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = backend.accept().await {
+            let mut buf = [0; 64];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket.set_linger(Some(Duration::ZERO));
+            drop(socket);
+        }
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, backend_addr)])
+        .env("PJ_LOG", "info")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    client.write_all(b"trigger reset").await.expect("Failed to write data");
+    sleep(Duration::from_millis(500)).await;
+    drop(client);
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(combined_output.contains("fail "), "Should log a failed connection");
+    assert!(combined_output.contains("Error: Data transfer error: upstream read:"),
+            "Error should name the failing leg and operation");
+}
+
+#[tokio::test]
+async fn test_connection_logging_peek_bytes() {
+    let echo_server_addr = "127.0.0.1:21012";
+    let proxy_listen_addr = "127.0.0.1:21013";
+    
+    // Start echo server
+    let echo_listener = TcpListener::bind(echo_server_addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        let (mut socket, _) = echo_listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        
+        loop {
+            match socket.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    socket.write_all(&buf[0..n]).await.unwrap();
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_LOG", "pj=debug")
+        .env("PJ_PEEK_BYTES", "8")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let test_data = b"SSH-2.0-OpenSSH_9.6\r\n";
+    client.write_all(test_data).await.expect("Failed to write data");
+    
+    // Peeking must not alter what gets proxied
+    let mut buffer = vec![0u8; test_data.len()];
+    client.read_exact(&mut buffer).await.expect("Failed to read response");
+    assert_eq!(&buffer[..], test_data);
+    
+    drop(client);
+    sleep(Duration::from_millis(500)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(combined_output.contains("Conn #0 first 8 bytes"), "Should log the peeked byte count");
+    assert!(combined_output.contains("0000  53 53 48 2d 32 2e 30 2d"), "Should log the hex dump");
+    assert!(combined_output.contains("|SSH-2.0-|"), "Should log the ASCII column");
+}
+
+#[tokio::test]
+async fn test_connection_logging_max_lifetime() {
+    let echo_server_addr = "127.0.0.1:21014";
+    let proxy_listen_addr = "127.0.0.1:21015";
+    
+    // Start echo server
+    let echo_listener = TcpListener::bind(echo_server_addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        let (mut socket, _) = echo_listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        
+        loop {
+            match socket.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if socket.write_all(&buf[0..n]).await.is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_MAX_LIFETIME", "2s")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let opened = std::time::Instant::now();
+    
+    // Keep the connection busy; activity must not extend its lifetime
+    let mut buffer = [0u8; 4];
+    loop {
+        if client.write_all(b"ping").await.is_err() {
+            break;
+        }
+        match client.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => sleep(Duration::from_millis(100)).await,
+        }
+        assert!(opened.elapsed() < Duration::from_secs(5), "Connection outlived its max lifetime");
+    }
+    let lifetime = opened.elapsed();
+    assert!(lifetime >= Duration::from_millis(1800), "Closed too early: {:?}", lifetime);
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(combined_output.contains("Conn #0 fail"), "Should log the end of the connection");
+    assert!(combined_output.contains("Error: max lifetime reached"), "Should log why it was closed");
+}
+
+#[tokio::test]
+async fn test_connection_logging_failed_connect() {
+    let unreachable_addr = "127.0.0.1:21098";
+    let proxy_listen_addr = "127.0.0.1:21016";
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
+        .env("PJ_LOG", "info")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    // Each attempt is closed once the upstream connect fails
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+        let mut buffer = [0u8; 16];
+        let _ = stream.read(&mut buffer).await;
+    }
+    
+    sleep(Duration::from_millis(500)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    // Numbered like any other connection, and never counted as active
+    for id in 0..2 {
+        let line = combined_output
+            .lines()
+            .find(|line| line.contains(&format!("Conn #{} fail ", id)))
+            .unwrap_or_else(|| panic!("Should log a fail line for attempt #{}", id));
+        assert!(line.contains("[0]: Duration:"), "Should log the active count and duration: {}", line);
+        assert!(line.contains("Error: Connection failed: Connection refused"), "Should log why it failed: {}", line);
+    }
+    assert!(!combined_output.contains("estab"), "A failed connect is not established");
+}
+
+#[tokio::test]
+async fn test_connection_logging_idle_timeout() {
+    // Start echo server on a port of its own
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind echo server");
+    let echo_server_addr = echo_listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = echo_listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        
+        loop {
+            match socket.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if socket.write_all(&buf[0..n]).await.is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    
+    // The mapping's own idle timeout wins over the global one
+    let (proxy, proxy_listen_addr) =
+        ProxyRun::start(&format!("127.0.0.1:0:{}?idle=2s", echo_server_addr), &[("PJ_IDLE_TIMEOUT", "1h")]).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let opened = std::time::Instant::now();
+    
+    // Traffic keeps the connection open past the idle timeout
+    let mut buffer = [0u8; 4];
+    while opened.elapsed() < Duration::from_secs(3) {
+        client.write_all(b"ping").await.expect("Connection closed while active");
+        client.read_exact(&mut buffer).await.expect("Connection closed while active");
+        sleep(Duration::from_millis(500)).await;
+    }
+    
+    // Then going quiet gets it closed
+    let idle_since = std::time::Instant::now();
+    let n = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buffer))
+        .await
+        .expect("Idle connection was not closed")
+        .unwrap_or(0);
+    assert_eq!(n, 0, "Expected the proxy to close the connection");
+    assert!(idle_since.elapsed() >= Duration::from_secs(1), "Closed too early: {:?}", idle_since.elapsed());
+    
+    proxy.wait_for_log("Conn #0 fail").await;
+    let combined_output = proxy.finish();
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(combined_output.contains("Conn #0 fail"), "Should log the end of the connection");
+    assert!(combined_output.contains("Error: idle timeout"), "Should log why it was closed");
+}
+
+#[tokio::test]
+async fn test_connection_logging_client_gone_during_connect() {
+    let unreachable_addr = "127.0.0.1:21097";
+    let proxy_listen_addr = "127.0.0.1:21019";
+    
+    // Retries keep the connect phase going for several seconds
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
+        .env("PJ_LOG", "info")
+        .env("PJ_CONNECT_ATTEMPTS", "10")
+        .env("PJ_RETRY_BASE_MS", "1000")
+        .env("PJ_RETRY_MAX_MS", "1000")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    drop(stream);
+    
+    sleep(Duration::from_millis(500)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(combined_output.contains("disconnected while connecting upstream"), "Should abandon the connect");
+    assert!(!combined_output.contains("estab"), "The connection was never established");
+    assert!(!combined_output.contains("Conn #0 fail"), "The abandoned connect is not a failure");
+}
+
+#[tokio::test]
+async fn test_connection_logging_close_reason() {
+    let closing_server_addr = "127.0.0.1:21020";
+    let echo_server_addr = "127.0.0.1:21021";
+    let upstream_closes_addr = "127.0.0.1:21022";
+    let client_closes_addr = "127.0.0.1:21023";
+    
+    // Says goodbye and hangs up first
+    let closing_listener = TcpListener::bind(closing_server_addr).await.expect("Failed to bind closing server");
+    tokio::spawn(async move {
+        let (mut socket, _) = closing_listener.accept().await.unwrap();
+        let _ = socket.write_all(b"bye").await;
+    });
+    
+    // Echoes until the client hangs up
+    let echo_listener = TcpListener::bind(echo_server_addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        let (mut socket, _) = echo_listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args([
+            "run", "--",
+            "--proxy", &format!("{}:{}", upstream_closes_addr, closing_server_addr),
+            "--proxy", &format!("{}:{}", client_closes_addr, echo_server_addr),
+        ])
+        .env("PJ_LOG", "info")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut stream = TcpStream::connect(upstream_closes_addr).await.expect("Failed to connect to proxy");
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.expect("Failed to read until the upstream closed");
+    assert_eq!(received, b"bye");
+    
+    let mut stream = TcpStream::connect(client_closes_addr).await.expect("Failed to connect to proxy");
+    stream.write_all(b"hello").await.unwrap();
+    let mut buffer = [0u8; 5];
+    stream.read_exact(&mut buffer).await.unwrap();
+    drop(stream);
+    
+    sleep(Duration::from_millis(500)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    let close_line = |listen_addr: &str| {
+        combined_output
+            .lines()
+            .find(|line| line.contains(&format!("[{}]", listen_addr)) && line.contains(" close ["))
+            .unwrap_or_else(|| panic!("Should log the close on {}", listen_addr))
+            .to_string()
+    };
+    let line = close_line(upstream_closes_addr);
+    assert!(line.contains("Reason: upstream_eof"), "The upstream hung up first: {}", line);
+    let line = close_line(client_closes_addr);
+    assert!(line.contains("Reason: downstream_eof"), "The client hung up first: {}", line);
+}
+
+#[tokio::test]
+async fn test_connection_logging_byte_cap() {
+    // Sends 2 KB, more than the client may receive
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind upstream");
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream_listener.accept().await.unwrap();
+        let _ = socket.write_all(&[b'x'; 2000]).await;
+        let mut buf = [0u8; 16];
+        let _ = socket.read(&mut buf).await;
+    });
+    
+    let (proxy, proxy_listen_addr) =
+        ProxyRun::start(&format!("127.0.0.1:0:{}", upstream_addr), &[("PJ_LOG", "info"), ("PJ_MAX_DOWN_BYTES", "1k")]).await;
+    
+    // The client sends nothing: bytes left unread when the proxy closes at
+    // the cap would turn its close into a reset
+    let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+        .await
+        .expect("Connection was not closed at the cap")
+        .expect("Failed to read from proxy");
+    assert_eq!(received.len(), 1000, "Exactly the capped amount should be relayed");
+    
+    proxy.wait_for_log("Reason: byte_cap").await;
+    let combined_output = proxy.finish();
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(combined_output.contains("Reason: byte_cap"), "Should log why it was closed");
+    assert!(combined_output.contains("Error: byte cap exceeded (downstream)"), "Should log which direction hit its cap");
+}
+
+#[tokio::test]
+async fn test_connection_logging_heartbeat() {
+    let echo_server_addr = "127.0.0.1:21026";
+    let proxy_listen_addr = "127.0.0.1:21027";
+    
+    let echo_listener = TcpListener::bind(echo_server_addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        let (mut socket, _) = echo_listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_LOG", "info")
+        .env("PJ_HEARTBEAT_INTERVAL", "1s")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    stream.write_all(b"beat").await.unwrap();
+    let mut buffer = [0u8; 4];
+    stream.read_exact(&mut buffer).await.unwrap();
+    
+    // Long enough for a heartbeat while the connection is open
+    sleep(Duration::from_millis(2500)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(
+        combined_output.contains("Heartbeat: 1 active | 1 connections since start | Sent: 4 B | Received: 4 B"),
+        "Should log a heartbeat covering the open connection"
+    );
+}
+
+#[tokio::test]
+async fn test_connection_logging_peer_reset_delivers_last_chunk() {
+    let upstream_addr = "127.0.0.1:21028";
+    let proxy_listen_addr = "127.0.0.1:21029";
+    
+    // Once the client speaks, sends one full relay buffer and resets instead of closing cleanly
+    let upstream_listener = TcpListener::bind(upstream_addr).await.expect("Failed to bind upstream");
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream_listener.accept().await.unwrap();
+        let mut go = [0u8; 2];
+        socket.read_exact(&mut go).await.unwrap();
+        socket.write_all(&[b'z'; 1024]).await.unwrap();
+        socket.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(socket);
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, upstream_addr)])
+        .env("PJ_LOG", "info")
+        // Full reads wait briefly for more, so the reset lands while the chunk is still buffered
+        .env("PJ_FLUSH_MODE", "coalesce")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    stream.write_all(b"go").await.unwrap();
+    let mut received = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received)).await;
+    
+    sleep(Duration::from_millis(500)).await;
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert_eq!(received.len(), 1024, "The chunk sent before the reset should reach the client");
+    let line = combined_output.lines().find(|l| l.contains("Conn #0 fail")).expect("Should log the failed close");
+    assert!(line.contains("Reason: peer_reset"), "The upstream reset the connection: {}", line);
+}
+
+#[tokio::test]
+async fn test_connection_logging_id_offset_and_width() {
+    let echo_server_addr = "127.0.0.1:21030";
+    let proxy_listen_addr = "127.0.0.1:21031";
+    
+    let echo_listener = TcpListener::bind(echo_server_addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = echo_listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_LOG", "info")
+        .env("PJ_CONN_ID_OFFSET", "1000")
+        .env("PJ_CONN_ID_WIDTH", "6")
+        .env("PJ_CONN_ID_RESET_COUNT", "2")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+        stream.write_all(b"id").await.unwrap();
+        let mut buffer = [0u8; 2];
+        stream.read_exact(&mut buffer).await.unwrap();
+        drop(stream);
+        sleep(Duration::from_millis(200)).await;
+    }
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    let ids: Vec<&str> = combined_output
+        .lines()
+        .filter(|line| line.contains(" estab "))
+        .filter_map(|line| line.split("Conn #").nth(1)?.split(' ').next())
+        .collect();
+    // The third connection crosses the reset threshold and starts over at the offset
+    assert_eq!(ids, vec!["001000", "001001", "001000"]);
+}
+
+#[tokio::test]
+async fn test_connection_logging_slow_io() {
+    let upstream_addr = "127.0.0.1:21032";
+    let proxy_listen_addr = "127.0.0.1:21033";
+    
+    // Stops reading for a while once the transfer starts, so the proxy's writes back up
+    let upstream_listener = TcpListener::bind(upstream_addr).await.expect("Failed to bind upstream");
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream_listener.accept().await.unwrap();
+        let mut buf = [0u8; 1];
+        socket.read_exact(&mut buf).await.unwrap();
+        sleep(Duration::from_secs(3)).await;
+        let mut sink = tokio::io::sink();
+        let _ = tokio::io::copy(&mut socket, &mut sink).await;
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, upstream_addr)])
+        .env("PJ_LOG", "debug")
+        .env("PJ_SLOW_IO_THRESHOLD", "1s")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    // More than the socket buffers on both legs can hold
+    let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let payload = vec![b's'; 64 * 1024 * 1024];
+    tokio::time::timeout(Duration::from_secs(30), stream.write_all(&payload))
+        .await
+        .expect("Transfer should finish once the upstream reads again")
+        .expect("Failed to write data");
+    drop(stream);
+    
+    sleep(Duration::from_millis(500)).await;
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    let slow: Vec<&str> = combined_output.lines().filter(|l| l.contains("slow upstream write")).collect();
+    println!("Slow I/O lines:\n{}", slow.join("\n"));
+    assert!(!slow.is_empty(), "Should trace the stalled write");
+    assert!(slow.iter().all(|l| l.contains("Conn #0") && l.contains("bytes took")), "Unexpected lines: {:?}", slow);
+    assert!(!combined_output.contains("slow downstream write"), "Nothing stalled towards the client");
+}
+
+#[tokio::test]
+async fn test_connection_logging_start_level() {
+    let echo_server_addr = "127.0.0.1:21034";
+    let proxy_listen_addr = "127.0.0.1:21035";
+    
+    let echo_listener = TcpListener::bind(echo_server_addr).await.expect("Failed to bind echo server");
+    tokio::spawn(async move {
+        let (mut socket, _) = echo_listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_LOG", "info")
+        .env("PJ_LOG_CONN_LEVEL", "start=debug")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    stream.write_all(b"hello").await.unwrap();
+    let mut buffer = [0u8; 5];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"hello");
+    drop(stream);
+    
+    sleep(Duration::from_millis(500)).await;
+    proxy_process.kill().expect("Failed to kill proxy");
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let combined_output = format!("{}\n{}", stderr, stdout);
+    
+    println!("Proxy output:\n{}", combined_output);
+    
+    assert!(!combined_output.contains(" estab ["), "Start lines should be logged at debug");
+    assert!(combined_output.contains("Conn #0 close ["), "End lines should still be logged at info");
+}
+
+#[tokio::test]
+async fn test_connection_logging_self_check() {
+    // The echo keeps reading while it writes back: the relay writes one way
+    // at a time, so an echo that stopped reading would leave both blocked
+    // once the socket buffers fill
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind echo server");
+    let echo_server_addr = echo_listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = echo_listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.into_split();
+        let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+        tokio::spawn(async move {
+            while let Some(chunk) = chunks_rx.recv().await {
+                if writer.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut buf = vec![0u8; 64 * 1024];
+        while let Ok(n @ 1..) = reader.read(&mut buf).await {
+            let _ = chunks_tx.send(buf[..n].to_vec());
+        }
+    });
+    
+    // The debug log is read as it is written, so a full pipe can't stall the relay
+    let (proxy, proxy_listen_addr) = ProxyRun::start(
+        &format!("127.0.0.1:0:{}", echo_server_addr),
+        &[("PJ_LOG", "debug"), ("PJ_SELF_CHECK", "true"), ("PJ_FLUSH_MODE", "buffered")],
+    )
+    .await;
+    
+    // Several megabytes each way, written while the echo is read back
+    let stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let (mut reader, mut writer) = stream.into_split();
+    let payload: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let expected = payload.clone();
+    let writing = tokio::spawn(async move {
+        writer.write_all(&payload).await.expect("Failed to write data");
+        writer
+    });
+    let mut echoed = vec![0u8; expected.len()];
+    tokio::time::timeout(Duration::from_secs(30), reader.read_exact(&mut echoed))
+        .await
+        .expect("Echo should finish")
+        .expect("Failed to read echo");
+    drop(writing.await.unwrap());
+    assert!(echoed == expected, "Echo should match what was sent");
+    drop(reader);
+    
+    // The check runs as the connection ends
+    proxy.wait_for_log("Conn #0 self-check").await;
+    let combined_output = proxy.finish();
+    
+    let checks: Vec<&str> = combined_output.lines().filter(|l| l.contains("self-check")).collect();
+    println!("Self-check lines:\n{}", checks.join("\n"));
+    assert!(!combined_output.contains("self-check failed"), "No bytes should go missing");
+    assert!(combined_output.contains("Conn #0 self-check passed: 8388608 bytes up, 8388608 down"), "The relay should be checked");
+}
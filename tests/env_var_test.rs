@@ -28,7 +28,7 @@ async fn test_env_var_single_proxy() {
     
     // Start proxy with PJ_PROXY environment variable
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--"])
+        .args(["run", "--"])
         .env("PJ_PROXY", format!("{}:{}", proxy_listen_addr, echo_server_addr))
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
@@ -109,7 +109,7 @@ async fn test_env_var_multiple_proxies() {
         proxy_listen2_addr, echo_server2_addr);
     
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--"])
+        .args(["run", "--"])
         .env("PJ_PROXIES", mappings)
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
@@ -180,7 +180,7 @@ async fn test_cli_overrides_env_var() {
     // Start proxy with both environment variable and CLI argument
     // CLI argument should take precedence
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .env("PJ_PROXY", format!("{}:{}", proxy_listen_addr, env_server_addr))
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
@@ -261,7 +261,7 @@ async fn test_env_var_with_semicolon_separator() {
         proxy_listen2_addr, echo_server2_addr);
     
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--"])
+        .args(["run", "--"])
         .env("PJ_PROXIES", mappings)
         .env("PJ_LOG", "info")
         .stdout(Stdio::piped())
@@ -293,4 +293,118 @@ async fn test_env_var_with_semicolon_separator() {
     
     // Kill proxy
     proxy_process.kill().expect("Failed to kill proxy");
-}
\ No newline at end of file
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_env_var_proxies_from_file() {
+    let mut file = tempfile::NamedTempFile::new().expect("Failed to create mappings file");
+    std::io::Write::write_all(
+        &mut file,
+        b"# Web tier\n127.0.0.1:22014:127.0.0.1:9000?name=web\n\n  # Forward proxies\nsocks5://127.0.0.1:22015\n   \nconnect://127.0.0.1:22016\n",
+    )
+    .expect("Failed to write mappings file");
+    
+    let output = Command::new("cargo")
+        .args(["run", "--", "--check"])
+        .env("PJ_PROXIES", format!("@{}", file.path().display()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "The mappings file should pass the check: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("would start 3 mappings"), "Should load every mapping in the file: {}", stdout);
+    assert!(stdout.contains("'web' 127.0.0.1:22014 -> 127.0.0.1:9000"), "Should list each mapping: {}", stdout);
+    
+    // A file that can't be read fails with its path
+    let output = Command::new("cargo")
+        .args(["run", "--", "--check"])
+        .env("PJ_PROXIES", "@/nonexistent/pj-mappings.txt")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    assert!(!output.status.success(), "A missing mappings file should fail");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(
+        combined.contains("Failed to read PJ_PROXIES mappings file '/nonexistent/pj-mappings.txt'"),
+        "Should name the file: {}",
+        combined
+    );
+}
+
+#[test]
+fn test_log_color_and_time() {
+    let run = |color: &str, time: &str| {
+        Command::new("cargo")
+            .args(["run", "--", "--check", "--proxy", "127.0.0.1:22017:127.0.0.1:9000"])
+            .env("PJ_LOG_COLOR", color)
+            .env("PJ_LOG_TIME", time)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .expect("Failed to run proxy")
+    };
+    
+    let output = run("never", "none");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "The check should pass: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("would start 1 mapping"), "Should still log: {}", stdout);
+    assert!(!stdout.contains('\x1b'), "Should not contain ANSI escape codes: {:?}", stdout);
+    assert!(stdout.trim_start().starts_with("INFO pj: Using proxy mappings"), "Log lines should start with their level: {}", stdout);
+    
+    let output = run("always", "uptime");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\x1b["), "Should color lines even when piped: {:?}", stdout);
+    
+    let output = run("sometimes", "none");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(!output.status.success(), "An unknown color mode should fail");
+    assert!(combined.contains("Invalid PJ_LOG_COLOR"), "Should name the setting: {}", combined);
+}
+
+#[test]
+fn test_list_mappings_from_env() {
+    // The listen addresses are held here, so a listing that tried to bind them would fail
+    let held: Vec<_> = (0..4).map(|_| std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to hold a port")).collect();
+    let addrs: Vec<_> = held.iter().map(|listener| listener.local_addr().unwrap()).collect();
+    let list = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_pj"))
+            .arg("--list-mappings")
+            .args(args)
+            .env("PJ_PROXIES", format!("{}:127.0.0.1:9000?name=web; socks5://{}", addrs[0], addrs[1]))
+            .env("PJ_PROXY", format!("{}:127.0.0.1:9001", addrs[2]))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .expect("Failed to run proxy")
+    };
+    
+    // PJ_PROXIES wins over PJ_PROXY, and only the list is printed to stdout
+    let output = list(&[]);
+    assert!(output.status.success(), "Listing should succeed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{} -> 127.0.0.1:9000\n{} -> SOCKS5 proxy\n", addrs[0], addrs[1])
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Using 2 proxy mappings from PJ_PROXIES"), "Logs go to stderr");
+    
+    // --proxy wins over both
+    let output = list(&["--proxy", &format!("{}:127.0.0.1:9002", addrs[3])]);
+    assert!(output.status.success(), "Listing should succeed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{} -> 127.0.0.1:9002\n", addrs[3]));
+}
+
+// Slow, as it compiles the proxy again; CI runs it with `cargo test -- --ignored`
+#[test]
+#[ignore]
+fn test_builds_without_jemalloc() {
+    let status = Command::new("cargo")
+        .args(["check", "--bin", "pj", "--no-default-features", "--target-dir", "target/no-jemalloc"])
+        .status()
+        .expect("Failed to run cargo check");
+    assert!(status.success(), "The proxy should build with the system allocator");
+}
@@ -13,7 +13,7 @@ async fn test_port_already_in_use() {
     
     // Try to start proxy on the same port
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:127.0.0.1:22", addr)])
+        .args(["run", "--", "--proxy", &format!("{}:127.0.0.1:22", addr)])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -51,7 +51,7 @@ async fn test_port_already_in_use() {
 async fn test_invalid_listen_address() {
     // Test with invalid IP address
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", "999.999.999.999:8080:127.0.0.1:22"])
+        .args(["run", "--", "--proxy", "999.999.999.999:8080:127.0.0.1:22"])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -76,6 +76,226 @@ async fn test_invalid_listen_address() {
     }
 }
 
+#[tokio::test]
+async fn test_proxy_loop_rejected() {
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", "127.0.0.1:8080:127.0.0.1:8080"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(3)).await;
+    
+    match proxy_process.try_wait() {
+        Ok(Some(status)) => {
+            assert!(!status.success(), "Proxy loop should exit with an error status");
+        }
+        Ok(None) => {
+            proxy_process.kill().expect("Failed to kill proxy");
+            panic!("Proxy should refuse to start with a looped mapping");
+        }
+        Err(e) => {
+            panic!("Failed to check proxy status: {}", e);
+        }
+    }
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("Proxy loop"), "Should explain why startup was refused");
+}
+
+#[tokio::test]
+async fn test_duplicate_listen_address_rejected() {
+    let mut proxy_process = Command::new("cargo")
+        .args([
+            "run", "--",
+            "--proxy", "127.0.0.1:20012:127.0.0.1:20098",
+            "--proxy", "127.0.0.1:20012:127.0.0.1:20099",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(3)).await;
+    
+    match proxy_process.try_wait() {
+        Ok(Some(status)) => {
+            assert!(!status.success(), "Duplicate listen addresses should exit with an error status");
+        }
+        Ok(None) => {
+            proxy_process.kill().expect("Failed to kill proxy");
+            panic!("Proxy should refuse to start with a duplicate listen address");
+        }
+        Err(e) => {
+            panic!("Failed to check proxy status: {}", e);
+        }
+    }
+    
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(
+        combined.contains("Listen address 127.0.0.1:20012 is used by more than one mapping"),
+        "Should name the duplicate address"
+    );
+}
+
+#[tokio::test]
+async fn test_blank_mapping_rejected() {
+    let output = Command::new("cargo")
+        .args(["run", "--", "--proxy", "   :   :   :   "])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    assert!(!output.status.success(), "A blank mapping should exit with an error status");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("blank listen address"), "Should explain why startup was refused");
+}
+
+#[tokio::test]
+async fn test_too_many_mappings_rejected() {
+    // Nothing is bound: the count is checked before any listener starts
+    let mappings = |count: u16| (0..count).map(|i| format!("127.0.0.1:{}:127.0.0.1:9", 30000 + i)).collect::<Vec<_>>().join(",");
+    let refused = |mappings: String, max: Option<&str>| {
+        let mut command = Command::new("cargo");
+        command.args(["run", "--"]).env("PJ_PROXIES", mappings).env_remove("PJ_PROXY");
+        match max {
+            Some(max) => command.env("PJ_MAX_MAPPINGS", max),
+            None => command.env_remove("PJ_MAX_MAPPINGS"),
+        };
+        let output = command.output().expect("Failed to run proxy");
+        assert!(!output.status.success(), "Too many mappings should exit with an error status");
+        format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout))
+    };
+    
+    let combined = refused(mappings(1025), None);
+    assert!(combined.contains("1025 mappings exceed PJ_MAX_MAPPINGS (1024)"), "Should give the count and the default cap");
+    
+    let combined = refused(mappings(3), Some("2"));
+    assert!(combined.contains("3 mappings exceed PJ_MAX_MAPPINGS (2)"), "Should give the count and the configured cap");
+}
+
+#[tokio::test]
+async fn test_unset_variable_in_mapping_rejected() {
+    let output = Command::new("cargo")
+        .args(["run"])
+        .env("PJ_PROXIES", "127.0.0.1:20013:${PJ_TEST_UNSET_BACKEND}:9000")
+        .env_remove("PJ_TEST_UNSET_BACKEND")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    assert!(!output.status.success(), "A mapping naming an unset variable should exit with an error status");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(
+        combined.contains("Environment variable 'PJ_TEST_UNSET_BACKEND' is not set"),
+        "Should name the missing variable"
+    );
+}
+
+#[tokio::test]
+async fn test_check_accepts_valid_config() {
+    let output = Command::new("cargo")
+        .args(["run", "--", "--check", "--proxy", "127.0.0.1:20014:127.0.0.1:9000?name=web", "--proxy", "socks5://127.0.0.1:20015"])
+        .env("PJ_IDLE_TIMEOUT", "5m")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "A valid config should pass the check: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Configuration OK, would start 2 mappings"), "Should summarise the config: {}", stdout);
+    assert!(stdout.contains("'web' 127.0.0.1:20014 -> 127.0.0.1:9000"), "Should list each mapping: {}", stdout);
+    assert!(!stdout.contains("Starting proxy server"), "Should exit before starting: {}", stdout);
+    
+    // The check binds nothing, so a stats port held by a running instance doesn't fail it
+    let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_pj"))
+        .args(["--check", "--proxy", "127.0.0.1:0:127.0.0.1:9000"])
+        .env("PJ_STATS_PORT", held.local_addr().unwrap().to_string())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "A held stats port should pass the check: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("stats port on {}", held.local_addr().unwrap())), "Should list the stats port: {}", stdout);
+}
+
+#[tokio::test]
+async fn test_check_rejects_invalid_config() {
+    let output = Command::new("cargo")
+        .args(["run", "--", "--check", "--proxy", "127.0.0.1:20016:127.0.0.1"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    assert!(!output.status.success(), "An invalid mapping should fail the check");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("Invalid proxy mapping format"), "Should print the error: {}", combined);
+    
+    // Settings that are otherwise only logged and ignored fail the check too
+    let output = Command::new("cargo")
+        .args(["run", "--", "--check", "--proxy", "127.0.0.1:20016:127.0.0.1:9000"])
+        .env("PJ_CONN_ID_WIDTH", "wide")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    assert!(!output.status.success(), "An invalid setting should fail the check");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("Invalid PJ_CONN_ID_WIDTH"), "Should name the setting: {}", combined);
+    
+    // An upstream that can't be resolved would stop a real start, so it fails the check
+    let output = Command::new(env!("CARGO_BIN_EXE_pj"))
+        .args(["--check", "--proxy", "127.0.0.1:0:nonexistent.invalid:9000"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("Failed to run proxy");
+    
+    assert!(!output.status.success(), "An unresolvable upstream should fail the check");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("Invalid upstream for 127.0.0.1:0"), "Should name the mapping: {}", combined);
+    assert!(!combined.contains("Configuration OK"), "Should not pass: {}", combined);
+}
+
+#[test]
+fn test_invalid_limit_exits() {
+    // Limits that would silently be lifted by a typo stop the proxy instead
+    for (var, value) in [
+        ("PJ_MAX_LIFETIME", "1 hour"),
+        ("PJ_MAX_UP_BYTES", "10 gigs"),
+        ("PJ_MAX_DOWN_BYTES", "-1"),
+        ("PJ_WRITE_TIMEOUT", "30"),
+        ("PJ_IDLE_TIMEOUT", "soon"),
+        ("PJ_CONNECT_TIMEOUT", "5 secs"),
+        ("PJ_PEEK_BYTES", "all"),
+        ("PJ_MAX_HANDSHAKE_BYTES", "0"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_pj"))
+            .args(["--proxy", "127.0.0.1:20025:127.0.0.1:9000"])
+            .env(var, value)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .expect("Failed to run proxy");
+        
+        assert!(!output.status.success(), "An invalid {} should exit with an error status", var);
+        let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+        assert!(combined.contains(&format!("Invalid {} '{}'", var, value)), "Should name the setting: {}", combined);
+        assert!(!combined.contains("Starting proxy server"), "Should exit before starting: {}", combined);
+    }
+}
+
 #[tokio::test]
 async fn test_connection_interrupted() {
     let echo_server_addr = "127.0.0.1:20003";
@@ -96,7 +316,7 @@ async fn test_connection_interrupted() {
     
     // Start proxy
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -152,7 +372,7 @@ async fn test_rapid_connection_failures() {
     
     // Start proxy pointing to unreachable address
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -246,7 +466,7 @@ async fn test_large_data_with_sudden_disconnection() {
     
     // Start proxy
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -306,7 +526,7 @@ async fn test_simultaneous_connection_errors() {
     
     // Start proxy with multiple mappings to unreachable addresses
     let mut proxy_process = Command::new("cargo")
-        .args(&[
+        .args([
             "run", "--",
             "--proxy", &format!("{}:{}", proxy_listen_addr1, unreachable1),
             "--proxy", &format!("{}:{}", proxy_listen_addr2, unreachable2),
@@ -363,4 +583,195 @@ async fn test_simultaneous_connection_errors() {
             panic!("Failed to check proxy status: {}", e);
         }
     }
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_connect_retry_with_backoff() {
+    let backend_addr = "127.0.0.1:20010";
+    let proxy_listen_addr = "127.0.0.1:20011";
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, backend_addr)])
+        .env("PJ_LOG", "debug")
+        .env("PJ_CONNECT_ATTEMPTS", "5")
+        .env("PJ_RETRY_BASE_MS", "400")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    
+    // Retries come after 200-400ms and then 400-800ms more, so a backend
+    // showing up at 500ms refuses the first two attempts and takes the third
+    sleep(Duration::from_millis(500)).await;
+    let backend = TcpListener::bind(backend_addr).await.expect("Failed to bind backend");
+    tokio::spawn(async move {
+        let (mut socket, _) = backend.accept().await.unwrap();
+        let mut buf = [0u8; 64];
+        let n = socket.read(&mut buf).await.unwrap();
+        socket.write_all(&buf[..n]).await.unwrap();
+    });
+    
+    client.write_all(b"retried").await.unwrap();
+    let mut buffer = [0u8; 7];
+    let result = timeout(Duration::from_secs(5), client.read_exact(&mut buffer)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined_output = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    println!("Proxy output:\n{}", combined_output);
+    
+    result.expect("Timeout waiting for response").expect("Failed to read response");
+    assert_eq!(&buffer, b"retried");
+    assert!(combined_output.contains("failed (attempt 1/5), retrying in"), "Should log the first retry");
+    assert!(combined_output.contains("failed (attempt 2/5), retrying in"), "Should log the second retry");
+    assert!(!combined_output.contains("Error: Connection failed"), "The connection should eventually succeed");
+}
+
+#[tokio::test]
+async fn test_fail_response_on_unreachable_upstream() {
+    let unreachable_addr = "127.0.0.1:20018";  // Nothing listens here
+    let proxy_listen_addr = "127.0.0.1:20017";
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
+        .env("PJ_FAIL_RESPONSE", "http502")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: example\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Connection should be closed after the fail response")
+        .expect("Failed to read fail response");
+    assert_eq!(
+        String::from_utf8_lossy(&response),
+        "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_accept_logged_before_failed_connect() {
+    let unreachable_addr = "127.0.0.1:20020";  // Nothing listens here
+    let proxy_listen_addr = "127.0.0.1:20019";
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
+        .env("PJ_LOG", "debug")
+        .env("PJ_LOG_ACCEPT", "1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(5)).await;
+    
+    let mut stream = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let local_addr = stream.local_addr().unwrap();
+    let mut buf = [0; 16];
+    let _ = timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+    drop(stream);
+    sleep(Duration::from_millis(500)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    let accepted = combined
+        .find(&format!("Accepted connection from {} on {}", local_addr, proxy_listen_addr))
+        .unwrap_or_else(|| panic!("Should log the client as soon as it is accepted: {}", combined));
+    let failed = combined
+        .find("Failed to create client session to")
+        .unwrap_or_else(|| panic!("The upstream connect should have failed: {}", combined));
+    assert!(accepted < failed, "The accept should be logged before the upstream is dialed: {}", combined);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_bind_failure_abort() {
+    let occupied_addr = "127.0.0.1:20021";
+    let free_addr = "127.0.0.1:20022";
+    let _occupant = TcpListener::bind(occupied_addr).await.expect("Failed to occupy the port");
+    
+    let mut proxy_process = Command::new("cargo")
+        .args([
+            "run", "--",
+            "--proxy", &format!("{}:127.0.0.1:20098", free_addr),
+            "--proxy", &format!("{}:127.0.0.1:20099", occupied_addr),
+        ])
+        .env("PJ_BIND_FAILURE", "abort")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    for _ in 0..50 {
+        if !matches!(proxy_process.try_wait(), Ok(None)) {
+            break;
+        }
+        sleep(Duration::from_millis(200)).await;
+    }
+    
+    match proxy_process.try_wait() {
+        Ok(Some(status)) => assert!(!status.success(), "A listener that can't bind should exit with an error status"),
+        Ok(None) => {
+            proxy_process.kill().expect("Failed to kill proxy");
+            panic!("Proxy should exit when a listener can't bind");
+        }
+        Err(e) => panic!("Failed to check proxy status: {}", e),
+    }
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains(&format!("Failed to bind {}", occupied_addr)), "Should name the address: {}", combined);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_bind_failure_skip() {
+    let occupied_addr = "127.0.0.1:20023";
+    let free_addr = "127.0.0.1:20024";
+    let _occupant = TcpListener::bind(occupied_addr).await.expect("Failed to occupy the port");
+    
+    let mut proxy_process = Command::new("cargo")
+        .args([
+            "run", "--",
+            "--proxy", &format!("{}:127.0.0.1:20098", occupied_addr),
+            "--proxy", &format!("{}:127.0.0.1:20099", free_addr),
+        ])
+        .env("PJ_BIND_FAILURE", "skip")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    sleep(Duration::from_secs(3)).await;
+    
+    assert!(matches!(proxy_process.try_wait(), Ok(None)), "Proxy should keep running with the mapping that bound");
+    assert!(TcpStream::connect(free_addr).await.is_ok(), "The other mapping should be listening");
+    proxy_process.kill().expect("Failed to kill proxy");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains(&format!("Failed to bind {}, skipping its mapping", occupied_addr)), "{}", combined);
+    assert!(combined.contains("Starting proxy server with 1 of 2 mappings, 1 failed to bind"), "{}", combined);
+    
+    // With nothing left to start, it exits after all
+    let output = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:127.0.0.1:20098", occupied_addr)])
+        .env("PJ_BIND_FAILURE", "skip")
+        .output()
+        .expect("Failed to run proxy");
+    assert!(!output.status.success(), "Proxy should exit when no listener could be bound");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("None of the 1 mappings could be bound"), "{}", combined);
+}
@@ -1,9 +1,12 @@
 use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time::timeout;
 
+use pj::harness::TestProxy;
+use pj::{DelayDirection, InjectedDelay, ProxyOptions};
+
 async fn start_echo_server(addr: &str) -> Result<tokio::task::JoinHandle<()>, std::io::Error> {
     let listener = TcpListener::bind(addr).await?;
     println!("Echo server listening on {}", addr);
@@ -44,7 +47,7 @@ async fn test_basic_proxy_functionality() {
     tokio::time::sleep(Duration::from_millis(500)).await;
     
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -73,19 +76,14 @@ async fn test_basic_proxy_functionality() {
 #[tokio::test]
 async fn test_multiple_concurrent_connections() {
     let echo_server_addr = "127.0.0.1:19003";
-    let proxy_listen_addr = "127.0.0.1:19004";
     
     let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
-    tokio::time::sleep(Duration::from_millis(500)).await;
     
-    let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
+    // In-process, so it is listening as soon as it has started
+    let proxy = TestProxy::start(&format!("127.0.0.1:0:{}", echo_server_addr), ProxyOptions::default())
+        .await
         .expect("Failed to start proxy");
-    
-    tokio::time::sleep(Duration::from_secs(5)).await;
+    let proxy_listen_addr = proxy.addr();
     
     let mut handles = vec![];
     for i in 0..5 {
@@ -111,8 +109,8 @@ async fn test_multiple_concurrent_connections() {
         handle.await.unwrap();
     }
     
-    proxy_process.kill().expect("Failed to kill proxy process");
-    let _ = proxy_process.wait();
+    timeout(Duration::from_secs(5), proxy.shutdown()).await.expect("Proxy should shut down");
+    assert!(TcpStream::connect(proxy_listen_addr).await.is_err(), "The listener should be closed");
 }
 
 #[tokio::test]
@@ -127,7 +125,7 @@ async fn test_multiple_proxy_mappings() {
     tokio::time::sleep(Duration::from_millis(500)).await;
     
     let mut proxy_process = Command::new("cargo")
-        .args(&[
+        .args([
             "run", "--",
             "--proxy", &format!("{}:{}", proxy_listen_addr1, echo_server_addr1),
             "--proxy", &format!("{}:{}", proxy_listen_addr2, echo_server_addr2),
@@ -165,6 +163,44 @@ async fn test_multiple_proxy_mappings() {
     let _ = proxy_process.wait();
 }
 
+#[tokio::test]
+async fn test_proxy_mapping_list_in_one_flag() {
+    let echo_server_addr1 = "127.0.0.1:19046";
+    let echo_server_addr2 = "127.0.0.1:19047";
+    let proxy_listen_addr1 = "127.0.0.1:19048";
+    let proxy_listen_addr2 = "127.0.0.1:19049";
+    
+    let _echo_handle1 = start_echo_server(echo_server_addr1).await.expect("Failed to start echo server 1");
+    let _echo_handle2 = start_echo_server(echo_server_addr2).await.expect("Failed to start echo server 2");
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    
+    let mut proxy_process = Command::new("cargo")
+        .args([
+            "run", "--",
+            "--proxy", &format!("{}:{},{}:{}", proxy_listen_addr1, echo_server_addr1, proxy_listen_addr2, echo_server_addr2),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    for (proxy_listen_addr, message) in [(proxy_listen_addr1, b"via mapping 1"), (proxy_listen_addr2, b"via mapping 2")] {
+        let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+        client.write_all(message).await.unwrap();
+        let mut buffer = vec![0u8; message.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response from proxy")
+            .expect("Failed to read response from proxy");
+        assert_eq!(&buffer[..], message);
+    }
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
 #[tokio::test]
 async fn test_large_data_transfer() {
     let echo_server_addr = "127.0.0.1:19009";
@@ -174,7 +210,7 @@ async fn test_large_data_transfer() {
     tokio::time::sleep(Duration::from_millis(500)).await;
     
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -218,7 +254,7 @@ async fn test_connection_to_unreachable_upstream() {
     let proxy_listen_addr = "127.0.0.1:19011";
     
     let mut proxy_process = Command::new("cargo")
-        .args(&["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, unreachable_addr)])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
@@ -256,4 +292,1058 @@ async fn test_connection_to_unreachable_upstream() {
     
     proxy_process.kill().expect("Failed to kill proxy process");
     let _ = proxy_process.wait();
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_upstream_bind_source() {
+    let backend_addr = "127.0.0.1:19012";
+    let proxy_listen_addr = "127.0.0.1:19013";
+    let override_listen_addr = "127.0.0.1:19014";
+    
+    // Backend that replies with the source IP it sees
+    let backend = TcpListener::bind(backend_addr).await.expect("Failed to bind backend");
+    tokio::spawn(async move {
+        while let Ok((mut socket, peer)) = backend.accept().await {
+            let _ = socket.write_all(peer.ip().to_string().as_bytes()).await;
+        }
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args([
+            "run", "--",
+            "--proxy", &format!("{}:{}", proxy_listen_addr, backend_addr),
+            "--proxy", &format!("{}:{}?bind=127.0.0.3", override_listen_addr, backend_addr),
+        ])
+        .env("PJ_BIND_SOURCE", "127.0.0.2")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    for (listen_addr, expected_source) in [(proxy_listen_addr, "127.0.0.2"), (override_listen_addr, "127.0.0.3")] {
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let mut buffer = vec![0u8; expected_source.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        assert_eq!(String::from_utf8_lossy(&buffer), expected_source);
+    }
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_unavailable_bind_source() {
+    let backend_addr = "127.0.0.1:19015";
+    let proxy_listen_addr = "127.0.0.1:19016";
+    
+    let _echo_handle = start_echo_server(backend_addr).await.expect("Failed to start echo server");
+    
+    // TEST-NET-1 is never assigned to a local interface
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, backend_addr)])
+        .env("PJ_BIND_SOURCE", "192.0.2.1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    let _ = client.write_all(b"hello").await;
+    let mut buffer = vec![0u8; 16];
+    let result = timeout(Duration::from_secs(2), client.read(&mut buffer)).await;
+    assert!(!matches!(result, Ok(Ok(n)) if n > 0), "No data should be proxied without a usable source");
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("from source 192.0.2.1"), "Should explain which source failed");
+}
+
+#[tokio::test]
+async fn test_connection_mirroring() {
+    let echo_server_addr = "127.0.0.1:19017";
+    let mirror_addr = "127.0.0.1:19018";
+    let proxy_listen_addr = "127.0.0.1:19019";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    // Mirror that records everything it receives and never replies
+    let mirror = TcpListener::bind(mirror_addr).await.expect("Failed to bind mirror");
+    let mirror_handle = tokio::spawn(async move {
+        let (mut socket, _) = mirror.accept().await.unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        received
+    });
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}?mirror={}", proxy_listen_addr, echo_server_addr, mirror_addr)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    let mut echoed = Vec::new();
+    for message in [&b"first chunk, "[..], &b"second chunk"[..]] {
+        client.write_all(message).await.unwrap();
+        let mut buffer = vec![0u8; message.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        echoed.extend_from_slice(&buffer);
+    }
+    assert_eq!(&echoed[..], b"first chunk, second chunk", "Primary path should be unaffected");
+    
+    // Closing the client closes the mirror stream too
+    drop(client);
+    let mirrored = timeout(Duration::from_secs(5), mirror_handle)
+        .await
+        .expect("Mirror connection should be closed")
+        .unwrap();
+    assert_eq!(&mirrored[..], b"first chunk, second chunk");
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+async fn start_tagged_server(addr: &str, tag: &'static [u8]) -> tokio::task::JoinHandle<()> {
+    let listener = TcpListener::bind(addr).await.expect("Failed to bind tagged server");
+    serve_tagged(listener, tag)
+}
+
+/// Replies to everything read on `listener` with `tag` followed by the bytes read
+fn serve_tagged(listener: TcpListener, tag: &'static [u8]) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    let mut reply = tag.to_vec();
+                    reply.extend_from_slice(&buf[..n]);
+                    if socket.write_all(&reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_protocol_detection_routing() {
+    let http_backend_addr = "127.0.0.1:19020";
+    let raw_backend_addr = "127.0.0.1:19021";
+    let proxy_listen_addr = "127.0.0.1:19022";
+    
+    let _http_handle = start_tagged_server(http_backend_addr, b"A:").await;
+    let _raw_handle = start_tagged_server(raw_backend_addr, b"B:").await;
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}?http={}", proxy_listen_addr, raw_backend_addr, http_backend_addr)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let cases: [(&[u8], &[u8]); 2] = [
+        (b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n", b"A:"),
+        (b"SSH-2.0-OpenSSH_9.6\r\n", b"B:"),
+    ];
+    for (request, tag) in cases {
+        let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+        client.write_all(request).await.unwrap();
+        
+        // The sniffed bytes must be replayed in full to the chosen backend
+        let mut buffer = vec![0u8; tag.len() + request.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        assert_eq!(&buffer[..tag.len()], tag, "Routed to the wrong backend");
+        assert_eq!(&buffer[tag.len()..], request, "Peeked bytes should be replayed");
+    }
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_tls_passthrough_routes_by_server_name() {
+    let a_backend = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind backend");
+    let b_backend = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind backend");
+    let (a_backend_addr, b_backend_addr) = (a_backend.local_addr().unwrap(), b_backend.local_addr().unwrap());
+    let _a_handle = serve_tagged(a_backend, b"A:");
+    let _b_handle = serve_tagged(b_backend, b"B:");
+    
+    let mapping = format!("tls://127.0.0.1:0?sni=a.example.com={}&sni=b.example.com={}", a_backend_addr, b_backend_addr);
+    let proxy = TestProxy::start(&mapping, ProxyOptions::default()).await.expect("Failed to start proxy");
+    
+    for (server_name, tag) in [("a.example.com", b"A:"), ("B.Example.com", b"B:")] {
+        let hello = pj::sni::client_hello(server_name);
+        let mut client = TcpStream::connect(proxy.addr()).await.unwrap();
+        client.write_all(&hello).await.unwrap();
+        
+        // The ClientHello reaches the backend untouched
+        let mut buffer = vec![0u8; tag.len() + hello.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        assert_eq!(&buffer[..tag.len()], tag, "{} was routed to the wrong backend", server_name);
+        assert_eq!(&buffer[tag.len()..], hello, "The ClientHello should be replayed");
+    }
+    
+    // With no fallback route, an unknown name is refused
+    let mut client = TcpStream::connect(proxy.addr()).await.unwrap();
+    client.write_all(&pj::sni::client_hello("c.example.com")).await.unwrap();
+    let mut buffer = Vec::new();
+    let refused = timeout(Duration::from_secs(5), client.read_to_end(&mut buffer)).await.expect("Should be closed");
+    assert!(refused.is_err() || buffer.is_empty(), "Nothing should be relayed for an unknown name");
+    
+    proxy.shutdown().await;
+}
+
+async fn connect_request(proxy_addr: &str, target: &str) -> (TcpStream, String) {
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    client.write_all(request.as_bytes()).await.unwrap();
+    
+    // Read the response head byte by byte so no tunnelled data is consumed
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        let n = timeout(Duration::from_secs(5), client.read(&mut byte))
+            .await
+            .expect("Timeout waiting for CONNECT response")
+            .expect("Failed to read CONNECT response");
+        if n == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    (client, String::from_utf8_lossy(&head).to_string())
+}
+
+#[tokio::test]
+async fn test_connect_tunnel() {
+    let echo_server_addr = "127.0.0.1:19023";
+    let proxy_listen_addr = "127.0.0.1:19024";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("connect://{}", proxy_listen_addr)])
+        .env("PJ_CONNECT_ALLOW", echo_server_addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let (mut client, head) = connect_request(proxy_listen_addr, echo_server_addr).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "Unexpected CONNECT response: {}", head);
+    
+    let test_message = b"Hello through the tunnel";
+    client.write_all(test_message).await.unwrap();
+    let mut buffer = vec![0u8; test_message.len()];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .expect("Timeout waiting for response")
+        .expect("Failed to read response");
+    assert_eq!(&buffer[..], test_message);
+    
+    // Targets outside the allowlist are refused
+    let (_client, head) = connect_request(proxy_listen_addr, "127.0.0.1:19999").await;
+    assert!(head.starts_with("HTTP/1.1 403"), "Unexpected response for disallowed target: {}", head);
+    
+    // Malformed requests get a 400 and the connection is closed
+    let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .expect("Connection should be closed after a 400")
+        .unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 400"), "Unexpected response: {}", String::from_utf8_lossy(&response));
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_connect_request_head_cap() {
+    let proxy_listen_addr = "127.0.0.1:19067";
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("connect://{}", proxy_listen_addr)])
+        .env("PJ_CONNECT_ALLOW", "*:*")
+        .env("PJ_MAX_HANDSHAKE_BYTES", "2k")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    // A header line that never ends: the proxy answers 400 and closes once
+    // it has read the cap, rather than buffering for as long as it is fed
+    let client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    let (mut reader, mut writer) = client.into_split();
+    let feeder = tokio::spawn(async move {
+        writer.write_all(b"CONNECT example.com:443 HTTP/1.1\r\nX-Padding: ").await.unwrap();
+        let mut fed = 0;
+        while writer.write_all(&[b'a'; 512]).await.is_ok() {
+            fed += 512;
+            if fed > 64 * 1024 * 1024 {
+                break;
+            }
+        }
+        fed
+    });
+    let mut response = Vec::new();
+    let _ = timeout(Duration::from_secs(5), reader.read_to_end(&mut response))
+        .await
+        .expect("Connection should be closed at the cap");
+    assert!(response.starts_with(b"HTTP/1.1 400"), "Unexpected response: {}", String::from_utf8_lossy(&response));
+    let fed = timeout(Duration::from_secs(5), feeder).await.expect("Writes should fail once closed").unwrap();
+    assert!(fed < 64 * 1024 * 1024, "The proxy kept reading");
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_socks5_tunnel() {
+    use tokio_socks::tcp::Socks5Stream;
+    
+    let echo_server_addr = "127.0.0.1:19025";
+    let proxy_listen_addr = "127.0.0.1:19026";
+    let auth_listen_addr = "127.0.0.1:19027";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("socks5://{}", proxy_listen_addr)])
+        .env("PJ_CONNECT_ALLOW", "127.0.0.1:19025,localhost:19025")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    let mut auth_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("socks5://{}", auth_listen_addr)])
+        .env("PJ_CONNECT_ALLOW", echo_server_addr)
+        .env("PJ_SOCKS5_AUTH", "alice:s3cret")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    // IPv4 and domain targets both tunnel through
+    for target in [echo_server_addr, "localhost:19025"] {
+        let mut stream = Socks5Stream::connect(proxy_listen_addr, target)
+            .await
+            .unwrap_or_else(|e| panic!("SOCKS5 connect to {} failed: {}", target, e));
+        let test_message = b"Hello over SOCKS5";
+        stream.write_all(test_message).await.unwrap();
+        let mut buffer = vec![0u8; test_message.len()];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        assert_eq!(&buffer[..], test_message);
+    }
+    
+    // Targets outside the allowlist get "connection not allowed by ruleset"
+    match Socks5Stream::connect(proxy_listen_addr, "127.0.0.1:19999").await {
+        Err(tokio_socks::Error::ConnectionNotAllowedByRuleset) => {}
+        other => panic!("Expected a ruleset rejection, got {:?}", other.map(|_| ())),
+    }
+    
+    // Unsupported commands (BIND) get the proper error reply
+    let mut raw = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    raw.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    raw.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+    raw.write_all(&[0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0x4a, 0x71]).await.unwrap();
+    let mut reply = [0u8; 10];
+    raw.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0x07, "Expected 'command not supported'");
+    
+    // Listeners with credentials require them
+    let mut stream = Socks5Stream::connect_with_password(auth_listen_addr, echo_server_addr, "alice", "s3cret")
+        .await
+        .expect("SOCKS5 connect with valid credentials failed");
+    stream.write_all(b"authed").await.unwrap();
+    let mut buffer = [0u8; 6];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"authed");
+    assert!(Socks5Stream::connect_with_password(auth_listen_addr, echo_server_addr, "alice", "wrong").await.is_err());
+    assert!(Socks5Stream::connect(auth_listen_addr, echo_server_addr).await.is_err());
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    auth_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+    let _ = auth_process.wait();
+}
+
+#[tokio::test]
+async fn test_upstream_through_socks5_proxy() {
+    let echo_server_addr = "127.0.0.1:19061";
+    let socks5_addr = "127.0.0.1:19062";
+    let proxy_listen_addr = "127.0.0.1:19063";
+    let bad_auth_listen_addr = "127.0.0.1:19064";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    // pj's own SOCKS5 listener stands in for the egress proxy
+    let mut socks5_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("socks5://{}", socks5_addr)])
+        .env("PJ_CONNECT_ALLOW", echo_server_addr)
+        .env("PJ_SOCKS5_AUTH", "alice:s3cret")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start SOCKS5 proxy");
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_UPSTREAM_SOCKS5", socks5_addr)
+        .env("PJ_UPSTREAM_SOCKS5_AUTH", "alice:s3cret")
+        .env("PJ_CONNECT_TIMEOUT", "5s")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    let mut bad_auth_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", bad_auth_listen_addr, echo_server_addr)])
+        .env("PJ_UPSTREAM_SOCKS5", socks5_addr)
+        .env("PJ_UPSTREAM_SOCKS5_AUTH", "alice:wrong")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let test_message = b"Hello through the egress proxy";
+    client.write_all(test_message).await.unwrap();
+    let mut buffer = vec![0u8; test_message.len()];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .expect("Timeout waiting for response")
+        .expect("Failed to read response");
+    assert_eq!(&buffer[..], test_message);
+    drop(client);
+    
+    // A proxy that turns the credentials down fails the connect, closing the client
+    let mut client = TcpStream::connect(bad_auth_listen_addr).await.expect("Failed to connect to proxy");
+    let _ = client.write_all(b"hello").await;
+    let mut response = Vec::new();
+    let read = timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .expect("The client should be closed when the tunnel is refused");
+    assert!(read.map_or(true, |_| response.is_empty()), "Nothing should be relayed: {:?}", response);
+    
+    bad_auth_process.kill().expect("Failed to kill proxy process");
+    let output = bad_auth_process.wait_with_output().expect("Failed to collect proxy output");
+    let logs = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    assert!(logs.contains("proxy rejected the username or password"), "Should log why the tunnel failed: {}", logs);
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    socks5_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+    let _ = socks5_process.wait();
+}
+
+#[tokio::test]
+async fn test_statsd_metrics() {
+    let echo_server_addr = "127.0.0.1:19028";
+    let proxy_listen_addr = "127.0.0.1:19029";
+    let statsd_addr = "127.0.0.1:19030";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    let sink = tokio::net::UdpSocket::bind(statsd_addr).await.expect("Failed to bind StatsD sink");
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}?name=web", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_STATSD_ADDR", statsd_addr)
+        .env("PJ_STATSD_TAGS", "1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    client.write_all(b"metrics").await.unwrap();
+    let mut buffer = [0u8; 7];
+    client.read_exact(&mut buffer).await.unwrap();
+    drop(client);
+    
+    // Collect datagrams until the end-of-connection timing shows up
+    let mut lines = Vec::new();
+    let mut buf = [0u8; 2048];
+    let collect = async {
+        while !lines.iter().any(|l: &String| l.starts_with("pj.connection.duration:")) {
+            let n = sink.recv(&mut buf).await.expect("Failed to receive metrics");
+            lines.extend(String::from_utf8_lossy(&buf[..n]).lines().map(str::to_string));
+        }
+    };
+    timeout(Duration::from_secs(5), collect).await.expect("Timeout waiting for metrics");
+    println!("Metrics:\n{}", lines.join("\n"));
+    
+    let tags = format!("|#name:web,backend:{}", echo_server_addr);
+    assert!(lines.contains(&format!("pj.connections.accepted:1|c{}", tags)));
+    assert!(lines.contains(&"pj.connections.active:1|g|#name:web".to_string()));
+    assert!(lines.contains(&"pj.connections.active:0|g|#name:web".to_string()));
+    assert!(lines.iter().any(|l| l.starts_with("pj.connection.duration:") && l.ends_with(&format!("|ms{}", tags))));
+    assert!(!lines.iter().any(|l| l.starts_with("pj.connections.failed")));
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_ip_hash_sticky_routing() {
+    let backend_addrs = ["127.0.0.1:19031", "127.0.0.1:19032", "127.0.0.1:19033"];
+    let proxy_listen_addr = "127.0.0.1:19034";
+    
+    let _a = start_tagged_server(backend_addrs[0], b"A:").await;
+    let _b = start_tagged_server(backend_addrs[1], b"B:").await;
+    let _c = start_tagged_server(backend_addrs[2], b"C:").await;
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, backend_addrs.join("|"))])
+        .env("PJ_LB_STRATEGY", "ip_hash")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut tags = Vec::new();
+    for _ in 0..10 {
+        let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+        client.write_all(b"x").await.unwrap();
+        let mut buffer = [0u8; 3];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        tags.push(buffer[..2].to_vec());
+    }
+    
+    assert!(tags.iter().all(|tag| tag == &tags[0]), "Every connection from loopback should hit the same backend");
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_weighted_load_balancing() {
+    let heavy_addr = "127.0.0.1:19035";
+    let light_addr = "127.0.0.1:19036";
+    let proxy_listen_addr = "127.0.0.1:19037";
+    
+    let _heavy = start_tagged_server(heavy_addr, b"H:").await;
+    let _light = start_tagged_server(light_addr, b"L:").await;
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}*3|{}", proxy_listen_addr, heavy_addr, light_addr)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut heavy = 0;
+    let total = 40;
+    for _ in 0..total {
+        let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+        client.write_all(b"x").await.unwrap();
+        let mut buffer = [0u8; 3];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        if &buffer[..2] == b"H:" {
+            heavy += 1;
+        }
+    }
+    
+    assert!((27..=33).contains(&heavy), "Expected about 3/4 of {} connections on the heavy backend, got {}", total, heavy);
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_coalesced_flush() {
+    let echo_server_addr = "127.0.0.1:19041";
+    let proxy_listen_addr = "127.0.0.1:19042";
+    
+    let _echo_server = start_echo_server(echo_server_addr).await.unwrap();
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .env("PJ_FLUSH_MODE", "coalesce")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    // Many tiny writes, then a bulk one that fills whole read buffers
+    let mut expected = Vec::new();
+    for i in 0..5000u32 {
+        expected.extend_from_slice(format!("{:07}\n", i).as_bytes());
+    }
+    expected.extend((0..64 * 1024).map(|i| (i % 251) as u8));
+    
+    let client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    let (mut reader, mut writer) = client.into_split();
+    let sent = expected.clone();
+    let writer_task = tokio::spawn(async move {
+        for chunk in sent[..5000 * 8].chunks(8) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        writer.write_all(&sent[5000 * 8..]).await.unwrap();
+        // Kept open so only the flush timer can release a held-back tail
+        writer
+    });
+    
+    let mut received = vec![0u8; expected.len()];
+    timeout(Duration::from_secs(10), reader.read_exact(&mut received))
+        .await
+        .expect("Timeout waiting for coalesced data")
+        .expect("Failed to read response");
+    assert!(received == expected, "Relayed bytes differ from what was sent");
+    
+    drop(writer_task.await.unwrap());
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_upstream_from_command() {
+    let echo_server_addr = "127.0.0.1:19043";
+    let proxy_listen_addr = "127.0.0.1:19044";
+    // Nothing listens here; the command's answer must win
+    let static_upstream = "127.0.0.1:19045";
+    
+    let _echo_server = start_echo_server(echo_server_addr).await.unwrap();
+    
+    let script = std::env::temp_dir().join(format!("pj-upstream-{}.sh", std::process::id()));
+    std::fs::write(&script, format!("#!/bin/sh\necho {}\n", echo_server_addr)).unwrap();
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}", proxy_listen_addr, static_upstream)])
+        .env("PJ_UPSTREAM_CMD", format!("sh {}", script.display()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    client.write_all(b"discovered").await.unwrap();
+    let mut buffer = [0u8; 10];
+    let result = timeout(Duration::from_secs(5), client.read_exact(&mut buffer)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let _ = std::fs::remove_file(&script);
+    let combined_output = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    println!("Proxy output:\n{}", combined_output);
+    
+    result.expect("Timeout waiting for response").expect("Failed to read response");
+    assert_eq!(&buffer, b"discovered");
+    assert!(combined_output.contains(&format!("Upstream command returned {}", echo_server_addr)));
+    assert!(combined_output.contains(&format!("-> {}", echo_server_addr)), "Should connect to the command's upstream");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_socket_activation() {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+    
+    let echo_server_addr = "127.0.0.1:19038";
+    let inherited_addr = "127.0.0.1:19039";
+    let mapping_listen_addr = "127.0.0.1:19040";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    // Pre-bind the socket the way systemd would
+    let listener = std::net::TcpListener::bind(inherited_addr).expect("Failed to pre-bind socket");
+    let fd = listener.as_raw_fd();
+    
+    // The shell execs the proxy, so $$ is the proxy's PID
+    let script = format!(
+        "LISTEN_PID=$$ LISTEN_FDS=1 exec {} --proxy {}:{}",
+        env!("CARGO_BIN_EXE_pj"), mapping_listen_addr, echo_server_addr
+    );
+    let mut command = Command::new("sh");
+    command.args(["-c", &script]).stdout(Stdio::piped()).stderr(Stdio::piped());
+    // Hand the socket over as fd 3, where systemd puts the first one
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(fd, 3) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut proxy_process = command.spawn().expect("Failed to start proxy");
+    drop(listener);
+    
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    
+    let mut client = TcpStream::connect(inherited_addr).await.expect("Inherited socket should accept connections");
+    let test_message = b"Hello, systemd!";
+    client.write_all(test_message).await.unwrap();
+    let mut buffer = vec![0u8; test_message.len()];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .expect("Timeout waiting for response")
+        .expect("Failed to read response");
+    assert_eq!(&buffer[..], test_message);
+    
+    // The mapping's own address was never bound
+    assert!(TcpStream::connect(mapping_listen_addr).await.is_err(), "Proxy should not bind the mapping address");
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    println!("Proxy output:\n{}", combined);
+    assert!(combined.contains("Using inherited socket (fd 3"), "Should log the inherited socket");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_listener_bound_to_interface() {
+    let echo_server_addr = "127.0.0.1:19050";
+    let proxy_listen_addr = "127.0.0.1:19051";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    let mut proxy_process = Command::new(env!("CARGO_BIN_EXE_pj"))
+        .args(["--proxy", &format!("{}:{}?iface=lo", proxy_listen_addr, echo_server_addr)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    
+    // Loopback traffic arrives on lo, so the bound listener still takes it
+    let mut client = TcpStream::connect(proxy_listen_addr).await.expect("Listener on lo should accept loopback connections");
+    let test_message = b"Hello, lo!";
+    client.write_all(test_message).await.unwrap();
+    let mut buffer = vec![0u8; test_message.len()];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .expect("Timeout waiting for response")
+        .expect("Failed to read response");
+    assert_eq!(&buffer[..], test_message);
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    println!("Proxy output:\n{}", combined);
+    assert!(combined.contains("on interface lo"), "Should log the interface binding");
+}
+
+#[tokio::test]
+async fn test_connect_destination_rules() {
+    let echo_server_addr = "127.0.0.1:19052";
+    let denied_server_addr = "127.0.0.1:19053";
+    let proxy_listen_addr = "127.0.0.1:19054";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    let _denied_handle = start_echo_server(denied_server_addr).await.expect("Failed to start echo server");
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("connect://{}", proxy_listen_addr)])
+        .env("PJ_CONNECT_ALLOW", "127.0.0.0/8:*")
+        .env("PJ_CONNECT_DENY", "*:19053")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    // A name is admitted by the allowed network it resolves into
+    for target in [echo_server_addr, "localhost:19052"] {
+        let (mut client, head) = connect_request(proxy_listen_addr, target).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "Unexpected CONNECT response for {}: {}", target, head);
+        client.write_all(b"allowed").await.unwrap();
+        let mut buffer = [0u8; 7];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        assert_eq!(&buffer, b"allowed");
+    }
+    
+    // The denied port is refused although its network is allowed and it is listening
+    let (_client, head) = connect_request(proxy_listen_addr, denied_server_addr).await;
+    assert!(head.starts_with("HTTP/1.1 403"), "Unexpected response for denied port: {}", head);
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_forwarded_for_added_to_first_request() {
+    let http_backend_addr = "127.0.0.1:19055";
+    let raw_backend_addr = "127.0.0.1:19056";
+    let proxy_listen_addr = "127.0.0.1:19057";
+    
+    // The HTTP backend echoes what it receives, headers included
+    let _http_handle = start_echo_server(http_backend_addr).await.expect("Failed to start echo server");
+    let _raw_handle = start_tagged_server(raw_backend_addr, b"B:").await;
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}?http={}", proxy_listen_addr, raw_backend_addr, http_backend_addr)])
+        .env("PJ_FORWARDED_FOR", "1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+    // The headers follow the request line separately, after detection has routed it
+    client.write_all(b"GET /first HTTP/1.1\r\n").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.write_all(b"Host: localhost\r\n\r\n").await.unwrap();
+    
+    let expected = b"GET /first HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 127.0.0.1\r\n\r\n";
+    let mut buffer = vec![0u8; expected.len()];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .expect("Timeout waiting for response")
+        .expect("Failed to read response");
+    assert_eq!(String::from_utf8_lossy(&buffer), String::from_utf8_lossy(expected));
+    
+    // Later requests on the connection are relayed untouched
+    let second = b"GET /second HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    client.write_all(second).await.unwrap();
+    let mut buffer = vec![0u8; second.len()];
+    timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+        .await
+        .expect("Timeout waiting for response")
+        .expect("Failed to read response");
+    assert_eq!(&buffer, second);
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let _ = proxy_process.wait();
+}
+
+#[tokio::test]
+async fn test_failover_to_secondary_tier() {
+    let primary_addr = "127.0.0.1:19058";  // Nothing listens here at first
+    let secondary_addr = "127.0.0.1:19059";
+    let proxy_listen_addr = "127.0.0.1:19060";
+    
+    let _secondary = start_tagged_server(secondary_addr, b"S:").await;
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("{}:{}>{}", proxy_listen_addr, primary_addr, secondary_addr)])
+        // The failed primary is retried on the secondary within the same connection
+        .env("PJ_CONNECT_ATTEMPTS", "2")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    let tag = || async {
+        let mut client = TcpStream::connect(proxy_listen_addr).await.unwrap();
+        client.write_all(b"x").await.unwrap();
+        let mut buffer = [0u8; 3];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        String::from_utf8_lossy(&buffer[..2]).to_string()
+    };
+    for _ in 0..3 {
+        assert_eq!(tag().await, "S:", "The secondary should serve while the primary is down");
+    }
+    
+    // Once its failure cooldown is over, the primary takes the traffic back
+    let _primary = start_tagged_server(primary_addr, b"P:").await;
+    tokio::time::sleep(Duration::from_secs(11)).await;
+    for _ in 0..3 {
+        assert_eq!(tag().await, "P:", "The primary should serve again once it is back");
+    }
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    assert!(combined.contains("Failing over from the primary tier to the secondary tier"), "Should log the failover: {}", combined);
+    assert!(combined.contains("Recovered from the secondary tier to the primary tier"), "Should log the recovery: {}", combined);
+    assert!(combined.contains(&format!("-> {} (secondary tier)", secondary_addr)), "Should log the tier serving each connection: {}", combined);
+}
+
+#[tokio::test]
+async fn test_listen_group_fans_in_to_one_upstream() {
+    let echo_server_addr = "127.0.0.1:19065";
+    let ipv4_listen_addr = "127.0.0.1:19066";
+    // The IPv6 wildcard on the same port must leave the IPv4 listener its port
+    let ipv6_listen_addr = "[::]:19066";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    let mut proxy_process = Command::new("cargo")
+        .args(["run", "--", "--proxy", &format!("listen={},{} -> {}", ipv4_listen_addr, ipv6_listen_addr, echo_server_addr)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    
+    for (client_addr, message) in [(ipv4_listen_addr, &b"Hello over IPv4"[..]), ("[::1]:19066", &b"Hello over IPv6"[..])] {
+        let mut client = TcpStream::connect(client_addr).await.unwrap_or_else(|e| panic!("Failed to connect to {}: {}", client_addr, e));
+        client.write_all(message).await.unwrap();
+        let mut buffer = vec![0u8; message.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        assert_eq!(buffer, message, "Both listeners should relay to the echo server");
+    }
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    for listen_addr in [ipv4_listen_addr, ipv6_listen_addr] {
+        assert!(
+            combined.contains(&format!("listening on {}, proxying to {}", listen_addr, echo_server_addr)),
+            "Should add a mapping for {}: {}", listen_addr, combined
+        );
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shutdown_summary_after_drain() {
+    let echo_server_addr = "127.0.0.1:19068";
+    let proxy_listen_addr = "127.0.0.1:19069";
+    
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    // Run the binary itself so the signal reaches the proxy, not cargo
+    let mut proxy_process = Command::new(env!("CARGO_BIN_EXE_pj"))
+        .args(["--proxy", &format!("{}:{}", proxy_listen_addr, echo_server_addr)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start proxy");
+    
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    
+    async fn echo(client: &mut TcpStream) {
+        client.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+    }
+    // Two at once, then a third that is still open when the proxy is told to stop
+    let mut first = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    let mut second = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    echo(&mut first).await;
+    echo(&mut second).await;
+    drop((first, second));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let mut third = TcpStream::connect(proxy_listen_addr).await.expect("Failed to connect to proxy");
+    echo(&mut third).await;
+    
+    unsafe { libc::kill(proxy_process.id() as i32, libc::SIGTERM) };
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    drop(third);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    
+    proxy_process.kill().expect("Failed to kill proxy process");
+    let output = proxy_process.wait_with_output().expect("Failed to get proxy output");
+    let combined = format!("{}\n{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    let summary = combined
+        .find("Shutdown summary: ")
+        .unwrap_or_else(|| panic!("Should log a summary at shutdown: {}", combined));
+    assert!(
+        combined[summary..].starts_with("Shutdown summary: 3 connections served | Sent: 15 B | Received: 15 B | Peak: 2 concurrent | Uptime: "),
+        "{}", &combined[summary..]
+    );
+    let last_close = combined.find("Conn #2 close ").unwrap_or_else(|| panic!("Should close the third connection: {}", combined));
+    assert!(last_close < summary, "The summary should wait for the open connection: {}", combined);
+}
+
+#[tokio::test]
+async fn test_injected_delay_slows_round_trip() {
+    let echo_server_addr = "127.0.0.1:19070";
+    let _echo_handle = start_echo_server(echo_server_addr).await.expect("Failed to start echo server");
+    
+    async fn round_trip(inject_delay: Option<InjectedDelay>, echo_server_addr: &str) -> Duration {
+        let options = ProxyOptions { inject_delay, ..Default::default() };
+        let proxy = TestProxy::start(&format!("127.0.0.1:0:{}", echo_server_addr), options)
+            .await
+            .expect("Failed to start proxy");
+        let mut client = TcpStream::connect(proxy.addr()).await.expect("Failed to connect to proxy");
+        let started = Instant::now();
+        client.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        timeout(Duration::from_secs(5), client.read_exact(&mut buffer))
+            .await
+            .expect("Timeout waiting for response")
+            .expect("Failed to read response");
+        let elapsed = started.elapsed();
+        assert_eq!(&buffer, b"hello");
+        proxy.shutdown().await;
+        elapsed
+    }
+    
+    let delay = Duration::from_millis(150);
+    let baseline = round_trip(None, echo_server_addr).await;
+    let both = round_trip(Some(InjectedDelay { delay, direction: DelayDirection::Both }), echo_server_addr).await;
+    let up = round_trip(Some(InjectedDelay { delay, direction: DelayDirection::Up }), echo_server_addr).await;
+    
+    // Each way delayed adds its sleep to the round trip
+    assert!(baseline < delay, "Without a delay the round trip should be quick: {:?}", baseline);
+    assert!(both >= delay * 2, "Delaying both ways should add {:?} each way: {:?}", delay, both);
+    assert!(up >= delay && up < delay * 2, "Delaying only the way up should add {:?} once: {:?}", delay, up);
+}